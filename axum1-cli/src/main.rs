@@ -22,12 +22,33 @@ struct Cli {
 enum Commands {
     /// Trigger a MeiliSearch indexing task on the server.
     Index,
-    /// Resume the indexing
+    /// Resume the scheduled indexing
     Resume,
-    /// Pause the indexing
+    /// Pause the scheduled indexing
     Pause,
+    /// List recurring jobs with their next run times
+    Schedules,
+    /// Change the cron expression of a recurring job, e.g. `schedule refresh_hot_recipes "0 */5 * * * *"`
+    Schedule { name: String, expr: String },
     /// Reload config
     ReloadConfig,
+    /// Manage jobs that exhausted their retries
+    #[command(subcommand)]
+    FailedJobs(FailedJobsCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum FailedJobsCommand {
+    /// List the most recently failed jobs, optionally of a single type
+    List { job_type: Option<String> },
+    /// Show a failed job with its payload and last error
+    Show { id: String },
+    /// Put a failed job back into the queue with fresh attempts
+    Requeue { id: String },
+    /// Delete a single failed job
+    Delete { id: String },
+    /// Delete all failed jobs, optionally only those of a single type
+    Purge { job_type: Option<String> },
 }
 
 #[tokio::main]
@@ -44,20 +65,41 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // TODO: Tracing instead of println.
     println!("Connected to {:?}", socket_path);
     let msg = match &cli.command {
-        Some(Commands::Index) => "index",
-        Some(Commands::Resume) => "resume",
-        Some(Commands::Pause) => "pause",
-        Some(Commands::ReloadConfig) => "reload_config",
-        _ => "todo",
+        Some(Commands::Index) => "index".to_string(),
+        Some(Commands::Resume) => "resume".to_string(),
+        Some(Commands::Pause) => "pause".to_string(),
+        Some(Commands::Schedules) => "schedules".to_string(),
+        Some(Commands::Schedule { name, expr }) => format!("schedule {name} {expr}"),
+        Some(Commands::ReloadConfig) => "reload_config".to_string(),
+        Some(Commands::FailedJobs(command)) => match command {
+            FailedJobsCommand::List { job_type } => {
+                format!(
+                    "failed_jobs list {}",
+                    job_type.as_deref().unwrap_or_default()
+                )
+            }
+            FailedJobsCommand::Show { id } => format!("failed_jobs show {id}"),
+            FailedJobsCommand::Requeue { id } => format!("failed_jobs requeue {id}"),
+            FailedJobsCommand::Delete { id } => format!("failed_jobs delete {id}"),
+            FailedJobsCommand::Purge { job_type } => {
+                format!(
+                    "failed_jobs purge {}",
+                    job_type.as_deref().unwrap_or_default()
+                )
+            }
+        },
+        _ => "todo".to_string(),
     };
 
     stream.write_all(msg.as_bytes()).await?;
 
-    let mut buf = [0; 1024];
+    // Listing failed jobs may not fit into a single read, the server closes the connection once
+    // it's done responding.
+    let mut buf = Vec::new();
 
-    let n = stream.read(&mut buf).await?;
+    stream.read_to_end(&mut buf).await?;
 
-    let response = String::from_utf8_lossy(&buf[..n]);
+    let response = String::from_utf8_lossy(&buf);
 
     println!("Received response: {}", response);
 
@@ -0,0 +1,22 @@
+-- Uploads are stored by their BLAKE3 content hash, so identical files only take up disk space once,
+-- no matter who uploaded them or under what name. `ref_count` tracks how many rows in `uploads`
+-- point to a blob, the file on disk is removed once it drops to zero.
+CREATE TABLE "upload_blobs"
+(
+    hash         TEXT PRIMARY KEY,
+
+    bytes        INT NOT NULL,
+
+    ref_count    INT NOT NULL DEFAULT 0 CHECK (ref_count >= 0),
+
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at   TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('"upload_blobs"');
+
+-- Uploads from before content addressing don't have a blob, they still live under `uploads/<uploader_id>/`.
+ALTER TABLE uploads ADD COLUMN blob_hash TEXT REFERENCES upload_blobs (hash);
+
+CREATE INDEX uploads_blob_hash_idx ON uploads (blob_hash);
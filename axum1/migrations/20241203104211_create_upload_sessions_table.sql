@@ -0,0 +1,25 @@
+-- Resumable uploads that are still in progress. The chunks received so far are appended to
+-- `uploads/tmp/<id>.part`, `bytes_received` is the offset where the next chunk has to start.
+--
+-- Open sessions count against the daily upload quota with their full `bytes_total`, so
+-- a quota can't be exceeded by starting many sessions at once.
+CREATE TABLE "upload_sessions"
+(
+    id              UUID PRIMARY KEY DEFAULT uuid_generate_v1mc(),
+
+    uploader_id     UUID NOT NULL REFERENCES "users" (user_id) ON DELETE CASCADE,
+
+    file_name       TEXT NOT NULL,
+
+    bytes_total     INT NOT NULL CHECK (bytes_total > 0),
+
+    bytes_received  INT NOT NULL DEFAULT 0 CHECK (bytes_received <= bytes_total),
+
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at      TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('"upload_sessions"');
+
+CREATE INDEX upload_sessions_uploader_id_idx ON upload_sessions (uploader_id);
@@ -0,0 +1,19 @@
+CREATE TYPE scan_status AS ENUM (
+    'pending',
+    'clean',
+    'quarantined'
+);
+
+-- Everything uploaded before scanning existed is considered clean, from now on uploads start out as pending
+-- and only become downloadable once the scanner has looked at them.
+ALTER TABLE uploads ADD COLUMN scan_status scan_status NOT NULL DEFAULT 'clean';
+ALTER TABLE uploads ALTER COLUMN scan_status SET DEFAULT 'pending';
+
+-- Works the same way as `confirmation_delivery_queue`.
+CREATE TABLE upload_scan_queue (
+    uploader_id UUID NOT NULL,
+    file_name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (uploader_id, file_name),
+    FOREIGN KEY (uploader_id, file_name) REFERENCES uploads (uploader_id, file_name) ON DELETE CASCADE
+);
@@ -0,0 +1,36 @@
+-- A generic persistent job queue. Workers pick up due jobs with `FOR UPDATE SKIP LOCKED`, highest priority first.
+-- `job_type` selects the handler, `payload` is the serialized job itself.
+CREATE TABLE jobs
+(
+    id          UUID PRIMARY KEY DEFAULT uuid_generate_v1mc(),
+
+    job_type    TEXT NOT NULL,
+
+    payload     JSONB NOT NULL,
+
+    run_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    attempts    INT NOT NULL DEFAULT 0,
+
+    priority    INT NOT NULL DEFAULT 0,
+
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at  TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('jobs');
+
+CREATE INDEX jobs_dequeue_idx ON jobs (priority DESC, run_at);
+
+-- Carry over whatever is still waiting in the single-purpose queues.
+INSERT INTO jobs (job_type, payload, priority)
+SELECT 'confirmation_email', jsonb_build_object('email', user_email, 'confirmation_token', confirmation_id), 10
+FROM confirmation_delivery_queue;
+
+INSERT INTO jobs (job_type, payload, run_at)
+SELECT 'upload_scan', jsonb_build_object('uploader_id', uploader_id, 'file_name', file_name), created_at
+FROM upload_scan_queue;
+
+DROP TABLE confirmation_delivery_queue;
+DROP TABLE upload_scan_queue;
@@ -0,0 +1,19 @@
+-- Failed jobs are retried with exponential backoff. `last_error` keeps the most recent failure around for debugging.
+ALTER TABLE jobs ADD COLUMN last_error TEXT;
+
+-- Jobs only end up here once their attempts are exhausted. Keep everything that's needed to requeue them.
+ALTER TABLE failed_jobs
+    ADD COLUMN payload  JSONB,
+    ADD COLUMN error    TEXT,
+    ADD COLUMN attempts INT NOT NULL DEFAULT 0,
+    ADD COLUMN priority INT NOT NULL DEFAULT 0;
+
+-- Jobs that failed since the generic queue was introduced stored their payload in `context`.
+-- Rows from before that don't have a usable payload, so they can only be inspected and purged.
+UPDATE failed_jobs
+SET payload  = context::jsonb -> 'payload',
+    error    = context::jsonb ->> 'error',
+    attempts = 1
+WHERE context::jsonb ? 'payload';
+
+CREATE INDEX failed_jobs_failed_at_idx ON failed_jobs (failed_at DESC);
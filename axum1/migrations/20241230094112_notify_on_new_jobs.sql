@@ -0,0 +1,17 @@
+-- Idle workers `LISTEN` on the `jobs` channel instead of polling, so every job that's due right away wakes one up.
+-- Jobs scheduled for later (including retries) are still picked up by the workers' fallback poll.
+CREATE OR REPLACE FUNCTION notify_job_inserted()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    PERFORM pg_notify('jobs', NEW.job_type);
+    RETURN NULL;
+end;
+$$ language plpgsql;
+
+CREATE TRIGGER notify_job_inserted
+    AFTER INSERT
+    ON jobs
+    FOR EACH ROW
+    WHEN (NEW.run_at <= NOW())
+EXECUTE FUNCTION notify_job_inserted();
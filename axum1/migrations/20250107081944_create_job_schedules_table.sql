@@ -0,0 +1,34 @@
+-- Recurring jobs. Every replica runs the scheduler, but only the one that manages to lock a due row enqueues its job
+-- and moves `next_run_at` forward, so each occurrence is enqueued exactly once.
+CREATE TABLE job_schedules
+(
+    name            TEXT PRIMARY KEY,
+
+    cron_expression TEXT NOT NULL,
+
+    next_run_at     TIMESTAMPTZ NOT NULL,
+
+    last_run_at     TIMESTAMPTZ,
+
+    paused          BOOLEAN NOT NULL DEFAULT FALSE,
+
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at      TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('job_schedules');
+
+-- Expired tokens are cleaned up periodically, which needs to know how old they are.
+ALTER TABLE confirmation_tokens ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+-- "Hot" recipes are the most favorited ones of the last two weeks. Counting that on every request doesn't scale,
+-- so it's recomputed periodically instead. The unique index allows `REFRESH MATERIALIZED VIEW CONCURRENTLY`.
+CREATE MATERIALIZED VIEW hot_recipes AS
+SELECT r.id AS recipe_id, r.name, COUNT(fr.recipe_id) AS favorites
+FROM favorite_recipe fr
+INNER JOIN recipes r ON r.id = fr.recipe_id
+WHERE fr.created_at > current_timestamp - INTERVAL '14 days'
+GROUP BY r.id, r.name;
+
+CREATE UNIQUE INDEX hot_recipes_recipe_id_idx ON hot_recipes (recipe_id);
@@ -0,0 +1,2 @@
+-- The language emails are sent in. It's taken from `Accept-Language` on registration.
+ALTER TABLE users ADD COLUMN locale TEXT NOT NULL DEFAULT 'en';
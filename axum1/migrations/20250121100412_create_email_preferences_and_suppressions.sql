@@ -0,0 +1,40 @@
+-- Categories of non-transactional email that users can opt out of. Transactional email (confirmations, password
+-- resets, security notices) is always sent, so it has no category here.
+CREATE TYPE email_category AS ENUM ('digest', 'announcements');
+
+-- Users are subscribed to every category until they opt out, so only explicit choices are stored.
+CREATE TABLE email_preferences
+(
+    user_id    UUID           NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    category   email_category NOT NULL,
+
+    enabled    BOOLEAN        NOT NULL,
+
+    created_at TIMESTAMPTZ    NOT NULL DEFAULT NOW(),
+
+    updated_at TIMESTAMPTZ,
+
+    PRIMARY KEY (user_id, category)
+);
+
+SELECT trigger_updated_at('email_preferences');
+
+CREATE TYPE suppression_reason AS ENUM ('hard_bounce', 'spam_complaint', 'manual');
+
+-- Addresses that bounced or complained. Non-transactional email is never sent to them.
+CREATE TABLE email_suppressions
+(
+    email      TEXT COLLATE "case_insensitive" PRIMARY KEY,
+
+    reason     suppression_reason NOT NULL,
+
+    -- Whatever the provider's webhook told us, for debugging.
+    details    JSONB,
+
+    created_at TIMESTAMPTZ        NOT NULL DEFAULT NOW(),
+
+    updated_at TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('email_suppressions');
@@ -0,0 +1,21 @@
+-- One row per user and week the weekly digest was handled for, so reruns of the digest job don't send it twice.
+-- The row is inserted in the same transaction that sends the digest, which also keeps concurrent runs for the same
+-- user and week from both sending it.
+CREATE TABLE digest_deliveries
+(
+    user_id    UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    -- The Monday the digest's week starts on.
+    week       DATE        NOT NULL,
+
+    -- `FALSE` if there was nothing to tell the user about, or they opted out in the meantime.
+    sent       BOOLEAN     NOT NULL,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at TIMESTAMPTZ,
+
+    PRIMARY KEY (user_id, week)
+);
+
+SELECT trigger_updated_at('digest_deliveries');
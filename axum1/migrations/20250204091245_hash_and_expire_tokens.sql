@@ -0,0 +1,34 @@
+-- From now on only hashes of confirmation and password reset tokens are stored, and every token has its own expiry
+-- instead of a window hardcoded in the queries. Outstanding tokens can't be converted, so they're dropped: unconfirmed
+-- users can ask for a new confirmation email, and password resets can be requested again.
+DROP TABLE confirmation_tokens;
+DROP TABLE forget_password_tokens;
+
+CREATE TABLE confirmation_tokens
+(
+    -- The tokens are long random strings rather than passwords, so an unsalted hash is enough to keep a leaked
+    -- database from confirming accounts.
+    token_hash TEXT        PRIMARY KEY,
+
+    user_id    UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+-- Using any token of a user invalidates all the others.
+CREATE INDEX confirmation_tokens_user_id_idx ON confirmation_tokens (user_id);
+
+CREATE TABLE forget_password_tokens
+(
+    token_hash TEXT        PRIMARY KEY,
+
+    user_id    UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX forget_password_tokens_user_id_idx ON forget_password_tokens (user_id);
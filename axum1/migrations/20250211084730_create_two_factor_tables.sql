@@ -0,0 +1,32 @@
+-- TOTP secrets for two-factor authentication. A row without `confirmed_at` is an enrollment that wasn't confirmed
+-- with a code yet, and doesn't count until it is.
+CREATE TABLE user_totp
+(
+    user_id        UUID PRIMARY KEY REFERENCES users (user_id) ON DELETE CASCADE,
+
+    -- Base32, the way authenticator apps take it.
+    secret         TEXT        NOT NULL,
+
+    confirmed_at   TIMESTAMPTZ,
+
+    -- The time step of the last accepted code, so a code can't be used twice.
+    last_used_step BIGINT      NOT NULL DEFAULT 0,
+
+    created_at     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at     TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('user_totp');
+
+-- One-time codes for when the authenticator is lost. Like other tokens, only their hashes are stored.
+CREATE TABLE recovery_codes
+(
+    code_hash  TEXT PRIMARY KEY,
+
+    user_id    UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX recovery_codes_user_id_idx ON recovery_codes (user_id);
@@ -0,0 +1,29 @@
+-- Accounts may log in with passkeys only, those don't have a password.
+ALTER TABLE users ALTER COLUMN password_hash DROP NOT NULL;
+
+-- WebAuthn credentials. A user can have several, e.g. one per device.
+CREATE TABLE passkeys
+(
+    id            UUID PRIMARY KEY     DEFAULT uuid_generate_v1mc(),
+
+    user_id       UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    -- Raw credential ID the authenticator sends along with assertions.
+    credential_id BYTEA       NOT NULL UNIQUE,
+
+    -- Given by the user, to tell their passkeys apart.
+    name          TEXT        NOT NULL,
+
+    -- The credential as webauthn-rs serializes it, including its public key and signature counter.
+    passkey       JSONB       NOT NULL,
+
+    last_used_at  TIMESTAMPTZ,
+
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at    TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('passkeys');
+
+CREATE INDEX passkeys_user_id_idx ON passkeys (user_id);
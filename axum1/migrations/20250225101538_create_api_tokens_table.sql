@@ -0,0 +1,32 @@
+-- What an API token may be used for. `read` covers safe requests (GET, HEAD, OPTIONS), `write` every other
+-- request too, and `admin` the admin API.
+CREATE TYPE token_scope AS ENUM ('read', 'write', 'admin');
+
+-- Personal access tokens for scripts and other clients that don't keep a session cookie. Only their hashes are
+-- stored, like other tokens.
+CREATE TABLE api_tokens
+(
+    id           UUID PRIMARY KEY       DEFAULT uuid_generate_v1mc(),
+
+    user_id      UUID          NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    -- Given by the user, to tell their tokens apart.
+    name         TEXT          NOT NULL,
+
+    token_hash   TEXT          NOT NULL UNIQUE,
+
+    scopes       token_scope[] NOT NULL,
+
+    expires_at   TIMESTAMPTZ   NOT NULL,
+
+    -- Updated at most once a minute, to spare a write on every request.
+    last_used_at TIMESTAMPTZ,
+
+    created_at   TIMESTAMPTZ   NOT NULL DEFAULT NOW(),
+
+    updated_at   TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('api_tokens');
+
+CREATE INDEX api_tokens_user_id_idx ON api_tokens (user_id);
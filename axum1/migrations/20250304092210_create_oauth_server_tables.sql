@@ -0,0 +1,92 @@
+-- Third-party apps that act on behalf of our users, with us as the OAuth2 authorization server.
+CREATE TABLE oauth_clients
+(
+    client_id     UUID PRIMARY KEY     DEFAULT uuid_generate_v1mc(),
+
+    -- The user who registered the app, and manages it.
+    owner_id      UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    -- Shown on the consent screen.
+    name          TEXT        NOT NULL,
+
+    -- Confidential clients authenticate with a secret, public ones (mobile and single-page apps) only with PKCE.
+    secret_hash   TEXT,
+
+    -- Authorization codes are only sent to these, compared exactly.
+    redirect_uris TEXT[]      NOT NULL,
+
+    -- What the client may ask for at most.
+    scopes        TEXT[]      NOT NULL,
+
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at    TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('oauth_clients');
+
+CREATE INDEX oauth_clients_owner_id_idx ON oauth_clients (owner_id);
+
+-- What users agreed to share with a client, so they're asked again only for more.
+CREATE TABLE oauth_grants
+(
+    user_id    UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    client_id  UUID        NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+
+    scopes     TEXT[]      NOT NULL,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at TIMESTAMPTZ,
+
+    PRIMARY KEY (user_id, client_id)
+);
+
+SELECT trigger_updated_at('oauth_grants');
+
+-- Short-lived and single-use. Like other tokens, only their hashes are stored.
+CREATE TABLE oauth_authorization_codes
+(
+    code_hash      TEXT PRIMARY KEY,
+
+    client_id      UUID        NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+
+    user_id        UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    redirect_uri   TEXT        NOT NULL,
+
+    scopes         TEXT[]      NOT NULL,
+
+    -- The S256 PKCE challenge, the code is only exchanged along with its verifier.
+    code_challenge TEXT        NOT NULL,
+
+    -- Echoed in the ID token, for OpenID Connect clients.
+    nonce          TEXT,
+
+    created_at     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    expires_at     TIMESTAMPTZ NOT NULL
+);
+
+-- Access and refresh tokens issued to clients. Refresh tokens are rotated: using one replaces it.
+CREATE TYPE oauth_token_kind AS ENUM ('access', 'refresh');
+
+CREATE TABLE oauth_tokens
+(
+    token_hash TEXT PRIMARY KEY,
+
+    kind       oauth_token_kind NOT NULL,
+
+    client_id  UUID             NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+
+    user_id    UUID             NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    scopes     TEXT[]           NOT NULL,
+
+    created_at TIMESTAMPTZ      NOT NULL DEFAULT NOW(),
+
+    expires_at TIMESTAMPTZ      NOT NULL
+);
+
+CREATE INDEX oauth_tokens_user_id_client_id_idx ON oauth_tokens (user_id, client_id);
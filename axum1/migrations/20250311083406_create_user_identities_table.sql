@@ -0,0 +1,37 @@
+-- Accounts at external OAuth/OpenID Connect providers that log into an account. A user can link several, one
+-- per provider, replacing the single `oauth_provider`/`oauth_id` pair on `users`.
+CREATE TABLE user_identities
+(
+    id         UUID PRIMARY KEY     DEFAULT uuid_generate_v1mc(),
+
+    user_id    UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    -- The provider's key in the `oauth.providers` configuration.
+    provider   TEXT        NOT NULL,
+
+    -- The provider's ID of the account, the `sub` claim for OpenID Connect.
+    subject    TEXT        NOT NULL,
+
+    -- The email address the provider had for the account when it was linked, only shown to the user.
+    email      TEXT,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at TIMESTAMPTZ,
+
+    UNIQUE (provider, subject),
+    UNIQUE (user_id, provider)
+);
+
+SELECT trigger_updated_at('user_identities');
+
+-- Accounts created by an OAuth login keep the random password they were given, a password reset still works for
+-- them once their last identity is unlinked.
+INSERT INTO user_identities (user_id, provider, subject, email)
+SELECT user_id, oauth_provider, oauth_id, email
+FROM users
+WHERE oauth_provider IS NOT NULL AND oauth_id IS NOT NULL;
+
+ALTER TABLE users DROP CONSTRAINT unique_oauth;
+ALTER TABLE users DROP COLUMN oauth_provider;
+ALTER TABLE users DROP COLUMN oauth_id;
@@ -0,0 +1,29 @@
+-- An index of the sessions users are logged into, which live in Redis, so they can be listed and revoked. Rows are
+-- refreshed at most once a minute while a session is used.
+CREATE TABLE user_sessions
+(
+    -- Shown to users instead of the session ID, which is as good as the session cookie.
+    id           UUID PRIMARY KEY     DEFAULT uuid_generate_v1mc(),
+
+    -- The session's key in the session store, needed to revoke it there.
+    session_id   TEXT        NOT NULL UNIQUE,
+
+    user_id      UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    user_agent   TEXT,
+
+    ip           TEXT        NOT NULL,
+
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    -- When the session expires if it isn't used again, as of `last_seen_at`.
+    expires_at   TIMESTAMPTZ NOT NULL,
+
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at   TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('user_sessions');
+
+CREATE INDEX user_sessions_user_id_idx ON user_sessions (user_id);
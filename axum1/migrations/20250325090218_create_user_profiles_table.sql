@@ -0,0 +1,45 @@
+-- What users tell about themselves, shown on their public page. Created the first time it's edited, users without a
+-- row have an empty profile.
+CREATE TABLE user_profiles
+(
+    user_id              UUID PRIMARY KEY REFERENCES users (user_id) ON DELETE CASCADE,
+
+    -- Shown instead of `users.name` where set. Unlike names, these don't have to be unique.
+    display_name         TEXT,
+
+    bio                  TEXT,
+
+    -- One of the user's own uploads. There's no foreign key, `(user_id, avatar_file_name)` would null `user_id` too
+    -- when the upload is deleted, so deleting an upload clears it instead.
+    avatar_file_name     TEXT,
+
+    -- Suggestions are deleted once they're applied, this is all that's left of them.
+    accepted_suggestions INT         NOT NULL DEFAULT 0,
+
+    created_at           TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    updated_at           TIMESTAMPTZ
+);
+
+SELECT trigger_updated_at('user_profiles');
+
+-- A best-effort backfill of `accepted_suggestions`. Suggestions applied before this migration are gone along with who
+-- made them, the closest thing left is `ingredients.creator_id`: every ingredient that was edited since it was added
+-- is counted for its creator, as if they had suggested the change. Edits admins made directly are counted too, and
+-- ingredients without a creator aren't counted, so the numbers are only roughly right for old accounts.
+INSERT INTO user_profiles (user_id, accepted_suggestions)
+SELECT creator_id, COUNT(*) FROM ingredients
+WHERE creator_id IS NOT NULL AND updated_at IS NOT NULL
+GROUP BY creator_id;
+
+-- Pretty much the same as the `create_favorite_ingredient_table` migration.
+CREATE TABLE user_preferred_cuisines
+(
+    user_id    UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    cuisine_id UUID        NOT NULL REFERENCES cuisines (id) ON DELETE CASCADE,
+
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    PRIMARY KEY (user_id, cuisine_id)
+);
@@ -0,0 +1,24 @@
+-- Every byte counted against the daily upload quota, rows are only ever added. The quota used to be summed over
+-- `uploads`, which forgot about deleted and overwritten uploads, so deleting and uploading again got around it.
+--
+-- Direct uploads are counted once they're stored, resumable ones with their full length when the session is opened.
+CREATE TABLE upload_usage
+(
+    id          UUID PRIMARY KEY     DEFAULT uuid_generate_v1mc(),
+
+    uploader_id UUID        NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+
+    bytes       BIGINT      NOT NULL CHECK (bytes >= 0),
+
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX upload_usage_uploader_id_created_at_idx ON upload_usage (uploader_id, created_at);
+
+-- Carries over what the current quota window has used so far.
+INSERT INTO upload_usage (uploader_id, bytes, created_at)
+SELECT uploader_id, bytes, created_at FROM uploads
+WHERE created_at > NOW() - INTERVAL '1 days'
+UNION ALL
+SELECT uploader_id, bytes_total, created_at FROM upload_sessions
+WHERE created_at > NOW() - INTERVAL '1 days';
@@ -0,0 +1,27 @@
+-- Confirmation and password reset emails used to carry their token in the job payload, which is kept in
+-- `failed_jobs` when sending fails for good. The jobs make the token themselves now, so only the account is
+-- referenced. Tokens that were sent already stay valid, their hashes are untouched.
+UPDATE jobs j
+SET payload = jsonb_build_object('user_id', u.user_id)
+FROM users u
+WHERE j.job_type IN ('confirmation_email', 'password_reset_email')
+  AND u.email = j.payload ->> 'email';
+
+UPDATE failed_jobs f
+SET payload = jsonb_build_object('user_id', u.user_id)
+FROM users u
+WHERE f.job_type IN ('confirmation_email', 'password_reset_email')
+  AND u.email = f.payload ->> 'email';
+
+-- Whatever is left belongs to accounts that are gone.
+DELETE FROM jobs
+WHERE job_type IN ('confirmation_email', 'password_reset_email') AND NOT payload ? 'user_id';
+
+UPDATE failed_jobs
+SET payload = NULL
+WHERE job_type IN ('confirmation_email', 'password_reset_email') AND NOT payload ? 'user_id';
+
+-- Jobs that failed before the generic queue kept their payload in `context`.
+UPDATE failed_jobs
+SET context = (context::jsonb - 'payload')::json
+WHERE job_type IN ('confirmation_email', 'password_reset_email') AND context::jsonb ? 'payload';
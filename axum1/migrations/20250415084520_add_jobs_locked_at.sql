@@ -0,0 +1,4 @@
+-- When a worker claimed the job. Workers keep the row locked while the job runs, but the lock is invisible to anyone
+-- who doesn't try to take it, so this is what tells running jobs apart from pending ones. It's cleared when a failed
+-- job is scheduled for a retry.
+ALTER TABLE jobs ADD COLUMN locked_at TIMESTAMPTZ;
@@ -0,0 +1,9 @@
+-- TOTP secrets are encrypted with a key from the configuration (`auth.totp_secret_key`), the database alone isn't
+-- enough to generate codes. The key isn't available here, so the server encrypts the plaintext `secret`s left from
+-- before on startup, and clears them.
+ALTER TABLE user_totp
+    ADD COLUMN secret_ciphertext BYTEA,
+    ADD COLUMN secret_nonce      BYTEA,
+    ALTER COLUMN secret DROP NOT NULL,
+    ADD CONSTRAINT user_totp_secret_check
+        CHECK (secret IS NOT NULL OR (secret_ciphertext IS NOT NULL AND secret_nonce IS NOT NULL));
@@ -0,0 +1,5 @@
+-- Chunks used to be written while holding the session row's lock, which kept a connection busy for as long as the
+-- client took to send the chunk. Now a chunk claims the session until `writing_until` instead, and other chunks for
+-- the same session are turned away until it's done or the claim runs out.
+ALTER TABLE upload_sessions
+    ADD COLUMN writing_until TIMESTAMPTZ;
@@ -1,16 +1,16 @@
-use meilisearch_sdk::client::Client;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixListener;
+use tokio::net::{UnixListener, UnixStream};
 
 use crate::config::Settings;
-use crate::queue::get_connection_pool;
-use crate::search::run_meili_indexer;
+use crate::queue::{dead_letter, enqueue, get_connection_pool, schedule};
+use crate::search::IndexSearch;
 use crate::task::PausableFutureSupervisor;
-use crate::utils::report_exit;
+
+/// The schedule `pause` and `resume` act on.
+const SEARCH_INDEX_SCHEDULE: &str = "search_index";
 
 pub async fn cli_manager(
     config: tokio::sync::watch::Sender<Settings>,
-    mut supervisor: PausableFutureSupervisor,
     mut _worker: PausableFutureSupervisor,
 ) -> Result<(), anyhow::Error> {
     let cfg = config.borrow().clone();
@@ -28,6 +28,7 @@ pub async fn cli_manager(
     }
 
     let listener = UnixListener::bind(socket_path)?;
+    let pool = get_connection_pool(&cfg.database);
 
     tracing::info!("Listening CLI on {:?}", socket_path);
 
@@ -46,20 +47,26 @@ pub async fn cli_manager(
         match msg.as_ref() {
             "index" => {
                 tracing::warn!("Requested MeiliSearch indexing through CLI..");
-                let result = tokio::spawn(run_meili_indexer_once(config.subscribe())).await;
-                report_exit("Meili indexing", result);
-                socket.write_all(b"ok").await?;
-                supervisor.pause();
+                let result = enqueue(&pool, &IndexSearch).await;
+                respond(&mut socket, result.map(|_| "ok".into()).map_err(Into::into)).await?;
             }
             "resume" => {
                 tracing::warn!("Resuming MeiliSearch indexing from CLI..");
-                supervisor.resume();
-                socket.write_all(b"ok").await?;
+                let result = set_paused(&pool, SEARCH_INDEX_SCHEDULE, false).await;
+                respond(&mut socket, result).await?;
             }
             "pause" => {
                 tracing::warn!("Pausing MeiliSearch indexing from CLI..");
-                supervisor.pause();
-                socket.write_all(b"ok").await?;
+                let result = set_paused(&pool, SEARCH_INDEX_SCHEDULE, true).await;
+                respond(&mut socket, result).await?;
+            }
+            "schedules" => {
+                let result = list_schedules(&pool).await;
+                respond(&mut socket, result).await?;
+            }
+            cmd if cmd.starts_with("schedule ") => {
+                let result = set_cron_expression(&pool, &cmd["schedule ".len()..]).await;
+                respond(&mut socket, result).await?;
             }
             "reload_config" => {
                 tracing::warn!("Reloading configuration..");
@@ -67,6 +74,11 @@ pub async fn cli_manager(
                 tracing::info!("Configuration reloaded.");
                 socket.write_all(b"ok").await?;
             }
+            cmd if cmd.starts_with("failed_jobs") => {
+                let mut args = cmd.split_whitespace().skip(1);
+                let result = failed_jobs_command(&pool, args.next(), args.next()).await;
+                respond(&mut socket, result).await?;
+            }
             cmd => {
                 tracing::warn!("Received command '{cmd}', which is not valid in this context.");
                 socket.write_all(b"error").await?;
@@ -75,15 +87,86 @@ pub async fn cli_manager(
     }
 }
 
-// TODO: it's really similar to other functions in `search.rs`. I'm sure there's a better way.
-// Currently this is only to work around `tokio::spawn` 'static bound.
-pub async fn run_meili_indexer_once(
-    mut config: tokio::sync::watch::Receiver<Settings>,
-) -> Result<(), anyhow::Error> {
-    let Settings {
-        database, meili, ..
-    } = config.borrow_and_update().clone();
-    let meili_client = Client::new(meili.url, Some(meili.master_key))?;
-    let pool = get_connection_pool(&database);
-    run_meili_indexer(&pool, &meili_client).await
+/// Handles `failed_jobs <list|show|requeue|delete|purge> [argument]`, responding with JSON.
+async fn failed_jobs_command(
+    pool: &sqlx::PgPool,
+    subcommand: Option<&str>,
+    argument: Option<&str>,
+) -> Result<String, anyhow::Error> {
+    let job_id = || {
+        argument
+            .ok_or_else(|| anyhow::anyhow!("a job id is required"))?
+            .parse::<uuid::Uuid>()
+            .map_err(anyhow::Error::from)
+    };
+    let response = match subcommand {
+        Some("list") => {
+            serde_json::to_string_pretty(&dead_letter::list(pool, argument, 50).await?)?
+        }
+        Some("show") => match dead_letter::get(pool, job_id()?).await? {
+            Some(job) => serde_json::to_string_pretty(&job)?,
+            None => anyhow::bail!("no such failed job"),
+        },
+        Some("requeue") => {
+            if !dead_letter::requeue(pool, job_id()?).await? {
+                anyhow::bail!("no such failed job, or it has no payload to requeue");
+            }
+            "ok".into()
+        }
+        Some("delete") => {
+            if !dead_letter::delete(pool, job_id()?).await? {
+                anyhow::bail!("no such failed job");
+            }
+            "ok".into()
+        }
+        Some("purge") => {
+            let purged = dead_letter::purge(pool, argument).await?;
+            tracing::warn!("Purged {purged} failed jobs through CLI..");
+            format!("purged {purged} failed jobs")
+        }
+        _ => anyhow::bail!("expected one of list, show, requeue, delete or purge"),
+    };
+    Ok(response)
+}
+
+async fn respond(
+    socket: &mut UnixStream,
+    result: Result<String, anyhow::Error>,
+) -> Result<(), std::io::Error> {
+    match result {
+        Ok(response) => socket.write_all(response.as_bytes()).await,
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, "CLI command failed");
+            socket.write_all(format!("error: {e}").as_bytes()).await
+        }
+    }
+}
+
+/// Lists recurring jobs with their next run times, as JSON.
+async fn list_schedules(pool: &sqlx::PgPool) -> Result<String, anyhow::Error> {
+    Ok(serde_json::to_string_pretty(&schedule::list(pool).await?)?)
+}
+
+async fn set_paused(
+    pool: &sqlx::PgPool,
+    name: &str,
+    paused: bool,
+) -> Result<String, anyhow::Error> {
+    if !schedule::set_paused(pool, name, paused).await? {
+        anyhow::bail!("no such schedule `{name}`");
+    }
+    Ok("ok".into())
+}
+
+/// Handles `schedule <name> <cron expression>`.
+async fn set_cron_expression(pool: &sqlx::PgPool, args: &str) -> Result<String, anyhow::Error> {
+    let (name, expression) = args
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| anyhow::anyhow!("expected a schedule name and a cron expression"))?;
+    tracing::warn!("Changing schedule `{name}` to `{expression}` through CLI..");
+    if !schedule::set_cron_expression(pool, name, expression.trim()).await? {
+        anyhow::bail!("no such schedule `{name}`");
+    }
+    Ok("ok".into())
 }
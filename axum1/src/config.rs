@@ -2,12 +2,19 @@ use secrecy::{ExposeSecret, SecretString};
 use serde::Deserialize;
 use sqlx::{
     postgres::{PgConnectOptions, PgSslMode},
-    ConnectOptions,
+    ConnectOptions, PgPool,
 };
 
+use std::sync::Arc;
+
 use crate::{
-    email::{Email, EmailClient},
+    email::{
+        unsubscribe::UnsubscribeLinks, Email, EmailClient, EmailTransport, FileTransport,
+        InMemoryTransport, PostmarkTransport, SmtpSecurity, SmtpTransport,
+    },
     error::ApiError,
+    rate_limit::Limit,
+    upload::scan::{ClamAvScanner, NoopScanner, Scanner},
 };
 
 #[derive(Deserialize, Clone)]
@@ -19,13 +26,46 @@ pub struct Settings {
     pub sentry_dsn: Option<String>,
     pub email_client: EmailClientSettings,
     pub meili: MeiliConfig,
-    pub oauth: OAuth,
+    #[serde(default)]
+    pub oauth: OAuthSettings,
+    #[serde(default)]
+    pub upload_scanner: UploadScannerSettings,
+    #[serde(default)]
+    pub queue: QueueSettings,
+    #[serde(default)]
+    pub tokens: TokenSettings,
+    #[serde(default)]
+    pub auth: AuthSettings,
+    #[serde(default)]
+    pub session: SessionSettings,
+    #[serde(default)]
+    pub webauthn: WebauthnSettings,
+    #[serde(default)]
+    pub oauth_server: OAuthServerSettings,
 }
 
 impl Settings {
     pub fn reload() -> anyhow::Result<Self> {
         get_config().map_err(Into::into)
     }
+
+    pub fn email_client(&self, pool: PgPool) -> EmailClient {
+        let sender_email = self
+            .email_client
+            .sender()
+            .expect("Invalid sender email address.");
+        let unsubscribe = UnsubscribeLinks::new(
+            &self.email_client.unsubscribe_secret,
+            self.application_settings.public_url(),
+            self.frontend_url.clone(),
+        );
+        EmailClient::new(
+            sender_email.into(),
+            self.email_client.transport(),
+            pool,
+            unsubscribe,
+        )
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -34,39 +74,475 @@ pub struct ApplicationSettings {
     pub host: [u8; 4],
     pub daily_upload_limit_bytes: i64,
     pub cli_unix_socket: Option<String>,
+    /// Where the API is reachable from the outside, for links in emails. Defaults to
+    /// `http://<host>:<port>`.
+    pub public_url: Option<String>,
+    /// Take the client's IP from the `X-Forwarded-For` header the reverse proxy in front of us
+    /// sets. Never enable it without one, clients could make up their IP otherwise.
+    #[serde(default)]
+    pub trust_forwarded_for: bool,
+}
+
+impl ApplicationSettings {
+    pub fn public_url(&self) -> String {
+        self.public_url.clone().unwrap_or_else(|| {
+            let [a, b, c, d] = self.host;
+            format!("http://{a}.{b}.{c}.{d}:{}", self.port)
+        })
+    }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct MeiliConfig {
     pub url: String,
     pub master_key: String,
-    pub retry_seconds: Option<u64>,
-    pub max_retries: Option<usize>,
-    pub indexing_interval_seconds: Option<u64>,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct EmailClientSettings {
-    pub base_url: String,
     pub sender_email: String,
-    pub authorization_token: SecretString,
     pub timeout_milliseconds: u64,
+    pub transport: EmailTransportSettings,
+    /// Signs unsubscribe links. Changing it invalidates every link that was sent before.
+    pub unsubscribe_secret: SecretString,
+    /// The password of the basic auth the provider's bounce and complaint webhooks are sent
+    /// with. The webhooks are disabled without it.
+    pub webhook_secret: Option<SecretString>,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EmailTransportSettings {
+    Postmark {
+        base_url: String,
+        authorization_token: SecretString,
+    },
+    Smtp {
+        host: String,
+        port: u16,
+        #[serde(default)]
+        security: SmtpSecurity,
+        username: Option<String>,
+        password: Option<SecretString>,
+    },
+    /// Writes emails to `directory`, or to stdout if it's not set.
+    File { directory: Option<String> },
+    /// Only keeps emails in memory, nothing is delivered.
+    Memory,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UploadScannerSettings {
+    #[default]
+    Noop,
+    Clamav {
+        socket_path: String,
+        timeout_milliseconds: u64,
+    },
+}
+
+impl UploadScannerSettings {
+    pub fn scanner(self) -> std::sync::Arc<dyn Scanner> {
+        match self {
+            Self::Noop => std::sync::Arc::new(NoopScanner),
+            Self::Clamav {
+                socket_path,
+                timeout_milliseconds,
+            } => std::sync::Arc::new(ClamAvScanner::new(
+                socket_path,
+                std::time::Duration::from_millis(timeout_milliseconds),
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct QueueSettings {
+    /// How many jobs are processed concurrently.
+    pub concurrency: usize,
+    /// Idle workers are woken up by new jobs, but still poll this often for scheduled jobs.
+    pub poll_interval_seconds: u64,
+    /// How long to wait for in-flight jobs to finish on shutdown.
+    pub drain_timeout_seconds: u64,
+    /// A job that failed this many times is moved to `failed_jobs`.
+    pub max_attempts: i32,
+    pub base_backoff_seconds: u64,
+    pub max_backoff_seconds: u64,
+    /// Replaces the cron expressions of recurring jobs, by schedule name, when they're first
+    /// scheduled. Schedules that already exist are changed with the `schedule` CLI command.
+    pub schedules: std::collections::HashMap<String, String>,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            poll_interval_seconds: 10,
+            drain_timeout_seconds: 30,
+            max_attempts: 5,
+            base_backoff_seconds: 30,
+            max_backoff_seconds: 6 * 60 * 60,
+            schedules: Default::default(),
+        }
+    }
+}
+
+impl QueueSettings {
+    /// Exponential backoff with "equal jitter": half of the delay is fixed, the other half is
+    /// random, so jobs that failed together don't all retry at the same moment.
+    pub fn backoff(&self, attempts: i32) -> std::time::Duration {
+        use rand::Rng;
+
+        let exponent = attempts.saturating_sub(1).clamp(0, 31) as u32;
+        let delay = self
+            .base_backoff_seconds
+            .saturating_mul(2u64.saturating_pow(exponent))
+            .min(self.max_backoff_seconds);
+        let half = delay / 2;
+        let jitter = rand::thread_rng().gen_range(0..=delay - half);
+        std::time::Duration::from_secs(half + jitter)
+    }
+
+    /// Whether a job that failed `attempts` times goes to `failed_jobs` instead of being retried.
+    pub fn gives_up(&self, attempts: i32) -> bool {
+        attempts >= self.max_attempts
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthSettings {
+    /// Don't reveal whether an email address has an account: logins fail the same way, taking
+    /// about the same time, and registering an existing address emails its owner instead of
+    /// failing. Turning it off gives more helpful errors, e.g. during development.
+    pub uniform_errors: bool,
+    pub login_throttle: LoginThrottleSettings,
+    /// Shown next to the account in authenticator apps.
+    pub totp_issuer: String,
+    /// Encrypts TOTP secrets at rest. It's required, and changing it makes every enrolled
+    /// authenticator useless.
+    pub totp_secret_key: Option<SecretString>,
+    /// Attempts at the second factor of a pending login, per account.
+    pub second_factor_limit: Limit,
+    /// How long API tokens may be valid for at most.
+    pub api_token_max_lifetime_days: u32,
+    pub password_policy: PasswordPolicy,
+}
+
+/// What new passwords have to be like. Existing ones aren't checked.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// Longer passwords are refused, hashing and scoring them gets expensive.
+    pub max_length: usize,
+    /// The zxcvbn score from 0 (guessable in a few tries) to 4 (practically unguessable). It
+    /// accounts for common and leaked passwords, dictionary words and the user's name and email.
+    pub min_score: u8,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 10,
+            max_length: 128,
+            min_score: 3,
+        }
+    }
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            uniform_errors: true,
+            login_throttle: Default::default(),
+            totp_issuer: "Recipe App".to_string(),
+            totp_secret_key: None,
+            second_factor_limit: Limit {
+                max_requests: 5,
+                window_seconds: 5 * 60,
+            },
+            api_token_max_lifetime_days: 365,
+            password_policy: Default::default(),
+        }
+    }
+}
+
+/// Session cookies and how long sessions last, see `routes::auth::sessions`. The cookie settings
+/// only take effect on restart.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SessionSettings {
+    pub cookie_name: String,
+    /// Defaults to the host the API is reached at, without subdomains.
+    pub domain: Option<String>,
+    /// `none` only works with `secure` cookies.
+    pub same_site: SameSite,
+    /// Defaults to whether `APP_ENVIRONMENT` is `production`.
+    pub secure: Option<bool>,
+    pub inactivity_timeout_minutes: u64,
+    /// How long a session lasts after logging in, however active it is.
+    pub absolute_lifetime_hours: u64,
+    /// Both the inactivity timeout and the lifetime of sessions that asked to be remembered.
+    pub remember_me_days: u64,
+    /// How often remembered sessions get a new ID, so a stolen cookie stops working eventually.
+    pub remember_me_rotation_hours: u64,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl From<SameSite> for tower_sessions::cookie::SameSite {
+    fn from(same_site: SameSite) -> Self {
+        match same_site {
+            SameSite::Strict => Self::Strict,
+            SameSite::Lax => Self::Lax,
+            SameSite::None => Self::None,
+        }
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            cookie_name: "id".to_string(),
+            domain: None,
+            same_site: SameSite::Strict,
+            secure: None,
+            inactivity_timeout_minutes: 10,
+            absolute_lifetime_hours: 24,
+            remember_me_days: 30,
+            remember_me_rotation_hours: 24,
+        }
+    }
+}
+
+impl SessionSettings {
+    pub fn secure(&self) -> bool {
+        self.secure.unwrap_or_else(|| {
+            std::env::var("APP_ENVIRONMENT").unwrap_or_else(|_| String::from("local"))
+                == "production"
+        })
+    }
+
+    pub fn inactivity_timeout(&self) -> time::Duration {
+        time::Duration::minutes(self.inactivity_timeout_minutes as i64)
+    }
+
+    pub fn remember_me(&self) -> time::Duration {
+        time::Duration::days(self.remember_me_days as i64)
+    }
+
+    /// How long after logging in a session expires.
+    pub fn lifetime(&self, remembered: bool) -> time::Duration {
+        if remembered {
+            self.remember_me()
+        } else {
+            time::Duration::hours(self.absolute_lifetime_hours as i64)
+        }
+    }
+
+    pub fn remember_me_rotation(&self) -> time::Duration {
+        time::Duration::hours(self.remember_me_rotation_hours as i64)
+    }
+}
+
+/// The relying party passkeys are registered for.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct WebauthnSettings {
+    /// The domain passkeys are bound to. Defaults to the host of `frontend_url`, it may be a
+    /// parent domain of it, but changing it later invalidates every passkey.
+    pub rp_id: Option<String>,
+    /// Shown by browsers when creating a passkey.
+    pub rp_name: String,
+    /// Where the ceremonies run. Defaults to `frontend_url`.
+    pub origin: Option<String>,
+}
+
+impl Default for WebauthnSettings {
+    fn default() -> Self {
+        Self {
+            rp_id: None,
+            rp_name: "Recipe App".to_string(),
+            origin: None,
+        }
+    }
 }
 
+/// Us as an OAuth2 authorization server for third-party apps, see `routes::oauth_server`.
 #[derive(Deserialize, Clone)]
-pub struct OAuth {
-    pub discord: OAuthCredentials,
-    pub google: OAuthCredentials,
+#[serde(default)]
+pub struct OAuthServerSettings {
+    /// The RSA private key that signs ID tokens, in PKCS#8 PEM. Without it, a new one is generated
+    /// on every start, so ID tokens only verify until a restart, and only against the instance
+    /// that issued them.
+    pub signing_key: Option<SecretString>,
+    pub authorization_code_ttl_seconds: u64,
+    pub access_token_ttl_seconds: u64,
+    pub refresh_token_ttl_days: u64,
+}
+
+impl Default for OAuthServerSettings {
+    fn default() -> Self {
+        Self {
+            signing_key: None,
+            authorization_code_ttl_seconds: 60,
+            access_token_ttl_seconds: 60 * 60,
+            refresh_token_ttl_days: 30,
+        }
+    }
+}
+
+/// Failed logins, see `routes::auth::LoginThrottle`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LoginThrottleSettings {
+    pub per_account: ThrottleLimits,
+    pub per_ip: ThrottleLimits,
+    /// Failures older than this are forgotten.
+    pub failure_window_seconds: u64,
+    /// The delay between attempts doubles with every failure, up to this.
+    pub max_delay_seconds: u64,
+    pub lockout_seconds: u64,
+}
+
+impl Default for LoginThrottleSettings {
+    fn default() -> Self {
+        Self {
+            per_account: ThrottleLimits {
+                free_attempts: 3,
+                lockout_after: 10,
+            },
+            per_ip: ThrottleLimits {
+                free_attempts: 10,
+                lockout_after: 100,
+            },
+            failure_window_seconds: 15 * 60,
+            max_delay_seconds: 60,
+            lockout_seconds: 15 * 60,
+        }
+    }
+}
+
+impl LoginThrottleSettings {
+    /// How long to wait after the given number of failures.
+    pub fn delay(&self, limits: ThrottleLimits, failures: u64) -> std::time::Duration {
+        let exponent = failures.saturating_sub(limits.free_attempts + 1).min(31) as u32;
+        let delay = if failures > limits.free_attempts {
+            2u64.saturating_pow(exponent).min(self.max_delay_seconds)
+        } else {
+            0
+        };
+        std::time::Duration::from_secs(delay)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+pub struct ThrottleLimits {
+    /// Failures that don't slow down the next attempt.
+    pub free_attempts: u64,
+    /// Failures within the window that lock logins out, even with the right password.
+    pub lockout_after: u64,
+}
+
+/// Confirmation and password reset tokens.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TokenSettings {
+    pub confirmation_ttl_hours: u64,
+    pub password_reset_ttl_minutes: u64,
+    /// How many tokens (registrations, resent confirmations and password resets) may be requested
+    /// for the same email address.
+    pub per_email_limit: Limit,
+    /// How many tokens may be requested from the same IP, for any email address.
+    pub per_ip_limit: Limit,
+}
+
+impl Default for TokenSettings {
+    fn default() -> Self {
+        Self {
+            confirmation_ttl_hours: 48,
+            password_reset_ttl_minutes: 60,
+            per_email_limit: Limit::per_hour(3),
+            per_ip_limit: Limit::per_hour(10),
+        }
+    }
 }
 
+impl TokenSettings {
+    pub fn confirmation_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.confirmation_ttl_hours * 60 * 60)
+    }
+
+    pub fn password_reset_ttl(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.password_reset_ttl_minutes * 60)
+    }
+}
+
+/// External providers users can log in with, see `routes::auth::oauth`.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct OAuthSettings {
+    /// Keyed by the name used in routes and stored with linked identities, renaming a provider
+    /// unlinks every account from it.
+    pub providers: std::collections::BTreeMap<String, OAuthProviderSettings>,
+}
+
+/// An OpenID Connect provider configured by its `issuer`, or a plain OAuth2 provider (like
+/// Discord or GitHub) by its endpoints.
 #[derive(Deserialize, Clone)]
-pub struct OAuthCredentials {
+pub struct OAuthProviderSettings {
+    /// Shown to users, defaults to the provider's name.
+    pub display_name: Option<String>,
     pub client_id: String,
-    pub client_secret: String,
-    pub auth_url: String,
-    pub token_url: String,
+    pub client_secret: SecretString,
     pub redirect_url: String,
-    pub revocation_url: String,
+    #[serde(default = "default_oauth_scopes")]
+    pub scopes: Vec<String>,
+    /// Where `/.well-known/openid-configuration` is discovered from. ID tokens are checked
+    /// against it, so it has to match their `iss` claim exactly.
+    pub issuer: Option<String>,
+    /// The endpoints of providers without discovery. These override discovered ones.
+    pub auth_url: Option<String>,
+    pub token_url: Option<String>,
+    pub userinfo_url: Option<String>,
+    pub revocation_url: Option<String>,
+    /// Where the user info response of a plain OAuth2 provider keeps its claims.
+    #[serde(default)]
+    pub claims: OAuthClaimNames,
+}
+
+fn default_oauth_scopes() -> Vec<String> {
+    ["openid", "email", "profile"].map(String::from).to_vec()
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct OAuthClaimNames {
+    pub subject: String,
+    pub email: String,
+    /// Without it, the provider's email addresses are never trusted to link existing accounts.
+    pub email_verified: Option<String>,
+    pub name: String,
+}
+
+impl Default for OAuthClaimNames {
+    fn default() -> Self {
+        Self {
+            subject: "sub".to_string(),
+            email: "email".to_string(),
+            email_verified: Some("email_verified".to_string()),
+            name: "name".to_string(),
+        }
+    }
 }
 
 impl EmailClientSettings {
@@ -74,15 +550,32 @@ impl EmailClientSettings {
         Email::parse(self.sender_email.clone())
     }
 
-    pub fn client(self) -> EmailClient {
-        let sender_email = self.sender().expect("Invalid sender email address.");
+    pub fn transport(&self) -> Arc<dyn EmailTransport> {
         let timeout = self.timeout();
-        EmailClient::new(
-            self.base_url,
-            sender_email.into(),
-            self.authorization_token,
-            timeout,
-        )
+        match self.transport.clone() {
+            EmailTransportSettings::Postmark {
+                base_url,
+                authorization_token,
+            } => Arc::new(PostmarkTransport::new(
+                base_url,
+                authorization_token,
+                timeout,
+            )),
+            EmailTransportSettings::Smtp {
+                host,
+                port,
+                security,
+                username,
+                password,
+            } => Arc::new(
+                SmtpTransport::new(&host, port, security, username.zip(password), timeout)
+                    .expect("Invalid SMTP settings."),
+            ),
+            EmailTransportSettings::File { directory } => {
+                Arc::new(FileTransport::new(directory.map(Into::into)))
+            }
+            EmailTransportSettings::Memory => Arc::new(InMemoryTransport::new()),
+        }
     }
 
     pub fn timeout(&self) -> std::time::Duration {
@@ -211,3 +704,58 @@ impl TryFrom<String> for Environment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let settings = QueueSettings {
+            base_backoff_seconds: 30,
+            max_backoff_seconds: 200,
+            ..Default::default()
+        };
+        for (attempts, delay) in [(0, 30), (1, 30), (2, 60), (3, 120), (4, 200), (100, 200)] {
+            for _ in 0..20 {
+                let backoff = settings.backoff(attempts);
+                assert!(
+                    backoff >= Duration::from_secs(delay / 2)
+                        && backoff <= Duration::from_secs(delay),
+                    "{attempts} attempts waited {backoff:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn jobs_are_given_up_after_max_attempts() {
+        let settings = QueueSettings {
+            max_attempts: 3,
+            ..Default::default()
+        };
+        assert!(!settings.gives_up(1));
+        assert!(!settings.gives_up(2));
+        assert!(settings.gives_up(3));
+        assert!(settings.gives_up(4));
+    }
+
+    #[test]
+    fn login_delay_doubles_after_the_free_attempts() {
+        let settings = LoginThrottleSettings {
+            max_delay_seconds: 10,
+            ..Default::default()
+        };
+        let limits = ThrottleLimits {
+            free_attempts: 2,
+            lockout_after: 100,
+        };
+        let delays: Vec<u64> = (0..8)
+            .map(|failures| settings.delay(limits, failures).as_secs())
+            .collect();
+        assert_eq!(delays, [0, 0, 0, 1, 2, 4, 8, 10]);
+        assert_eq!(settings.delay(limits, u64::MAX).as_secs(), 10);
+    }
+}
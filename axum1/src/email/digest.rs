@@ -0,0 +1,384 @@
+//! The weekly digest: trending recipes, new recipes from the cuisines of the user's favorite
+//! recipes, and new suggestions on their favorite ingredients.
+//!
+//! `SendWeeklyDigests` runs on a schedule and fans out into a `SendDigest` per subscribed user.
+//! Those record the user and week in `digest_deliveries` in the same transaction that sends the
+//! digest, so reruns (retries, a second fan-out, a requeued job) are no-ops. A crash between
+//! handing the email to the transport and committing can still send it twice.
+use anyhow::Context;
+use axum::async_trait;
+use chrono::{Duration, NaiveDate, Utc, Weekday};
+use sqlx::PgConnection;
+
+use super::{
+    messages::{named_page_link, DigestEmail, NewRecipe, SuggestedIngredient, TrendingRecipe},
+    Delivery,
+};
+use crate::queue::{enqueue, Job, JobContext};
+
+/// How many items each section of the digest has at most.
+const SECTION_LIMIT: i64 = 5;
+
+/// The Monday of the current week. Digests are about the week before it.
+fn current_week() -> NaiveDate {
+    Utc::now().date_naive().week(Weekday::Mon).first_day()
+}
+
+/// Enqueues this week's digest for every confirmed user who opted into it and hasn't got it yet.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SendWeeklyDigests;
+
+#[async_trait]
+impl Job for SendWeeklyDigests {
+    const JOB_TYPE: &'static str = "send_weekly_digests";
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        let week = current_week();
+        let mut tx = ctx.pool.begin().await?;
+        let user_ids = sqlx::query_scalar!(
+            r#"
+            SELECT u.user_id FROM users u
+            WHERE u.confirmed
+                AND EXISTS (
+                    SELECT 1 FROM email_preferences ep
+                    WHERE ep.user_id = u.user_id AND ep.category = 'digest' AND ep.enabled
+                )
+                AND NOT EXISTS (SELECT 1 FROM email_suppressions es WHERE es.email = u.email)
+                AND NOT EXISTS (
+                    SELECT 1 FROM digest_deliveries dd WHERE dd.user_id = u.user_id AND dd.week = $1
+                )
+            "#,
+            week
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for &user_id in &user_ids {
+            enqueue(&mut *tx, &SendDigest { user_id, week }).await?;
+        }
+        tx.commit().await?;
+        tracing::info!(%week, "Enqueued {} digests", user_ids.len());
+        Ok(())
+    }
+}
+
+/// Composes and sends the digest of a week to a single user, unless it was handled already.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SendDigest {
+    pub user_id: uuid::Uuid,
+    /// The Monday the digest's week starts on.
+    pub week: NaiveDate,
+}
+
+#[async_trait]
+impl Job for SendDigest {
+    const JOB_TYPE: &'static str = "send_digest";
+    const PRIORITY: i32 = -10;
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        let mut tx = ctx.pool.begin().await?;
+        // Concurrent runs for the same user and week wait here until this one commits or rolls
+        // back, then see its row.
+        let claimed = sqlx::query!(
+            r#"
+            INSERT INTO digest_deliveries (user_id, week, sent)
+            VALUES ($1, $2, FALSE)
+            ON CONFLICT (user_id, week) DO NOTHING
+            RETURNING user_id
+            "#,
+            self.user_id,
+            self.week,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if claimed.is_none() {
+            tracing::debug!(user_id = %self.user_id, week = %self.week, "Digest was handled already");
+            return Ok(());
+        }
+
+        let digest = compose(&mut tx, self.user_id, self.week, &ctx.frontend_url)
+            .await
+            .context("Failed to compose the digest")?;
+        if !digest.is_empty() {
+            let delivery = ctx.email_client.send_to_user(self.user_id, &digest).await?;
+            sqlx::query!(
+                "UPDATE digest_deliveries SET sent = $3 WHERE user_id = $1 AND week = $2",
+                self.user_id,
+                self.week,
+                delivery == Delivery::Sent,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+async fn compose(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    week: NaiveDate,
+    frontend_url: &str,
+) -> anyhow::Result<DigestEmail> {
+    let since = week - Duration::weeks(1);
+
+    let trending = sqlx::query!(
+        r#"
+        SELECT name AS "name!", favorites AS "favorites!" FROM hot_recipes
+        ORDER BY favorites DESC
+        LIMIT $1
+        "#,
+        SECTION_LIMIT
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let new_recipes = sqlx::query!(
+        r#"
+        SELECT r.name, c.name AS cuisine
+        FROM recipes r
+        INNER JOIN cuisines c ON c.id = r.cuisine_id
+        WHERE r.created_at >= $2::DATE AND r.created_at < $3::DATE
+            AND r.creator_id <> $1
+            AND r.cuisine_id IN (
+                SELECT fr_recipe.cuisine_id FROM favorite_recipe fr
+                INNER JOIN recipes fr_recipe ON fr_recipe.id = fr.recipe_id
+                WHERE fr.user_id = $1
+            )
+        ORDER BY r.created_at DESC
+        LIMIT $4
+        "#,
+        user_id,
+        since,
+        week,
+        SECTION_LIMIT,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let suggested = sqlx::query!(
+        r#"
+        SELECT i.name, COUNT(*) AS "suggestions!"
+        FROM ingredient_suggestions s
+        INNER JOIN ingredients i ON i.id = s.ingredient_id
+        INNER JOIN favorite_ingredient fi ON fi.ingredient_id = i.id AND fi.user_id = $1
+        WHERE s.created_at >= $2::DATE AND s.created_at < $3::DATE
+            AND s.user_id IS DISTINCT FROM $1
+        GROUP BY i.id, i.name
+        ORDER BY COUNT(*) DESC
+        LIMIT $4
+        "#,
+        user_id,
+        since,
+        week,
+        SECTION_LIMIT,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(DigestEmail {
+        week: since.to_string(),
+        trending_recipes: trending
+            .into_iter()
+            .map(|row| {
+                Ok(TrendingRecipe {
+                    link: named_page_link(frontend_url, "recipe", &row.name)?,
+                    name: row.name,
+                    favorites: row.favorites,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?,
+        new_recipes: new_recipes
+            .into_iter()
+            .map(|row| {
+                Ok(NewRecipe {
+                    link: named_page_link(frontend_url, "recipe", &row.name)?,
+                    name: row.name,
+                    cuisine: row.cuisine,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?,
+        suggested_ingredients: suggested
+            .into_iter()
+            .map(|row| {
+                Ok(SuggestedIngredient {
+                    link: named_page_link(frontend_url, "ingredient", &row.name)?,
+                    name: row.name,
+                    suggestions: row.suggestions,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        email::InMemoryTransport,
+        testing::{create_user, email_client, job_context},
+        upload::scan::NoopScanner,
+    };
+
+    fn context(pool: &PgPool, emails: &InMemoryTransport) -> JobContext {
+        let mut ctx = job_context(pool.clone(), Arc::new(NoopScanner));
+        ctx.email_client = email_client(pool.clone(), emails.clone());
+        ctx
+    }
+
+    async fn subscribe(pool: &PgPool, user_id: uuid::Uuid, enabled: bool) {
+        sqlx::query!(
+            "INSERT INTO email_preferences (user_id, category, enabled) VALUES ($1, 'digest', $2)",
+            user_id,
+            enabled,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn create_recipe(
+        pool: &PgPool,
+        creator_id: uuid::Uuid,
+        cuisine_id: uuid::Uuid,
+        name: &str,
+        created_at: chrono::DateTime<Utc>,
+    ) -> uuid::Uuid {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO recipes
+                (name, description, creator_id, prep_time, cook_time, difficulty, steps, cuisine_id,
+                meal_type, created_at)
+            VALUES ($1, '', $2, 10, 10, 'easy', '{}', $3, 'dinner', $4)
+            RETURNING id
+            "#,
+            name,
+            creator_id,
+            cuisine_id,
+            created_at,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn digests_are_only_enqueued_for_users_who_opted_in(pool: PgPool) {
+        let opted_in = create_user(&pool, "opted_in").await;
+        subscribe(&pool, opted_in, true).await;
+        create_user(&pool, "never_asked").await;
+        let opted_out = create_user(&pool, "opted_out").await;
+        subscribe(&pool, opted_out, false).await;
+        let bounced = create_user(&pool, "bounced").await;
+        subscribe(&pool, bounced, true).await;
+        sqlx::query!(
+            "INSERT INTO email_suppressions (email, reason) VALUES ('bounced@example.com', 'hard_bounce')"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let handled = create_user(&pool, "handled").await;
+        subscribe(&pool, handled, true).await;
+        sqlx::query!(
+            "INSERT INTO digest_deliveries (user_id, week, sent) VALUES ($1, $2, TRUE)",
+            handled,
+            current_week(),
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let emails = InMemoryTransport::new();
+        SendWeeklyDigests
+            .run(&context(&pool, &emails))
+            .await
+            .unwrap();
+        let payloads =
+            sqlx::query_scalar!("SELECT payload FROM jobs WHERE job_type = 'send_digest'")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            payloads,
+            [serde_json::json!({ "user_id": opted_in, "week": current_week() })]
+        );
+    }
+
+    #[sqlx::test]
+    async fn digests_are_sent_once_per_week(pool: PgPool) {
+        let reader = create_user(&pool, "reader").await;
+        subscribe(&pool, reader, true).await;
+        let cook = create_user(&pool, "cook").await;
+        // Cuisines are seeded by the migrations.
+        let italian = sqlx::query_scalar!("SELECT id FROM cuisines WHERE name = 'Italian'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let week = current_week();
+        let last_week = week.and_hms_opt(12, 0, 0).unwrap().and_utc() - Duration::days(3);
+        let favorite = create_recipe(
+            &pool,
+            cook,
+            italian,
+            "Lasagne",
+            last_week - Duration::weeks(4),
+        )
+        .await;
+        sqlx::query!(
+            "INSERT INTO favorite_recipe (recipe_id, user_id) VALUES ($1, $2)",
+            favorite,
+            reader,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        create_recipe(&pool, cook, italian, "Carbonara", last_week).await;
+
+        let emails = InMemoryTransport::new();
+        let ctx = context(&pool, &emails);
+        for _ in 0..2 {
+            SendDigest {
+                user_id: reader,
+                week,
+            }
+            .run(&ctx)
+            .await
+            .unwrap();
+        }
+
+        let sent = emails.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "reader@example.com");
+        assert!(sent[0].text_body.contains("Carbonara"));
+        assert!(!sent[0].text_body.contains("Lasagne"));
+        let delivered = sqlx::query_scalar!(
+            "SELECT sent FROM digest_deliveries WHERE user_id = $1 AND week = $2",
+            reader,
+            week,
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(delivered);
+    }
+
+    #[sqlx::test]
+    async fn empty_digests_are_not_sent(pool: PgPool) {
+        let reader = create_user(&pool, "reader").await;
+        subscribe(&pool, reader, true).await;
+
+        let emails = InMemoryTransport::new();
+        SendDigest {
+            user_id: reader,
+            week: current_week(),
+        }
+        .run(&context(&pool, &emails))
+        .await
+        .unwrap();
+        assert!(emails.sent().is_empty());
+    }
+}
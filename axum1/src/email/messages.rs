@@ -0,0 +1,202 @@
+use serde::Serialize;
+
+use super::{build_link, preferences::EmailCategory, templates::EmailMessage};
+
+#[derive(Serialize)]
+pub struct ConfirmationEmail {
+    pub confirmation_link: String,
+}
+
+impl ConfirmationEmail {
+    pub fn new(frontend_url: &str, confirmation_token: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            confirmation_link: build_link(
+                frontend_url,
+                "confirm",
+                &[("token", confirmation_token)],
+            )?,
+        })
+    }
+}
+
+impl EmailMessage for ConfirmationEmail {
+    const TEMPLATE: &'static str = "confirmation";
+
+    fn sample(frontend_url: &str) -> Self {
+        Self::new(frontend_url, "sample-confirmation-token").expect("frontend_url is valid")
+    }
+}
+
+#[derive(Serialize)]
+pub struct PasswordResetEmail {
+    pub reset_link: String,
+}
+
+impl PasswordResetEmail {
+    pub fn new(frontend_url: &str, token: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            reset_link: build_link(frontend_url, "forget_password", &[("token", token)])?,
+        })
+    }
+}
+
+impl EmailMessage for PasswordResetEmail {
+    const TEMPLATE: &'static str = "password_reset";
+
+    fn sample(frontend_url: &str) -> Self {
+        Self::new(frontend_url, "sample-reset-token").expect("frontend_url is valid")
+    }
+}
+
+/// Sent instead of a confirmation when someone registers with an address that has an account,
+/// so the response doesn't have to tell.
+#[derive(Serialize)]
+pub struct AccountExistsEmail {
+    pub login_link: String,
+    pub reset_link: String,
+}
+
+impl AccountExistsEmail {
+    pub fn new(frontend_url: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            login_link: build_link(frontend_url, "login", &[])?,
+            reset_link: build_link(frontend_url, "forget_password", &[])?,
+        })
+    }
+}
+
+impl EmailMessage for AccountExistsEmail {
+    const TEMPLATE: &'static str = "account_exists";
+
+    fn sample(frontend_url: &str) -> Self {
+        Self::new(frontend_url).expect("frontend_url is valid")
+    }
+}
+
+#[derive(Serialize)]
+pub struct AccountLockedEmail {
+    pub locked_minutes: u64,
+    pub reset_link: String,
+}
+
+impl AccountLockedEmail {
+    pub fn new(frontend_url: &str, locked_minutes: u64) -> anyhow::Result<Self> {
+        Ok(Self {
+            locked_minutes,
+            reset_link: build_link(frontend_url, "forget_password", &[])?,
+        })
+    }
+}
+
+impl EmailMessage for AccountLockedEmail {
+    const TEMPLATE: &'static str = "account_locked";
+
+    fn sample(frontend_url: &str) -> Self {
+        Self::new(frontend_url, 15).expect("frontend_url is valid")
+    }
+}
+
+/// Sent after a password change or reset, so the owner notices if it wasn't them.
+#[derive(Serialize)]
+pub struct PasswordChangedEmail {
+    pub reset_link: String,
+}
+
+impl PasswordChangedEmail {
+    pub fn new(frontend_url: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            reset_link: build_link(frontend_url, "forget_password", &[])?,
+        })
+    }
+}
+
+impl EmailMessage for PasswordChangedEmail {
+    const TEMPLATE: &'static str = "password_changed";
+
+    fn sample(frontend_url: &str) -> Self {
+        Self::new(frontend_url).expect("frontend_url is valid")
+    }
+}
+
+#[derive(Serialize)]
+pub struct TrendingRecipe {
+    pub name: String,
+    pub link: String,
+    pub favorites: i64,
+}
+
+#[derive(Serialize)]
+pub struct NewRecipe {
+    pub name: String,
+    pub link: String,
+    pub cuisine: String,
+}
+
+#[derive(Serialize)]
+pub struct SuggestedIngredient {
+    pub name: String,
+    pub link: String,
+    pub suggestions: i64,
+}
+
+/// The weekly digest, see `email::digest`.
+#[derive(Serialize)]
+pub struct DigestEmail {
+    /// The first day of the week the digest is about, as `YYYY-MM-DD`.
+    pub week: String,
+    pub trending_recipes: Vec<TrendingRecipe>,
+    /// New recipes from the cuisines of the user's favorite recipes.
+    pub new_recipes: Vec<NewRecipe>,
+    /// The user's favorite ingredients that got new suggestions.
+    pub suggested_ingredients: Vec<SuggestedIngredient>,
+}
+
+impl DigestEmail {
+    pub fn is_empty(&self) -> bool {
+        self.trending_recipes.is_empty()
+            && self.new_recipes.is_empty()
+            && self.suggested_ingredients.is_empty()
+    }
+}
+
+/// A link to the frontend's page of a recipe or an ingredient, which are addressed by name.
+pub fn named_page_link(frontend_url: &str, section: &str, name: &str) -> anyhow::Result<String> {
+    let mut url = reqwest::Url::parse(&build_link(frontend_url, section, &[])?)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("`{frontend_url}` can't be a base url"))?
+        .push(name);
+    Ok(url.into())
+}
+
+impl DigestEmail {
+    fn try_sample(frontend_url: &str) -> anyhow::Result<Self> {
+        let recipe = |name: &str| named_page_link(frontend_url, "recipe", name);
+        Ok(Self {
+            week: "2025-01-20".into(),
+            trending_recipes: vec![TrendingRecipe {
+                name: "Goulash".into(),
+                link: recipe("Goulash")?,
+                favorites: 42,
+            }],
+            new_recipes: vec![NewRecipe {
+                name: "Chicken paprikash".into(),
+                link: recipe("Chicken paprikash")?,
+                cuisine: "Hungarian".into(),
+            }],
+            suggested_ingredients: vec![SuggestedIngredient {
+                name: "Paprika".into(),
+                link: named_page_link(frontend_url, "ingredient", "Paprika")?,
+                suggestions: 3,
+            }],
+        })
+    }
+}
+
+impl EmailMessage for DigestEmail {
+    const TEMPLATE: &'static str = "digest";
+    const CATEGORY: Option<EmailCategory> = Some(EmailCategory::Digest);
+
+    fn sample(frontend_url: &str) -> Self {
+        Self::try_sample(frontend_url).expect("frontend_url is valid")
+    }
+}
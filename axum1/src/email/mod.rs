@@ -0,0 +1,210 @@
+use std::sync::Arc;
+
+use sqlx::PgPool;
+
+use crate::error::ApiError;
+
+pub mod digest;
+pub mod messages;
+pub mod preferences;
+pub mod templates;
+mod transport;
+pub mod unsubscribe;
+use templates::{EmailMessage, Locale};
+pub use transport::{
+    EmailTransport, FileTransport, InMemoryTransport, OutgoingEmail, PostmarkTransport,
+    SmtpSecurity, SmtpTransport,
+};
+use unsubscribe::UnsubscribeLinks;
+
+#[derive(Debug, Clone)]
+pub struct Email(String);
+
+impl Email {
+    pub fn parse(s: String) -> Result<Email, ApiError> {
+        if validator::ValidateEmail::validate_email(&s) {
+            Ok(Self(s))
+        } else {
+            Err(ApiError::unprocessable_entity([("email", "invalid email")]))
+        }
+    }
+}
+
+impl AsRef<str> for Email {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Email> for String {
+    fn from(e: Email) -> Self {
+        e.0
+    }
+}
+
+impl std::fmt::Display for Email {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Builds emails from the configured sender and hands them to a transport.
+#[derive(Clone)]
+pub struct EmailClient {
+    sender: String,
+    transport: Arc<dyn EmailTransport>,
+    pool: PgPool,
+    unsubscribe: Arc<UnsubscribeLinks>,
+}
+
+/// What became of a message sent with [`EmailClient::send_to_user`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Sent,
+    /// The address bounced or complained before.
+    Suppressed,
+    /// The user opted out of the message's category.
+    Unsubscribed,
+    NoSuchUser,
+}
+
+impl EmailClient {
+    pub fn new(
+        sender: String,
+        transport: Arc<dyn EmailTransport>,
+        pool: PgPool,
+        unsubscribe: UnsubscribeLinks,
+    ) -> Self {
+        Self {
+            sender,
+            transport,
+            pool,
+            unsubscribe: Arc::new(unsubscribe),
+        }
+    }
+
+    pub fn unsubscribe_links(&self) -> &UnsubscribeLinks {
+        &self.unsubscribe
+    }
+
+    pub async fn send_mail(
+        &self,
+        recipient: Email,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+    ) -> Result<(), anyhow::Error> {
+        let email = OutgoingEmail {
+            from: self.sender.clone(),
+            to: recipient.into(),
+            subject: subject.into(),
+            html_body: html_content.into(),
+            text_body: text_content.into(),
+            headers: Vec::new(),
+        };
+        self.transport.send(&email).await
+    }
+
+    /// Renders a transactional `message` from its templates in the given locale, and sends it.
+    ///
+    /// Messages with a category must go through [`EmailClient::send_to_user`] instead, which
+    /// respects preferences and suppressions.
+    pub async fn send_message<M: EmailMessage>(
+        &self,
+        recipient: Email,
+        locale: Locale,
+        message: &M,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(category) = M::CATEGORY {
+            anyhow::bail!(
+                "`{}` is a {} email, send it with `send_to_user`",
+                M::TEMPLATE,
+                category.as_str()
+            );
+        }
+        let rendered = templates::render(locale, message, None)?;
+        self.send_mail(
+            recipient,
+            &rendered.subject,
+            &rendered.html_body,
+            &rendered.text_body,
+        )
+        .await
+    }
+
+    /// Sends a non-transactional `message` to a user in their locale, unless they aren't subscribed
+    /// to its category or their address is suppressed. It's sent with one-click `List-Unsubscribe`
+    /// headers (RFC 8058), and its templates get a link to the unsubscribe page.
+    pub async fn send_to_user<M: EmailMessage>(
+        &self,
+        user_id: uuid::Uuid,
+        message: &M,
+    ) -> anyhow::Result<Delivery> {
+        let Some(category) = M::CATEGORY else {
+            anyhow::bail!(
+                "`{}` is transactional, send it with `send_message`",
+                M::TEMPLATE
+            );
+        };
+        let Some(recipient) = sqlx::query!(
+            r#"
+            SELECT u.email, u.locale,
+                COALESCE(ep.enabled, $3) AS "subscribed!",
+                EXISTS (SELECT 1 FROM email_suppressions es WHERE es.email = u.email) AS "suppressed!"
+            FROM users u
+            LEFT JOIN email_preferences ep ON ep.user_id = u.user_id AND ep.category = $2
+            WHERE u.user_id = $1
+            "#,
+            user_id,
+            category as _,
+            category.enabled_by_default(),
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(Delivery::NoSuchUser);
+        };
+        if recipient.suppressed {
+            return Ok(Delivery::Suppressed);
+        }
+        if !recipient.subscribed {
+            return Ok(Delivery::Unsubscribed);
+        }
+
+        let locale = recipient.locale.parse().unwrap_or_default();
+        let page_url = self.unsubscribe.page_url(user_id, category)?;
+        let rendered = templates::render(locale, message, Some(&page_url))?;
+        let email = OutgoingEmail {
+            from: self.sender.clone(),
+            to: recipient.email,
+            subject: rendered.subject,
+            html_body: rendered.html_body,
+            text_body: rendered.text_body,
+            headers: vec![
+                (
+                    "List-Unsubscribe".into(),
+                    format!("<{}>", self.unsubscribe.one_click_url(user_id, category)?),
+                ),
+                (
+                    "List-Unsubscribe-Post".into(),
+                    "List-Unsubscribe=One-Click".into(),
+                ),
+            ],
+        };
+        self.transport.send(&email).await?;
+        Ok(Delivery::Sent)
+    }
+}
+
+/// Builds an absolute link from a base url, e.g. `build_link(url, "confirm", &[("token", token)])`.
+pub fn build_link(base_url: &str, path: &str, query: &[(&str, &str)]) -> anyhow::Result<String> {
+    let mut url = reqwest::Url::parse(&format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    ))?;
+    if !query.is_empty() {
+        url.query_pairs_mut().extend_pairs(query);
+    }
+    Ok(url.into())
+}
@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+/// Categories of non-transactional email, which users can opt into or out of.
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case", type_name = "email_category")]
+#[serde(rename_all = "snake_case")]
+pub enum EmailCategory {
+    Digest,
+    Announcements,
+}
+
+impl EmailCategory {
+    pub const ALL: [EmailCategory; 2] = [EmailCategory::Digest, EmailCategory::Announcements];
+
+    /// Whether users who never chose get this email. The digest is only sent to those who asked
+    /// for it.
+    pub fn enabled_by_default(self) -> bool {
+        match self {
+            Self::Digest => false,
+            Self::Announcements => true,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Digest => "digest",
+            Self::Announcements => "announcements",
+        }
+    }
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case", type_name = "suppression_reason")]
+#[serde(rename_all = "snake_case")]
+pub enum SuppressionReason {
+    HardBounce,
+    SpamComplaint,
+    Manual,
+}
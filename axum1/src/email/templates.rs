@@ -0,0 +1,236 @@
+//! Email bodies are rendered from the templates in `templates/email`, which are embedded into the
+//! binary. Every message has a subject, an HTML and a text template per locale:
+//! `<locale>/<message>.subject.txt`, `<locale>/<message>.html` and `<locale>/<message>.txt`.
+//! HTML templates extend `layout.html`, and are autoescaped.
+use std::str::FromStr;
+
+use minijinja::Environment;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    messages::{
+        AccountExistsEmail, AccountLockedEmail, ConfirmationEmail, DigestEmail,
+        PasswordChangedEmail, PasswordResetEmail,
+    },
+    preferences::EmailCategory,
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    Hu,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::En, Locale::Hu];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::Hu => "hu",
+        }
+    }
+
+    /// Picks the first supported language from an `Accept-Language` header, ignoring weights.
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        header.split(',').find_map(|range| {
+            let tag = range.split(';').next()?.trim();
+            let language = tag.split('-').next()?;
+            language.parse().ok()
+        })
+    }
+}
+
+impl FromStr for Locale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|locale| locale.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| anyhow::anyhow!("Unsupported locale `{s}`"))
+    }
+}
+
+impl std::fmt::Display for Locale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// A message that's rendered from templates. Its fields are the template context.
+pub trait EmailMessage: Serialize {
+    /// The file name of its templates, without the extension.
+    const TEMPLATE: &'static str;
+
+    /// `None` for transactional email, which can't be unsubscribed from. Templates of the others
+    /// get an `unsubscribe_link`.
+    const CATEGORY: Option<EmailCategory> = None;
+
+    /// Made up data for previewing the templates.
+    fn sample(frontend_url: &str) -> Self;
+}
+
+#[derive(Serialize, Debug)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+}
+
+macro_rules! template {
+    ($($part:literal),+) => {
+        (
+            concat!($($part),+),
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/email/", $($part),+)),
+        )
+    };
+}
+
+macro_rules! message_templates {
+    ($($locale:literal / $message:literal),* $(,)?) => {
+        [$(
+            template!($locale, "/", $message, ".subject.txt"),
+            template!($locale, "/", $message, ".html"),
+            template!($locale, "/", $message, ".txt"),
+        )*]
+    };
+}
+
+static TEMPLATES: Lazy<Environment<'static>> = Lazy::new(|| {
+    let mut env = Environment::new();
+    let templates = [template!("layout.html")]
+        .into_iter()
+        .chain(message_templates![
+            "en" / "confirmation",
+            "en" / "password_reset",
+            "en" / "account_exists",
+            "en" / "account_locked",
+            "en" / "password_changed",
+            "en" / "digest",
+            "hu" / "confirmation",
+            "hu" / "password_reset",
+            "hu" / "account_exists",
+            "hu" / "account_locked",
+            "hu" / "password_changed",
+            "hu" / "digest",
+        ]);
+    for (name, source) in templates {
+        env.add_template(name, source)
+            .unwrap_or_else(|e| panic!("email template `{name}` is invalid: {e}"));
+    }
+    env
+});
+
+pub fn render<M: EmailMessage>(
+    locale: Locale,
+    message: &M,
+    unsubscribe_link: Option<&str>,
+) -> anyhow::Result<RenderedEmail> {
+    let context = minijinja::context! {
+        locale => locale.as_str(),
+        unsubscribe_link => unsubscribe_link,
+        ..minijinja::Value::from_serialize(message)
+    };
+    let render = |extension: &str| {
+        TEMPLATES
+            .get_template(&format!("{locale}/{}.{extension}", M::TEMPLATE))?
+            .render(&context)
+    };
+    Ok(RenderedEmail {
+        subject: render("subject.txt")?.trim().to_owned(),
+        html_body: render("html")?,
+        text_body: render("txt")?,
+    })
+}
+
+type Preview = fn(Locale, &str) -> anyhow::Result<RenderedEmail>;
+
+fn preview<M: EmailMessage>(locale: Locale, frontend_url: &str) -> anyhow::Result<RenderedEmail> {
+    let unsubscribe_link = M::CATEGORY.map(|_| format!("{frontend_url}/unsubscribe?sample"));
+    render(
+        locale,
+        &M::sample(frontend_url),
+        unsubscribe_link.as_deref(),
+    )
+}
+
+/// Every message that has templates, so they can be previewed with sample data.
+pub const PREVIEWS: [(&str, Preview); 6] = [
+    (ConfirmationEmail::TEMPLATE, preview::<ConfirmationEmail>),
+    (PasswordResetEmail::TEMPLATE, preview::<PasswordResetEmail>),
+    (AccountExistsEmail::TEMPLATE, preview::<AccountExistsEmail>),
+    (AccountLockedEmail::TEMPLATE, preview::<AccountLockedEmail>),
+    (
+        PasswordChangedEmail::TEMPLATE,
+        preview::<PasswordChangedEmail>,
+    ),
+    (DigestEmail::TEMPLATE, preview::<DigestEmail>),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_message_renders_in_every_locale() {
+        for locale in Locale::ALL {
+            for (name, preview) in PREVIEWS {
+                let rendered = preview(locale, "http://localhost:3000")
+                    .unwrap_or_else(|e| panic!("`{locale}/{name}` doesn't render: {e}"));
+                assert!(!rendered.subject.is_empty() && !rendered.subject.contains('\n'));
+                assert!(rendered
+                    .html_body
+                    .contains(&format!(r#"<html lang="{locale}">"#)));
+                assert!(!rendered.text_body.trim().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn messages_are_rendered_in_the_given_locale() {
+        let message = ConfirmationEmail::sample("http://localhost:3000");
+        let en = render(Locale::En, &message, None).unwrap();
+        let hu = render(Locale::Hu, &message, None).unwrap();
+        assert_eq!(en.subject, "Recipe App - Confirm your registration");
+        assert_eq!(hu.subject, "Recept App - Erősítsd meg a regisztrációdat");
+    }
+
+    #[test]
+    fn only_html_bodies_are_escaped() {
+        let message = ConfirmationEmail {
+            confirmation_link: r#"http://localhost:3000/confirm?a=1&b="2""#.into(),
+        };
+        let rendered = render(Locale::En, &message, None).unwrap();
+        assert!(rendered.html_body.contains("a=1&amp;b=&quot;2&quot;"));
+        assert!(rendered
+            .text_body
+            .contains(r#"http://localhost:3000/confirm?a=1&b="2""#));
+    }
+
+    #[test]
+    fn links_are_built_from_the_frontend_url() {
+        let message = ConfirmationEmail::new("https://recipes.example.com/", "a b&c").unwrap();
+        assert_eq!(
+            message.confirmation_link,
+            "https://recipes.example.com/confirm?token=a+b%26c"
+        );
+    }
+
+    #[test]
+    fn locales_are_picked_from_accept_language() {
+        assert_eq!(
+            Locale::from_accept_language("hu-HU,hu;q=0.9,en;q=0.8"),
+            Some(Locale::Hu)
+        );
+        assert_eq!(
+            Locale::from_accept_language("de-DE, en-GB;q=0.5"),
+            Some(Locale::En)
+        );
+        assert_eq!(Locale::from_accept_language("de, fr"), None);
+        assert_eq!("EN".parse::<Locale>().unwrap(), Locale::En);
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use axum::async_trait;
+use lettre::{
+    message::{
+        header::{HeaderName, HeaderValue},
+        Mailbox, MultiPart,
+    },
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use reqwest::Client;
+use secrecy::{ExposeSecret, SecretString};
+use tokio::io::AsyncWriteExt;
+
+/// A fully built message, ready to be handed to a transport.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct OutgoingEmail {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+    /// Extra headers, e.g. `List-Unsubscribe`.
+    pub headers: Vec<(String, String)>,
+}
+
+/// Delivers emails. `EmailClient` builds the messages and hands them to one of these.
+#[async_trait]
+pub trait EmailTransport: Send + Sync {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()>;
+}
+
+/// Sends through Postmark's HTTP API.
+pub struct PostmarkTransport {
+    http_client: Client,
+    base_url: String,
+    authorization_token: SecretString,
+}
+
+impl PostmarkTransport {
+    pub fn new(
+        base_url: String,
+        authorization_token: SecretString,
+        timeout: std::time::Duration,
+    ) -> Self {
+        let http_client = Client::builder().timeout(timeout).build().unwrap();
+        Self {
+            http_client,
+            base_url,
+            authorization_token,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<Header<'a>>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Header<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+impl<'a> From<&'a OutgoingEmail> for SendEmailRequest<'a> {
+    fn from(email: &'a OutgoingEmail) -> Self {
+        Self {
+            from: &email.from,
+            to: &email.to,
+            subject: &email.subject,
+            html_body: &email.html_body,
+            text_body: &email.text_body,
+            headers: email
+                .headers
+                .iter()
+                .map(|(name, value)| Header { name, value })
+                .collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl EmailTransport for PostmarkTransport {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        let url = format!("{}/email", self.base_url);
+        self.http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                self.authorization_token.expose_secret(),
+            )
+            .json(&SendEmailRequest::from(email))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Connect in plain text, then upgrade with `STARTTLS`.
+    #[default]
+    Starttls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// No encryption at all, only meant for local mail catchers like MailHog.
+    None,
+}
+
+/// Sends through any SMTP relay.
+pub struct SmtpTransport {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpTransport {
+    pub fn new(
+        host: &str,
+        port: u16,
+        security: SmtpSecurity,
+        credentials: Option<(String, SecretString)>,
+        timeout: std::time::Duration,
+    ) -> anyhow::Result<Self> {
+        let builder = match security {
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        };
+        let mut builder = builder.port(port).timeout(Some(timeout));
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(
+                username,
+                password.expose_secret().to_owned(),
+            ));
+        }
+        Ok(Self {
+            mailer: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl EmailTransport for SmtpTransport {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        let mut builder = lettre::Message::builder()
+            .from(email.from.parse::<Mailbox>().context("Invalid sender")?)
+            .to(email.to.parse::<Mailbox>().context("Invalid recipient")?)
+            .subject(&email.subject);
+        for (name, value) in &email.headers {
+            builder = builder.raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii(name.clone())
+                    .map_err(|_| anyhow::anyhow!("Invalid header name `{name}`"))?,
+                value.clone(),
+            ));
+        }
+        let message = builder.multipart(MultiPart::alternative_plain_html(
+            email.text_body.clone(),
+            email.html_body.clone(),
+        ))?;
+        self.mailer.send(message).await?;
+        Ok(())
+    }
+}
+
+/// Writes every email as JSON to a file in `directory`, or to stdout if there's no directory.
+/// Meant for local development and CI, where nothing should leave the machine.
+pub struct FileTransport {
+    directory: Option<PathBuf>,
+}
+
+impl FileTransport {
+    pub fn new(directory: Option<PathBuf>) -> Self {
+        Self { directory }
+    }
+}
+
+#[async_trait]
+impl EmailTransport for FileTransport {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        let mut json = serde_json::to_vec_pretty(email)?;
+        json.push(b'\n');
+        match &self.directory {
+            Some(directory) => {
+                tokio::fs::create_dir_all(directory).await?;
+                let path = directory.join(format!("{}.json", uuid::Uuid::new_v4()));
+                tokio::fs::write(&path, json)
+                    .await
+                    .with_context(|| format!("Failed to write email to {path:?}"))?;
+            }
+            None => {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(&json).await?;
+                stdout.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps every email in memory, so tests can assert on what would've been sent.
+#[derive(Clone, Default)]
+pub struct InMemoryTransport {
+    sent: Arc<Mutex<Vec<OutgoingEmail>>>,
+}
+
+impl InMemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sent(&self) -> Vec<OutgoingEmail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl EmailTransport for InMemoryTransport {
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        self.sent.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn email(to: &str) -> OutgoingEmail {
+        OutgoingEmail {
+            from: "recipes@example.com".into(),
+            to: to.into(),
+            subject: "Welcome".into(),
+            html_body: "<p>Hi</p>".into(),
+            text_body: "Hi".into(),
+            headers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn postmark_requests_only_have_headers_if_there_are_any() {
+        let mut email = email("ursula@example.com");
+        let request =
+            |email: &OutgoingEmail| serde_json::to_value(SendEmailRequest::from(email)).unwrap();
+        assert_eq!(
+            request(&email),
+            serde_json::json!({
+                "From": "recipes@example.com",
+                "To": "ursula@example.com",
+                "Subject": "Welcome",
+                "HtmlBody": "<p>Hi</p>",
+                "TextBody": "Hi",
+            })
+        );
+
+        email
+            .headers
+            .push(("List-Unsubscribe".into(), "<https://example.com>".into()));
+        assert_eq!(
+            request(&email)["Headers"],
+            serde_json::json!([{ "Name": "List-Unsubscribe", "Value": "<https://example.com>" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn smtp_rejects_invalid_messages_before_connecting() {
+        // Nothing listens on the port, sending would fail with a connection error instead.
+        let smtp = SmtpTransport::new(
+            "localhost",
+            1,
+            SmtpSecurity::None,
+            None,
+            std::time::Duration::from_secs(1),
+        )
+        .unwrap();
+        let error = smtp.send(&email("not an address")).await.unwrap_err();
+        assert_eq!(error.to_string(), "Invalid recipient");
+
+        let mut invalid_header = email("ursula@example.com");
+        invalid_header
+            .headers
+            .push(("List Unsubscribe".into(), "<https://example.com>".into()));
+        let error = smtp.send(&invalid_header).await.unwrap_err();
+        assert_eq!(error.to_string(), "Invalid header name `List Unsubscribe`");
+    }
+
+    #[tokio::test]
+    async fn file_transport_writes_every_email_to_its_own_file() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let transport = FileTransport::new(Some(directory.clone()));
+        transport.send(&email("ursula@example.com")).await.unwrap();
+        transport.send(&email("vera@example.com")).await.unwrap();
+
+        let mut recipients = Vec::new();
+        for entry in std::fs::read_dir(&directory).unwrap() {
+            let json: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(entry.unwrap().path()).unwrap()).unwrap();
+            assert_eq!(json["subject"], "Welcome");
+            recipients.push(json["to"].as_str().unwrap().to_owned());
+        }
+        recipients.sort();
+        assert_eq!(recipients, ["ursula@example.com", "vera@example.com"]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn in_memory_transport_is_shared_by_its_clones() {
+        let transport = InMemoryTransport::new();
+        let client_side = transport.clone();
+        client_side
+            .send(&email("ursula@example.com"))
+            .await
+            .unwrap();
+
+        let sent = transport.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "ursula@example.com");
+    }
+}
@@ -0,0 +1,105 @@
+//! Signed unsubscribe links, so users can opt out of a category of email without logging in.
+//!
+//! The link identifies the user and the category, and carries a keyed BLAKE3 hash of both. It's
+//! sent twice: as an RFC 8058 one-click `List-Unsubscribe` header pointing at the API, and as a
+//! link to the frontend's unsubscribe page in the body.
+use secrecy::{ExposeSecret, SecretString};
+
+use super::{build_link, preferences::EmailCategory};
+
+const KEY_CONTEXT: &str = "recipes 2025-01-21 email unsubscribe links";
+
+pub struct UnsubscribeLinks {
+    key: [u8; 32],
+    api_url: String,
+    frontend_url: String,
+}
+
+impl UnsubscribeLinks {
+    pub fn new(secret: &SecretString, api_url: String, frontend_url: String) -> Self {
+        Self {
+            key: blake3::derive_key(KEY_CONTEXT, secret.expose_secret().as_bytes()),
+            api_url,
+            frontend_url,
+        }
+    }
+
+    fn signature(&self, user_id: uuid::Uuid, category: EmailCategory) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        hasher.update(user_id.as_bytes());
+        hasher.update(category.as_str().as_bytes());
+        hasher.finalize()
+    }
+
+    pub fn verify(&self, user_id: uuid::Uuid, category: EmailCategory, signature: &str) -> bool {
+        // `blake3::Hash` compares in constant time.
+        blake3::Hash::from_hex(signature)
+            .is_ok_and(|signature| signature == self.signature(user_id, category))
+    }
+
+    fn link(
+        &self,
+        base_url: &str,
+        path: &str,
+        user_id: uuid::Uuid,
+        category: EmailCategory,
+    ) -> anyhow::Result<String> {
+        let user = user_id.to_string();
+        let signature = self.signature(user_id, category).to_hex();
+        build_link(
+            base_url,
+            path,
+            &[
+                ("user", &user),
+                ("category", category.as_str()),
+                ("signature", &signature),
+            ],
+        )
+    }
+
+    /// The target of the `List-Unsubscribe` header, which mail clients `POST` to.
+    pub fn one_click_url(
+        &self,
+        user_id: uuid::Uuid,
+        category: EmailCategory,
+    ) -> anyhow::Result<String> {
+        self.link(&self.api_url, "email/unsubscribe", user_id, category)
+    }
+
+    /// The frontend's unsubscribe page, for the link in the body.
+    pub fn page_url(&self, user_id: uuid::Uuid, category: EmailCategory) -> anyhow::Result<String> {
+        self.link(&self.frontend_url, "unsubscribe", user_id, category)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn links(secret: &str) -> UnsubscribeLinks {
+        UnsubscribeLinks::new(
+            &SecretString::from(secret.to_owned()),
+            "http://localhost:3000".to_owned(),
+            "http://localhost:3001".to_owned(),
+        )
+    }
+
+    #[test]
+    fn verifies_its_own_signatures() {
+        let links = links("secret");
+        let user_id = uuid::Uuid::new_v4();
+        let signature = links.signature(user_id, EmailCategory::Digest).to_hex();
+        assert!(links.verify(user_id, EmailCategory::Digest, &signature));
+    }
+
+    #[test]
+    fn rejects_signatures_of_something_else() {
+        let links = links("secret");
+        let user_id = uuid::Uuid::new_v4();
+        let signature = links.signature(user_id, EmailCategory::Digest).to_hex();
+        assert!(!links.verify(user_id, EmailCategory::Announcements, &signature));
+        assert!(!links.verify(uuid::Uuid::new_v4(), EmailCategory::Digest, &signature));
+        assert!(!self::links("other secret").verify(user_id, EmailCategory::Digest, &signature));
+        assert!(!links.verify(user_id, EmailCategory::Digest, "not a signature"));
+    }
+}
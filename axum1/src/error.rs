@@ -1,4 +1,4 @@
-use axum::http::header::WWW_AUTHENTICATE;
+use axum::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::Json;
@@ -35,6 +35,10 @@ pub enum ApiError {
     #[error("conflict")]
     Conflict,
 
+    /// Return `413 Payload Too Large`
+    #[error("request payload too large")]
+    PayloadTooLarge,
+
     /// Return `422 Unprocessable Entity`
     ///
     /// This also serializes the `errors` map to JSON.
@@ -46,6 +50,10 @@ pub enum ApiError {
         errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
     },
 
+    /// Return `429 Too Many Requests`, with a `Retry-After` header.
+    #[error("too many requests")]
+    TooManyRequests { retry_after: std::time::Duration },
+
     /// Automatically return `500 Internal Server Error` on a `sqlx::Error`.
     ///
     /// Via the generated `From<sqlx::Error> for Error` impl,
@@ -132,7 +140,9 @@ impl ApiError {
             Self::Forbidden => StatusCode::FORBIDDEN,
             Self::NotFound => StatusCode::NOT_FOUND,
             Self::Conflict => StatusCode::CONFLICT,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
             Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
             Self::Sqlx(_) | Self::Anyhow(_) | Self::Reqwest(_) | Self::Session(_) => {
                 StatusCode::INTERNAL_SERVER_ERROR
             }
@@ -170,6 +180,14 @@ impl IntoResponse for ApiError {
                 )
                     .into_response();
             }
+            Self::TooManyRequests { retry_after } => {
+                return (
+                    self.status_code(),
+                    [(RETRY_AFTER, retry_after.as_secs().max(1).to_string())],
+                    self.to_string(),
+                )
+                    .into_response();
+            }
             Self::Sqlx(ref e) => {
                 tracing::error!("SQLx error: {:?}", e);
             }
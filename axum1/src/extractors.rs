@@ -1,10 +1,26 @@
-use std::{convert::Infallible, ops::Deref};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    ops::Deref,
+};
 
-use crate::{error::ApiError, state::AppState};
+use crate::{
+    email::templates::Locale,
+    error::ApiError,
+    routes::{
+        auth::{authenticate_token, TokenScope},
+        oauth_server::{authenticate_access_token, ACCESS_TOKEN_PREFIX},
+    },
+    state::AppState,
+};
 use axum::{
     async_trait,
-    extract::{FromRef, FromRequestParts},
-    http::request::Parts,
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+    http::{
+        header::{ACCEPT_LANGUAGE, AUTHORIZATION},
+        request::Parts,
+        HeaderMap,
+    },
 };
 use sqlx::{pool, Postgres};
 use tower_sessions::Session;
@@ -43,22 +59,93 @@ impl Deref for AuthUser {
     }
 }
 
+/// The token of an `Authorization: Bearer` header, if the request has one.
+pub fn bearer_token(headers: &HeaderMap) -> Result<Option<&str>, ApiError> {
+    let Some(value) = headers.get(AUTHORIZATION) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| Some(token.trim()))
+        .ok_or(ApiError::Unauthorized)
+}
+
+/// The user the request is authenticated as: the owner of its API token if it has one, the one
+/// logged into the session otherwise. Personal access tokens need a scope that covers the
+/// request's method, access tokens of third-party apps one that covers the route's resource.
+async fn authenticated_user<S>(parts: &mut Parts, state: &S) -> Result<Option<uuid::Uuid>, ApiError>
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    if let Some(token) = bearer_token(&parts.headers)? {
+        let AppState { db_pool, .. } = AppState::from_ref(state);
+        if token.starts_with(ACCESS_TOKEN_PREFIX) {
+            return authenticate_access_token(&db_pool, token, parts)
+                .await
+                .map(Some);
+        }
+        let scope = TokenScope::for_method(&parts.method);
+        return authenticate_token(&db_pool, token, scope).await.map(Some);
+    }
+
+    let session = Session::from_request_parts(parts, state)
+        .await
+        .expect("`SessionLayer` should be added");
+    Ok(session.get::<uuid::Uuid>("user_id").await?)
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for AuthUser
 where
     S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        authenticated_user(parts, state)
+            .await?
+            .map(Self::new)
+            .ok_or(ApiError::Unauthorized)
+    }
+}
+
+/// The user logged into the session. Unlike `AuthUser`, tokens aren't accepted: routes that manage
+/// how an account is logged into, like passkeys, second factors and sessions, take this one, so a
+/// leaked token can't be turned into a way to log in.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionUser(pub uuid::Uuid);
+
+impl Deref for SessionUser {
+    type Target = uuid::Uuid;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SessionUser
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
 {
     type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if bearer_token(&parts.headers)?.is_some() {
+            return Err(ApiError::Forbidden);
+        }
         let session = Session::from_request_parts(parts, state)
             .await
             .expect("`SessionLayer` should be added");
-
         session
             .get::<uuid::Uuid>("user_id")
             .await?
-            .map(Self::new)
+            .map(Self)
             .ok_or(ApiError::Unauthorized)
     }
 }
@@ -75,15 +162,12 @@ impl MaybeAuthUser {
 impl<S> FromRequestParts<S> for MaybeAuthUser
 where
     S: Send + Sync,
+    AppState: FromRef<S>,
 {
     type Rejection = Infallible;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let session = Session::from_request_parts(parts, state)
-            .await
-            .expect("`SessionLayer` should be added");
-
-        let user_id = session.get::<uuid::Uuid>("user_id").await;
+        let user_id = authenticated_user(parts, state).await;
 
         match user_id {
             Ok(Some(id)) => Ok(Self(Some(AuthUser::new(id)))),
@@ -92,10 +176,73 @@ where
     }
 }
 
+/// The language the client prefers out of the ones we have email templates for, based on the
+/// `Accept-Language` header. Falls back to the default locale.
+pub struct PreferredLocale(pub Locale);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for PreferredLocale
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let locale = parts
+            .headers
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Locale::from_accept_language)
+            .unwrap_or_default();
+        Ok(Self(locale))
+    }
+}
+
+/// The IP the request came from. That's the peer address, unless
+/// `application_settings.trust_forwarded_for` is set, in which case it's the last address in
+/// `X-Forwarded-For`: the one our reverse proxy appended. The ones before it are up to the client.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+    AppState: FromRef<S>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AppState { config, .. } = AppState::from_ref(state);
+        if config.borrow().application_settings.trust_forwarded_for {
+            let forwarded_for = parts
+                .headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .last()
+                .and_then(|ip| ip.trim().parse().ok());
+            if let Some(ip) = forwarded_for {
+                return Ok(Self(ip));
+            }
+        }
+        let ConnectInfo(addr) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .ok_or_else(|| anyhow::anyhow!("The server isn't serving with connect info"))?;
+        Ok(Self(addr.ip()))
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Uploader {
     pub id: uuid::Uuid,
-    pub bytes_limit: i64,
+    /// The number of bytes the user may still upload in the current daily window, as of when the
+    /// request started. Concurrent uploads may use them up, the quota is checked again when an
+    /// upload is counted.
+    pub bytes_remaining: u64,
+    pub daily_limit_bytes: i64,
 }
 
 #[async_trait]
@@ -107,12 +254,14 @@ where
     type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let session = Session::from_request_parts(parts, state)
-            .await
-            .expect("`SessionLayer` should be added");
+        let user_id = authenticated_user(parts, state)
+            .await?
+            .ok_or(ApiError::Unauthorized)?;
 
         let AppState {
-            db_pool, mut config, ..
+            db_pool,
+            mut config,
+            ..
         } = AppState::from_ref(state);
         let daily_upload_limit_bytes = config
             .borrow_and_update()
@@ -121,24 +270,12 @@ where
 
         let mut db = db_pool.acquire().await?;
 
-        let user_id = session
-            .get::<uuid::Uuid>("user_id")
-            .await?
-            .ok_or(ApiError::Unauthorized)?;
+        let bytes_used = crate::upload::bytes_used_today(&mut db, user_id).await?;
 
-        let bytes_limit = sqlx::query!(
-            "SELECT COALESCE(SUM(bytes), 0) as upload_limit FROM uploads
-            WHERE uploader_id = $1 AND created_at > current_timestamp - INTERVAL '1 days';",
-            user_id
-        )
-        .fetch_one(&mut *db)
-        .await?
-        .upload_limit
-        .unwrap_or(daily_upload_limit_bytes);
-
-        if bytes_limit < daily_upload_limit_bytes {
+        if bytes_used < daily_upload_limit_bytes {
             Ok(Self {
-                bytes_limit,
+                bytes_remaining: (daily_upload_limit_bytes - bytes_used) as u64,
+                daily_limit_bytes: daily_upload_limit_bytes,
                 id: user_id,
             })
         } else {
@@ -7,12 +7,15 @@ pub mod email;
 pub mod error;
 pub mod extractors;
 pub mod queue;
+pub mod rate_limit;
 pub mod routes;
 pub mod search;
 pub mod sse;
 pub mod startup;
 pub mod state;
 pub mod task;
+#[cfg(test)]
+mod testing;
 pub mod upload;
 pub mod utils;
 
@@ -2,12 +2,12 @@ use axum1::{
     cli::cli_manager,
     config::get_config,
     queue::run_worker_until_stopped,
-    search::run_meili_indexer_until_stopped,
     startup::application,
     task::supervised_task,
     utils::{init_tracing_panic_hook, report_exit},
 };
 use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
@@ -36,21 +36,32 @@ async fn main() -> anyhow::Result<()> {
     init_tracing_panic_hook();
 
     let application_task = tokio::spawn(application(rx.clone()));
-    let worker_task = run_worker_until_stopped(rx.clone());
-    let meili_indexing_task = run_meili_indexer_until_stopped(rx.clone());
+    let worker_shutdown = CancellationToken::new();
+    let drain_timeout = std::time::Duration::from_secs(tx.borrow().queue.drain_timeout_seconds);
+    let worker_task = run_worker_until_stopped(rx.clone(), worker_shutdown.clone());
 
-    let (meili_task_spawned, meili_supervisor) = supervised_task(meili_indexing_task);
-    let (worker_task_spawned, worker_supervisor) = supervised_task(worker_task);
+    let (mut worker_task_spawned, worker_supervisor) = supervised_task(worker_task);
 
-    let cli_manager_task =
-        tokio::spawn(cli_manager(tx.clone(), meili_supervisor, worker_supervisor));
+    let cli_manager_task = tokio::spawn(cli_manager(tx.clone(), worker_supervisor));
 
+    let mut worker_exited = false;
     tokio::select! {
         f = application_task => report_exit("server", f),
-        f = meili_task_spawned => report_exit("meili indexing", f),
-        f = worker_task_spawned => report_exit("queue", f),
+        f = &mut worker_task_spawned => {
+            worker_exited = true;
+            report_exit("queue", f);
+        }
         f = cli_manager_task => report_exit("CLI Manager", f),
     };
 
+    // Let the workers finish the jobs they're on, so nothing is left half-processed.
+    if !worker_exited {
+        worker_shutdown.cancel();
+        match tokio::time::timeout(drain_timeout, worker_task_spawned).await {
+            Ok(f) => report_exit("queue", f),
+            Err(_) => tracing::warn!("Queue workers didn't finish within {drain_timeout:?}"),
+        }
+    }
+
     Ok(())
 }
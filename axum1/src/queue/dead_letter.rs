@@ -0,0 +1,148 @@
+//! Jobs that exhausted their attempts end up in `failed_jobs`. From there they can be inspected,
+//! requeued with a fresh set of attempts, or purged, both through the admin API and the CLI.
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, PgConnection, Postgres};
+use uuid::Uuid;
+
+pub(super) struct DeadJob<'a> {
+    pub id: Uuid,
+    pub job_type: &'a str,
+    pub payload: &'a serde_json::Value,
+    pub error: &'a str,
+    pub attempts: i32,
+    pub priority: i32,
+    /// The worker's claim on the job, it's only moved if the claim still holds.
+    pub claimed_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FailedJob {
+    pub job_id: Uuid,
+    pub job_type: String,
+    /// Missing for jobs that failed before retries were introduced, those can't be requeued.
+    pub payload: Option<serde_json::Value>,
+    pub error: Option<String>,
+    pub attempts: i32,
+    /// Whatever was recorded by older versions of the queue.
+    pub context: Option<serde_json::Value>,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Moves a job from the queue to `failed_jobs`. Returns `false` if another worker took the job
+/// over in the meantime, it's left alone then.
+pub(super) async fn insert(conn: &mut PgConnection, job: DeadJob<'_>) -> Result<bool, sqlx::Error> {
+    let removed = sqlx::query!(
+        "DELETE FROM jobs WHERE id = $1 AND locked_at = $2",
+        job.id,
+        job.claimed_at,
+    )
+    .execute(&mut *conn)
+    .await?;
+    if removed.rows_affected() == 0 {
+        return Ok(false);
+    }
+    sqlx::query!(
+        r#"
+        INSERT INTO failed_jobs (job_id, job_type, payload, error, attempts, priority)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        job.id,
+        job.job_type,
+        job.payload,
+        job.error,
+        job.attempts,
+        job.priority,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(true)
+}
+
+/// Lists the most recently failed jobs first, optionally only those of a given type.
+pub async fn list(
+    conn: impl Executor<'_, Database = Postgres>,
+    job_type: Option<&str>,
+    limit: i64,
+) -> Result<Vec<FailedJob>, sqlx::Error> {
+    sqlx::query_as!(
+        FailedJob,
+        r#"
+        SELECT job_id, job_type, payload, error, attempts, context, failed_at
+        FROM failed_jobs
+        WHERE $1::TEXT IS NULL OR job_type = $1
+        ORDER BY failed_at DESC
+        LIMIT $2
+        "#,
+        job_type,
+        limit,
+    )
+    .fetch_all(conn)
+    .await
+}
+
+pub async fn get(
+    conn: impl Executor<'_, Database = Postgres>,
+    job_id: Uuid,
+) -> Result<Option<FailedJob>, sqlx::Error> {
+    sqlx::query_as!(
+        FailedJob,
+        r#"
+        SELECT job_id, job_type, payload, error, attempts, context, failed_at
+        FROM failed_jobs
+        WHERE job_id = $1
+        "#,
+        job_id,
+    )
+    .fetch_optional(conn)
+    .await
+}
+
+/// Puts a failed job back into the queue with its attempts reset, to run as soon as possible.
+///
+/// Returns `false` if there's no such job, or it has no payload to requeue.
+pub async fn requeue(
+    conn: impl Executor<'_, Database = Postgres>,
+    job_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let requeued = sqlx::query!(
+        r#"
+        WITH failed AS (
+            DELETE FROM failed_jobs
+            WHERE job_id = $1 AND payload IS NOT NULL
+            RETURNING job_id, job_type, payload, priority
+        )
+        INSERT INTO jobs (id, job_type, payload, priority)
+        SELECT job_id, job_type, payload, priority FROM failed
+        "#,
+        job_id,
+    )
+    .execute(conn)
+    .await?;
+    Ok(requeued.rows_affected() > 0)
+}
+
+/// Deletes a single failed job for good. Returns `false` if there's no such job.
+pub async fn delete(
+    conn: impl Executor<'_, Database = Postgres>,
+    job_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let deleted = sqlx::query!("DELETE FROM failed_jobs WHERE job_id = $1", job_id)
+        .execute(conn)
+        .await?;
+    Ok(deleted.rows_affected() > 0)
+}
+
+/// Deletes all failed jobs, or only those of a given type, and returns how many were deleted.
+pub async fn purge(
+    conn: impl Executor<'_, Database = Postgres>,
+    job_type: Option<&str>,
+) -> Result<u64, sqlx::Error> {
+    let purged = sqlx::query!(
+        "DELETE FROM failed_jobs WHERE $1::TEXT IS NULL OR job_type = $1",
+        job_type
+    )
+    .execute(conn)
+    .await?;
+    Ok(purged.rows_affected())
+}
@@ -1,12 +1,44 @@
-use std::time::Duration;
+use std::{
+    any::Any,
+    collections::HashMap,
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use sqlx::{postgres::PgPoolOptions, PgPool, Postgres, Transaction};
-use tracing::{field::display, Span};
+use anyhow::Context;
+use axum::async_trait;
+use futures::{future::BoxFuture, FutureExt};
+use meilisearch_sdk::client::Client;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{
+    postgres::{PgListener, PgPoolOptions},
+    Executor, PgPool, Postgres,
+};
+use tokio::{sync::Notify, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::{field::display, Instrument, Span};
 
-use crate::config::{DatabaseSettings, Settings};
+use crate::config::{DatabaseSettings, QueueSettings, Settings, TokenSettings};
+use crate::email::{
+    digest::{SendDigest, SendWeeklyDigests},
+    EmailClient,
+};
+use crate::routes::auth::{
+    PurgeExpiredTokens, SendAccountExistsEmail, SendAccountLockedEmail, SendConfirmationEmail,
+    SendPasswordChangedEmail, SendPasswordResetEmail,
+};
+use crate::routes::recipe::RefreshHotRecipes;
+use crate::search::IndexSearch;
+use crate::upload::{
+    scan::{ScanUpload, Scanner},
+    PurgeExpiredUploadSessions,
+};
+use crate::utils::report_exit;
 
-use crate::email::{Email, EmailClient};
-use crate::error::ApiError;
+pub mod dead_letter;
+pub mod schedule;
+pub mod stats;
 
 pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
     PgPoolOptions::new()
@@ -14,31 +46,364 @@ pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
         .connect_lazy_with(configuration.with_db())
 }
 
+/// Everything a job may need while it's running.
+pub struct JobContext {
+    pub pool: PgPool,
+    pub email_client: EmailClient,
+    pub scanner: Arc<dyn Scanner>,
+    pub meili_client: Client,
+    /// Base of the links in emails.
+    pub frontend_url: String,
+    /// How long the tokens in emails are valid for.
+    pub tokens: TokenSettings,
+}
+
+/// A unit of background work that's persisted in the `jobs` table until a worker picks it up.
+///
+/// The job itself is the payload: it's serialized to JSON when enqueued, and deserialized again
+/// right before it runs. Jobs may run more than once, so `run` should be idempotent.
+#[async_trait]
+pub trait Job: Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// Identifies the handler in `jobs.job_type`, so it must be unique and must never change.
+    const JOB_TYPE: &'static str;
+
+    /// Due jobs with a higher priority are picked up first.
+    const PRIORITY: i32 = 0;
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()>;
+}
+
+type Handler = Box<
+    dyn Fn(serde_json::Value, Arc<JobContext>) -> BoxFuture<'static, anyhow::Result<()>>
+        + Send
+        + Sync,
+>;
+
+/// Maps job types to their handlers. Workers only pick up jobs with a registered type.
+#[derive(Default)]
+pub struct JobRegistry {
+    handlers: HashMap<&'static str, Handler>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<J: Job>(mut self) -> Self {
+        let handler: Handler = Box::new(|payload, ctx| {
+            Box::pin(async move {
+                let job = serde_json::from_value::<J>(payload)
+                    .with_context(|| format!("Invalid payload for job type {}", J::JOB_TYPE))?;
+                job.run(&ctx).await
+            })
+        });
+        if self.handlers.insert(J::JOB_TYPE, handler).is_some() {
+            panic!("job type `{}` is registered twice", J::JOB_TYPE);
+        }
+        self
+    }
+
+    fn job_types(&self) -> Vec<String> {
+        self.handlers
+            .keys()
+            .map(|&job_type| job_type.into())
+            .collect()
+    }
+}
+
+pub fn default_registry() -> JobRegistry {
+    JobRegistry::new()
+        .register::<SendConfirmationEmail>()
+        .register::<SendPasswordResetEmail>()
+        .register::<SendAccountExistsEmail>()
+        .register::<SendAccountLockedEmail>()
+        .register::<SendPasswordChangedEmail>()
+        .register::<ScanUpload>()
+        .register::<IndexSearch>()
+        .register::<PurgeExpiredTokens>()
+        .register::<PurgeExpiredUploadSessions>()
+        .register::<RefreshHotRecipes>()
+        .register::<SendWeeklyDigests>()
+        .register::<SendDigest>()
+}
+
+/// Enqueues a job to run as soon as possible.
+///
+/// Pass a transaction as the executor to only make the job visible to workers if the surrounding
+/// work is committed.
+pub async fn enqueue<J: Job>(
+    tx: impl Executor<'_, Database = Postgres>,
+    job: &J,
+) -> Result<uuid::Uuid, sqlx::Error> {
+    enqueue_at(tx, job, None).await
+}
+
+/// Enqueues a job that won't be picked up before `run_at`, or right away if it's `None`.
+#[tracing::instrument(skip_all, fields(job_type = J::JOB_TYPE))]
+pub async fn enqueue_at<J: Job>(
+    tx: impl Executor<'_, Database = Postgres>,
+    job: &J,
+    run_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Result<uuid::Uuid, sqlx::Error> {
+    let payload = serde_json::to_value(job).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO jobs (job_type, payload, priority, run_at)
+        VALUES ($1, $2, $3, COALESCE($4, NOW()))
+        RETURNING id
+        "#,
+        J::JOB_TYPE,
+        payload,
+        J::PRIORITY,
+        run_at,
+    )
+    .fetch_one(tx)
+    .await
+}
+
+/// Enqueues a job unless the same job is still waiting to be picked up, for jobs that catch up on
+/// everything that happened before they run. Returns `None` if one is waiting already.
+pub async fn enqueue_once<J: Job>(
+    tx: impl Executor<'_, Database = Postgres>,
+    job: &J,
+) -> Result<Option<uuid::Uuid>, sqlx::Error> {
+    let payload = serde_json::to_value(job).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    enqueue_unless_pending(tx, J::JOB_TYPE, &payload, J::PRIORITY).await
+}
+
+/// Enqueues an already serialized job, unless the same job is still waiting in the queue.
+/// Returns `None` in that case.
+///
+/// A job that's running doesn't count, it may have missed whatever the new one is for.
+async fn enqueue_unless_pending(
+    tx: impl Executor<'_, Database = Postgres>,
+    job_type: &str,
+    payload: &serde_json::Value,
+    priority: i32,
+) -> Result<Option<uuid::Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO jobs (job_type, payload, priority)
+        SELECT $1, $2, $3
+        WHERE NOT EXISTS (
+            SELECT 1 FROM jobs
+            WHERE job_type = $1 AND payload = $2 AND attempts = 0 AND locked_at IS NULL
+        )
+        RETURNING id
+        "#,
+        job_type,
+        payload,
+        priority,
+    )
+    .fetch_optional(tx)
+    .await
+}
+
+/// The channel `jobs` inserts are announced on, see the `notify_job_inserted` trigger.
+const JOBS_CHANNEL: &str = "jobs";
+
+/// Runs a pool of workers until `shutdown` is cancelled, then waits for in-flight jobs to finish.
 pub async fn run_worker_until_stopped(
     mut configuration: tokio::sync::watch::Receiver<Settings>,
+    shutdown: CancellationToken,
 ) -> Result<(), anyhow::Error> {
+    let settings = configuration.borrow_and_update().clone();
+    let pool = get_connection_pool(&settings.database);
+    let email_client = settings.email_client(pool.clone());
     let Settings {
-        database,
-        email_client,
+        upload_scanner,
+        meili,
+        queue,
+        frontend_url,
+        tokens,
         ..
-    } = configuration.borrow_and_update().clone();
-    let connection_pool = get_connection_pool(&database);
-    let email_client = email_client.client();
-    worker_loop(connection_pool, email_client).await
-}
+    } = settings;
+    let ctx = Arc::new(JobContext {
+        pool,
+        email_client,
+        scanner: upload_scanner.scanner(),
+        meili_client: Client::new(meili.url, Some(meili.master_key))?,
+        frontend_url,
+        tokens,
+    });
+    let registry = Arc::new(default_registry());
+    // Fails early on invalid schedules, instead of when the scheduler is spawned.
+    schedule::default_scheduler(queue.schedules.clone())?;
+    let settings = Arc::new(queue);
+    let wakeup = Arc::new(Notify::new());
+
+    let mut listener = PgListener::connect_with(&ctx.pool).await?;
+    listener.listen(JOBS_CHANNEL).await?;
+
+    let spawn_worker = |workers: &mut JoinSet<anyhow::Result<()>>, worker: usize| {
+        let handle = workers.spawn(
+            worker_loop(
+                Arc::clone(&ctx),
+                Arc::clone(&registry),
+                Arc::clone(&settings),
+                Arc::clone(&wakeup),
+                shutdown.clone(),
+            )
+            .instrument(tracing::info_span!("queue_worker", worker)),
+        );
+        (handle.id(), worker)
+    };
+    let mut workers = JoinSet::new();
+    let mut worker_ids: HashMap<_, _> = (0..settings.concurrency.max(1))
+        .map(|worker| spawn_worker(&mut workers, worker))
+        .collect();
+
+    let spawn_background = |tasks: &mut JoinSet<anyhow::Result<()>>, task| {
+        let handle = match task {
+            BackgroundTask::Scheduler => tasks.spawn(
+                schedule::run_scheduler_until_stopped(
+                    ctx.pool.clone(),
+                    schedule::default_scheduler(settings.schedules.clone())?,
+                    shutdown.clone(),
+                )
+                .instrument(tracing::info_span!("queue_scheduler")),
+            ),
+            BackgroundTask::DepthSampler => tasks.spawn(stats::sample_queue_depth(
+                ctx.pool.clone(),
+                registry.job_types(),
+                shutdown.clone(),
+            )),
+        };
+        anyhow::Ok((handle.id(), task))
+    };
+    let mut background = JoinSet::new();
+    let mut background_ids = HashMap::from([
+        spawn_background(&mut background, BackgroundTask::Scheduler)?,
+        spawn_background(&mut background, BackgroundTask::DepthSampler)?,
+    ]);
 
-async fn worker_loop(pool: PgPool, email_client: EmailClient) -> Result<(), anyhow::Error> {
     loop {
-        match try_execute_task(&pool, &email_client).await {
-            Ok(ExecutionOutcome::EmptyQueue) => {
-                tokio::time::sleep(Duration::from_secs(10)).await;
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            notification = listener.recv() => match notification {
+                Ok(_) => wakeup.notify_one(),
+                Err(e) => {
+                    // The listener reconnects on the next `recv`, but notifications sent in the
+                    // meantime are lost, so let every worker check the queue.
+                    tracing::warn!(error.cause_chain = ?e, "Lost the job notification listener");
+                    wakeup.notify_waiters();
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            },
+            Some(exited) = workers.join_next_with_id() => {
+                let worker = report_worker_exit(&mut worker_ids, exited);
+                if shutdown.is_cancelled() {
+                    continue;
+                }
+                // Jobs that panic are caught, so this is rare, but the pool shouldn't shrink.
+                tracing::warn!("Restarting queue worker {worker}..");
+                let (id, worker) = spawn_worker(&mut workers, worker);
+                worker_ids.insert(id, worker);
             }
-            Err(_) => {
-                tokio::time::sleep(Duration::from_secs(1)).await;
+            Some(exited) = background.join_next_with_id() => {
+                let task = report_background_exit(&mut background_ids, exited);
+                if shutdown.is_cancelled() {
+                    continue;
+                }
+                // Without the scheduler recurring jobs stop, so it's restarted rather than
+                // waiting for someone to notice.
+                tracing::warn!("Restarting the {} in {RESTART_BACKGROUND_AFTER:?}..", task.name());
+                tokio::select! {
+                    _ = tokio::time::sleep(RESTART_BACKGROUND_AFTER) => {}
+                    _ = shutdown.cancelled() => continue,
+                }
+                let (id, task) = spawn_background(&mut background, task)?;
+                background_ids.insert(id, task);
             }
-            Ok(ExecutionOutcome::TaskCompleted) => {}
         }
     }
+
+    tracing::info!(
+        "Waiting for {} workers to finish their jobs..",
+        workers.len()
+    );
+    while let Some(exited) = workers.join_next_with_id().await {
+        report_worker_exit(&mut worker_ids, exited);
+    }
+    while let Some(exited) = background.join_next_with_id().await {
+        report_background_exit(&mut background_ids, exited);
+    }
+    Ok(())
+}
+
+/// How long a background task that stopped is left alone before it's restarted.
+const RESTART_BACKGROUND_AFTER: Duration = Duration::from_secs(5);
+
+/// Tasks that run next to the workers, until shutdown.
+#[derive(Clone, Copy)]
+enum BackgroundTask {
+    Scheduler,
+    DepthSampler,
+}
+
+impl BackgroundTask {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Scheduler => "queue scheduler",
+            Self::DepthSampler => "queue depth sampler",
+        }
+    }
+}
+
+type TaskExit = Result<(tokio::task::Id, anyhow::Result<()>), tokio::task::JoinError>;
+
+/// Stops tracking a task that exited, returning what it was along with how it exited.
+fn untrack<T>(
+    running: &mut HashMap<tokio::task::Id, T>,
+    exited: TaskExit,
+) -> (T, Result<anyhow::Result<()>, tokio::task::JoinError>) {
+    let (id, outcome) = match exited {
+        Ok((id, result)) => (id, Ok(result)),
+        Err(e) => (e.id(), Err(e)),
+    };
+    let task = running.remove(&id).expect("every task is tracked");
+    (task, outcome)
+}
+
+fn report_background_exit(
+    running: &mut HashMap<tokio::task::Id, BackgroundTask>,
+    exited: TaskExit,
+) -> BackgroundTask {
+    let (task, outcome) = untrack(running, exited);
+    report_exit(task.name(), outcome);
+    task
+}
+
+fn report_worker_exit(running: &mut HashMap<tokio::task::Id, usize>, exited: TaskExit) -> usize {
+    let (worker, outcome) = untrack(running, exited);
+    report_exit(&format!("queue worker {worker}"), outcome);
+    worker
+}
+
+async fn worker_loop(
+    ctx: Arc<JobContext>,
+    registry: Arc<JobRegistry>,
+    settings: Arc<QueueSettings>,
+    wakeup: Arc<Notify>,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    let job_types = registry.job_types();
+    let poll_interval = Duration::from_secs(settings.poll_interval_seconds);
+    // A job that has been picked up always runs to completion, shutdown is only checked between jobs.
+    while !shutdown.is_cancelled() {
+        let idle_for = match try_execute_job(&ctx, &registry, &job_types, &settings).await {
+            Ok(ExecutionOutcome::TaskCompleted) => continue,
+            Ok(ExecutionOutcome::EmptyQueue) => poll_interval,
+            Err(_) => Duration::from_secs(1),
+        };
+        tokio::select! {
+            _ = wakeup.notified() => {}
+            _ = tokio::time::sleep(idle_for) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+    Ok(())
 }
 
 pub enum ExecutionOutcome {
@@ -46,123 +411,435 @@ pub enum ExecutionOutcome {
     EmptyQueue,
 }
 
-pub async fn try_execute_task(
-    pool: &PgPool,
-    email_client: &EmailClient,
+#[tracing::instrument(skip_all, fields(job_id, job_type, attempt))]
+pub async fn try_execute_job(
+    ctx: &Arc<JobContext>,
+    registry: &JobRegistry,
+    job_types: &[String],
+    settings: &QueueSettings,
 ) -> Result<ExecutionOutcome, anyhow::Error> {
-    let task = dequeue_task(pool).await?;
-    if task.is_none() {
+    // The claim is committed right away, so the job shows up as running in `stats::summary`.
+    // It's refreshed while the job runs, claims of workers that died are taken over after a minute.
+    let job = sqlx::query!(
+        r#"
+        UPDATE jobs SET locked_at = NOW()
+        WHERE id = (
+            SELECT id FROM jobs
+            WHERE run_at <= NOW() AND job_type = ANY($1)
+                AND (locked_at IS NULL OR locked_at < NOW() - INTERVAL '1 minute')
+            ORDER BY priority DESC, run_at
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        )
+        RETURNING id, job_type, payload, attempts, priority, locked_at AS "claimed_at!",
+            EXTRACT(EPOCH FROM NOW() - run_at)::FLOAT8 AS "waited_seconds!"
+        "#,
+        job_types,
+    )
+    .fetch_optional(&ctx.pool)
+    .await?;
+    let Some(job) = job else {
         return Ok(ExecutionOutcome::EmptyQueue);
-    }
-    let (mut transaction, confirmation_id, email) = task.unwrap();
+    };
+    let attempt = job.attempts + 1;
     Span::current()
-        .record("confirmation_id", display(confirmation_id.clone()))
-        .record("user_email", display(&email));
-    match Email::parse(email.clone()) {
-        Ok(email) => {
-            if let Err(e) = email_client
-                .send_mail(
-                    email.clone(),
-                    "Recipe App confirm registration",
-                    &format!(
-                        "Visit http://localhost:3001/confirm?token={} to confirm your registration.",
-                        confirmation_id
-                    ),
-                    &format!(
-                        "Visit <a href=http://localhost:3001/confirm?token={}>the website</a> to confirm your registration.",
-                        confirmation_id
-                    ),
-                )
-                .await
-            {
-                insert_failed_task(&mut *transaction, confirmation_id.clone(), email.as_ref(), &e).await?;
-                tracing::error!(
-                    error.cause_chain = ?e,
-                    error.message = %e,
-                    "Failed to deliver email to a confirm registration. \
-                        Skipping.",
-                );
+        .record("job_id", display(job.id))
+        .record("job_type", display(&job.job_type))
+        .record("attempt", attempt);
+    stats::record_pickup(
+        &job.job_type,
+        Duration::from_secs_f64(job.waited_seconds.max(0.0)),
+    );
+
+    let handler = registry
+        .handlers
+        .get(job.job_type.as_str())
+        .expect("only registered job types are dequeued");
+
+    let started = Instant::now();
+    // A panicking job counts as a failed attempt, instead of taking the worker down with it and
+    // being picked up again once its claim runs out, over and over.
+    let run = AssertUnwindSafe(handler(job.payload.clone(), Arc::clone(ctx))).catch_unwind();
+    tokio::pin!(run);
+    let mut claimed_at = job.claimed_at;
+    let mut refresh = tokio::time::interval_at(
+        tokio::time::Instant::now() + REFRESH_CLAIM_EVERY,
+        REFRESH_CLAIM_EVERY,
+    );
+    let result = loop {
+        tokio::select! {
+            result = &mut run => {
+                break result.unwrap_or_else(|panic| {
+                    Err(anyhow::anyhow!("The job panicked: {}", panic_message(&*panic)))
+                });
             }
+            _ = refresh.tick() => match refresh_claim(&ctx.pool, job.id, claimed_at).await {
+                Ok(Some(refreshed)) => claimed_at = refreshed,
+                Ok(None) => tracing::warn!("Another worker took the job over"),
+                Err(e) => {
+                    tracing::warn!(error.cause_chain = ?e, "Failed to refresh the claim on the job");
+                }
+            },
         }
-        Err(e) => {
-            insert_failed_task(&mut *transaction, confirmation_id.clone(), &email, &e).await?;
+    };
+    let took = started.elapsed();
+
+    // Whatever happened, the job is only touched if this worker still holds the claim.
+    let claim_held = match result {
+        Ok(()) => {
+            stats::record_execution(&job.job_type, stats::Outcome::Success, took);
+            sqlx::query!(
+                "DELETE FROM jobs WHERE id = $1 AND locked_at = $2",
+                job.id,
+                claimed_at,
+            )
+            .execute(&ctx.pool)
+            .await?
+            .rows_affected()
+                > 0
+        }
+        Err(e) if settings.gives_up(attempt) => {
+            stats::record_execution(&job.job_type, stats::Outcome::Failure, took);
             tracing::error!(
                 error.cause_chain = ?e,
                 error.message = %e,
-                "Skipping a sending confirmation email. \
-                    Their stored details are invalid",
+                "Failed to execute job, giving up after {attempt} attempts.",
+            );
+            let mut tx = ctx.pool.begin().await?;
+            let moved = dead_letter::insert(
+                &mut tx,
+                dead_letter::DeadJob {
+                    id: job.id,
+                    job_type: &job.job_type,
+                    payload: &job.payload,
+                    error: &e.to_string(),
+                    attempts: attempt,
+                    priority: job.priority,
+                    claimed_at,
+                },
+            )
+            .await?;
+            tx.commit().await?;
+            moved
+        }
+        Err(e) => {
+            stats::record_execution(&job.job_type, stats::Outcome::Retry, took);
+            let retry_in = settings.backoff(attempt);
+            tracing::warn!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to execute job, retrying in {}s.",
+                retry_in.as_secs(),
             );
+            sqlx::query!(
+                r#"
+                UPDATE jobs
+                SET attempts = $3, last_error = $4, run_at = NOW() + make_interval(secs => $5),
+                    locked_at = NULL
+                WHERE id = $1 AND locked_at = $2
+                "#,
+                job.id,
+                claimed_at,
+                attempt,
+                e.to_string(),
+                retry_in.as_secs_f64(),
+            )
+            .execute(&ctx.pool)
+            .await?
+            .rows_affected()
+                > 0
         }
+    };
+    if !claim_held {
+        tracing::warn!("Another worker took the job over, leaving it to that one");
     }
-    delete_task(transaction, confirmation_id, &email).await?;
     Ok(ExecutionOutcome::TaskCompleted)
 }
 
-type PgTransaction = Transaction<'static, Postgres>;
+/// Well within the minute after which the claim on a job is taken over by another worker.
+const REFRESH_CLAIM_EVERY: Duration = Duration::from_secs(20);
 
-#[tracing::instrument(skip_all)]
-async fn dequeue_task(
+/// Moves the claim on a job forward, returning the new claim, or `None` if the job was taken over.
+async fn refresh_claim(
     pool: &PgPool,
-) -> Result<Option<(PgTransaction, String, String)>, anyhow::Error> {
-    let mut tx = pool.begin().await?;
-    let r = sqlx::query!(
+    job_id: uuid::Uuid,
+    claimed_at: chrono::DateTime<chrono::Utc>,
+) -> Result<Option<chrono::DateTime<chrono::Utc>>, sqlx::Error> {
+    sqlx::query_scalar!(
         r#"
-        SELECT confirmation_id, user_email
-        FROM confirmation_delivery_queue
-        FOR UPDATE
-        SKIP LOCKED
-        LIMIT 1
+        UPDATE jobs SET locked_at = NOW()
+        WHERE id = $1 AND locked_at = $2
+        RETURNING locked_at AS "locked_at!"
         "#,
+        job_id,
+        claimed_at,
     )
-    .fetch_optional(&mut *tx)
-    .await?;
-    if let Some(r) = r {
-        Ok(Some((tx, r.confirmation_id, r.user_email)))
-    } else {
-        Ok(None)
-    }
+    .fetch_optional(pool)
+    .await
 }
 
-#[tracing::instrument(skip_all)]
-async fn delete_task(
-    mut tx: PgTransaction,
-    confirmation_id: String,
-    email: &str,
-) -> Result<(), anyhow::Error> {
-    sqlx::query!(
-        r#"
-        DELETE FROM confirmation_delivery_queue
-        WHERE 
-            confirmation_id = $1 AND
-            user_email = $2 
-        "#,
-        confirmation_id,
-        email
-    )
-    .execute(&mut *tx)
-    .await?;
-    tx.commit().await?;
-    Ok(())
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
 }
 
-#[tracing::instrument(skip_all)]
-async fn insert_failed_task<E>(
-    tx: impl sqlx::Executor<'_, Database = Postgres>,
-    confirmation_id: String,
-    email: &str,
-    e: &E,
-) -> Result<(), anyhow::Error>
-where
-    E: Into<ApiError> + std::fmt::Display,
-{
-    sqlx::query!(
-        r#"
-        INSERT INTO failed_jobs (job_type, context)
-        VALUES ('email_delivery', $1)
-        "#,
-        serde_json::json!({ "email": email, "confirmation_id": confirmation_id, "error": e.to_string() })
-    )
-    .execute(tx)
-    .await?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{testing::job_context, upload::scan::NoopScanner};
+
+    #[derive(Serialize, Deserialize)]
+    struct Succeeds;
+
+    #[async_trait]
+    impl Job for Succeeds {
+        const JOB_TYPE: &'static str = "test_succeeds";
+
+        async fn run(self, _ctx: &JobContext) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Fails;
+
+    #[async_trait]
+    impl Job for Fails {
+        const JOB_TYPE: &'static str = "test_fails";
+
+        async fn run(self, _ctx: &JobContext) -> anyhow::Result<()> {
+            anyhow::bail!("Out of flour")
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Panics;
+
+    #[async_trait]
+    impl Job for Panics {
+        const JOB_TYPE: &'static str = "test_panics";
+
+        async fn run(self, _ctx: &JobContext) -> anyhow::Result<()> {
+            panic!("Out of sugar")
+        }
+    }
+
+    /// Stands in for a worker that stalled for so long that another one claimed its job.
+    #[derive(Serialize, Deserialize)]
+    struct TakenOver;
+
+    #[async_trait]
+    impl Job for TakenOver {
+        const JOB_TYPE: &'static str = "test_taken_over";
+
+        async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+            sqlx::query!("UPDATE jobs SET locked_at = locked_at + INTERVAL '1 minute'")
+                .execute(&ctx.pool)
+                .await?;
+            Ok(())
+        }
+    }
+
+    fn context(pool: &PgPool) -> Arc<JobContext> {
+        Arc::new(job_context(pool.clone(), Arc::new(NoopScanner)))
+    }
+
+    async fn execute(
+        pool: &PgPool,
+        registry: &JobRegistry,
+        settings: &QueueSettings,
+    ) -> ExecutionOutcome {
+        try_execute_job(&context(pool), registry, &registry.job_types(), settings)
+            .await
+            .unwrap()
+    }
+
+    async fn queued(pool: &PgPool) -> i64 {
+        sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM jobs"#)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn enqueue_once_skips_jobs_that_are_waiting(pool: PgPool) {
+        assert!(enqueue_once(&pool, &Succeeds).await.unwrap().is_some());
+        assert!(enqueue_once(&pool, &Succeeds).await.unwrap().is_none());
+        assert_eq!(queued(&pool).await, 1);
+
+        // Once it's been picked up, it may have missed what the next one is for.
+        sqlx::query!("UPDATE jobs SET locked_at = NOW()")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(enqueue_once(&pool, &Succeeds).await.unwrap().is_some());
+        assert_eq!(queued(&pool).await, 2);
+    }
+
+    #[sqlx::test]
+    async fn jobs_that_succeed_are_removed(pool: PgPool) {
+        let registry = JobRegistry::new().register::<Succeeds>();
+        let settings = QueueSettings::default();
+        enqueue(&pool, &Succeeds).await.unwrap();
+
+        assert!(matches!(
+            execute(&pool, &registry, &settings).await,
+            ExecutionOutcome::TaskCompleted
+        ));
+        assert_eq!(queued(&pool).await, 0);
+        assert!(matches!(
+            execute(&pool, &registry, &settings).await,
+            ExecutionOutcome::EmptyQueue
+        ));
+    }
+
+    #[sqlx::test]
+    async fn only_registered_and_due_jobs_are_picked_up(pool: PgPool) {
+        let settings = QueueSettings::default();
+        enqueue_at(
+            &pool,
+            &Succeeds,
+            Some(chrono::Utc::now() + chrono::Duration::hours(1)),
+        )
+        .await
+        .unwrap();
+        let registry = JobRegistry::new().register::<Succeeds>();
+        assert!(matches!(
+            execute(&pool, &registry, &settings).await,
+            ExecutionOutcome::EmptyQueue
+        ));
+
+        enqueue(&pool, &Succeeds).await.unwrap();
+        assert!(matches!(
+            execute(&pool, &JobRegistry::new(), &settings).await,
+            ExecutionOutcome::EmptyQueue
+        ));
+        assert_eq!(queued(&pool).await, 2);
+    }
+
+    #[sqlx::test]
+    async fn fresh_claims_are_skipped_and_stale_ones_taken_over(pool: PgPool) {
+        let registry = JobRegistry::new().register::<Succeeds>();
+        let settings = QueueSettings::default();
+        enqueue(&pool, &Succeeds).await.unwrap();
+
+        sqlx::query!("UPDATE jobs SET locked_at = NOW() - INTERVAL '30 seconds'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            execute(&pool, &registry, &settings).await,
+            ExecutionOutcome::EmptyQueue
+        ));
+
+        sqlx::query!("UPDATE jobs SET locked_at = NOW() - INTERVAL '2 minutes'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(matches!(
+            execute(&pool, &registry, &settings).await,
+            ExecutionOutcome::TaskCompleted
+        ));
+        assert_eq!(queued(&pool).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn failed_jobs_are_released_for_a_retry(pool: PgPool) {
+        let registry = JobRegistry::new().register::<Fails>();
+        let settings = QueueSettings::default();
+        enqueue(&pool, &Fails).await.unwrap();
+
+        execute(&pool, &registry, &settings).await;
+        let job = sqlx::query!(
+            r#"SELECT attempts, last_error, locked_at, run_at > NOW() AS "later!" FROM jobs"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(job.attempts, 1);
+        assert_eq!(job.last_error.as_deref(), Some("Out of flour"));
+        assert!(job.locked_at.is_none());
+        assert!(job.later);
+    }
+
+    #[sqlx::test]
+    async fn jobs_are_dead_lettered_after_their_last_attempt(pool: PgPool) {
+        let registry = JobRegistry::new().register::<Fails>();
+        let settings = QueueSettings::default();
+        let id = enqueue(&pool, &Fails).await.unwrap();
+        sqlx::query!("UPDATE jobs SET attempts = $1", settings.max_attempts - 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        execute(&pool, &registry, &settings).await;
+        assert_eq!(queued(&pool).await, 0);
+        let failed = dead_letter::get(&pool, id).await.unwrap().unwrap();
+        assert_eq!(failed.attempts, settings.max_attempts);
+        assert_eq!(failed.error.as_deref(), Some("Out of flour"));
+    }
+
+    #[sqlx::test]
+    async fn panicking_jobs_count_as_failed_attempts(pool: PgPool) {
+        let registry = JobRegistry::new().register::<Panics>();
+        let settings = QueueSettings::default();
+        enqueue(&pool, &Panics).await.unwrap();
+
+        execute(&pool, &registry, &settings).await;
+        let job = sqlx::query!("SELECT attempts, last_error, locked_at FROM jobs")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(job.attempts, 1);
+        assert_eq!(
+            job.last_error.as_deref(),
+            Some("The job panicked: Out of sugar")
+        );
+        assert!(job.locked_at.is_none());
+    }
+
+    #[sqlx::test]
+    async fn jobs_taken_over_by_another_worker_are_left_to_it(pool: PgPool) {
+        let registry = JobRegistry::new().register::<TakenOver>();
+        let settings = QueueSettings::default();
+        enqueue(&pool, &TakenOver).await.unwrap();
+
+        execute(&pool, &registry, &settings).await;
+        let locked_at = sqlx::query_scalar!("SELECT locked_at FROM jobs")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(locked_at.is_some());
+    }
+
+    #[sqlx::test]
+    async fn workers_stop_between_jobs_on_shutdown(pool: PgPool) {
+        let registry = Arc::new(JobRegistry::new().register::<Succeeds>());
+        let shutdown = CancellationToken::new();
+        enqueue(&pool, &Succeeds).await.unwrap();
+        let worker = tokio::spawn(worker_loop(
+            context(&pool),
+            registry,
+            Arc::new(QueueSettings::default()),
+            Arc::new(Notify::new()),
+            shutdown.clone(),
+        ));
+        while queued(&pool).await > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The worker is idle until the next poll, shutdown wakes it up.
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), worker)
+            .await
+            .expect("the worker stops right away")
+            .unwrap()
+            .unwrap();
+    }
 }
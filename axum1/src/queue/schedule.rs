@@ -0,0 +1,282 @@
+//! Recurring jobs, defined by cron expressions (with seconds, e.g. `0 */15 * * * *`).
+//!
+//! Schedules are stored in `job_schedules`. Every replica runs the scheduler, but a due schedule is
+//! locked with `FOR UPDATE SKIP LOCKED` while its job is enqueued and `next_run_at` moves forward,
+//! so each occurrence is enqueued by exactly one of them. Missed occurrences (e.g. while nothing
+//! was running) are collapsed into a single run.
+//!
+//! During a rolling deploy replicas with different schedules and configurations run side by side,
+//! so the table is the only source of truth: replicas add the schedules that are missing, but
+//! never change or remove the ones that exist. Their cron expressions are changed with
+//! [`set_cron_expression`], and the rows of schedules that were removed from the code are left
+//! alone, they're skipped by every replica that doesn't know them.
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Executor, PgPool, Postgres};
+use tokio_util::sync::CancellationToken;
+
+use super::{enqueue_unless_pending, Job};
+use crate::email::digest::SendWeeklyDigests;
+use crate::routes::{auth::PurgeExpiredTokens, recipe::RefreshHotRecipes};
+use crate::search::IndexSearch;
+use crate::upload::PurgeExpiredUploadSessions;
+
+/// Schedules are synced and due schedules are checked at least this often.
+const MAX_TICK: Duration = Duration::from_secs(60);
+
+struct Schedule {
+    name: &'static str,
+    cron: cron::Schedule,
+    job_type: &'static str,
+    payload: serde_json::Value,
+    priority: i32,
+}
+
+fn next_run_after(
+    name: &str,
+    cron: &cron::Schedule,
+    time: &DateTime<Utc>,
+) -> anyhow::Result<DateTime<Utc>> {
+    cron.after(time)
+        .next()
+        .with_context(|| format!("Schedule `{name}` never runs again"))
+}
+
+pub struct Scheduler {
+    schedules: Vec<Schedule>,
+    overrides: HashMap<String, String>,
+}
+
+impl Scheduler {
+    /// `overrides` maps schedule names to cron expressions that replace their defaults when the
+    /// schedules are first added to `job_schedules`.
+    pub fn new(overrides: HashMap<String, String>) -> Self {
+        Self {
+            schedules: Vec::new(),
+            overrides,
+        }
+    }
+
+    pub fn add<J: Job>(
+        mut self,
+        name: &'static str,
+        expression: &str,
+        job: J,
+    ) -> anyhow::Result<Self> {
+        if self.schedules.iter().any(|schedule| schedule.name == name) {
+            anyhow::bail!("Schedule `{name}` is added twice");
+        }
+        let expression = self.overrides.get(name).map_or(expression, String::as_str);
+        let cron = cron::Schedule::from_str(expression)
+            .with_context(|| format!("Invalid cron expression for schedule `{name}`"))?;
+        self.schedules.push(Schedule {
+            name,
+            cron,
+            job_type: J::JOB_TYPE,
+            payload: serde_json::to_value(job)?,
+            priority: J::PRIORITY,
+        });
+        Ok(self)
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.schedules
+            .iter()
+            .map(|schedule| schedule.name.into())
+            .collect()
+    }
+}
+
+pub fn default_scheduler(overrides: HashMap<String, String>) -> anyhow::Result<Scheduler> {
+    Scheduler::new(overrides)
+        .add("search_index", "0 0 * * * *", IndexSearch)?
+        .add("refresh_hot_recipes", "0 */15 * * * *", RefreshHotRecipes)?
+        .add("purge_expired_tokens", "0 30 3 * * *", PurgeExpiredTokens)?
+        .add(
+            "purge_expired_upload_sessions",
+            "0 45 3 * * *",
+            PurgeExpiredUploadSessions,
+        )?
+        .add("weekly_digest", "0 0 8 * * Mon", SendWeeklyDigests)
+}
+
+/// Enqueues due jobs until `shutdown` is cancelled.
+pub async fn run_scheduler_until_stopped(
+    pool: PgPool,
+    scheduler: Scheduler,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    sync_schedules(&pool, &scheduler).await?;
+    while !shutdown.is_cancelled() {
+        let sleep_for = match enqueue_due_jobs(&pool, &scheduler).await {
+            Ok(next_run_at) => next_run_at
+                .and_then(|next| (next - Utc::now()).to_std().ok())
+                .map_or(MAX_TICK, |until_next| until_next.min(MAX_TICK)),
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to enqueue scheduled jobs");
+                MAX_TICK
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(sleep_for) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+    Ok(())
+}
+
+/// Adds the schedules defined in code that are missing from `job_schedules`. Existing schedules
+/// are left as they are, even if this replica was configured differently.
+async fn sync_schedules(pool: &PgPool, scheduler: &Scheduler) -> anyhow::Result<()> {
+    let now = Utc::now();
+    for schedule in &scheduler.schedules {
+        let expression = schedule.cron.to_string();
+        let stored = sqlx::query_scalar!(
+            r#"
+            WITH inserted AS (
+                INSERT INTO job_schedules (name, cron_expression, next_run_at)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (name) DO NOTHING
+                RETURNING cron_expression
+            )
+            SELECT cron_expression AS "cron_expression!" FROM inserted
+            UNION ALL
+            SELECT cron_expression FROM job_schedules WHERE name = $1
+            LIMIT 1
+            "#,
+            schedule.name,
+            expression,
+            next_run_after(schedule.name, &schedule.cron, &now)?,
+        )
+        .fetch_one(pool)
+        .await?;
+        if stored != expression {
+            tracing::warn!(
+                "Schedule `{}` runs on `{stored}` instead of `{expression}`, use the `schedule` CLI command to change it",
+                schedule.name,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns when the next schedule is due.
+#[tracing::instrument(skip_all)]
+async fn enqueue_due_jobs(
+    pool: &PgPool,
+    scheduler: &Scheduler,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let mut tx = pool.begin().await?;
+    let names = scheduler.names();
+    // Another replica may run a different version with schedules we don't know about.
+    let due = sqlx::query!(
+        r#"
+        SELECT name, cron_expression FROM job_schedules
+        WHERE next_run_at <= NOW() AND NOT paused AND name = ANY($1)
+        FOR UPDATE
+        SKIP LOCKED
+        "#,
+        &names,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let now = Utc::now();
+    for row in due {
+        let name = row.name;
+        let Some(schedule) = scheduler.schedules.iter().find(|s| s.name == name) else {
+            continue;
+        };
+        // The stored expression wins over this replica's, so every replica agrees on the next run.
+        let cron = cron::Schedule::from_str(&row.cron_expression)
+            .with_context(|| format!("Invalid cron expression stored for schedule `{name}`"))?;
+        // Don't pile up jobs while the previous run is still waiting.
+        enqueue_unless_pending(
+            &mut *tx,
+            schedule.job_type,
+            &schedule.payload,
+            schedule.priority,
+        )
+        .await?;
+        sqlx::query!(
+            "UPDATE job_schedules SET next_run_at = $2, last_run_at = NOW() WHERE name = $1",
+            name,
+            next_run_after(&name, &cron, &now)?,
+        )
+        .execute(&mut *tx)
+        .await?;
+        tracing::info!("Enqueued scheduled job `{name}`");
+    }
+
+    let next_run_at = sqlx::query_scalar!(
+        "SELECT MIN(next_run_at) FROM job_schedules WHERE NOT paused AND name = ANY($1)",
+        &names,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(next_run_at)
+}
+
+#[derive(Serialize, Debug)]
+pub struct ScheduleInfo {
+    pub name: String,
+    pub cron_expression: String,
+    pub next_run_at: DateTime<Utc>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub paused: bool,
+}
+
+pub async fn list(
+    conn: impl Executor<'_, Database = Postgres>,
+) -> Result<Vec<ScheduleInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        ScheduleInfo,
+        r#"
+        SELECT name, cron_expression, next_run_at, last_run_at, paused
+        FROM job_schedules
+        ORDER BY next_run_at
+        "#
+    )
+    .fetch_all(conn)
+    .await
+}
+
+/// Pauses or resumes a schedule on every replica. Returns `false` if there's no such schedule.
+pub async fn set_paused(
+    conn: impl Executor<'_, Database = Postgres>,
+    name: &str,
+    paused: bool,
+) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query!(
+        "UPDATE job_schedules SET paused = $2 WHERE name = $1",
+        name,
+        paused
+    )
+    .execute(conn)
+    .await?;
+    Ok(updated.rows_affected() > 0)
+}
+
+/// Changes the cron expression of a schedule on every replica, it next runs according to the new
+/// one. Returns `false` if there's no such schedule.
+pub async fn set_cron_expression(
+    conn: impl Executor<'_, Database = Postgres>,
+    name: &str,
+    expression: &str,
+) -> anyhow::Result<bool> {
+    let cron = cron::Schedule::from_str(expression)
+        .with_context(|| format!("Invalid cron expression for schedule `{name}`"))?;
+    let updated = sqlx::query!(
+        "UPDATE job_schedules SET cron_expression = $2, next_run_at = $3 WHERE name = $1",
+        name,
+        cron.to_string(),
+        next_run_after(name, &cron, &Utc::now())?,
+    )
+    .execute(conn)
+    .await?;
+    Ok(updated.rows_affected() > 0)
+}
@@ -0,0 +1,228 @@
+//! Queue metrics, exported through the Prometheus recorder `axum_prometheus` installs, and the
+//! summary behind `GET /admin/queue`.
+use std::{collections::BTreeMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use tokio_util::sync::CancellationToken;
+
+/// How often the queue depth gauges are refreshed.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Copy)]
+pub(super) enum Outcome {
+    Success,
+    Retry,
+    Failure,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Retry => "retry",
+            Self::Failure => "failure",
+        }
+    }
+}
+
+/// Records how long a job waited after it was due, before a worker picked it up.
+pub(super) fn record_pickup(job_type: &str, waited: Duration) {
+    metrics::histogram!("queue_job_wait_seconds", "job_type" => job_type.to_owned())
+        .record(waited.as_secs_f64());
+}
+
+pub(super) fn record_execution(job_type: &str, outcome: Outcome, took: Duration) {
+    metrics::histogram!("queue_job_duration_seconds", "job_type" => job_type.to_owned())
+        .record(took.as_secs_f64());
+    metrics::counter!(
+        "queue_jobs_total",
+        "job_type" => job_type.to_owned(),
+        "outcome" => outcome.as_str()
+    )
+    .increment(1);
+}
+
+/// Periodically sets the depth gauges from the database, until `shutdown` is cancelled.
+///
+/// Every replica reports the same numbers, so aggregate them with `max` rather than `sum`.
+pub(super) async fn sample_queue_depth(
+    pool: PgPool,
+    job_types: Vec<String>,
+    shutdown: CancellationToken,
+) -> Result<(), anyhow::Error> {
+    while !shutdown.is_cancelled() {
+        if let Err(e) = set_depth_gauges(&pool, &job_types).await {
+            tracing::warn!(error.cause_chain = ?e, "Failed to sample the queue depth");
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(SAMPLE_INTERVAL) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+    Ok(())
+}
+
+async fn set_depth_gauges(pool: &PgPool, job_types: &[String]) -> Result<(), sqlx::Error> {
+    let depths = sqlx::query!(
+        r#"
+        SELECT job_type,
+            COUNT(*) FILTER (WHERE run_at <= NOW()) AS "due!",
+            COUNT(*) FILTER (WHERE run_at > NOW()) AS "scheduled!"
+        FROM jobs
+        GROUP BY job_type
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+    let failed =
+        sqlx::query!(r#"SELECT job_type, COUNT(*) AS "count!" FROM failed_jobs GROUP BY job_type"#)
+            .fetch_all(pool)
+            .await?;
+
+    // Types without any jobs left don't show up in the results, but their gauges must drop to 0.
+    for job_type in job_types {
+        let depth = depths.iter().find(|row| &row.job_type == job_type);
+        let failed = failed.iter().find(|row| &row.job_type == job_type);
+        metrics::gauge!("queue_depth", "job_type" => job_type.clone())
+            .set(depth.map_or(0, |row| row.due) as f64);
+        metrics::gauge!("queue_scheduled", "job_type" => job_type.clone())
+            .set(depth.map_or(0, |row| row.scheduled) as f64);
+        metrics::gauge!("queue_failed", "job_type" => job_type.clone())
+            .set(failed.map_or(0, |row| row.count) as f64);
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct JobTypeSummary {
+    /// Due, but not picked up by a worker yet.
+    pub pending: i64,
+    /// Currently being processed.
+    pub running: i64,
+    /// Not due yet, e.g. waiting for a retry.
+    pub scheduled: i64,
+    /// Exhausted their attempts, see `failed_jobs`.
+    pub failed: i64,
+    pub oldest_pending_age_seconds: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueueSummary {
+    pub pending: i64,
+    pub running: i64,
+    pub scheduled: i64,
+    pub failed: i64,
+    pub oldest_pending_age_seconds: Option<i64>,
+    pub job_types: BTreeMap<String, JobTypeSummary>,
+}
+
+/// Summarizes the queue per job type.
+///
+/// Jobs are running from the moment a worker claims them, see `jobs.locked_at`. A job whose worker
+/// died counts as running until another worker takes it over.
+pub async fn summary(conn: &mut PgConnection) -> Result<QueueSummary, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT job_type,
+            COUNT(*) FILTER (WHERE run_at <= NOW() AND locked_at IS NULL) AS "pending!",
+            COUNT(*) FILTER (WHERE locked_at IS NOT NULL) AS "running!",
+            COUNT(*) FILTER (WHERE run_at > NOW() AND locked_at IS NULL) AS "scheduled!",
+            MIN(run_at) FILTER (WHERE run_at <= NOW() AND locked_at IS NULL) AS oldest_pending
+        FROM jobs
+        GROUP BY job_type
+        "#
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let failed =
+        sqlx::query!(r#"SELECT job_type, COUNT(*) AS "count!" FROM failed_jobs GROUP BY job_type"#)
+            .fetch_all(&mut *conn)
+            .await?;
+
+    let now = Utc::now();
+    let age = |oldest: Option<DateTime<Utc>>| oldest.map(|oldest| (now - oldest).num_seconds());
+
+    let mut job_types = BTreeMap::<String, JobTypeSummary>::new();
+    for row in rows {
+        let summary = job_types.entry(row.job_type).or_default();
+        summary.pending = row.pending;
+        summary.running = row.running;
+        summary.scheduled = row.scheduled;
+        summary.oldest_pending_age_seconds = age(row.oldest_pending);
+    }
+    for row in failed {
+        job_types.entry(row.job_type).or_default().failed = row.count;
+    }
+
+    Ok(QueueSummary {
+        pending: job_types.values().map(|s| s.pending).sum(),
+        running: job_types.values().map(|s| s.running).sum(),
+        scheduled: job_types.values().map(|s| s.scheduled).sum(),
+        failed: job_types.values().map(|s| s.failed).sum(),
+        oldest_pending_age_seconds: job_types
+            .values()
+            .filter_map(|s| s.oldest_pending_age_seconds)
+            .max(),
+        job_types,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn summary_counts_jobs_by_state(pool: PgPool) {
+        sqlx::query!(
+            r#"
+            INSERT INTO jobs (job_type, payload, run_at, locked_at) VALUES
+                ('index_search', 'null', NOW() - INTERVAL '1 minute', NULL),
+                ('index_search', 'null', NOW() - INTERVAL '10 seconds', NULL),
+                ('index_search', 'null', NOW(), NOW()),
+                ('scan_upload', 'null', NOW() + INTERVAL '1 hour', NULL)
+            "#
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"
+            INSERT INTO failed_jobs (job_id, job_type, payload, error, attempts)
+            VALUES (uuid_generate_v1mc(), 'scan_upload', 'null', 'Out of flour', 5)
+            "#
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let summary = summary(&mut pool.acquire().await.unwrap()).await.unwrap();
+        assert_eq!(
+            (
+                summary.pending,
+                summary.running,
+                summary.scheduled,
+                summary.failed
+            ),
+            (2, 1, 1, 1)
+        );
+        assert!(summary
+            .oldest_pending_age_seconds
+            .is_some_and(|age| (60..70).contains(&age)));
+
+        let index = &summary.job_types["index_search"];
+        assert_eq!((index.pending, index.running, index.scheduled), (2, 1, 0));
+        let scan = &summary.job_types["scan_upload"];
+        assert_eq!((scan.scheduled, scan.failed), (1, 1));
+        assert_eq!(scan.oldest_pending_age_seconds, None);
+    }
+
+    #[sqlx::test]
+    async fn empty_queues_are_summarized_as_such(pool: PgPool) {
+        let summary = summary(&mut pool.acquire().await.unwrap()).await.unwrap();
+        assert_eq!(summary.pending + summary.running + summary.scheduled, 0);
+        assert_eq!(summary.oldest_pending_age_seconds, None);
+        assert!(summary.job_types.is_empty());
+    }
+}
@@ -0,0 +1,78 @@
+//! Fixed window rate limits, counted in Redis so they hold across replicas.
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+use tower_sessions_redis_store::fred::prelude::*;
+
+use crate::error::ApiError;
+
+/// At most `max_requests` per `window_seconds`.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct Limit {
+    pub max_requests: u64,
+    pub window_seconds: u64,
+}
+
+impl Limit {
+    pub const fn per_hour(max_requests: u64) -> Self {
+        Self {
+            max_requests,
+            window_seconds: 60 * 60,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    redis: RedisPool,
+}
+
+impl RateLimiter {
+    pub fn new(redis: RedisPool) -> Self {
+        Self { redis }
+    }
+
+    /// Counts a request by `key` (e.g. an email address or an IP) in `scope`, and fails with
+    /// `429 Too Many Requests` once it's over `limit`. Keys are hashed, so they aren't kept in
+    /// Redis in plain text.
+    pub async fn check(&self, scope: &str, key: &str, limit: Limit) -> Result<(), ApiError> {
+        let key = format!(
+            "rate_limit:{scope}:{}",
+            blake3::hash(key.as_bytes()).to_hex()
+        );
+        let count: u64 = self
+            .redis
+            .incr(key.as_str())
+            .await
+            .context("Failed to count a rate limited request")?;
+        if count == 1 {
+            self.expire(&key, limit).await?;
+        }
+        if count <= limit.max_requests {
+            return Ok(());
+        }
+
+        let ttl: i64 = self
+            .redis
+            .ttl(key.as_str())
+            .await
+            .context("Failed to get the rate limit window")?;
+        if ttl < 0 {
+            // The window was never set to expire, e.g. because the first request failed right
+            // after counting itself. Don't lock the key out forever.
+            self.expire(&key, limit).await?;
+        }
+        tracing::info!(scope, "Rate limit exceeded");
+        Err(ApiError::TooManyRequests {
+            retry_after: Duration::from_secs(ttl.try_into().unwrap_or(limit.window_seconds)),
+        })
+    }
+
+    async fn expire(&self, key: &str, limit: Limit) -> anyhow::Result<()> {
+        self.redis
+            .expire::<(), _>(key, limit.window_seconds as i64)
+            .await
+            .context("Failed to set the rate limit window")
+    }
+}
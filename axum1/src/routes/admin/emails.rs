@@ -0,0 +1,74 @@
+use axum::{
+    extract::{Path, Query, State},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    email::templates::{Locale, PREVIEWS},
+    error::ApiError,
+    state::AppState,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_templates))
+        .route("/:template/:locale", get(preview_template))
+}
+
+#[derive(Serialize)]
+struct TemplateInfo {
+    template: &'static str,
+    locales: [Locale; 2],
+}
+
+async fn list_templates() -> Json<Vec<TemplateInfo>> {
+    let templates = PREVIEWS
+        .iter()
+        .map(|&(template, _)| TemplateInfo {
+            template,
+            locales: Locale::ALL,
+        })
+        .collect();
+    Json(templates)
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum PreviewFormat {
+    /// Subject and both bodies.
+    #[default]
+    Json,
+    /// Only the HTML body, so it can be looked at in a browser.
+    Html,
+    Text,
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    #[serde(default)]
+    format: PreviewFormat,
+}
+
+/// Renders a template with sample data.
+async fn preview_template(
+    State(state): State<AppState>,
+    Path((template, locale)): Path<(String, String)>,
+    Query(query): Query<PreviewQuery>,
+) -> Result<Response, ApiError> {
+    let locale = locale.parse::<Locale>().map_err(|_| ApiError::NotFound)?;
+    let (_, preview) = PREVIEWS
+        .iter()
+        .find(|(name, _)| *name == template)
+        .ok_or(ApiError::NotFound)?;
+    let frontend_url = state.config.borrow().frontend_url.clone();
+    let rendered = preview(locale, &frontend_url)?;
+
+    Ok(match query.format {
+        PreviewFormat::Json => Json(rendered).into_response(),
+        PreviewFormat::Html => Html(rendered.html_body).into_response(),
+        PreviewFormat::Text => rendered.text_body.into_response(),
+    })
+}
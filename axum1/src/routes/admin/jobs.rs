@@ -0,0 +1,86 @@
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ApiError,
+    extractors::DatabaseConnection,
+    queue::dead_letter::{self, FailedJob},
+    state::AppState,
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(list_failed_jobs).delete(purge_failed_jobs))
+        .route("/:id", get(get_failed_job).delete(delete_failed_job))
+        .route("/:id/requeue", post(requeue_failed_job))
+}
+
+#[derive(Debug, Deserialize)]
+struct FailedJobsQuery {
+    job_type: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct Purged {
+    purged: u64,
+}
+
+#[tracing::instrument(skip(conn))]
+async fn list_failed_jobs(
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Query(query): Query<FailedJobsQuery>,
+) -> Result<Json<Vec<FailedJob>>, ApiError> {
+    let limit = query.limit.filter(|&limit| limit >= 0).unwrap_or(50);
+    let jobs = dead_letter::list(&mut *conn, query.job_type.as_deref(), limit).await?;
+    Ok(Json(jobs))
+}
+
+#[tracing::instrument(skip(conn))]
+async fn get_failed_job(
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<Json<FailedJob>, ApiError> {
+    dead_letter::get(&mut *conn, id)
+        .await?
+        .map(Json)
+        .ok_or(ApiError::NotFound)
+}
+
+#[tracing::instrument(skip(conn))]
+async fn requeue_failed_job(
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<StatusCode, ApiError> {
+    if dead_letter::requeue(&mut *conn, id).await? {
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+#[tracing::instrument(skip(conn))]
+async fn delete_failed_job(
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<(), ApiError> {
+    if dead_letter::delete(&mut *conn, id).await? {
+        Ok(())
+    } else {
+        Err(ApiError::NotFound)
+    }
+}
+
+#[tracing::instrument(skip(conn))]
+async fn purge_failed_jobs(
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Query(query): Query<FailedJobsQuery>,
+) -> Result<Json<Purged>, ApiError> {
+    let purged = dead_letter::purge(&mut *conn, query.job_type.as_deref()).await?;
+    Ok(Json(Purged { purged }))
+}
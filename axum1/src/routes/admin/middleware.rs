@@ -5,7 +5,12 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::{error::ApiError, state::AppState};
+use crate::{
+    error::ApiError,
+    extractors::bearer_token,
+    routes::auth::{authenticate_token, TokenScope, SECOND_FACTOR},
+    state::AppState,
+};
 use tower_sessions::Session;
 
 #[derive(sqlx::FromRow, Serialize, Deserialize, Clone, Debug)]
@@ -23,18 +28,29 @@ where
     type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let session = Session::from_request_parts(parts, state)
-            .await
-            .expect("`SessionLayer` should be added");
-
         let AppState { db_pool, .. } = AppState::from_ref(state);
 
         let mut db = db_pool.acquire().await?;
 
-        let user_id = session
-            .get::<uuid::Uuid>("user_id")
-            .await?
-            .ok_or(ApiError::Unauthorized)?;
+        // Tokens need the admin scope. Sessions need to have passed a second factor, so admins
+        // have to enroll before using the admin API.
+        let user_id = match bearer_token(&parts.headers)? {
+            Some(token) => authenticate_token(&db_pool, token, TokenScope::Admin).await?,
+            None => {
+                let session = Session::from_request_parts(parts, state)
+                    .await
+                    .expect("`SessionLayer` should be added");
+                let user_id = session
+                    .get::<uuid::Uuid>("user_id")
+                    .await?
+                    .ok_or(ApiError::Unauthorized)?;
+                let second_factor = session.get::<bool>(SECOND_FACTOR).await?.unwrap_or(false);
+                if !second_factor {
+                    return Err(ApiError::Forbidden);
+                }
+                user_id
+            }
+        };
 
         let user = sqlx::query_as!(
             Self,
@@ -1,13 +1,25 @@
+mod emails;
+mod jobs;
 mod middleware;
+mod users;
 pub use middleware::AdminUser;
 
-use axum::{http::StatusCode, middleware::from_extractor_with_state, routing::get, Router};
+use axum::{http::StatusCode, middleware::from_extractor_with_state, routing::get, Json, Router};
 
-use crate::{error::ApiError, extractors::DatabaseConnection, state::AppState};
+use crate::{
+    error::ApiError,
+    extractors::DatabaseConnection,
+    queue::stats::{self, QueueSummary},
+    state::AppState,
+};
 
 pub fn router(state: AppState) -> Router<AppState> {
     Router::new()
         .route("/pg", get(pg_health))
+        .route("/queue", get(queue_summary))
+        .nest("/failed_jobs", jobs::router())
+        .nest("/emails", emails::router())
+        .nest("/users", users::router())
         .route_layer(from_extractor_with_state::<AdminUser, _>(state))
         .route("/health_check", get(|| async { StatusCode::OK }))
 }
@@ -18,3 +30,9 @@ async fn pg_health(DatabaseConnection(mut conn): DatabaseConnection) -> Result<(
         .await?;
     Ok(())
 }
+
+async fn queue_summary(
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<QueueSummary>, ApiError> {
+    Ok(Json(stats::summary(&mut conn).await?))
+}
@@ -0,0 +1,27 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::post,
+    Router,
+};
+
+use crate::{error::ApiError, extractors::DatabaseConnection, state::AppState};
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/:user_id/unlock", post(unlock))
+}
+
+/// Lifts the lockout of an account that had too many failed logins.
+async fn unlock(
+    State(state): State<AppState>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(user_id): Path<uuid::Uuid>,
+) -> Result<StatusCode, ApiError> {
+    let email = sqlx::query_scalar!("SELECT email FROM users WHERE user_id = $1", user_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    state.login_throttle.unlock(&email).await?;
+    tracing::info!(%user_id, "Unlocked an account");
+    Ok(StatusCode::NO_CONTENT)
+}
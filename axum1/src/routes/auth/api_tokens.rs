@@ -0,0 +1,225 @@
+//! Personal access tokens, for scripts and other clients that don't keep a session cookie. They're
+//! sent as `Authorization: Bearer <token>`, and the extractors accept them alongside the session.
+//!
+//! Tokens are only managed from a session: a token can't create, list or revoke tokens. The same
+//! goes for everything else about logging in, like passkeys, second factors, sessions, passwords
+//! and OAuth consent, which take `SessionUser` instead of `AuthUser`.
+use axum::{
+    extract::{Path, State},
+    http::Method,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tower_sessions::Session;
+use validator::Validate;
+
+use super::{tokens, two_factor::SECOND_FACTOR};
+use crate::{
+    error::ApiError,
+    extractors::{DatabaseConnection, SessionUser},
+    state::AppState,
+};
+
+/// Tells tokens apart from other secrets, e.g. for secret scanners.
+const PREFIX: &str = "rcp_";
+const DEFAULT_LIFETIME_DAYS: u32 = 30;
+
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case", type_name = "token_scope")]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    /// Safe requests, which don't change anything.
+    Read,
+    /// Any request.
+    Write,
+    /// The admin API.
+    Admin,
+}
+
+impl TokenScope {
+    /// The scope a request with `method` needs, outside the admin API.
+    pub fn for_method(method: &Method) -> Self {
+        if method.is_safe() {
+            Self::Read
+        } else {
+            Self::Write
+        }
+    }
+
+    fn covers(self, required: Self) -> bool {
+        match required {
+            Self::Read => matches!(self, Self::Read | Self::Write),
+            _ => self == required,
+        }
+    }
+}
+
+/// Returns the owner of `token`, if it's valid and has a scope that covers `required`.
+pub async fn authenticate_token(
+    pool: &PgPool,
+    token: &str,
+    required: TokenScope,
+) -> Result<uuid::Uuid, ApiError> {
+    let token_hash = tokens::hash(token);
+    let stored = sqlx::query!(
+        r#"
+        SELECT id, user_id, scopes AS "scopes: Vec<TokenScope>",
+            last_used_at IS NULL OR last_used_at < NOW() - INTERVAL '1 minute' AS "stale!"
+        FROM api_tokens
+        WHERE token_hash = $1 AND expires_at > NOW()
+        "#,
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::Unauthorized)?;
+
+    if !stored.scopes.iter().any(|scope| scope.covers(required)) {
+        return Err(ApiError::Forbidden);
+    }
+    if stored.stale {
+        sqlx::query!(
+            "UPDATE api_tokens SET last_used_at = NOW() WHERE id = $1",
+            stored.id
+        )
+        .execute(pool)
+        .await?;
+    }
+    Ok(stored.user_id)
+}
+
+#[derive(Serialize)]
+pub(super) struct TokenDetails {
+    id: uuid::Uuid,
+    name: String,
+    scopes: Vec<TokenScope>,
+    expires_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+pub(super) async fn list(
+    SessionUser(user_id): SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Vec<TokenDetails>>, ApiError> {
+    let tokens = sqlx::query_as!(
+        TokenDetails,
+        r#"
+        SELECT id, name, scopes AS "scopes: Vec<TokenScope>", expires_at, last_used_at, created_at
+        FROM api_tokens
+        WHERE user_id = $1
+        ORDER BY created_at DESC
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(Json(tokens))
+}
+
+#[derive(Deserialize, Validate)]
+pub(super) struct NewToken {
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    name: String,
+    #[validate(length(min = 1, message = "must have at least one scope"))]
+    scopes: Vec<TokenScope>,
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub(super) struct CreatedToken {
+    /// Shown only this once.
+    token: String,
+    #[serde(flatten)]
+    details: TokenDetails,
+}
+
+pub(super) async fn create(
+    State(state): State<AppState>,
+    SessionUser(user_id): SessionUser,
+    session: Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(form): Json<NewToken>,
+) -> Result<Json<CreatedToken>, ApiError> {
+    form.validate()
+        .map_err(ApiError::unprocessable_entity_from_validation_errors)?;
+
+    let max_days = state.config.borrow().auth.api_token_max_lifetime_days;
+    let days = form.expires_in_days.unwrap_or(DEFAULT_LIFETIME_DAYS);
+    if days == 0 || days > max_days {
+        return Err(ApiError::unprocessable_entity([(
+            "expires_in_days",
+            format!("must be between 1 and {max_days}"),
+        )]));
+    }
+
+    if form.scopes.contains(&TokenScope::Admin) {
+        // The same as `AdminUser` asks of sessions.
+        let is_admin =
+            sqlx::query_scalar!("SELECT is_admin FROM users WHERE user_id = $1", user_id)
+                .fetch_one(&mut *conn)
+                .await?;
+        let second_factor = session.get::<bool>(SECOND_FACTOR).await?.unwrap_or(false);
+        if !is_admin || !second_factor {
+            return Err(ApiError::Forbidden);
+        }
+    }
+
+    let token = format!("{PREFIX}{}", tokens::generate());
+    let details = sqlx::query_as!(
+        TokenDetails,
+        r#"
+        INSERT INTO api_tokens (user_id, name, token_hash, scopes, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, name, scopes AS "scopes: Vec<TokenScope>", expires_at, last_used_at, created_at
+        "#,
+        user_id,
+        form.name,
+        tokens::hash(&token),
+        &form.scopes as &[TokenScope],
+        Utc::now() + Duration::days(days.into()),
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    tracing::info!(%user_id, token_id = %details.id, scopes = ?details.scopes, "Created an API token");
+    Ok(Json(CreatedToken { token, details }))
+}
+
+pub(super) async fn revoke(
+    SessionUser(user_id): SessionUser,
+    Path(token_id): Path<uuid::Uuid>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<(), ApiError> {
+    let revoked = sqlx::query!(
+        "DELETE FROM api_tokens WHERE id = $1 AND user_id = $2",
+        token_id,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    if revoked.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenScope::{self, *};
+
+    #[test]
+    fn write_covers_read_but_admin_covers_only_itself() {
+        let covers = |scope: TokenScope, required| scope.covers(required);
+        assert!(covers(Read, Read));
+        assert!(!covers(Read, Write));
+        assert!(!covers(Read, Admin));
+        assert!(covers(Write, Read));
+        assert!(covers(Write, Write));
+        assert!(!covers(Write, Admin));
+        assert!(!covers(Admin, Read));
+        assert!(!covers(Admin, Write));
+        assert!(covers(Admin, Admin));
+    }
+}
@@ -1,14 +1,22 @@
 use anyhow::Context;
-use axum::extract::Query;
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use axum::{
+    async_trait,
+    extract::{Query, State},
+    Form,
+};
 use sqlx::{Acquire, Executor, PgExecutor, Postgres};
+use tower_sessions::Session;
 
 use crate::{
-    email::{Email, EmailClient},
+    email::{messages::ConfirmationEmail, templates::Locale, Email, EmailClient},
     error::ApiError,
-    extractors::DatabaseConnection,
+    extractors::{ClientIp, DatabaseConnection},
+    queue::{enqueue, Job, JobContext},
+    state::AppState,
 };
 
+use super::{passkey, tokens, SendPasswordResetEmail};
+
 // TODO: This is done through a queue, we might delete this
 pub async fn _send_confirmation_email(
     email_client: &EmailClient,
@@ -16,70 +24,106 @@ pub async fn _send_confirmation_email(
     base_url: &str,
     subscription_token: &str,
 ) -> Result<(), ApiError> {
-    let confirmation_link = format!("{}/confirm?token={}", base_url, subscription_token);
-    let plain_body = format!(
-        "Welcome to Recipes app!\nVisit {} to confirm your registration.",
-        confirmation_link
-    );
-    let html_body = format!(
-        "Welcome to our Recipes app!<br />Click <a href=\"{}\">here</a> to confirm your registration.",
-        confirmation_link
-    );
+    let message = ConfirmationEmail::new(base_url, subscription_token)?;
     let email = Email::parse(subscriber_email.to_owned())?;
     email_client
-        .send_mail(email, "Welcome to Recipes!", &html_body, &plain_body)
-        .await
-        .map_err(ApiError::Reqwest)
-}
-
-pub fn generate_confirmation_token() -> String {
-    let mut rng = thread_rng();
-    std::iter::repeat_with(|| rng.sample(Alphanumeric))
-        .map(char::from)
-        .take(25)
-        .collect()
+        .send_message(email, Locale::default(), &message)
+        .await?;
+    Ok(())
 }
 
 #[tracing::instrument(
     name = "Store subscription token in the database",
     skip(confirmation_token, tx)
 )]
-pub async fn store_token(
+async fn store_token(
     tx: impl Executor<'_, Database = Postgres>,
     confirmation_token: &str,
     user_id: uuid::Uuid,
+    ttl: std::time::Duration,
 ) -> Result<(), ApiError> {
     sqlx::query!(
         r#"
-        INSERT INTO confirmation_tokens (confirmation_token, user_id)
-        VALUES ($1, $2)
+        INSERT INTO confirmation_tokens (token_hash, user_id, expires_at)
+        VALUES ($1, $2, NOW() + make_interval(secs => $3))
         "#,
-        confirmation_token,
-        user_id
+        tokens::hash(confirmation_token),
+        user_id,
+        ttl.as_secs_f64(),
     )
     .execute(tx)
     .await?;
     Ok(())
 }
 
-#[tracing::instrument(skip_all)]
-pub async fn enqueue_delivery_task(
-    tx: impl Executor<'_, Database = Postgres>,
-    confirmation_id: String,
-    user_email: String,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"
-        INSERT INTO confirmation_delivery_queue (
-            confirmation_id, 
-            user_email
-        ) VALUES ($1, $2)
-        "#,
-        confirmation_id,
-        user_email,
+/// Emails a confirmation link to an account. The token is only made when the job runs, so it's
+/// never stored in plain text, not even in the job's payload, which is kept if sending fails.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SendConfirmationEmail {
+    pub user_id: uuid::Uuid,
+}
+
+#[async_trait]
+impl Job for SendConfirmationEmail {
+    const JOB_TYPE: &'static str = "confirmation_email";
+    const PRIORITY: i32 = 10;
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        let Some(user) = sqlx::query!(
+            "SELECT email, locale FROM users WHERE user_id = $1 AND NOT confirmed",
+            self.user_id
+        )
+        .fetch_optional(&ctx.pool)
+        .await?
+        else {
+            // Confirmed or deleted in the meantime.
+            return Ok(());
+        };
+        let token = tokens::generate();
+        store_token(
+            &ctx.pool,
+            &token,
+            self.user_id,
+            ctx.tokens.confirmation_ttl(),
+        )
+        .await
+        .context("Failed to store the confirmation token")?;
+
+        let email = Email::parse(user.email)?;
+        let message = ConfirmationEmail::new(&ctx.frontend_url, &token)?;
+        ctx.email_client
+            .send_message(email, user.locale.parse().unwrap_or_default(), &message)
+            .await
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResendConfirmation {
+    email: String,
+}
+
+/// Sends a new confirmation email to an unconfirmed account. Responds the same way whether or
+/// not there's such an account, so it can't be used to find out who's registered.
+#[tracing::instrument(name = "Resend a confirmation email", skip_all)]
+pub async fn resend_confirmation(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Form(form): Form<ResendConfirmation>,
+) -> Result<(), ApiError> {
+    tokens::check_rate_limits(&state, &form.email, ip).await?;
+
+    let user_id = sqlx::query_scalar!(
+        "SELECT user_id FROM users WHERE email = $1 AND NOT confirmed",
+        form.email,
     )
-    .execute(tx)
+    .fetch_optional(&mut *conn)
     .await?;
+    if let Some(user_id) = user_id {
+        enqueue(&mut *conn, &SendConfirmationEmail { user_id })
+            .await
+            .context("Failed to enqueue confirmation delivery task")?;
+    }
     Ok(())
 }
 
@@ -88,9 +132,12 @@ pub struct Parameters {
     token: String,
 }
 
-#[tracing::instrument(name = "Confirm a registration", skip(parameters, conn))]
+/// Confirms an account. Its passkeys are removed unless it's confirmed in the session it was signed
+/// up in; an account left without a way to log in gets a password reset link instead.
+#[tracing::instrument(name = "Confirm a registration", skip(parameters, session, conn))]
 pub async fn confirm(
     parameters: Query<Parameters>,
+    session: Session,
     DatabaseConnection(mut conn): DatabaseConnection,
 ) -> Result<(), ApiError> {
     let mut tx = conn.begin().await?;
@@ -98,6 +145,21 @@ pub async fn confirm(
         .await
         .context("Failed to retrieve the user_id associated with the provided token.")?
         .ok_or(ApiError::BadRequest)?;
+    if !passkey::signed_up_in(&session, user_id).await?
+        && passkey::remove_unconfirmed(&mut tx, user_id).await? > 0
+    {
+        let has_password = sqlx::query_scalar!(
+            r#"SELECT password_hash IS NOT NULL AS "has_password!" FROM users WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if !has_password {
+            enqueue(&mut *tx, &SendPasswordResetEmail { user_id })
+                .await
+                .context("Failed to enqueue password reset delivery task")?;
+        }
+    }
     confirm_subscriber(&mut *tx, user_id)
         .await
         .context("Failed to update the user status to `confirmed`.")?;
@@ -137,8 +199,11 @@ where
     E: PgExecutor<'c>,
 {
     let result = sqlx::query!(
-        r#"SELECT user_id FROM confirmation_tokens WHERE confirmation_token = $1"#,
-        confirmation_token,
+        r#"
+        SELECT user_id FROM confirmation_tokens
+        WHERE token_hash = $1 AND expires_at > NOW()
+        "#,
+        tokens::hash(confirmation_token),
     )
     .fetch_optional(pool)
     .await?;
@@ -1,101 +1,181 @@
 use anyhow::Context;
 use axum::{
+    async_trait,
     extract::{Query, State},
-    routing::{get, post, put},
+    routing::{delete, get, patch, post, put},
     Form, Json, Router,
 };
 use secrecy::{ExposeSecret, SecretString};
-use sqlx::Acquire;
+use sqlx::{Acquire, PgConnection};
 use tower_sessions::Session;
 use validator::Validate;
 
 use crate::{
-    email::Email,
-    error::{ApiError, ResultExt},
-    extractors::{AuthUser, DatabaseConnection, MaybeAuthUser},
+    email::{
+        messages::{AccountExistsEmail, AccountLockedEmail},
+        templates::Locale,
+        Email,
+    },
+    error::ApiError,
+    extractors::{AuthUser, ClientIp, DatabaseConnection, PreferredLocale, SessionUser},
+    queue::{enqueue, Job, JobContext},
     state::AppState,
     RE_USERNAME,
 };
 
+mod api_tokens;
 mod confirm;
 mod oauth;
-mod password;
-
-use oauth::{discord_auth, discord_authorize, google_auth, google_authorize};
-use password::{compute_password_hash, validate_credentials};
-
-use self::confirm::{confirm, enqueue_delivery_task, generate_confirmation_token, store_token};
+mod passkey;
+pub(crate) mod password;
+mod sessions;
+mod throttle;
+pub(crate) mod tokens;
+mod two_factor;
+
+use password::{check_strength, compute_password_hash, validate_credentials, verify_password};
+use two_factor::{start_session, LoginStatus};
+
+use self::confirm::{confirm, resend_confirmation};
+pub use api_tokens::{authenticate_token, TokenScope};
+pub use confirm::SendConfirmationEmail;
+pub use oauth::OAuthProviders;
+pub use password::{SendPasswordChangedEmail, SendPasswordResetEmail};
+pub use sessions::{track as track_session, SessionIndex};
+pub use throttle::LoginThrottle;
+pub use two_factor::{encrypt_plaintext_secrets, TotpSecrets, SECOND_FACTOR};
 
 pub fn router() -> Router<AppState> {
     Router::new()
-        .route("/me", get(me))
         .route("/auth", post(authorize))
         .route("/register", post(register))
+        .route("/register/passkey/start", post(passkey::signup_start))
+        .route("/register/passkey/finish", post(passkey::signup_finish))
         .route("/logout", get(logout))
         .route("/update_password", put(update_password))
         .route("/confirm", get(confirm))
+        .route("/resend_confirmation", post(resend_confirmation))
         .route("/forget_password_gen", post(forget_password_gen))
         .route("/forget_password", post(forget_password))
         .route("/is_token_valid", get(is_token_valid))
-        .route("/auth/discord_authorize", get(discord_authorize))
-        .route("/auth/google_authorize", get(google_authorize))
-        .route("/auth/discord", get(discord_auth))
-        .route("/auth/google", get(google_auth))
-}
-
-#[derive(sqlx::FromRow, serde::Serialize, Debug)]
-struct UserDetails {
-    name: String,
-}
-
-async fn me(
-    maybe_auth_user: MaybeAuthUser,
-    DatabaseConnection(mut conn): DatabaseConnection,
-) -> Result<Json<Option<UserDetails>>, ApiError> {
-    if let Some(auth_user) = maybe_auth_user.into_inner() {
-        let name = sqlx::query_as!(
-            UserDetails,
-            r#"SELECT name FROM users WHERE user_id = $1"#,
-            *auth_user
+        .route(
+            "/auth/2fa",
+            post(two_factor::verify).delete(two_factor::disable),
+        )
+        .route("/auth/2fa/enroll", post(two_factor::enroll))
+        .route("/auth/2fa/confirm", post(two_factor::confirm_enrollment))
+        .route(
+            "/auth/2fa/recovery_codes",
+            post(two_factor::regenerate_recovery_codes),
+        )
+        .route("/auth/passkey/start", post(passkey::login_start))
+        .route("/auth/passkey/finish", post(passkey::login_finish))
+        .route("/auth/passkeys", get(passkey::list))
+        .route(
+            "/auth/passkeys/register_start",
+            post(passkey::register_start),
+        )
+        .route(
+            "/auth/passkeys/register_finish",
+            post(passkey::register_finish),
+        )
+        .route(
+            "/auth/passkeys/:passkey_id",
+            patch(passkey::rename).delete(passkey::delete),
+        )
+        .route(
+            "/auth/tokens",
+            get(api_tokens::list).post(api_tokens::create),
+        )
+        .route("/auth/tokens/:token_id", delete(api_tokens::revoke))
+        .route(
+            "/auth/sessions",
+            get(sessions::list).delete(sessions::revoke_others),
+        )
+        .route("/auth/sessions/:session_id", delete(sessions::revoke))
+        .route("/auth/oauth/providers", get(oauth::providers))
+        .route("/auth/oauth/:provider", get(oauth::login))
+        .route("/auth/oauth/:provider/authorize", get(oauth::authorize))
+        .route("/auth/identities", get(oauth::identities))
+        .route(
+            "/auth/identities/:provider",
+            post(oauth::link).delete(oauth::unlink),
         )
-        .fetch_one(&mut *conn)
-        .await
-        .map_err(|_| ApiError::NotFound)?;
-        return Ok(Json(Some(name)));
-    }
-    Ok(Json(None))
 }
 
 #[derive(Debug, serde::Deserialize, Clone)]
 pub struct Credentials {
     email: String,
     password: SecretString,
+    /// Keeps the session for `session.remember_me_days` instead of the usual lifetime.
+    #[serde(default)]
+    remember_me: bool,
 }
 
 async fn authorize(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     session: Session,
-    conn: DatabaseConnection,
+    DatabaseConnection(mut conn): DatabaseConnection,
     Form(credentials): Form<Credentials>,
-) -> Result<(), ApiError> {
-    let user_id = validate_credentials(credentials, conn).await?;
-    // Rotate the session cookie on privilege level change.
-    // This is to prevent session-fixation attacks.
-    session.cycle_id().await?;
-    session
-        .insert("user_id", user_id)
-        .await
-        .expect("user_id is serializable");
-    Ok(())
-}
+) -> Result<Json<LoginStatus>, ApiError> {
+    let (uniform_errors, throttle_settings) = {
+        let config = state.config.borrow();
+        (
+            config.auth.uniform_errors,
+            config.auth.login_throttle.clone(),
+        )
+    };
+    let email = credentials.email.clone();
+    let remember_me = credentials.remember_me;
+    let account_throttled = state
+        .login_throttle
+        .check(&email, ip, &throttle_settings)
+        .await?;
 
-async fn logout(_user: AuthUser, session: Session) -> Result<(), ApiError> {
-    session.delete().await?;
-    Ok(())
+    let user_id = match validate_credentials(credentials, &mut conn, uniform_errors).await {
+        Ok(user_id) => user_id,
+        Err(e @ ApiError::UnprocessableEntity { .. }) => {
+            let locked_for = state
+                .login_throttle
+                .record_failure(&email, ip, &throttle_settings)
+                .await?;
+            if let Some(throttled) = account_throttled {
+                return Err(throttled);
+            }
+            if let Some(locked_for) = locked_for {
+                tracing::warn!("Locked out an account after too many failed logins");
+                enqueue(
+                    &state.db_pool,
+                    &SendAccountLockedEmail {
+                        email,
+                        locked_minutes: locked_for.as_secs().div_ceil(60),
+                    },
+                )
+                .await
+                .context("Failed to enqueue account locked delivery task")?;
+            }
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    state.login_throttle.record_success(&email).await?;
+
+    let status = start_session(&mut conn, &session, user_id, false).await?;
+    if remember_me {
+        sessions::remember(&session).await?;
+    }
+    Ok(status)
 }
 
-#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, Clone)]
-struct UserId {
-    user_id: uuid::Uuid,
+async fn logout(
+    _user: AuthUser,
+    session: Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<(), ApiError> {
+    sessions::forget(&mut conn, &session).await?;
+    session.flush().await?;
+    Ok(())
 }
 
 #[derive(serde::Deserialize, validator::Validate)]
@@ -113,19 +193,24 @@ pub struct Register {
     password: SecretString,
 }
 
-#[tracing::instrument(name = "Registering a new user", skip(form, conn))]
+#[tracing::instrument(name = "Registering a new user", skip_all)]
 async fn register(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     DatabaseConnection(mut conn): DatabaseConnection,
+    PreferredLocale(locale): PreferredLocale,
     Form(form): Form<Register>,
 ) -> Result<(), ApiError> {
     form.validate()
         .map_err(ApiError::unprocessable_entity_from_validation_errors)?;
-
+    tokens::check_rate_limits(&state, &form.email, ip).await?;
+    let policy = state.config.borrow().auth.password_policy.clone();
     let Register {
         name,
         email,
         password,
     } = form;
+    check_strength(&password, &[&name, &email], &policy)?;
 
     let password_hash =
         crate::utils::spawn_blocking_with_tracing(move || compute_password_hash(password))
@@ -133,69 +218,191 @@ async fn register(
             .context("Failed to hash password")??;
 
     let mut tx = conn.begin().await?;
+    let account = NewAccount {
+        user_id: None,
+        name,
+        email,
+        password_hash: Some(password_hash),
+        locale,
+    };
+    create_account(&state, &mut tx, account).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
 
-    let user_id = sqlx::query_as!(
-        UserId,
+struct NewAccount {
+    /// Generated by the database if not given.
+    user_id: Option<uuid::Uuid>,
+    name: String,
+    email: String,
+    /// Passkey-only accounts have none.
+    password_hash: Option<SecretString>,
+    locale: Locale,
+}
+
+/// Creates an unconfirmed account and emails a confirmation link to it.
+///
+/// With uniform errors, an email address that has an account already isn't an error: its owner
+/// gets an email about it instead, and there's no new account to return.
+async fn create_account(
+    state: &AppState,
+    conn: &mut PgConnection,
+    account: NewAccount,
+) -> Result<Option<uuid::Uuid>, ApiError> {
+    let uniform_errors = state.config.borrow().auth.uniform_errors;
+    let NewAccount {
+        user_id,
+        name,
+        email,
+        password_hash,
+        locale,
+    } = account;
+
+    let user_id = sqlx::query_scalar!(
         r#"
-        INSERT INTO users (name, email, password_hash)
-        VALUES ($1, $2, $3)
+        INSERT INTO users (user_id, name, email, password_hash, locale)
+        VALUES (COALESCE($1, uuid_generate_v1mc()), $2, $3, $4, $5)
+        ON CONFLICT (email) DO NOTHING
         RETURNING user_id;
         "#,
+        user_id,
         name,
         email,
-        password_hash.expose_secret(),
+        password_hash.as_ref().map(|hash| hash.expose_secret()),
+        locale.as_str(),
     )
-    .fetch_one(&mut *tx)
-    .await
-    .on_constraint("users_email_key", |_| {
-        ApiError::unprocessable_entity([("email", "email already taken")])
-    })?;
-
-    let token = generate_confirmation_token();
+    .fetch_optional(&mut *conn)
+    .await?;
 
-    store_token(&mut *tx, &token, user_id.user_id)
+    let Some(user_id) = user_id else {
+        if !uniform_errors {
+            return Err(ApiError::unprocessable_entity([(
+                "email",
+                "email already taken",
+            )]));
+        }
+        // Respond as if the registration went through, and let the owner of the address know.
+        let locale = sqlx::query_scalar!("SELECT locale FROM users WHERE email = $1", email)
+            .fetch_one(&mut *conn)
+            .await?;
+        enqueue(
+            &mut *conn,
+            &SendAccountExistsEmail {
+                email,
+                locale: locale.parse().unwrap_or_default(),
+            },
+        )
         .await
-        .context("Failed to store the confirmation token for a new subscriber.")?;
+        .context("Failed to enqueue account exists delivery task")?;
+        return Ok(None);
+    };
 
-    enqueue_delivery_task(&mut *tx, token, email)
+    enqueue(&mut *conn, &SendConfirmationEmail { user_id })
         .await
         .context("Failed to enqueue confirmation delivery task")?;
 
-    tx.commit().await?;
-
-    Ok(())
+    Ok(Some(user_id))
 }
 
 #[derive(serde::Deserialize)]
 pub struct UpdatePassword {
-    name: String,
+    current_password: SecretString,
     password: SecretString,
 }
 
+/// Changes the password, given the current one. Wrong guesses count as failed logins. Every other
+/// session is logged out, in case someone else had access, and the owner gets an email about it.
+///
+/// Accounts without a password set one with a password reset, which proves they own the email.
 async fn update_password(
-    user_id: AuthUser,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    user_id: SessionUser,
+    session: Session,
     DatabaseConnection(mut conn): DatabaseConnection,
     Form(form): Form<UpdatePassword>,
 ) -> Result<(), ApiError> {
-    let UpdatePassword { name, password } = form;
+    let (policy, throttle_settings) = {
+        let config = state.config.borrow();
+        (
+            config.auth.password_policy.clone(),
+            config.auth.login_throttle.clone(),
+        )
+    };
+    let UpdatePassword {
+        current_password,
+        password,
+    } = form;
+
+    let user = sqlx::query!(
+        "SELECT name, email, locale, password_hash FROM users WHERE user_id = $1",
+        *user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+    let Some(current_hash) = user.password_hash else {
+        return Err(ApiError::unprocessable_entity([(
+            "current_password",
+            "the account has no password, reset it to set one",
+        )]));
+    };
+
+    let account_throttled = state
+        .login_throttle
+        .check(&user.email, ip, &throttle_settings)
+        .await?;
+    let matches = crate::utils::spawn_blocking_with_tracing(move || {
+        verify_password(&current_hash, &current_password)
+    })
+    .await
+    .context("unexpected error happened during password hashing")??;
+    if !matches {
+        state
+            .login_throttle
+            .record_failure(&user.email, ip, &throttle_settings)
+            .await?;
+        if let Some(throttled) = account_throttled {
+            return Err(throttled);
+        }
+        return Err(ApiError::unprocessable_entity([(
+            "current_password",
+            "password is wrong",
+        )]));
+    }
+    check_strength(&password, &[&user.name, &user.email], &policy)?;
+
     let password_hash =
         crate::utils::spawn_blocking_with_tracing(move || compute_password_hash(password))
             .await
             .context("Failed to hash password")??;
 
+    let mut tx = conn.begin().await?;
     sqlx::query!(
-        r#"
-        UPDATE users
-        SET password_hash = $1
-        WHERE name = $2 AND user_id = $3
-        "#,
+        "UPDATE users SET password_hash = $1 WHERE user_id = $2",
         password_hash.expose_secret(),
-        name,
         *user_id,
     )
-    .execute(&mut *conn)
+    .execute(&mut *tx)
     .await
     .context("Failed to change user's password in the database.")?;
+    enqueue(
+        &mut *tx,
+        &SendPasswordChangedEmail {
+            email: user.email,
+            locale: user.locale.parse().unwrap_or_default(),
+        },
+    )
+    .await
+    .context("Failed to enqueue password changed delivery task")?;
+    tx.commit().await?;
+
+    state
+        .sessions
+        .revoke_all(&mut conn, *user_id, session.id())
+        .await?;
+    tracing::info!(user_id = %*user_id, "Changed a password");
     Ok(())
 }
 
@@ -206,13 +413,15 @@ struct ForgetPassword {
 }
 
 async fn forget_password_gen(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     DatabaseConnection(mut conn): DatabaseConnection,
-    State(AppState { email_client, .. }): State<AppState>,
     Form(form): Form<ForgetPassword>,
 ) -> Result<(), ApiError> {
     let ForgetPassword { name, email } = form;
+    tokens::check_rate_limits(&state, &email, ip).await?;
 
-    let result = sqlx::query!(
+    let user_id = sqlx::query_scalar!(
         r#"
         SELECT user_id
         FROM users
@@ -224,41 +433,17 @@ async fn forget_password_gen(
     .fetch_optional(&mut *conn)
     .await?;
 
-    if let Some(user_id) = result.map(|r| r.user_id) {
-        let token = uuid::Uuid::new_v4();
-
-        sqlx::query!(
-            r#"
-            INSERT INTO forget_password_tokens (token, user_id)
-            VALUES ($1, $2)
-            "#,
-            token,
-            user_id
-        )
-        .execute(&mut *conn)
-        .await?;
-
-        email_client
-            .send_mail(
-                Email::parse(email)?,
-                "Recipe App - Your password reset",
-                &format!(
-                    "Visit http://localhost:3001/forget_password?token={}",
-                    token
-                ),
-                &format!(
-                    "Visit http://localhost:3001/forget_password?token={}",
-                    token
-                ),
-            )
-            .await?;
+    if let Some(user_id) = user_id {
+        enqueue(&mut *conn, &SendPasswordResetEmail { user_id })
+            .await
+            .context("Failed to enqueue password reset delivery task")?;
     }
     Ok(())
 }
 
 #[derive(serde::Deserialize)]
 struct ForgetPasswordParameters {
-    token: uuid::Uuid,
+    token: String,
 }
 
 #[derive(serde::Deserialize)]
@@ -266,35 +451,37 @@ pub struct ResetPassword {
     password: SecretString,
 }
 
-#[derive(serde::Deserialize)]
-pub struct ResetDetails {
-    token: uuid::Uuid,
-    user_id: uuid::Uuid,
-}
-
 async fn forget_password(
+    State(state): State<AppState>,
     Query(params): Query<ForgetPasswordParameters>,
     DatabaseConnection(mut conn): DatabaseConnection,
     Form(form): Form<ResetPassword>,
 ) -> Result<(), ApiError> {
     let mut tx = conn.begin().await?;
 
-    // TODO: expiry is a little dumb this way, but let's just don't care about it for now.
-    let result = sqlx::query_as!(
-        ResetDetails,
+    // Locks the token, so it can't be used twice concurrently.
+    let user_id = sqlx::query_scalar!(
         r#"
-        SELECT user_id, token
+        SELECT user_id
         FROM forget_password_tokens
-        WHERE created_at > current_timestamp - INTERVAL '2 days' AND token = $1
-        ORDER BY created_at DESC
-        LIMIT 1;
+        WHERE token_hash = $1 AND expires_at > NOW()
+        FOR UPDATE
         "#,
-        params.token,
+        tokens::hash(&params.token),
     )
     .fetch_optional(&mut *tx)
     .await?;
 
-    if let Some(reset_details) = result {
+    if let Some(user_id) = user_id {
+        let user = sqlx::query!(
+            "SELECT name, email, locale FROM users WHERE user_id = $1",
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let policy = state.config.borrow().auth.password_policy.clone();
+        check_strength(&form.password, &[&user.name, &user.email], &policy)?;
+
         let password_hash =
             crate::utils::spawn_blocking_with_tracing(move || compute_password_hash(form.password))
                 .await
@@ -307,25 +494,39 @@ async fn forget_password(
             WHERE user_id = $2
             "#,
             password_hash.expose_secret(),
-            reset_details.user_id,
+            user_id,
         )
         .execute(&mut *tx)
         .await
         .context("Failed to change user's password in the database.")?;
+        // Whoever signed up with the address before its owner doesn't keep a way in.
+        passkey::remove_unconfirmed(&mut tx, user_id).await?;
+        let email = user.email;
 
+        // Any other reset link that was sent is invalidated too.
         sqlx::query!(
-            r#"
-            DELETE FROM forget_password_tokens
-            WHERE user_id = $1 AND token = $2
-            "#,
-            reset_details.user_id,
-            reset_details.token,
+            "DELETE FROM forget_password_tokens WHERE user_id = $1",
+            user_id,
         )
         .execute(&mut *tx)
         .await
         .context("Failed to delete from forget_password_tokens.")?;
 
+        enqueue(
+            &mut *tx,
+            &SendPasswordChangedEmail {
+                email: email.clone(),
+                locale: user.locale.parse().unwrap_or_default(),
+            },
+        )
+        .await
+        .context("Failed to enqueue password changed delivery task")?;
+
         tx.commit().await?;
+        // Whoever knew the old password is logged out everywhere.
+        state.sessions.revoke_all(&mut conn, user_id, None).await?;
+        // Whoever reset the password can log in with it right away, even if it was locked out.
+        state.login_throttle.unlock(&email).await?;
         Ok(())
     } else {
         Err(ApiError::BadRequest)
@@ -338,13 +539,11 @@ async fn is_token_valid(
 ) -> Result<(), ApiError> {
     sqlx::query!(
         r#"
-        SELECT token
+        SELECT user_id
         FROM forget_password_tokens
-        WHERE created_at > current_timestamp - INTERVAL '2 days' AND token = $1
-        ORDER BY created_at DESC
-        LIMIT 1;
+        WHERE token_hash = $1 AND expires_at > NOW()
         "#,
-        params.token,
+        tokens::hash(&params.token),
     )
     .fetch_optional(&mut *conn)
     .await?
@@ -352,3 +551,259 @@ async fn is_token_valid(
 
     Ok(())
 }
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SendAccountExistsEmail {
+    pub email: String,
+    #[serde(default)]
+    pub locale: Locale,
+}
+
+#[async_trait]
+impl Job for SendAccountExistsEmail {
+    const JOB_TYPE: &'static str = "account_exists_email";
+    const PRIORITY: i32 = 10;
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        let email = Email::parse(self.email)?;
+        let message = AccountExistsEmail::new(&ctx.frontend_url)?;
+        ctx.email_client
+            .send_message(email, self.locale, &message)
+            .await
+    }
+}
+
+/// Tells the owner of an account that it was locked out after too many failed logins. There may
+/// not be an account with `email`, the throttle doesn't know.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SendAccountLockedEmail {
+    pub email: String,
+    pub locked_minutes: u64,
+}
+
+#[async_trait]
+impl Job for SendAccountLockedEmail {
+    const JOB_TYPE: &'static str = "account_locked_email";
+    const PRIORITY: i32 = 10;
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        let Some(locale) =
+            sqlx::query_scalar!("SELECT locale FROM users WHERE email = $1", self.email)
+                .fetch_optional(&ctx.pool)
+                .await?
+        else {
+            return Ok(());
+        };
+        let email = Email::parse(self.email)?;
+        let message = AccountLockedEmail::new(&ctx.frontend_url, self.locked_minutes)?;
+        ctx.email_client
+            .send_message(email, locale.parse().unwrap_or_default(), &message)
+            .await
+    }
+}
+
+/// Deletes confirmation and password reset tokens that expired, and rows of expired sessions.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PurgeExpiredTokens;
+
+#[async_trait]
+impl Job for PurgeExpiredTokens {
+    const JOB_TYPE: &'static str = "purge_expired_tokens";
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        let reset_tokens =
+            sqlx::query!("DELETE FROM forget_password_tokens WHERE expires_at <= NOW()")
+                .execute(&ctx.pool)
+                .await?;
+        let confirmation_tokens =
+            sqlx::query!("DELETE FROM confirmation_tokens WHERE expires_at <= NOW()")
+                .execute(&ctx.pool)
+                .await?;
+        let oauth_codes =
+            sqlx::query!("DELETE FROM oauth_authorization_codes WHERE expires_at <= NOW()")
+                .execute(&ctx.pool)
+                .await?;
+        let oauth_tokens = sqlx::query!("DELETE FROM oauth_tokens WHERE expires_at <= NOW()")
+            .execute(&ctx.pool)
+            .await?;
+        // Rows lag the session store by up to a minute, the margin keeps live sessions' rows.
+        let sessions =
+            sqlx::query!("DELETE FROM user_sessions WHERE expires_at <= NOW() - INTERVAL '1 hour'")
+                .execute(&ctx.pool)
+                .await?;
+        tracing::info!(
+            "Purged {} password reset, {} confirmation and {} OAuth tokens, {} OAuth codes and {} sessions",
+            reset_tokens.rows_affected(),
+            confirmation_tokens.rows_affected(),
+            oauth_tokens.rows_affected(),
+            oauth_codes.rows_affected(),
+            sessions.rows_affected()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use sqlx::PgPool;
+
+    use crate::testing::{create_user_with_password, TestApp};
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    async fn jobs(pool: &PgPool, job_type: &str) -> Vec<serde_json::Value> {
+        sqlx::query_scalar!("SELECT payload FROM jobs WHERE job_type = $1", job_type)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn register(
+        app: &mut TestApp,
+        name: &str,
+        email: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        app.submit(
+            Method::POST,
+            "/register",
+            &[("name", name), ("email", email), ("password", PASSWORD)],
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn logins_fail_the_same_way_whether_or_not_there_is_an_account(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::new(pool).await;
+
+        let wrong_password = app.log_in("ursula@example.com", "wrong").await;
+        let no_account = app.log_in("nobody@example.com", "wrong").await;
+        assert_eq!(wrong_password.0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(wrong_password, no_account);
+
+        let (status, _) = app.log_in("ursula@example.com", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn logins_tell_what_is_wrong_without_uniform_errors(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app =
+            TestApp::with_settings(pool, |settings| settings.auth.uniform_errors = false).await;
+
+        let (_, wrong_password) = app.log_in("ursula@example.com", "wrong").await;
+        assert_eq!(
+            wrong_password,
+            serde_json::json!({ "errors": { "password": ["password is wrong"] } })
+        );
+        let (_, no_account) = app.log_in("nobody@example.com", "wrong").await;
+        assert_eq!(
+            no_account,
+            serde_json::json!({ "errors": { "email": ["this email does not exist"] } })
+        );
+    }
+
+    #[sqlx::test]
+    async fn registering_a_taken_address_emails_its_owner_instead(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::new(pool.clone()).await;
+
+        let (status, _) = register(&mut app, "impostor", "ursula@example.com").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            jobs(&pool, "account_exists_email").await,
+            [serde_json::json!({ "email": "ursula@example.com", "locale": "en" })]
+        );
+        assert!(jobs(&pool, "confirmation_email").await.is_empty());
+
+        let (status, _) = register(&mut app, "vera", "vera@example.com").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(jobs(&pool, "confirmation_email").await.len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn registering_a_taken_address_fails_without_uniform_errors(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::with_settings(pool.clone(), |settings| {
+            settings.auth.uniform_errors = false
+        })
+        .await;
+
+        let (status, body) = register(&mut app, "impostor", "ursula@example.com").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body,
+            serde_json::json!({ "errors": { "email": ["email already taken"] } })
+        );
+        assert!(jobs(&pool, "account_exists_email").await.is_empty());
+    }
+
+    async fn change_password(
+        app: &mut TestApp,
+        current_password: &str,
+        password: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        app.submit(
+            Method::PUT,
+            "/update_password",
+            &[
+                ("current_password", current_password),
+                ("password", password),
+            ],
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn changing_the_password_takes_the_current_one_and_a_strong_one(pool: PgPool) {
+        const NEW_PASSWORD: &str = "tr0mbone pelican ledger";
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::new(pool.clone()).await;
+        app.log_in("ursula@example.com", PASSWORD).await;
+
+        let (status, body) = change_password(&mut app, "wrong", NEW_PASSWORD).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body,
+            serde_json::json!({ "errors": { "current_password": ["password is wrong"] } })
+        );
+        let (status, body) = change_password(&mut app, PASSWORD, "password123").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body["errors"]["password"].is_array());
+        assert!(jobs(&pool, "password_changed_email").await.is_empty());
+
+        let (status, _) = change_password(&mut app, PASSWORD, NEW_PASSWORD).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            jobs(&pool, "password_changed_email").await,
+            [serde_json::json!({ "email": "ursula@example.com", "locale": "en" })]
+        );
+        let mut other = app.another_browser();
+        assert_eq!(
+            other.log_in("ursula@example.com", PASSWORD).await.0,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            other.log_in("ursula@example.com", NEW_PASSWORD).await.0,
+            StatusCode::OK
+        );
+    }
+
+    #[sqlx::test]
+    async fn changing_the_password_logs_out_the_other_sessions(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut laptop = TestApp::new(pool).await;
+        let mut phone = laptop.another_browser();
+        laptop.log_in("ursula@example.com", PASSWORD).await;
+        phone.log_in("ursula@example.com", PASSWORD).await;
+
+        let (status, _) = change_password(&mut laptop, PASSWORD, "tr0mbone pelican ledger").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = phone.submit(Method::GET, "/auth/sessions", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = laptop.submit(Method::GET, "/auth/sessions", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
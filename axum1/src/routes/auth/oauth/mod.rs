@@ -0,0 +1,490 @@
+//! Logging in with external OAuth2/OpenID Connect providers, and linking them to accounts.
+//!
+//! Both start by redirecting to the provider, and end up at `/auth/oauth/:provider/authorize`.
+//! What the login was started for is kept in the session in between, with the PKCE verifier.
+use axum::{
+    extract::{Path, Query},
+    Extension, Json,
+};
+use oauth2::{
+    reqwest::async_http_client, AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier,
+    Scope, StandardRevocableToken, TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, PgConnection};
+use tower_sessions::Session;
+
+use super::{
+    tokens,
+    two_factor::{start_session, LoginStatus},
+};
+use crate::{
+    error::{ApiError, ResultExt},
+    extractors::{DatabaseConnection, PreferredLocale, SessionUser},
+};
+
+mod provider;
+
+pub use provider::OAuthProviders;
+use provider::{Provider, ProviderIdentity};
+
+const PENDING_AUTHORIZATION: &str = "oauth_authorization";
+
+#[derive(Serialize, Deserialize)]
+struct PendingAuthorization {
+    provider: String,
+    csrf_token: CsrfToken,
+    pkce_verifier: PkceCodeVerifier,
+    nonce: String,
+    /// The account the provider is being linked to, rather than logged in with.
+    link_to: Option<uuid::Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AuthRequest {
+    code: String,
+    state: String,
+}
+
+#[derive(Clone, Serialize)]
+pub(super) struct RedirectUri {
+    uri: String,
+}
+
+#[derive(Serialize)]
+pub(super) struct ProviderDetails {
+    name: String,
+    display_name: String,
+}
+
+/// The providers to offer on the login page.
+pub(super) async fn providers(
+    Extension(providers): Extension<OAuthProviders>,
+) -> Json<Vec<ProviderDetails>> {
+    let providers = providers
+        .iter()
+        .map(|provider| ProviderDetails {
+            name: provider.name.clone(),
+            display_name: provider.display_name().to_owned(),
+        })
+        .collect();
+    Json(providers)
+}
+
+/// Builds the provider's authorization URL, and remembers what it's for in the session.
+async fn redirect_to_provider(
+    provider: &Provider,
+    session: &Session,
+    link_to: Option<uuid::Uuid>,
+) -> Result<Json<RedirectUri>, ApiError> {
+    let client = provider.client().await?;
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let nonce = tokens::generate();
+
+    let mut request = client
+        .authorize_url(CsrfToken::new_random)
+        .add_scopes(provider.scopes().iter().cloned().map(Scope::new))
+        .set_pkce_challenge(pkce_challenge);
+    if provider.is_openid_connect() {
+        request = request.add_extra_param("nonce", &nonce);
+    }
+    let (auth_url, csrf_token) = request.url();
+
+    session
+        .insert(
+            PENDING_AUTHORIZATION,
+            PendingAuthorization {
+                provider: provider.name.clone(),
+                csrf_token,
+                pkce_verifier,
+                nonce,
+                link_to,
+            },
+        )
+        .await?;
+
+    Ok(Json(RedirectUri {
+        uri: auth_url.to_string(),
+    }))
+}
+
+#[tracing::instrument(skip_all, fields(provider = %provider_name))]
+pub(super) async fn login(
+    Path(provider_name): Path<String>,
+    Extension(providers): Extension<OAuthProviders>,
+    session: Session,
+) -> Result<Json<RedirectUri>, ApiError> {
+    let provider = providers.get(&provider_name)?;
+    redirect_to_provider(provider, &session, None).await
+}
+
+/// Starts linking a provider to the account logged into the session.
+#[tracing::instrument(skip_all, fields(provider = %provider_name))]
+pub(super) async fn link(
+    auth_user: SessionUser,
+    Path(provider_name): Path<String>,
+    Extension(providers): Extension<OAuthProviders>,
+    session: Session,
+) -> Result<Json<RedirectUri>, ApiError> {
+    let provider = providers.get(&provider_name)?;
+    redirect_to_provider(provider, &session, Some(*auth_user)).await
+}
+
+#[derive(Serialize)]
+pub(super) struct IdentityDetails {
+    provider: String,
+    email: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub(super) enum Authorized {
+    LoggedIn(LoginStatus),
+    Linked(IdentityDetails),
+}
+
+#[tracing::instrument(skip_all, fields(provider = %provider_name))]
+pub(super) async fn authorize(
+    Path(provider_name): Path<String>,
+    Query(query): Query<AuthRequest>,
+    session: Session,
+    Extension(providers): Extension<OAuthProviders>,
+    PreferredLocale(locale): PreferredLocale,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Authorized>, ApiError> {
+    // Removed right away, whatever happens next: the state is only good for one attempt.
+    let pending = session
+        .remove::<PendingAuthorization>(PENDING_AUTHORIZATION)
+        .await?
+        .ok_or(ApiError::BadRequest)?;
+
+    // Protect Cross-site Request Forgery Attacks
+    if pending.provider != provider_name || pending.csrf_token.secret() != &query.state {
+        return Err(ApiError::BadRequest);
+    }
+
+    let provider = providers.get(&provider_name)?;
+    let client = provider.client().await?;
+
+    // Get an auth token
+    let token = client
+        .exchange_code(AuthorizationCode::new(query.code))
+        .set_pkce_verifier(pending.pkce_verifier)
+        .request_async(async_http_client)
+        .await
+        .map_err(|_| ApiError::BadRequest)?;
+
+    let identity = provider.identity(&token, &pending.nonce).await?;
+
+    // We only needed the token to find out who the user is.
+    let token_to_revoke: StandardRevocableToken = match token.refresh_token() {
+        Some(token) => token.into(),
+        None => token.access_token().into(),
+    };
+    if let Ok(revocation) = client.revoke_token(token_to_revoke) {
+        revocation.request_async(async_http_client).await.ok();
+    }
+
+    if let Some(user_id) = pending.link_to {
+        // The session may have been logged out, or into another account, in the meantime.
+        if session.get::<uuid::Uuid>("user_id").await? != Some(user_id) {
+            return Err(ApiError::Unauthorized);
+        }
+        let identity = link_identity(&mut conn, user_id, provider, &identity).await?;
+        tracing::info!(%user_id, "Linked an OAuth provider");
+        return Ok(Json(Authorized::Linked(identity)));
+    }
+
+    let mut tx = conn.begin().await?;
+    let linked = sqlx::query_scalar!(
+        "SELECT user_id FROM user_identities WHERE provider = $1 AND subject = $2",
+        provider.name,
+        identity.subject,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user_id = match linked {
+        Some(user_id) => user_id,
+        None => {
+            let email = identity.email.clone().ok_or_else(|| {
+                ApiError::unprocessable_entity([(
+                    "email",
+                    format!("{} didn't share an email address", provider.display_name()),
+                )])
+            })?;
+            let existing = sqlx::query!(
+                "SELECT user_id, confirmed FROM users WHERE email = $1",
+                email
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            let user_id = match existing {
+                // Both sides proved they own the address, so it's the same person.
+                Some(user) if user.confirmed && identity.email_verified => user.user_id,
+                Some(_) => {
+                    return Err(ApiError::unprocessable_entity([(
+                        "email",
+                        format!(
+                            "an account with this email address exists, log into it to link {}",
+                            provider.display_name()
+                        ),
+                    )]))
+                }
+                None => {
+                    let name = identity
+                        .name
+                        .clone()
+                        .or_else(|| email.split('@').next().map(str::to_owned))
+                        .unwrap_or_default();
+                    sqlx::query_scalar!(
+                        r#"
+                        INSERT INTO users (name, email, confirmed, locale)
+                        VALUES ($1, $2, 'TRUE', $3)
+                        RETURNING user_id;
+                        "#,
+                        name,
+                        email,
+                        locale.as_str(),
+                    )
+                    .fetch_one(&mut *tx)
+                    .await?
+                }
+            };
+            link_identity(&mut tx, user_id, provider, &identity).await?;
+            user_id
+        }
+    };
+    tx.commit().await?;
+
+    let Json(status) = start_session(&mut conn, &session, user_id, false).await?;
+    Ok(Json(Authorized::LoggedIn(status)))
+}
+
+async fn link_identity(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    provider: &Provider,
+    identity: &ProviderIdentity,
+) -> Result<IdentityDetails, ApiError> {
+    let identity = sqlx::query_as!(
+        IdentityDetails,
+        r#"
+        INSERT INTO user_identities (user_id, provider, subject, email)
+        VALUES ($1, $2, $3, $4)
+        RETURNING provider, email, created_at
+        "#,
+        user_id,
+        provider.name,
+        identity.subject,
+        identity.email,
+    )
+    .fetch_one(conn)
+    .await
+    .on_constraint("user_identities_provider_subject_key", |_| {
+        ApiError::unprocessable_entity([(
+            "provider",
+            format!(
+                "this {} account is linked to another account",
+                provider.display_name()
+            ),
+        )])
+    })
+    .on_constraint("user_identities_user_id_provider_key", |_| {
+        ApiError::unprocessable_entity([(
+            "provider",
+            format!("a {} account is linked already", provider.display_name()),
+        )])
+    })?;
+    Ok(identity)
+}
+
+/// The providers linked to the account logged into the session.
+pub(super) async fn identities(
+    auth_user: SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Vec<IdentityDetails>>, ApiError> {
+    let identities = sqlx::query_as!(
+        IdentityDetails,
+        r#"
+        SELECT provider, email, created_at
+        FROM user_identities
+        WHERE user_id = $1
+        ORDER BY created_at
+        "#,
+        *auth_user
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(Json(identities))
+}
+
+/// Unlinks a provider, unless the account couldn't be logged into without it.
+pub(super) async fn unlink(
+    auth_user: SessionUser,
+    Path(provider_name): Path<String>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<(), ApiError> {
+    let user_id = *auth_user;
+    let mut tx = conn.begin().await?;
+    // Locks the user, so concurrent unlinking can't remove the last two ways to log in.
+    sqlx::query!(
+        "SELECT user_id FROM users WHERE user_id = $1 FOR UPDATE",
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let unlinked = sqlx::query!(
+        "DELETE FROM user_identities WHERE user_id = $1 AND provider = $2",
+        user_id,
+        provider_name
+    )
+    .execute(&mut *tx)
+    .await?;
+    if unlinked.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    let can_log_in = sqlx::query_scalar!(
+        r#"
+        SELECT password_hash IS NOT NULL
+            OR EXISTS (SELECT 1 FROM passkeys p WHERE p.user_id = u.user_id)
+            OR EXISTS (SELECT 1 FROM user_identities i WHERE i.user_id = u.user_id)
+            AS "can_log_in!"
+        FROM users u
+        WHERE u.user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if !can_log_in {
+        return Err(ApiError::unprocessable_entity([(
+            "provider",
+            "the only way to log into an account can't be removed",
+        )]));
+    }
+    tx.commit().await?;
+    tracing::info!(%user_id, provider = %provider_name, "Unlinked an OAuth provider");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use sqlx::PgPool;
+
+    use crate::testing::{create_user_with_password, TestApp};
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    async fn link_identity(pool: &PgPool, user_id: uuid::Uuid, provider: &str) {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_identities (user_id, provider, subject, email)
+            VALUES ($1, $2, $3, 'ursula@example.com')
+            "#,
+            user_id,
+            provider,
+            format!("{provider}-subject"),
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn logged_in(pool: &PgPool) -> (TestApp, uuid::Uuid) {
+        let user_id = create_user_with_password(pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::new(pool.clone()).await;
+        let (status, _) = app.log_in("ursula@example.com", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK);
+        (app, user_id)
+    }
+
+    #[sqlx::test]
+    async fn identities_take_a_logged_in_session(pool: PgPool) {
+        let mut app = TestApp::new(pool).await;
+        for (method, uri) in [
+            (Method::GET, "/auth/identities"),
+            (Method::POST, "/auth/identities/discord"),
+            (Method::DELETE, "/auth/identities/discord"),
+        ] {
+            let (status, _) = app.submit(method.clone(), uri, &[]).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{method} {uri}");
+
+            let request = Request::builder()
+                .method(method.clone())
+                .uri(uri)
+                .header(header::AUTHORIZATION, "Bearer some-token")
+                .body(Body::empty())
+                .unwrap();
+            let (status, _) = app.request(request).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{method} {uri} with a token");
+        }
+    }
+
+    #[sqlx::test]
+    async fn linking_starts_at_the_provider(pool: PgPool) {
+        let (mut app, _) = logged_in(&pool).await;
+
+        let (status, body) = app
+            .submit(Method::POST, "/auth/identities/discord", &[])
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["uri"]
+            .as_str()
+            .unwrap()
+            .starts_with("https://discord.com/api/oauth2/authorize"));
+        let (status, _) = app
+            .submit(Method::POST, "/auth/identities/nowhere", &[])
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn linked_identities_are_listed_and_unlinked(pool: PgPool) {
+        let (mut app, user_id) = logged_in(&pool).await;
+        link_identity(&pool, user_id, "discord").await;
+
+        let (status, body) = app.submit(Method::GET, "/auth/identities", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["provider"], "discord");
+        assert_eq!(body.as_array().unwrap().len(), 1);
+
+        let (status, _) = app
+            .submit(Method::DELETE, "/auth/identities/discord", &[])
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = app.submit(Method::GET, "/auth/identities", &[]).await;
+        assert_eq!(body, serde_json::json!([]));
+        let (status, _) = app
+            .submit(Method::DELETE, "/auth/identities/discord", &[])
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn the_only_way_to_log_in_stays_linked(pool: PgPool) {
+        let (mut app, user_id) = logged_in(&pool).await;
+        link_identity(&pool, user_id, "discord").await;
+        sqlx::query!(
+            "UPDATE users SET password_hash = NULL WHERE user_id = $1",
+            user_id
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let (status, _) = app
+            .submit(Method::DELETE, "/auth/identities/discord", &[])
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (_, body) = app.submit(Method::GET, "/auth/identities", &[]).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+    }
+}
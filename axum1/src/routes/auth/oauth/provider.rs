@@ -0,0 +1,382 @@
+//! The providers configured in `oauth.providers`. OpenID Connect providers are discovered from
+//! their issuer and identify users by a verified ID token, plain OAuth2 ones by their user info
+//! endpoint.
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{bail, Context};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use oauth2::{
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenType,
+    },
+    AuthUrl, Client, ClientId, ClientSecret, ExtraTokenFields, RedirectUrl, RevocationUrl,
+    StandardRevocableToken, StandardTokenResponse, TokenResponse, TokenUrl,
+};
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::{OnceCell, RwLock};
+
+use crate::{
+    config::{OAuthProviderSettings, Settings},
+    error::ApiError,
+};
+
+/// OpenID Connect providers send the ID token along with the access token.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(super) struct IdTokenField {
+    id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenField {}
+
+pub(super) type ProviderTokenResponse = StandardTokenResponse<IdTokenField, BasicTokenType>;
+
+pub(super) type ProviderClient = Client<
+    BasicErrorResponse,
+    ProviderTokenResponse,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+/// Every configured provider, by name.
+#[derive(Clone)]
+pub struct OAuthProviders(Arc<BTreeMap<String, Provider>>);
+
+impl OAuthProviders {
+    pub fn new(config: &Settings) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            // Some providers, like GitHub, reject requests without one.
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .build()?;
+        let providers = config
+            .oauth
+            .providers
+            .iter()
+            .map(|(name, settings)| {
+                Provider::new(name, settings.clone(), http.clone())
+                    .with_context(|| format!("Invalid OAuth provider `{name}`"))
+                    .map(|provider| (name.clone(), provider))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self(Arc::new(providers)))
+    }
+
+    pub(super) fn get(&self, name: &str) -> Result<&Provider, ApiError> {
+        self.0.get(name).ok_or(ApiError::NotFound)
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = &Provider> {
+        self.0.values()
+    }
+}
+
+/// The parts of `/.well-known/openid-configuration` we use.
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    revocation_endpoint: Option<String>,
+    jwks_uri: String,
+}
+
+pub(super) struct Provider {
+    pub name: String,
+    settings: OAuthProviderSettings,
+    http: reqwest::Client,
+    /// Fetched on first use, so a provider that's down doesn't keep us from starting.
+    discovery: OnceCell<Discovery>,
+    /// Refetched when an ID token is signed with a key that isn't in it.
+    jwks: RwLock<JwkSet>,
+}
+
+/// Who the provider says the user is.
+pub(super) struct ProviderIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+    /// Only then the identity may be linked to an existing account with the same email address.
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+impl Provider {
+    fn new(
+        name: &str,
+        settings: OAuthProviderSettings,
+        http: reqwest::Client,
+    ) -> anyhow::Result<Self> {
+        if settings.issuer.is_none()
+            && (settings.auth_url.is_none()
+                || settings.token_url.is_none()
+                || settings.userinfo_url.is_none())
+        {
+            bail!("Either an issuer, or auth_url, token_url and userinfo_url are required");
+        }
+        // Catches malformed URLs at startup rather than on the first login.
+        RedirectUrl::new(settings.redirect_url.clone()).context("Invalid redirect_url")?;
+        for url in [
+            &settings.auth_url,
+            &settings.token_url,
+            &settings.userinfo_url,
+            &settings.revocation_url,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            reqwest::Url::parse(url).with_context(|| format!("Invalid URL `{url}`"))?;
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            settings,
+            http,
+            discovery: OnceCell::new(),
+            jwks: RwLock::new(JwkSet { keys: Vec::new() }),
+        })
+    }
+
+    pub fn display_name(&self) -> &str {
+        self.settings.display_name.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn scopes(&self) -> &[String] {
+        &self.settings.scopes
+    }
+
+    pub fn is_openid_connect(&self) -> bool {
+        self.settings.issuer.is_some()
+    }
+
+    async fn discovery(&self) -> anyhow::Result<Option<&Discovery>> {
+        let Some(issuer) = &self.settings.issuer else {
+            return Ok(None);
+        };
+        let discovery = self
+            .discovery
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    issuer.trim_end_matches('/')
+                );
+                let discovery: Discovery = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await
+                    .context("Invalid OpenID Connect discovery document")?;
+                if &discovery.issuer != issuer {
+                    bail!(
+                        "The provider's issuer is `{}`, not `{issuer}`",
+                        discovery.issuer
+                    );
+                }
+                Ok::<_, anyhow::Error>(discovery)
+            })
+            .await
+            .with_context(|| format!("Failed to discover OAuth provider `{}`", self.name))?;
+        Ok(Some(discovery))
+    }
+
+    /// Configured endpoints take precedence over discovered ones.
+    pub async fn client(&self) -> anyhow::Result<ProviderClient> {
+        let discovery = self.discovery().await?;
+        let endpoint = |configured: &Option<String>, discovered: Option<&String>| {
+            configured.clone().or_else(|| discovered.cloned())
+        };
+        let auth_url = endpoint(
+            &self.settings.auth_url,
+            discovery.map(|d| &d.authorization_endpoint),
+        )
+        .context("No authorization endpoint")?;
+        let token_url = endpoint(
+            &self.settings.token_url,
+            discovery.map(|d| &d.token_endpoint),
+        )
+        .context("No token endpoint")?;
+        let revocation_url = endpoint(
+            &self.settings.revocation_url,
+            discovery.and_then(|d| d.revocation_endpoint.as_ref()),
+        );
+
+        let mut client = ProviderClient::new(
+            ClientId::new(self.settings.client_id.clone()),
+            Some(ClientSecret::new(
+                self.settings.client_secret.expose_secret().to_owned(),
+            )),
+            AuthUrl::new(auth_url)?,
+            Some(TokenUrl::new(token_url)?),
+        )
+        .set_redirect_uri(RedirectUrl::new(self.settings.redirect_url.clone())?);
+        if let Some(revocation_url) = revocation_url {
+            client = client.set_revocation_uri(RevocationUrl::new(revocation_url)?);
+        }
+        Ok(client)
+    }
+
+    /// Identifies the user the tokens were issued for. OpenID Connect providers have to send an
+    /// ID token with the `nonce` the login started with.
+    pub async fn identity(
+        &self,
+        token: &ProviderTokenResponse,
+        nonce: &str,
+    ) -> Result<ProviderIdentity, ApiError> {
+        let claims = match self.discovery().await? {
+            Some(discovery) => {
+                let id_token = token
+                    .extra_fields()
+                    .id_token
+                    .as_deref()
+                    .ok_or(ApiError::BadRequest)?;
+                let mut claims = self.verify_id_token(discovery, id_token).await?;
+                if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+                    return Err(ApiError::BadRequest);
+                }
+                // Some providers leave the email address out of ID tokens.
+                let userinfo_url = self
+                    .settings
+                    .userinfo_url
+                    .as_ref()
+                    .or(discovery.userinfo_endpoint.as_ref());
+                if let (None, Some(url)) = (claims.get(&self.settings.claims.email), userinfo_url) {
+                    let userinfo = self.userinfo(url, token).await?;
+                    if userinfo.get("sub") == claims.get("sub") {
+                        claims.extend(userinfo);
+                    }
+                }
+                claims
+            }
+            None => {
+                let url = self
+                    .settings
+                    .userinfo_url
+                    .as_ref()
+                    .context("No user info endpoint")?;
+                self.userinfo(url, token).await?
+            }
+        };
+        self.identity_from_claims(&claims).ok_or_else(|| {
+            anyhow::anyhow!("OAuth provider `{}` didn't send a subject", self.name).into()
+        })
+    }
+
+    async fn userinfo(
+        &self,
+        url: &str,
+        token: &ProviderTokenResponse,
+    ) -> Result<Map<String, Value>, ApiError> {
+        let userinfo = self
+            .http
+            .get(url)
+            .bearer_auth(token.access_token().secret())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(userinfo)
+    }
+
+    async fn verify_id_token(
+        &self,
+        discovery: &Discovery,
+        id_token: &str,
+    ) -> Result<Map<String, Value>, ApiError> {
+        let header = jsonwebtoken::decode_header(id_token).map_err(|_| ApiError::BadRequest)?;
+        // Only asymmetric algorithms, the key comes from the provider's JWKS.
+        if !matches!(
+            header.alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+                | Algorithm::ES256
+                | Algorithm::ES384
+                | Algorithm::EdDSA
+        ) {
+            return Err(ApiError::BadRequest);
+        }
+        let key = self
+            .decoding_key(&discovery.jwks_uri, header.kid.as_deref())
+            .await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&discovery.issuer]);
+        validation.set_audience(&[&self.settings.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let token = jsonwebtoken::decode::<Map<String, Value>>(id_token, &key, &validation)
+            .map_err(|e| {
+                tracing::warn!(provider = %self.name, error = %e, "Rejected an ID token");
+                ApiError::BadRequest
+            })?;
+        Ok(token.claims)
+    }
+
+    async fn decoding_key(
+        &self,
+        jwks_uri: &str,
+        key_id: Option<&str>,
+    ) -> Result<DecodingKey, ApiError> {
+        let find = |jwks: &JwkSet| match key_id {
+            Some(key_id) => jwks.find(key_id).cloned(),
+            None => match jwks.keys.as_slice() {
+                [jwk] => Some(jwk.clone()),
+                _ => None,
+            },
+        };
+
+        let cached = find(&*self.jwks.read().await);
+        let jwk = match cached {
+            Some(jwk) => jwk,
+            None => {
+                // The provider may have rotated its keys since we fetched them.
+                let jwks: JwkSet = self
+                    .http
+                    .get(jwks_uri)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                let jwk = find(&jwks);
+                *self.jwks.write().await = jwks;
+                jwk.ok_or(ApiError::BadRequest)?
+            }
+        };
+        let key = DecodingKey::from_jwk(&jwk).context("Invalid JWK")?;
+        Ok(key)
+    }
+
+    fn identity_from_claims(&self, claims: &Map<String, Value>) -> Option<ProviderIdentity> {
+        let names = &self.settings.claims;
+        // Plain OAuth2 providers may use numeric IDs, like GitHub.
+        let string = |name: &str| match claims.get(name)? {
+            Value::String(value) => Some(value.clone()),
+            Value::Number(value) => Some(value.to_string()),
+            _ => None,
+        };
+        Some(ProviderIdentity {
+            subject: string(&names.subject)?,
+            email: string(&names.email),
+            email_verified: names
+                .email_verified
+                .as_deref()
+                .and_then(|name| claims.get(name))
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            name: string(&names.name),
+        })
+    }
+}
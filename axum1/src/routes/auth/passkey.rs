@@ -0,0 +1,609 @@
+//! Passkeys (WebAuthn) as an alternative to passwords, both for signing up and for logging in.
+//!
+//! Every ceremony takes two requests: the first one returns a challenge for
+//! `navigator.credentials.create()` or `.get()`, the second one takes what the browser made of it.
+//! The ceremony state is kept in the session in between, like the PKCE verifier of OAuth logins.
+//! Logins are discoverable, the user picks a passkey without entering their email first.
+//!
+//! Anyone can sign up with any email address, so passkeys only log into confirmed accounts, and
+//! whoever owns the address gets the account without them, unless they confirm it in the session
+//! the passkey was made in.
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, State},
+    Extension, Form, Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json as SqlxJson, Acquire, PgConnection};
+use tower_sessions::Session;
+use validator::Validate;
+use webauthn_rs::prelude::{
+    CreationChallengeResponse, DiscoverableAuthentication, Passkey, PasskeyRegistration,
+    PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Webauthn,
+    WebauthnError,
+};
+
+use super::{
+    create_account, tokens,
+    two_factor::{start_session, LoginStatus},
+    NewAccount,
+};
+use crate::{
+    email::templates::Locale,
+    error::ApiError,
+    extractors::{ClientIp, DatabaseConnection, PreferredLocale, SessionUser},
+    state::AppState,
+    RE_USERNAME,
+};
+
+const SIGNUP: &str = "passkey_signup";
+const REGISTRATION: &str = "passkey_registration";
+const AUTHENTICATION: &str = "passkey_authentication";
+/// The account that was signed up for in the session.
+const SIGNED_UP: &str = "passkey_signed_up";
+
+/// What a passkey is called if its owner didn't name it.
+const DEFAULT_NAME: &str = "Passkey";
+
+fn verification_failed(e: WebauthnError) -> ApiError {
+    tracing::debug!(error = %e, "Passkey verification failed");
+    ApiError::unprocessable_entity([("credential", "passkey verification failed")])
+}
+
+async fn store_passkey(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    name: &str,
+    passkey: &Passkey,
+) -> Result<PasskeyDetails, ApiError> {
+    let details = sqlx::query_as!(
+        PasskeyDetails,
+        r#"
+        INSERT INTO passkeys (user_id, credential_id, name, passkey)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, name, created_at, last_used_at
+        "#,
+        user_id,
+        passkey.cred_id().to_vec(),
+        name,
+        SqlxJson(passkey) as _,
+    )
+    .fetch_one(conn)
+    .await?;
+    Ok(details)
+}
+
+#[derive(Deserialize, Validate)]
+pub(super) struct PasskeySignup {
+    #[validate(
+        length(min = 2, max = 40, message = "must be between 2 and 40 characters"),
+        regex(
+            path = *RE_USERNAME,
+            message = "can only contain letters, digits and . (period); periods cannot appear at start or end position, neither consecutively."
+        )
+    )]
+    name: String,
+    #[validate(email(message = "must be a valid email"))]
+    email: String,
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    passkey_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingSignup {
+    state: PasskeyRegistration,
+    user_id: uuid::Uuid,
+    name: String,
+    email: String,
+    passkey_name: String,
+    locale: Locale,
+}
+
+/// Starts signing up without a password.
+pub(super) async fn signup_start(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    PreferredLocale(locale): PreferredLocale,
+    session: Session,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    Form(form): Form<PasskeySignup>,
+) -> Result<Json<CreationChallengeResponse>, ApiError> {
+    form.validate()
+        .map_err(ApiError::unprocessable_entity_from_validation_errors)?;
+    tokens::check_rate_limits(&state, &form.email, ip).await?;
+
+    // The passkey's user handle is the ID of the account it creates.
+    let user_id = uuid::Uuid::new_v4();
+    let (challenge, registration) = webauthn
+        .start_passkey_registration(user_id, &form.email, &form.name, None)
+        .context("Failed to start the passkey registration")?;
+    session
+        .insert(
+            SIGNUP,
+            PendingSignup {
+                state: registration,
+                user_id,
+                name: form.name,
+                email: form.email,
+                passkey_name: form.passkey_name.unwrap_or_else(|| DEFAULT_NAME.to_owned()),
+                locale,
+            },
+        )
+        .await?;
+    Ok(Json(challenge))
+}
+
+/// Creates the account with the passkey, which has to be confirmed like any other.
+pub(super) async fn signup_finish(
+    State(state): State<AppState>,
+    session: Session,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(credential): Json<RegisterPublicKeyCredential>,
+) -> Result<(), ApiError> {
+    let signup = session
+        .remove::<PendingSignup>(SIGNUP)
+        .await?
+        .ok_or(ApiError::BadRequest)?;
+    let passkey = webauthn
+        .finish_passkey_registration(&credential, &signup.state)
+        .map_err(verification_failed)?;
+
+    let mut tx = conn.begin().await?;
+    let account = NewAccount {
+        user_id: Some(signup.user_id),
+        name: signup.name,
+        email: signup.email,
+        password_hash: None,
+        locale: signup.locale,
+    };
+    let user_id = create_account(&state, &mut tx, account).await?;
+    if let Some(user_id) = user_id {
+        store_passkey(&mut tx, user_id, &signup.passkey_name, &passkey).await?;
+    }
+    tx.commit().await?;
+    if let Some(user_id) = user_id {
+        session.insert(SIGNED_UP, user_id).await?;
+    }
+    Ok(())
+}
+
+/// Whether the account was signed up for in the session, which forgets about it.
+pub(super) async fn signed_up_in(session: &Session, user_id: uuid::Uuid) -> Result<bool, ApiError> {
+    Ok(session.remove::<uuid::Uuid>(SIGNED_UP).await? == Some(user_id))
+}
+
+/// Removes the passkeys of an account that was never confirmed, which whoever signed up with
+/// someone else's address could have made. Returns how many there were.
+pub(super) async fn remove_unconfirmed(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<u64, ApiError> {
+    let removed = sqlx::query!(
+        r#"
+        DELETE FROM passkeys p
+        USING users u
+        WHERE p.user_id = $1 AND u.user_id = p.user_id AND NOT u.confirmed
+        "#,
+        user_id
+    )
+    .execute(conn)
+    .await?
+    .rows_affected();
+    if removed > 0 {
+        tracing::info!(%user_id, "Removed the passkeys of an unconfirmed account");
+    }
+    Ok(removed)
+}
+
+#[derive(Serialize)]
+pub(super) struct PasskeyDetails {
+    id: uuid::Uuid,
+    name: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize, Validate)]
+pub(super) struct PasskeyName {
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingRegistration {
+    state: PasskeyRegistration,
+    name: String,
+}
+
+/// Starts adding a passkey to the account.
+pub(super) async fn register_start(
+    auth_user: SessionUser,
+    session: Session,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(form): Json<PasskeyName>,
+) -> Result<Json<CreationChallengeResponse>, ApiError> {
+    form.validate()
+        .map_err(ApiError::unprocessable_entity_from_validation_errors)?;
+    let user = sqlx::query!(
+        "SELECT name, email FROM users WHERE user_id = $1",
+        *auth_user
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    // Authenticators that hold a passkey of the account already aren't offered again.
+    let existing = sqlx::query_scalar!(
+        "SELECT credential_id FROM passkeys WHERE user_id = $1",
+        *auth_user
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let (challenge, registration) = webauthn
+        .start_passkey_registration(
+            *auth_user,
+            &user.email,
+            &user.name,
+            Some(existing.into_iter().map(Into::into).collect()),
+        )
+        .context("Failed to start the passkey registration")?;
+    session
+        .insert(
+            REGISTRATION,
+            PendingRegistration {
+                state: registration,
+                name: form.name,
+            },
+        )
+        .await?;
+    Ok(Json(challenge))
+}
+
+pub(super) async fn register_finish(
+    auth_user: SessionUser,
+    session: Session,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(credential): Json<RegisterPublicKeyCredential>,
+) -> Result<Json<PasskeyDetails>, ApiError> {
+    let registration = session
+        .remove::<PendingRegistration>(REGISTRATION)
+        .await?
+        .ok_or(ApiError::BadRequest)?;
+    let passkey = webauthn
+        .finish_passkey_registration(&credential, &registration.state)
+        .map_err(verification_failed)?;
+    let details = store_passkey(&mut conn, *auth_user, &registration.name, &passkey).await?;
+    tracing::info!(user_id = %*auth_user, "Added a passkey");
+    Ok(Json(details))
+}
+
+/// Starts logging in with any passkey.
+pub(super) async fn login_start(
+    session: Session,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+) -> Result<Json<RequestChallengeResponse>, ApiError> {
+    let (challenge, authentication) = webauthn
+        .start_discoverable_authentication()
+        .context("Failed to start the passkey authentication")?;
+    session.insert(AUTHENTICATION, authentication).await?;
+    Ok(Json(challenge))
+}
+
+/// Logs in with the passkey the user picked, into a confirmed account. It counts as the second
+/// factor too.
+pub(super) async fn login_finish(
+    session: Session,
+    Extension(webauthn): Extension<Arc<Webauthn>>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(credential): Json<PublicKeyCredential>,
+) -> Result<Json<LoginStatus>, ApiError> {
+    let authentication = session
+        .remove::<DiscoverableAuthentication>(AUTHENTICATION)
+        .await?
+        .ok_or(ApiError::BadRequest)?;
+    let (user_handle, credential_id) = webauthn
+        .identify_discoverable_authentication(&credential)
+        .map_err(verification_failed)?;
+
+    let mut tx = conn.begin().await?;
+    let stored = sqlx::query!(
+        r#"
+        SELECT p.id, p.user_id, p.passkey AS "passkey: SqlxJson<Passkey>", u.confirmed
+        FROM passkeys p
+        JOIN users u USING (user_id)
+        WHERE p.credential_id = $1
+        FOR UPDATE OF p
+        "#,
+        credential_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    // A passkey that was removed from the account, but not from the authenticator.
+    .filter(|stored| stored.user_id == user_handle)
+    .ok_or_else(|| verification_failed(WebauthnError::CredentialNotFound))?;
+    let SqlxJson(mut passkey) = stored.passkey;
+
+    let result = webauthn
+        .finish_discoverable_authentication(&credential, authentication, &[(&passkey).into()])
+        .map_err(verification_failed)?;
+    if !stored.confirmed {
+        return Err(ApiError::unprocessable_entity([(
+            "credential",
+            "the account has to be confirmed first",
+        )]));
+    }
+    // Keeps the signature counter, which gives cloned authenticators away, up to date.
+    passkey.update_credential(&result);
+    sqlx::query!(
+        "UPDATE passkeys SET passkey = $2, last_used_at = NOW() WHERE id = $1",
+        stored.id,
+        SqlxJson(&passkey) as _,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    start_session(&mut conn, &session, stored.user_id, true).await
+}
+
+pub(super) async fn list(
+    auth_user: SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Vec<PasskeyDetails>>, ApiError> {
+    let passkeys = sqlx::query_as!(
+        PasskeyDetails,
+        r#"
+        SELECT id, name, created_at, last_used_at FROM passkeys
+        WHERE user_id = $1
+        ORDER BY created_at
+        "#,
+        *auth_user
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(Json(passkeys))
+}
+
+pub(super) async fn rename(
+    auth_user: SessionUser,
+    Path(passkey_id): Path<uuid::Uuid>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(form): Json<PasskeyName>,
+) -> Result<Json<PasskeyDetails>, ApiError> {
+    form.validate()
+        .map_err(ApiError::unprocessable_entity_from_validation_errors)?;
+    let passkey = sqlx::query_as!(
+        PasskeyDetails,
+        r#"
+        UPDATE passkeys SET name = $3
+        WHERE id = $1 AND user_id = $2
+        RETURNING id, name, created_at, last_used_at
+        "#,
+        passkey_id,
+        *auth_user,
+        form.name,
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+    Ok(Json(passkey))
+}
+
+/// Removes a passkey, unless the account couldn't be logged into without it.
+pub(super) async fn delete(
+    auth_user: SessionUser,
+    Path(passkey_id): Path<uuid::Uuid>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<(), ApiError> {
+    let mut tx = conn.begin().await?;
+    // Locks the user, so concurrent deletions can't remove the last two passkeys.
+    let user = sqlx::query!(
+        r#"
+        SELECT password_hash IS NOT NULL
+            OR EXISTS (SELECT 1 FROM user_identities i WHERE i.user_id = u.user_id)
+            AS "has_other_login!"
+        FROM users u
+        WHERE u.user_id = $1
+        FOR UPDATE
+        "#,
+        *auth_user
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    let deleted = sqlx::query_scalar!(
+        "DELETE FROM passkeys WHERE id = $1 AND user_id = $2 RETURNING id",
+        passkey_id,
+        *auth_user
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if deleted.is_none() {
+        return Err(ApiError::NotFound);
+    }
+
+    let remaining = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM passkeys WHERE user_id = $1"#,
+        *auth_user
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if remaining == 0 && !user.has_other_login {
+        return Err(ApiError::unprocessable_entity([(
+            "passkey",
+            "the only passkey of an account without a password can't be removed",
+        )]));
+    }
+    tx.commit().await?;
+    tracing::info!(user_id = %*auth_user, "Removed a passkey");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        extract::Query,
+        http::{Method, Request, StatusCode},
+    };
+    use sqlx::PgPool;
+    use tower_sessions::{MemoryStore, Session};
+
+    use super::SIGNED_UP;
+    use crate::{
+        extractors::DatabaseConnection,
+        routes::auth::{confirm, tokens},
+        testing::{create_user, TestApp},
+    };
+
+    const TOKEN: &str = "the-token";
+
+    /// A user who signed up with a passkey, but hasn't confirmed their address.
+    async fn signed_up(pool: &PgPool, name: &str) -> uuid::Uuid {
+        let user_id = create_user(pool, name).await;
+        sqlx::query!(
+            "UPDATE users SET confirmed = FALSE WHERE user_id = $1",
+            user_id
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        add_passkey(pool, user_id).await;
+        sqlx::query!(
+            r#"
+            INSERT INTO confirmation_tokens (token_hash, user_id, expires_at)
+            VALUES ($1, $2, NOW() + INTERVAL '1 hour')
+            "#,
+            tokens::hash(TOKEN),
+            user_id,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        user_id
+    }
+
+    /// Stores a passkey as is, it's never verified in these tests.
+    async fn add_passkey(pool: &PgPool, user_id: uuid::Uuid) {
+        sqlx::query!(
+            r#"
+            INSERT INTO passkeys (user_id, credential_id, name, passkey)
+            VALUES ($1, $2, 'Passkey', '{}')
+            "#,
+            user_id,
+            uuid::Uuid::new_v4().as_bytes().to_vec(),
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn passkeys(pool: &PgPool, user_id: uuid::Uuid) -> i64 {
+        sqlx::query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM passkeys WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn reset_emails(pool: &PgPool) -> i64 {
+        sqlx::query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM jobs WHERE job_type = 'password_reset_email'"#
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn confirm_in(pool: &PgPool, session: Session) {
+        let parameters =
+            Query::try_from_uri(&format!("/confirm?token={TOKEN}").parse().unwrap()).unwrap();
+        let conn = DatabaseConnection(pool.acquire().await.unwrap());
+        confirm::confirm(parameters, session, conn).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn confirming_elsewhere_drops_the_passkeys_of_the_signup(pool: PgPool) {
+        let user_id = signed_up(&pool, "ursula").await;
+        let mut app = TestApp::new(pool.clone()).await;
+
+        let (status, _) = app
+            .request(
+                Request::get(format!("/confirm?token={TOKEN}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(passkeys(&pool, user_id).await, 0);
+        assert_eq!(
+            reset_emails(&pool).await,
+            1,
+            "the owner needs a way to log in"
+        );
+    }
+
+    #[sqlx::test]
+    async fn confirming_in_the_signup_session_keeps_the_passkeys(pool: PgPool) {
+        let user_id = signed_up(&pool, "ursula").await;
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+        session.insert(SIGNED_UP, user_id).await.unwrap();
+
+        confirm_in(&pool, session).await;
+        assert_eq!(passkeys(&pool, user_id).await, 1);
+        assert_eq!(reset_emails(&pool).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn confirming_in_another_signup_session_drops_the_passkeys(pool: PgPool) {
+        let user_id = signed_up(&pool, "ursula").await;
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+        session
+            .insert(SIGNED_UP, uuid::Uuid::new_v4())
+            .await
+            .unwrap();
+
+        confirm_in(&pool, session).await;
+        assert_eq!(passkeys(&pool, user_id).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn password_resets_drop_the_passkeys_of_unconfirmed_accounts_only(pool: PgPool) {
+        let unconfirmed = signed_up(&pool, "ursula").await;
+        let confirmed = create_user(&pool, "vera").await;
+        add_passkey(&pool, confirmed).await;
+        let mut app = TestApp::new(pool.clone()).await;
+
+        for (user_id, token) in [(unconfirmed, "reset-ursula"), (confirmed, "reset-vera")] {
+            sqlx::query!(
+                r#"
+                INSERT INTO forget_password_tokens (token_hash, user_id, expires_at)
+                VALUES ($1, $2, NOW() + INTERVAL '1 hour')
+                "#,
+                tokens::hash(token),
+                user_id,
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+            let (status, _) = app
+                .submit(
+                    Method::POST,
+                    &format!("/forget_password?token={token}"),
+                    &[("password", "correct horse battery staple")],
+                )
+                .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert_eq!(passkeys(&pool, unconfirmed).await, 0);
+        assert_eq!(passkeys(&pool, confirmed).await, 1);
+    }
+}
@@ -3,15 +3,41 @@ use argon2::{
     password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher,
     PasswordVerifier, Version,
 };
+use axum::async_trait;
+use once_cell::sync::Lazy;
 use secrecy::{ExposeSecret, SecretString};
+use sqlx::PgConnection;
 
-use crate::{error::ApiError, extractors::DatabaseConnection};
+use crate::{
+    config::PasswordPolicy,
+    email::{
+        messages::{PasswordChangedEmail, PasswordResetEmail},
+        templates::Locale,
+        Email,
+    },
+    error::ApiError,
+    queue::{Job, JobContext},
+};
+
+use super::{tokens, Credentials};
 
-use super::Credentials;
+/// Verified against when there's no user with the given email, so that takes as long as a wrong
+/// password.
+static DUMMY_PASSWORD_HASH: Lazy<String> = Lazy::new(|| {
+    compute_password_hash(SecretString::from(super::tokens::generate()))
+        .expect("hashing a random password never fails")
+        .expose_secret()
+        .to_owned()
+});
 
+/// Checks an email and password, and returns the user's ID if they match.
+///
+/// With `uniform_errors`, a missing user and a wrong password fail with the same error, after the
+/// same Argon2 verification.
 pub async fn validate_credentials(
     credentials: Credentials,
-    DatabaseConnection(mut conn): DatabaseConnection,
+    conn: &mut PgConnection,
+    uniform_errors: bool,
 ) -> Result<uuid::Uuid, ApiError> {
     let row: Option<_> = sqlx::query!(
         r#"
@@ -25,37 +51,300 @@ pub async fn validate_credentials(
     .await
     .context("Failed to perform a query to retrieve stored credentials.")?;
 
-    let (expected_password_hash, user_id) = match row {
-        Some(row) => (row.password_hash, row.user_id),
-        None => {
-            return Err(ApiError::unprocessable_entity([(
-                "email",
-                "this email does not exist",
-            )]))
+    let wrong_credentials = |field, message| {
+        if uniform_errors {
+            ApiError::unprocessable_entity([("credentials", "email or password is wrong")])
+        } else {
+            ApiError::unprocessable_entity([(field, message)])
         }
     };
 
-    crate::utils::spawn_blocking_with_tracing(move || {
-        let expected_password_hash = PasswordHash::new(&expected_password_hash)?;
-        Argon2::default().verify_password(
-            credentials.password.expose_secret().as_bytes(),
-            &expected_password_hash,
-        )
+    let (expected_password_hash, user_id) = match row {
+        // Accounts with only passkeys have no password, every password is wrong for them.
+        Some(row) => (row.password_hash, Some(row.user_id)),
+        None if uniform_errors => (None, None),
+        None => return Err(wrong_credentials("email", "this email does not exist")),
+    };
+
+    let stored_hash = expected_password_hash.clone();
+    let rehashed = crate::utils::spawn_blocking_with_tracing(move || {
+        // The dummy hash is computed on first use, which shouldn't block the runtime either.
+        let expected_password_hash = expected_password_hash
+            .as_deref()
+            .unwrap_or(&DUMMY_PASSWORD_HASH);
+        if !verify_password(expected_password_hash, &credentials.password)? {
+            return Ok(None);
+        }
+        // The password is only at hand now, so that's when a hash made with old parameters can be
+        // replaced.
+        if needs_rehash(expected_password_hash) {
+            compute_password_hash(credentials.password).map(|hash| Some(Some(hash)))
+        } else {
+            Ok(Some(None))
+        }
     })
     .await
-    .context("unexpected error happened during password hashing")?
-    .map_err(|_| ApiError::unprocessable_entity([("password", "password is wrong")]))?;
+    .context("unexpected error happened during password hashing")??
+    .ok_or_else(|| wrong_credentials("password", "password is wrong"))?;
+
+    // Only a random password would match the dummy hash, and nobody knows it.
+    let user_id = user_id.ok_or_else(|| wrong_credentials("email", "this email does not exist"))?;
+
+    if let (Some(new_hash), Some(stored_hash)) = (rehashed, stored_hash) {
+        // Unless the password was changed in the meantime.
+        let rehash = sqlx::query!(
+            "UPDATE users SET password_hash = $1 WHERE user_id = $2 AND password_hash = $3",
+            new_hash.expose_secret(),
+            user_id,
+            stored_hash,
+        )
+        .execute(&mut *conn)
+        .await;
+        if let Err(e) = rehash {
+            tracing::warn!(error = %e, %user_id, "Failed to rehash a password");
+        }
+    }
     Ok(user_id)
 }
 
-pub fn compute_password_hash(password: SecretString) -> Result<SecretString, anyhow::Error> {
-    let salt = SaltString::generate(&mut rand::thread_rng());
-    let password_hash = Argon2::new(
+/// The parameters new passwords are hashed with. Changing them rehashes passwords as their owners
+/// log in.
+fn hasher() -> Argon2<'static> {
+    Argon2::new(
         Algorithm::Argon2id,
         Version::V0x13,
-        Params::new(15000, 2, 1, None).unwrap(),
+        Params::new(15000, 2, 1, None).expect("valid Argon2 parameters"),
     )
-    .hash_password(password.expose_secret().as_bytes(), &salt)?
-    .to_string();
+}
+
+pub fn compute_password_hash(password: SecretString) -> Result<SecretString, anyhow::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = hasher()
+        .hash_password(password.expose_secret().as_bytes(), &salt)?
+        .to_string();
     Ok(SecretString::from(password_hash))
 }
+
+/// Checks `password` against a PHC string hash. Blocks for as long as hashing takes.
+pub fn verify_password(expected_hash: &str, password: &SecretString) -> anyhow::Result<bool> {
+    let expected_hash = PasswordHash::new(expected_hash)?;
+    // The parameters come from the hash, not the hasher.
+    Ok(Argon2::default()
+        .verify_password(password.expose_secret().as_bytes(), &expected_hash)
+        .is_ok())
+}
+
+/// Whether `hash` was made with another algorithm or other parameters than `hasher` uses now.
+fn needs_rehash(hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    let hasher = hasher();
+    let current = hasher.params();
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || Params::try_from(&hash).map_or(true, |params| {
+            (params.m_cost(), params.t_cost(), params.p_cost())
+                != (current.m_cost(), current.t_cost(), current.p_cost())
+        })
+}
+
+/// Refuses passwords that are too short, too long or too easy to guess. `user_inputs` are words
+/// the password shouldn't be based on, like the user's name and email address.
+pub fn check_strength(
+    password: &SecretString,
+    user_inputs: &[&str],
+    policy: &PasswordPolicy,
+) -> Result<(), ApiError> {
+    let password = password.expose_secret();
+    let length = password.chars().count();
+    if length < policy.min_length || length > policy.max_length {
+        return Err(ApiError::unprocessable_entity([(
+            "password",
+            format!(
+                "must be between {} and {} characters",
+                policy.min_length, policy.max_length
+            ),
+        )]));
+    }
+
+    let too_weak = zxcvbn::zxcvbn(password, user_inputs).map_or(Some(None), |entropy| {
+        (entropy.score() < policy.min_score).then(|| {
+            entropy
+                .feedback()
+                .as_ref()
+                .and_then(|feedback| feedback.warning())
+        })
+    });
+    if let Some(warning) = too_weak {
+        let message = match warning {
+            Some(warning) => format!("is too easy to guess: {warning}"),
+            None => "is too easy to guess".to_owned(),
+        };
+        return Err(ApiError::unprocessable_entity([("password", message)]));
+    }
+    Ok(())
+}
+
+/// Emails a password reset link. Like `SendConfirmationEmail`, the token is only made when the job
+/// runs, so it's never stored in plain text.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SendPasswordResetEmail {
+    pub user_id: uuid::Uuid,
+}
+
+#[async_trait]
+impl Job for SendPasswordResetEmail {
+    const JOB_TYPE: &'static str = "password_reset_email";
+    const PRIORITY: i32 = 10;
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        let Some(user) = sqlx::query!(
+            "SELECT email, locale FROM users WHERE user_id = $1",
+            self.user_id
+        )
+        .fetch_optional(&ctx.pool)
+        .await?
+        else {
+            return Ok(());
+        };
+        let token = tokens::generate();
+        sqlx::query!(
+            r#"
+            INSERT INTO forget_password_tokens (token_hash, user_id, expires_at)
+            VALUES ($1, $2, NOW() + make_interval(secs => $3))
+            "#,
+            tokens::hash(&token),
+            self.user_id,
+            ctx.tokens.password_reset_ttl().as_secs_f64(),
+        )
+        .execute(&ctx.pool)
+        .await
+        .context("Failed to store the password reset token")?;
+
+        let email = Email::parse(user.email)?;
+        let message = PasswordResetEmail::new(&ctx.frontend_url, &token)?;
+        ctx.email_client
+            .send_message(email, user.locale.parse().unwrap_or_default(), &message)
+            .await
+    }
+}
+
+/// Lets the owner of an account know its password was changed or reset, in case it wasn't them.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SendPasswordChangedEmail {
+    pub email: String,
+    #[serde(default)]
+    pub locale: Locale,
+}
+
+#[async_trait]
+impl Job for SendPasswordChangedEmail {
+    const JOB_TYPE: &'static str = "password_changed_email";
+    const PRIORITY: i32 = 10;
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        let email = Email::parse(self.email)?;
+        let message = PasswordChangedEmail::new(&ctx.frontend_url)?;
+        ctx.email_client
+            .send_message(email, self.locale, &message)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{password_hash::SaltString, Algorithm, Argon2, Params, PasswordHasher, Version};
+    use secrecy::{ExposeSecret, SecretString};
+    use sqlx::PgPool;
+
+    use super::{
+        check_strength, compute_password_hash, needs_rehash, validate_credentials, verify_password,
+    };
+    use crate::{config::PasswordPolicy, routes::auth::Credentials, testing::create_user};
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    /// A hash like the ones made before the parameters were raised.
+    fn old_hash(password: &str) -> String {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(4096, 1, 1, None).unwrap(),
+        )
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+    }
+
+    async fn stored_hash(pool: &PgPool, user_id: uuid::Uuid) -> String {
+        sqlx::query_scalar!(
+            r#"SELECT password_hash AS "password_hash!" FROM users WHERE user_id = $1"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn log_in(pool: &PgPool, password: &str) -> bool {
+        let credentials = Credentials {
+            email: "ursula@example.com".to_owned(),
+            password: SecretString::from(password.to_owned()),
+            remember_me: false,
+        };
+        let mut conn = pool.acquire().await.unwrap();
+        validate_credentials(credentials, &mut conn, true)
+            .await
+            .is_ok()
+    }
+
+    #[test]
+    fn hashes_with_other_parameters_need_rehashing() {
+        let current = compute_password_hash(SecretString::from(PASSWORD.to_owned())).unwrap();
+        assert!(!needs_rehash(current.expose_secret()));
+        assert!(needs_rehash(&old_hash(PASSWORD)));
+        assert!(needs_rehash("not a PHC string"));
+    }
+
+    #[sqlx::test]
+    async fn logging_in_rehashes_old_hashes(pool: PgPool) {
+        let user_id = create_user(&pool, "ursula").await;
+        let old_hash = old_hash(PASSWORD);
+        sqlx::query!(
+            "UPDATE users SET password_hash = $2 WHERE user_id = $1",
+            user_id,
+            old_hash,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert!(!log_in(&pool, "wrong").await);
+        assert_eq!(stored_hash(&pool, user_id).await, old_hash);
+
+        assert!(log_in(&pool, PASSWORD).await);
+        let new_hash = stored_hash(&pool, user_id).await;
+        assert!(!needs_rehash(&new_hash));
+        assert!(verify_password(&new_hash, &SecretString::from(PASSWORD.to_owned())).unwrap());
+        assert!(log_in(&pool, PASSWORD).await);
+    }
+
+    #[test]
+    fn weak_passwords_are_refused() {
+        let policy = PasswordPolicy::default();
+        let check = |password: &str| {
+            check_strength(
+                &SecretString::from(password.to_owned()),
+                &["ursula", "ursula@example.com"],
+                &policy,
+            )
+        };
+        assert!(check("short").is_err());
+        assert!(check(&"long enough ".repeat(20)).is_err(), "too long");
+        assert!(check("password123").is_err());
+        assert!(check("ursula@example.com1").is_err(), "based on the user");
+        assert!(check(PASSWORD).is_ok());
+    }
+}
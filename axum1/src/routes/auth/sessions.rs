@@ -0,0 +1,533 @@
+//! An index of the sessions each user is logged into, kept in `user_sessions` next to the session
+//! store, so users can see where they're logged in and log out other devices.
+//!
+//! `track` records a session on the first request it's logged in, and refreshes its device, IP
+//! and expiry at most once a minute after that. Revoking deletes both the row and the session.
+//!
+//! It also enforces the session policy: sessions expire after the configured lifetime, however
+//! active they are. Logins can ask to be remembered, those sessions last `remember_me_days` and
+//! get a new ID every `remember_me_rotation_hours`.
+use std::net::IpAddr;
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, Request, State},
+    http::header::USER_AGENT,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgConnection;
+use tower_sessions::{session::Id, Expiry, Session, SessionStore};
+use tower_sessions_redis_store::{fred::prelude::RedisPool, RedisStore};
+
+use crate::{
+    config::SessionSettings,
+    error::ApiError,
+    extractors::{ClientIp, DatabaseConnection, SessionUser},
+    state::AppState,
+};
+
+/// When the session's row was last refreshed, as a Unix timestamp.
+const SEEN_AT: &str = "seen_at";
+/// When the session was logged in, its lifetime counts from then.
+const LOGGED_IN_AT: &str = "logged_in_at";
+/// When a remembered session last got a new ID.
+const ROTATED_AT: &str = "rotated_at";
+const REMEMBER_ME: &str = "remember_me";
+const REFRESH_INTERVAL_SECONDS: i64 = 60;
+
+#[derive(Clone)]
+pub struct SessionIndex {
+    store: RedisStore<RedisPool>,
+}
+
+impl SessionIndex {
+    pub fn new(store: RedisStore<RedisPool>) -> Self {
+        Self { store }
+    }
+
+    /// Revokes every session of `user_id`, except `keep` if given. Returns how many there were.
+    pub async fn revoke_all(
+        &self,
+        conn: &mut PgConnection,
+        user_id: uuid::Uuid,
+        keep: Option<Id>,
+    ) -> Result<usize, ApiError> {
+        let session_ids = sqlx::query_scalar!(
+            r#"
+            DELETE FROM user_sessions
+            WHERE user_id = $1 AND session_id IS DISTINCT FROM $2
+            RETURNING session_id
+            "#,
+            user_id,
+            keep.map(|id| id.to_string()),
+        )
+        .fetch_all(conn)
+        .await?;
+        self.delete(&session_ids).await?;
+        Ok(session_ids.len())
+    }
+
+    async fn delete(&self, session_ids: &[String]) -> anyhow::Result<()> {
+        for session_id in session_ids {
+            let Ok(id) = session_id.parse::<Id>() else {
+                continue;
+            };
+            // Sessions that expired in the meantime are gone already, that's not an error.
+            self.store
+                .delete(&id)
+                .await
+                .context("Failed to delete a revoked session")?;
+        }
+        Ok(())
+    }
+}
+
+/// Forgets the session's row, when it's logged out or into another account.
+pub(super) async fn forget(conn: &mut PgConnection, session: &Session) -> Result<(), ApiError> {
+    if let Some(id) = session.id() {
+        sqlx::query!(
+            "DELETE FROM user_sessions WHERE session_id = $1",
+            id.to_string()
+        )
+        .execute(conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Asks for the session the request logs into to be remembered, see `track`.
+pub(super) async fn remember(session: &Session) -> Result<(), ApiError> {
+    session.insert(REMEMBER_ME, true).await?;
+    Ok(())
+}
+
+/// Keeps the index up to date and enforces the session policy, see the module docs. A session
+/// whose row is gone was revoked while this request was on its way, it's logged out before the
+/// request is handled, like one that outlived its lifetime.
+pub async fn track(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    session: Session,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let settings = state.config.borrow().session.clone();
+    let user_agent = request
+        .headers()
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.chars().take(300).collect::<String>());
+
+    if session.get::<uuid::Uuid>("user_id").await?.is_some() {
+        let now = Utc::now().timestamp();
+        let remembered = apply_expiry(&session, &settings).await?;
+        let logged_in_at = session.get::<i64>(LOGGED_IN_AT).await?;
+        let seen_at = session.get::<i64>(SEEN_AT).await?;
+        let rotated_at = session.get::<i64>(ROTATED_AT).await?;
+
+        let expired = logged_in_at
+            .is_some_and(|at| now - at >= settings.lifetime(remembered).whole_seconds());
+        let stale = seen_at.is_some_and(|at| now - at >= REFRESH_INTERVAL_SECONDS);
+        let rotation_due = remembered
+            && rotated_at
+                .is_some_and(|at| now - at >= settings.remember_me_rotation().whole_seconds());
+
+        if expired || stale || rotation_due {
+            let mut conn = state.db_pool.acquire().await?;
+            if expired {
+                forget(&mut conn, &session).await?;
+                session.flush().await?;
+            } else if stale && !refresh(&mut conn, &session, user_agent.as_deref(), ip).await? {
+                session.flush().await?;
+            } else if rotation_due {
+                rotate(&mut conn, &session).await?;
+            }
+        }
+    }
+
+    let response = next.run(request).await;
+
+    // Logged in by this request.
+    if let Some(user_id) = session.get::<uuid::Uuid>("user_id").await? {
+        if session.get::<i64>(SEEN_AT).await?.is_none() {
+            apply_expiry(&session, &settings).await?;
+            let mut conn = state.db_pool.acquire().await?;
+            record(&mut conn, &session, user_id, user_agent.as_deref(), ip).await?;
+        }
+    }
+    Ok(response)
+}
+
+/// Remembered sessions last longer than the session layer's default. It has to be set on every
+/// request, the expiry isn't stored with the session. Returns whether the session is remembered.
+async fn apply_expiry(session: &Session, settings: &SessionSettings) -> Result<bool, ApiError> {
+    let remembered = session.get::<bool>(REMEMBER_ME).await?.unwrap_or(false);
+    if remembered {
+        session.set_expiry(Some(Expiry::OnInactivity(settings.remember_me())));
+    }
+    Ok(remembered)
+}
+
+/// Gives the session a new ID, moving its row along.
+async fn rotate(conn: &mut PgConnection, session: &Session) -> Result<(), ApiError> {
+    let old_id = session.id().context("A stored session has no ID")?;
+    session.cycle_id().await?;
+    session.insert(ROTATED_AT, Utc::now().timestamp()).await?;
+    session.save().await?;
+    let new_id = session.id().context("A saved session has no ID")?;
+    sqlx::query!(
+        "UPDATE user_sessions SET session_id = $2 WHERE session_id = $1",
+        old_id.to_string(),
+        new_id.to_string(),
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+fn expires_at(session: &Session) -> DateTime<Utc> {
+    DateTime::from_timestamp(session.expiry_date().unix_timestamp(), 0).unwrap_or_default()
+}
+
+async fn record(
+    conn: &mut PgConnection,
+    session: &Session,
+    user_id: uuid::Uuid,
+    user_agent: Option<&str>,
+    ip: IpAddr,
+) -> Result<(), ApiError> {
+    let now = Utc::now().timestamp();
+    session.insert(SEEN_AT, now).await?;
+    session.insert(LOGGED_IN_AT, now).await?;
+    session.insert(ROTATED_AT, now).await?;
+    // New sessions only get their ID once they're saved.
+    session.save().await?;
+    let id = session.id().context("A saved session has no ID")?;
+    sqlx::query!(
+        r#"
+        INSERT INTO user_sessions (session_id, user_id, user_agent, ip, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (session_id) DO UPDATE
+        SET user_id = $2, user_agent = $3, ip = $4, last_seen_at = NOW(), expires_at = $5
+        "#,
+        id.to_string(),
+        user_id,
+        user_agent,
+        ip.to_string(),
+        expires_at(session),
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Returns false if the session has no row anymore.
+async fn refresh(
+    conn: &mut PgConnection,
+    session: &Session,
+    user_agent: Option<&str>,
+    ip: IpAddr,
+) -> Result<bool, ApiError> {
+    let Some(id) = session.id() else {
+        return Ok(false);
+    };
+    let refreshed = sqlx::query!(
+        r#"
+        UPDATE user_sessions
+        SET user_agent = $2, ip = $3, last_seen_at = NOW(), expires_at = $4
+        WHERE session_id = $1
+        "#,
+        id.to_string(),
+        user_agent,
+        ip.to_string(),
+        expires_at(session),
+    )
+    .execute(conn)
+    .await?;
+    if refreshed.rows_affected() == 0 {
+        return Ok(false);
+    }
+    session.insert(SEEN_AT, Utc::now().timestamp()).await?;
+    Ok(true)
+}
+
+#[derive(Serialize)]
+pub(super) struct SessionDetails {
+    id: uuid::Uuid,
+    user_agent: Option<String>,
+    ip: String,
+    last_seen_at: DateTime<Utc>,
+    created_at: DateTime<Utc>,
+    /// The session this request was made with.
+    current: bool,
+}
+
+pub(super) async fn list(
+    SessionUser(user_id): SessionUser,
+    session: Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Vec<SessionDetails>>, ApiError> {
+    let current = session.id().map(|id| id.to_string());
+    let sessions = sqlx::query_as!(
+        SessionDetails,
+        r#"
+        SELECT id, user_agent, ip, last_seen_at, created_at,
+            session_id IS NOT DISTINCT FROM $2 AS "current!"
+        FROM user_sessions
+        WHERE user_id = $1 AND expires_at > NOW()
+        ORDER BY last_seen_at DESC
+        "#,
+        user_id,
+        current,
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(Json(sessions))
+}
+
+/// Logs a session out. Revoking the current one is the same as logging out.
+pub(super) async fn revoke(
+    State(state): State<AppState>,
+    SessionUser(user_id): SessionUser,
+    session: Session,
+    Path(id): Path<uuid::Uuid>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<(), ApiError> {
+    let session_id = sqlx::query_scalar!(
+        "DELETE FROM user_sessions WHERE id = $1 AND user_id = $2 RETURNING session_id",
+        id,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    if session.id().map(|id| id.to_string()).as_ref() == Some(&session_id) {
+        session.flush().await?;
+    } else {
+        state.sessions.delete(&[session_id]).await?;
+    }
+    tracing::info!(%user_id, session = %id, "Revoked a session");
+    Ok(())
+}
+
+/// Logs out every session but the current one.
+pub(super) async fn revoke_others(
+    State(state): State<AppState>,
+    SessionUser(user_id): SessionUser,
+    session: Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<(), ApiError> {
+    let revoked = state
+        .sessions
+        .revoke_all(&mut conn, user_id, session.id())
+        .await?;
+    tracing::info!(%user_id, revoked, "Revoked all other sessions");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use chrono::{Duration, Utc};
+    use sqlx::PgPool;
+
+    use crate::testing::{create_user_with_password, TestApp};
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    async fn log_in(app: &mut TestApp, name: &str) {
+        let (status, _) = app.log_in(&format!("{name}@example.com"), PASSWORD).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    async fn sessions(app: &mut TestApp) -> (StatusCode, serde_json::Value) {
+        app.submit(Method::GET, "/auth/sessions", &[]).await
+    }
+
+    /// The ID of the first session of the list that isn't the current one.
+    async fn other_session(app: &mut TestApp) -> String {
+        let (_, list) = sessions(app).await;
+        list.as_array()
+            .unwrap()
+            .iter()
+            .find(|session| session["current"] == false)
+            .unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[sqlx::test]
+    async fn sessions_are_listed_with_the_current_one_marked(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut laptop = TestApp::new(pool).await;
+        let mut phone = laptop.another_browser();
+        log_in(&mut laptop, "ursula").await;
+        log_in(&mut phone, "ursula").await;
+
+        let (status, list) = sessions(&mut laptop).await;
+        assert_eq!(status, StatusCode::OK);
+        let current: Vec<_> = list
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|session| session["current"].as_bool().unwrap())
+            .collect();
+        assert_eq!(current.len(), 2);
+        assert_eq!(current.iter().filter(|current| **current).count(), 1);
+    }
+
+    #[sqlx::test]
+    async fn revoking_a_session_logs_it_out(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut laptop = TestApp::new(pool).await;
+        let mut phone = laptop.another_browser();
+        log_in(&mut laptop, "ursula").await;
+        log_in(&mut phone, "ursula").await;
+
+        let phone_session = other_session(&mut laptop).await;
+        let (status, _) = laptop
+            .submit(
+                Method::DELETE,
+                &format!("/auth/sessions/{phone_session}"),
+                &[],
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        assert_eq!(sessions(&mut phone).await.0, StatusCode::UNAUTHORIZED);
+        let (status, list) = sessions(&mut laptop).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(list.as_array().unwrap().len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn revoking_the_current_session_logs_out(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::new(pool).await;
+        log_in(&mut app, "ursula").await;
+
+        let (_, list) = sessions(&mut app).await;
+        let id = list[0]["id"].as_str().unwrap().to_owned();
+        let (status, _) = app
+            .submit(Method::DELETE, &format!("/auth/sessions/{id}"), &[])
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(sessions(&mut app).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[sqlx::test]
+    async fn sessions_of_other_users_cannot_be_revoked(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        create_user_with_password(&pool, "vera", PASSWORD).await;
+        let mut ursula = TestApp::new(pool).await;
+        let mut vera = ursula.another_browser();
+        log_in(&mut ursula, "ursula").await;
+        log_in(&mut vera, "vera").await;
+
+        let (_, list) = sessions(&mut vera).await;
+        let id = list[0]["id"].as_str().unwrap().to_owned();
+        let (status, _) = ursula
+            .submit(Method::DELETE, &format!("/auth/sessions/{id}"), &[])
+            .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(sessions(&mut vera).await.0, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn revoking_the_others_keeps_the_current_session(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut laptop = TestApp::new(pool).await;
+        let mut phone = laptop.another_browser();
+        let mut tablet = laptop.another_browser();
+        for app in [&mut laptop, &mut phone, &mut tablet] {
+            log_in(app, "ursula").await;
+        }
+
+        let (status, _) = laptop.submit(Method::DELETE, "/auth/sessions", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        assert_eq!(sessions(&mut phone).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(sessions(&mut tablet).await.0, StatusCode::UNAUTHORIZED);
+        let (status, list) = sessions(&mut laptop).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(list.as_array().unwrap().len(), 1);
+    }
+
+    async fn log_in_remembered(app: &mut TestApp) {
+        let (status, _) = app
+            .submit(
+                Method::POST,
+                "/auth",
+                &[
+                    ("email", "ursula@example.com"),
+                    ("password", PASSWORD),
+                    ("remember_me", "true"),
+                ],
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    async fn expires_in(pool: &PgPool) -> Duration {
+        let expires_at = sqlx::query_scalar!("SELECT expires_at FROM user_sessions")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        expires_at - Utc::now()
+    }
+
+    #[sqlx::test]
+    async fn remembered_sessions_last_longer(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::new(pool.clone()).await;
+
+        log_in(&mut app, "ursula").await;
+        assert!(expires_in(&pool).await <= Duration::minutes(10));
+        app.submit(Method::GET, "/logout", &[]).await;
+
+        log_in_remembered(&mut app).await;
+        assert!(expires_in(&pool).await > Duration::days(29));
+    }
+
+    #[sqlx::test]
+    async fn sessions_end_after_their_lifetime_however_active(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::with_settings(pool.clone(), |settings| {
+            settings.session.absolute_lifetime_hours = 0
+        })
+        .await;
+        log_in(&mut app, "ursula").await;
+
+        assert_eq!(sessions(&mut app).await.0, StatusCode::UNAUTHORIZED);
+        let rows = sqlx::query_scalar!(r#"SELECT count(*) AS "count!" FROM user_sessions"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows, 0);
+    }
+
+    #[sqlx::test]
+    async fn remembered_sessions_get_new_ids(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::with_settings(pool, |settings| {
+            settings.session.remember_me_rotation_hours = 0
+        })
+        .await;
+        log_in_remembered(&mut app).await;
+        let mut stolen = app.clone();
+
+        let (status, list) = sessions(&mut app).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(list.as_array().unwrap().len(), 1);
+        assert_eq!(list[0]["current"], true, "the row moves along with the ID");
+
+        assert_eq!(sessions(&mut stolen).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(sessions(&mut app).await.0, StatusCode::OK);
+    }
+}
@@ -0,0 +1,334 @@
+//! Throttles failed logins per account and per IP, in Redis so it holds across replicas.
+//!
+//! After a few free attempts, every failure makes the next attempt wait, twice as long each time.
+//! Enough failures within the window lock logins out for a while. Accounts are keyed by the email
+//! address that was tried, whether or not there's an account with it, so the throttle doesn't
+//! reveal which addresses have one either.
+//!
+//! Anyone can fail logins into any account, so an account that has to wait only holds back wrong
+//! passwords. The right one still logs in from an IP that doesn't have to wait, otherwise keeping
+//! the owner out would be as easy as failing on purpose. A locked out account doesn't check any
+//! password until the lockout runs out or an admin lifts it; the owner gets an email about it.
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::Context;
+use tower_sessions_redis_store::fred::{prelude::*, types::Expiration};
+
+use crate::{
+    config::{LoginThrottleSettings, ThrottleLimits},
+    error::ApiError,
+};
+
+#[derive(Clone, Copy)]
+enum Subject {
+    Account,
+    Ip,
+}
+
+impl Subject {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Account => "account",
+            Self::Ip => "ip",
+        }
+    }
+}
+
+/// The keys of a subject's state.
+struct Keys {
+    failures: String,
+    delay: String,
+    lockout: String,
+}
+
+impl Keys {
+    fn new(subject: Subject, id: &str) -> Self {
+        let key = |kind: &str| format!("login_{kind}:{}:{id}", subject.as_str());
+        Self {
+            failures: key("failures"),
+            delay: key("delay"),
+            lockout: key("lockout"),
+        }
+    }
+
+    fn account(email: &str) -> Self {
+        let id = blake3::hash(email.to_lowercase().as_bytes()).to_hex();
+        Self::new(Subject::Account, &id)
+    }
+
+    fn ip(ip: IpAddr) -> Self {
+        Self::new(Subject::Ip, &ip.to_string())
+    }
+}
+
+#[derive(Clone)]
+pub struct LoginThrottle {
+    redis: RedisPool,
+}
+
+impl LoginThrottle {
+    pub fn new(redis: RedisPool) -> Self {
+        Self { redis }
+    }
+
+    /// Fails with `429 Too Many Requests` while the IP has to wait, or the account is locked out.
+    /// Logins into a locked account aren't checked at all, but still count against the IP.
+    ///
+    /// Otherwise returns the error to fail a wrong password with while the account has to wait,
+    /// instead of telling that the password is wrong.
+    pub async fn check(
+        &self,
+        email: &str,
+        ip: IpAddr,
+        settings: &LoginThrottleSettings,
+    ) -> Result<Option<ApiError>, ApiError> {
+        let wait = self.wait(&Keys::ip(ip)).await?;
+        if !wait.is_zero() {
+            return Err(ApiError::TooManyRequests { retry_after: wait });
+        }
+        let account = Keys::account(email);
+        let locked_for = self.ttl(&account.lockout).await?;
+        if !locked_for.is_zero() {
+            self.record_ip_failure(ip, settings).await?;
+            return Err(ApiError::TooManyRequests {
+                retry_after: locked_for,
+            });
+        }
+        let wait = self.ttl(&account.delay).await?;
+        Ok((!wait.is_zero()).then_some(ApiError::TooManyRequests { retry_after: wait }))
+    }
+
+    async fn wait(&self, keys: &Keys) -> anyhow::Result<Duration> {
+        Ok(self
+            .ttl(&keys.lockout)
+            .await?
+            .max(self.ttl(&keys.delay).await?))
+    }
+
+    async fn ttl(&self, key: &str) -> anyhow::Result<Duration> {
+        let ttl: i64 = self
+            .redis
+            .ttl(key)
+            .await
+            .context("Failed to check the login throttle")?;
+        Ok(Duration::from_secs(ttl.max(0) as u64))
+    }
+
+    /// Counts a failed login. Returns how long the account is locked out for, if this failure
+    /// locked it.
+    ///
+    /// Failures while the account has to wait only count against the IP, so they neither lock it
+    /// out again nor send its owner another email.
+    pub async fn record_failure(
+        &self,
+        email: &str,
+        ip: IpAddr,
+        settings: &LoginThrottleSettings,
+    ) -> anyhow::Result<Option<Duration>> {
+        let account = Keys::account(email);
+        let account_locked = if self.wait(&account).await?.is_zero() {
+            self.record_subject_failure(&account, settings.per_account, settings)
+                .await?
+        } else {
+            false
+        };
+        self.record_ip_failure(ip, settings).await?;
+        Ok(account_locked.then(|| Duration::from_secs(settings.lockout_seconds)))
+    }
+
+    async fn record_ip_failure(
+        &self,
+        ip: IpAddr,
+        settings: &LoginThrottleSettings,
+    ) -> anyhow::Result<()> {
+        let locked = self
+            .record_subject_failure(&Keys::ip(ip), settings.per_ip, settings)
+            .await?;
+        if locked {
+            tracing::warn!(%ip, "Locked out an IP after too many failed logins");
+        }
+        Ok(())
+    }
+
+    async fn record_subject_failure(
+        &self,
+        keys: &Keys,
+        limits: ThrottleLimits,
+        settings: &LoginThrottleSettings,
+    ) -> anyhow::Result<bool> {
+        let failures: u64 = self.redis.incr(keys.failures.as_str()).await?;
+        if failures == 1 {
+            self.redis
+                .expire::<(), _>(
+                    keys.failures.as_str(),
+                    settings.failure_window_seconds as i64,
+                )
+                .await?;
+        }
+
+        if failures >= limits.lockout_after {
+            self.set_expiring(&keys.lockout, settings.lockout_seconds)
+                .await?;
+            // Start over once the lockout is over.
+            self.redis
+                .del::<(), _>(vec![keys.failures.clone(), keys.delay.clone()])
+                .await?;
+            return Ok(true);
+        }
+        let delay = settings.delay(limits, failures);
+        if !delay.is_zero() {
+            self.set_expiring(&keys.delay, delay.as_secs()).await?;
+        }
+        Ok(false)
+    }
+
+    async fn set_expiring(&self, key: &str, seconds: u64) -> anyhow::Result<()> {
+        self.redis
+            .set::<(), _, _>(key, 1, Some(Expiration::EX(seconds as i64)), None, false)
+            .await?;
+        Ok(())
+    }
+
+    /// Forgets the failures of an account after a successful login. Failures of the IP are kept,
+    /// so an attacker can't reset them by logging into an account of their own.
+    pub async fn record_success(&self, email: &str) -> anyhow::Result<()> {
+        let keys = Keys::account(email);
+        self.redis
+            .del::<(), _>(vec![keys.failures, keys.delay])
+            .await?;
+        Ok(())
+    }
+
+    /// Lifts the lockout of an account, and forgets its failures.
+    pub async fn unlock(&self, email: &str) -> anyhow::Result<()> {
+        let keys = Keys::account(email);
+        self.redis
+            .del::<(), _>(vec![keys.failures, keys.delay, keys.lockout])
+            .await
+            .context("Failed to unlock the account")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use axum::http::StatusCode;
+    use sqlx::PgPool;
+
+    use crate::{
+        config::{Settings, ThrottleLimits},
+        testing::{create_user_with_password, TestApp},
+    };
+
+    const PASSWORD: &str = "correct horse battery staple";
+    const EMAIL: &str = "ursula@example.com";
+
+    /// Locks accounts out after three failures, without delays in the way.
+    fn lock_out_quickly(settings: &mut Settings) {
+        let throttle = &mut settings.auth.login_throttle;
+        throttle.per_account = ThrottleLimits {
+            free_attempts: 10,
+            lockout_after: 3,
+        };
+        throttle.per_ip = ThrottleLimits {
+            free_attempts: 10,
+            lockout_after: 100,
+        };
+    }
+
+    async fn fail_logins(app: &mut TestApp, email: &str, times: usize) {
+        for _ in 0..times {
+            let (status, _) = app.log_in(email, "wrong").await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+
+    async fn locked_emails(pool: &PgPool) -> i64 {
+        sqlx::query_scalar!(
+            r#"SELECT count(*) AS "count!" FROM jobs WHERE job_type = 'account_locked_email'"#
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn locked_out_accounts_reject_the_right_password_too(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::with_settings(pool.clone(), lock_out_quickly).await;
+
+        fail_logins(&mut app, EMAIL, 3).await;
+        assert_eq!(locked_emails(&pool).await, 1);
+
+        let (status, _) = app.log_in(EMAIL, PASSWORD).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = app.log_in(EMAIL, "wrong").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(locked_emails(&pool).await, 1, "the owner is only told once");
+    }
+
+    #[sqlx::test]
+    async fn unlocking_lets_the_owner_back_in(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::with_settings(pool, lock_out_quickly).await;
+        fail_logins(&mut app, EMAIL, 3).await;
+
+        app.state.login_throttle.unlock(EMAIL).await.unwrap();
+        let (status, _) = app.log_in(EMAIL, PASSWORD).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn lockouts_run_out(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::with_settings(pool, |settings| {
+            lock_out_quickly(settings);
+            settings.auth.login_throttle.lockout_seconds = 1;
+        })
+        .await;
+        fail_logins(&mut app, EMAIL, 3).await;
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let (status, _) = app.log_in(EMAIL, PASSWORD).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn logins_into_locked_out_accounts_count_against_the_ip(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        create_user_with_password(&pool, "vera", PASSWORD).await;
+        let mut app = TestApp::with_settings(pool, |settings| {
+            lock_out_quickly(settings);
+            settings.auth.login_throttle.per_ip.lockout_after = 5;
+        })
+        .await;
+        fail_logins(&mut app, EMAIL, 3).await;
+        for _ in 0..2 {
+            let (status, _) = app.log_in(EMAIL, "wrong").await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        let (status, _) = app.log_in("vera@example.com", PASSWORD).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        app.ip = Ipv4Addr::new(192, 0, 2, 1).into();
+        let (status, _) = app.log_in("vera@example.com", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn delays_only_hold_back_wrong_passwords(pool: PgPool) {
+        create_user_with_password(&pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::with_settings(pool, |settings| {
+            settings.auth.login_throttle.per_account.free_attempts = 1;
+        })
+        .await;
+        fail_logins(&mut app, EMAIL, 2).await;
+
+        let (status, _) = app.log_in(EMAIL, "wrong").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = app.log_in(EMAIL, PASSWORD).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
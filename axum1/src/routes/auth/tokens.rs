@@ -0,0 +1,67 @@
+//! Confirmation and password reset tokens are sent by email, and only their hashes are stored.
+//! They're long random strings rather than passwords, so a fast unsalted hash is enough.
+use std::net::IpAddr;
+
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+use crate::{error::ApiError, state::AppState};
+
+pub fn generate() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect()
+}
+
+pub fn hash(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+/// Whether `token` hashes to `expected`, compared in constant time. Use this rather than comparing
+/// `hash`es where the hash isn't looked up in the database.
+pub fn verify(token: &str, expected: &str) -> bool {
+    // `blake3::Hash` equality is constant time, unlike comparing the hex strings.
+    blake3::Hash::from_hex(expected)
+        .is_ok_and(|expected| blake3::hash(token.as_bytes()) == expected)
+}
+
+/// Keeps token requests from flooding someone's inbox, or from being used to probe for accounts.
+/// Counts requests whether or not there's an account with `email`.
+pub async fn check_rate_limits(state: &AppState, email: &str, ip: IpAddr) -> Result<(), ApiError> {
+    let (per_email, per_ip) = {
+        let config = state.config.borrow();
+        (config.tokens.per_email_limit, config.tokens.per_ip_limit)
+    };
+    state
+        .rate_limiter
+        .check("token_ip", &ip.to_string(), per_ip)
+        .await?;
+    state
+        .rate_limiter
+        .check("token_email", &email.to_lowercase(), per_email)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_are_hex_encoded_blake3() {
+        let hashed = hash("token");
+        assert_eq!(hashed.len(), 64);
+        assert!(hashed.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hashed, hash("token"));
+        assert_ne!(hashed, hash("other token"));
+    }
+
+    #[test]
+    fn verifies_tokens_against_their_hash() {
+        let token = generate();
+        let hashed = hash(&token);
+        assert!(verify(&token, &hashed));
+        assert!(!verify(&generate(), &hashed));
+        assert!(!verify(&token, "not a hash"));
+    }
+}
@@ -0,0 +1,583 @@
+//! Two-factor authentication with TOTP (RFC 6238) and one-time recovery codes.
+//!
+//! Logging into an account with a confirmed TOTP secret only leaves the session pending: it gets a
+//! `pending_user_id` rather than a `user_id`, so `AuthUser` rejects it until `POST /auth/2fa`
+//! accepts a code. Sessions that passed a second factor are marked with `second_factor`, which
+//! `AdminUser` requires, so admins have to enroll.
+//!
+//! Secrets are encrypted at rest with a key from the configuration, see `TotpSecrets`.
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use axum::{extract::State, Json};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, PgConnection, PgPool};
+use totp_rs::{Algorithm, Secret, TOTP};
+use tower_sessions::Session;
+
+use super::tokens;
+use crate::{
+    error::ApiError,
+    extractors::{DatabaseConnection, SessionUser},
+    state::AppState,
+};
+
+const PENDING_USER_ID: &str = "pending_user_id";
+/// Set on sessions that passed a second factor.
+pub const SECOND_FACTOR: &str = "second_factor";
+
+const STEP_SECONDS: u64 = 30;
+const RECOVERY_CODES: usize = 10;
+
+const KEY_CONTEXT: &str = "recipes 2025-04-22 totp secrets";
+
+/// Encrypts TOTP secrets with XChaCha20-Poly1305. The user's ID is authenticated along with the
+/// secret, so a ciphertext copied into another user's row doesn't decrypt.
+#[derive(Clone)]
+pub struct TotpSecrets {
+    cipher: Arc<XChaCha20Poly1305>,
+}
+
+/// A secret as it's stored in `user_totp`. Rows from before secrets were encrypted only have the
+/// plaintext, until `encrypt_plaintext_secrets` gets to them.
+struct StoredSecret {
+    secret: Option<String>,
+    secret_ciphertext: Option<Vec<u8>>,
+    secret_nonce: Option<Vec<u8>>,
+}
+
+impl TotpSecrets {
+    pub fn new(key: &SecretString) -> Self {
+        let key = blake3::derive_key(KEY_CONTEXT, key.expose_secret().as_bytes());
+        Self {
+            cipher: Arc::new(XChaCha20Poly1305::new(&key.into())),
+        }
+    }
+
+    /// Returns the ciphertext and the nonce.
+    fn encrypt(&self, user_id: uuid::Uuid, secret: &str) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: secret.as_bytes(),
+            aad: user_id.as_bytes(),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt a TOTP secret"))?;
+        Ok((ciphertext, nonce.to_vec()))
+    }
+
+    fn decrypt(&self, user_id: uuid::Uuid, stored: StoredSecret) -> anyhow::Result<String> {
+        let (ciphertext, nonce) = match stored {
+            StoredSecret {
+                secret_ciphertext: Some(ciphertext),
+                secret_nonce: Some(nonce),
+                ..
+            } => (ciphertext, nonce),
+            StoredSecret {
+                secret: Some(secret),
+                ..
+            } => return Ok(secret),
+            _ => anyhow::bail!("The TOTP secret of {user_id} is missing"),
+        };
+        anyhow::ensure!(
+            nonce.len() == 24,
+            "The TOTP secret of {user_id} has an invalid nonce"
+        );
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: user_id.as_bytes(),
+        };
+        let secret = self
+            .cipher
+            .decrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt the TOTP secret of {user_id}"))?;
+        String::from_utf8(secret).context("Decrypted TOTP secret isn't UTF-8")
+    }
+}
+
+/// Encrypts the secrets that were stored before secrets were encrypted at rest.
+pub async fn encrypt_plaintext_secrets(pool: &PgPool, secrets: &TotpSecrets) -> anyhow::Result<()> {
+    let plaintext = sqlx::query!(
+        r#"SELECT user_id, secret AS "secret!" FROM user_totp WHERE secret IS NOT NULL"#
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in &plaintext {
+        let (ciphertext, nonce) = secrets.encrypt(row.user_id, &row.secret)?;
+        // Unless the user enrolled again in the meantime.
+        sqlx::query!(
+            r#"
+            UPDATE user_totp SET secret = NULL, secret_ciphertext = $3, secret_nonce = $4
+            WHERE user_id = $1 AND secret = $2
+            "#,
+            row.user_id,
+            row.secret,
+            ciphertext,
+            nonce,
+        )
+        .execute(pool)
+        .await?;
+    }
+    if !plaintext.is_empty() {
+        tracing::info!("Encrypted {} plaintext TOTP secrets", plaintext.len());
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct LoginStatus {
+    /// The session is pending until `POST /auth/2fa` accepts a code.
+    second_factor_required: bool,
+    /// Admins can't use the admin API until they enroll, see `POST /auth/2fa/enroll`.
+    second_factor_enrollment_required: bool,
+}
+
+/// Logs `user_id` into the session, or leaves it pending if the user has a second factor that the
+/// login didn't check. Passkeys verify the user on the device, so they pass as both factors.
+pub(super) async fn start_session(
+    conn: &mut PgConnection,
+    session: &Session,
+    user_id: uuid::Uuid,
+    second_factor: bool,
+) -> Result<Json<LoginStatus>, ApiError> {
+    let user = sqlx::query!(
+        r#"
+        SELECT u.is_admin, t.confirmed_at IS NOT NULL AS "totp_enabled!"
+        FROM users u
+        LEFT JOIN user_totp t ON t.user_id = u.user_id
+        WHERE u.user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let pending = user.totp_enabled && !second_factor;
+
+    // Whatever the session was logged into before is gone, even if this login stays pending.
+    super::sessions::forget(conn, session).await?;
+    session.clear().await;
+    // Rotate the session cookie on privilege level change.
+    // This is to prevent session-fixation attacks.
+    session.cycle_id().await?;
+    if pending {
+        session.insert(PENDING_USER_ID, user_id).await?;
+    } else {
+        session.insert("user_id", user_id).await?;
+    }
+    if second_factor {
+        session.insert(SECOND_FACTOR, true).await?;
+    }
+
+    Ok(Json(LoginStatus {
+        second_factor_required: pending,
+        second_factor_enrollment_required: user.is_admin && !user.totp_enabled && !second_factor,
+    }))
+}
+
+fn totp(secret: &str, issuer: Option<String>, account_name: String) -> anyhow::Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_owned())
+        .to_bytes()
+        .map_err(|e| anyhow::anyhow!("Invalid TOTP secret: {e:?}"))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP_SECONDS,
+        secret,
+        issuer,
+        account_name,
+    )
+    .context("Invalid TOTP parameters")
+}
+
+/// Returns the time step `code` belongs to, allowing for a step of clock drift either way. Steps
+/// up to `last_used_step` were used already, so their codes are rejected.
+fn verify_code(secret: &str, code: &str, last_used_step: i64) -> anyhow::Result<Option<i64>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / STEP_SECONDS;
+    verify_code_at(secret, code, last_used_step, now)
+}
+
+fn verify_code_at(
+    secret: &str,
+    code: &str,
+    last_used_step: i64,
+    now: u64,
+) -> anyhow::Result<Option<i64>> {
+    let totp = totp(secret, None, String::new())?;
+    let code = code.trim();
+    Ok((now.saturating_sub(1)..=now + 1)
+        .filter(|&step| step as i64 > last_used_step)
+        .find(|&step| totp.check(code, step * STEP_SECONDS))
+        .map(|step| step as i64))
+}
+
+fn invalid_code() -> ApiError {
+    ApiError::unprocessable_entity([("code", "invalid code")])
+}
+
+/// Checks a TOTP code of a user with a confirmed secret, and uses it up.
+async fn use_code(
+    conn: &mut PgConnection,
+    secrets: &TotpSecrets,
+    user_id: uuid::Uuid,
+    code: &str,
+) -> Result<(), ApiError> {
+    let totp = sqlx::query!(
+        r#"
+        SELECT secret, secret_ciphertext, secret_nonce, last_used_step FROM user_totp
+        WHERE user_id = $1 AND confirmed_at IS NOT NULL
+        FOR UPDATE
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+    let secret = secrets.decrypt(
+        user_id,
+        StoredSecret {
+            secret: totp.secret,
+            secret_ciphertext: totp.secret_ciphertext,
+            secret_nonce: totp.secret_nonce,
+        },
+    )?;
+    let step = verify_code(&secret, code, totp.last_used_step)?.ok_or_else(invalid_code)?;
+    sqlx::query!(
+        "UPDATE user_totp SET last_used_step = $2 WHERE user_id = $1",
+        user_id,
+        step
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Recovery codes are compared without dashes and case.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    tokens::hash(&normalized)
+}
+
+/// Replaces the recovery codes of a user with new ones, and returns them.
+async fn replace_recovery_codes(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<Vec<String>, ApiError> {
+    let codes: Vec<String> = {
+        let mut rng = thread_rng();
+        (0..RECOVERY_CODES)
+            .map(|_| {
+                let code: String = (&mut rng)
+                    .sample_iter(Alphanumeric)
+                    .take(10)
+                    .map(|c| char::from(c).to_ascii_lowercase())
+                    .collect();
+                format!("{}-{}", &code[..5], &code[5..])
+            })
+            .collect()
+    };
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO recovery_codes (user_id, code_hash)
+        SELECT $1, code_hash FROM UNNEST($2::TEXT[]) AS code_hash
+        "#,
+        user_id,
+        &hashes,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(codes)
+}
+
+#[derive(Serialize)]
+pub(super) struct Enrollment {
+    /// Base32, for entering it by hand.
+    secret: String,
+    /// For QR codes.
+    otpauth_uri: String,
+}
+
+/// Starts enrolling with a new TOTP secret, which has to be confirmed with a code from it.
+pub(super) async fn enroll(
+    State(state): State<AppState>,
+    auth_user: SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Enrollment>, ApiError> {
+    let email = sqlx::query_scalar!("SELECT email FROM users WHERE user_id = $1", *auth_user)
+        .fetch_one(&mut *conn)
+        .await?;
+    let issuer = state.config.borrow().auth.totp_issuer.clone();
+    let secret = Secret::generate_secret().to_encoded().to_string();
+    // `:` separates the issuer from the account name in the URI.
+    let otpauth_uri = totp(&secret, Some(issuer), email.replace(':', ""))?.get_url();
+    let (ciphertext, nonce) = state.totp_secrets.encrypt(*auth_user, &secret)?;
+
+    let stored = sqlx::query!(
+        r#"
+        INSERT INTO user_totp (user_id, secret_ciphertext, secret_nonce)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE
+        SET secret = NULL,
+            secret_ciphertext = EXCLUDED.secret_ciphertext,
+            secret_nonce = EXCLUDED.secret_nonce,
+            last_used_step = 0
+        WHERE user_totp.confirmed_at IS NULL
+        "#,
+        *auth_user,
+        ciphertext,
+        nonce,
+    )
+    .execute(&mut *conn)
+    .await?;
+    if stored.rows_affected() == 0 {
+        // Already enrolled, disable it first.
+        return Err(ApiError::Conflict);
+    }
+    Ok(Json(Enrollment {
+        secret,
+        otpauth_uri,
+    }))
+}
+
+#[derive(Deserialize)]
+pub(super) struct Code {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub(super) struct RecoveryCodes {
+    /// Shown only this once.
+    recovery_codes: Vec<String>,
+}
+
+/// Finishes enrolling with the first code of the new secret, and hands out recovery codes.
+pub(super) async fn confirm_enrollment(
+    State(state): State<AppState>,
+    auth_user: SessionUser,
+    session: Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(Code { code }): Json<Code>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    let mut tx = conn.begin().await?;
+    let stored = sqlx::query_as!(
+        StoredSecret,
+        r#"
+        SELECT secret, secret_ciphertext, secret_nonce FROM user_totp
+        WHERE user_id = $1 AND confirmed_at IS NULL
+        FOR UPDATE
+        "#,
+        *auth_user
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+    let secret = state.totp_secrets.decrypt(*auth_user, stored)?;
+    let step = verify_code(&secret, &code, 0)?.ok_or_else(invalid_code)?;
+    sqlx::query!(
+        "UPDATE user_totp SET confirmed_at = NOW(), last_used_step = $2 WHERE user_id = $1",
+        *auth_user,
+        step
+    )
+    .execute(&mut *tx)
+    .await?;
+    let recovery_codes = replace_recovery_codes(&mut tx, *auth_user).await?;
+    tx.commit().await?;
+
+    // The code proves the session has the second factor.
+    session.insert(SECOND_FACTOR, true).await?;
+    tracing::info!(user_id = %*auth_user, "Enrolled in two-factor authentication");
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[derive(Deserialize)]
+pub(super) struct SecondFactor {
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+/// Finishes a pending login with a TOTP code or a recovery code.
+pub(super) async fn verify(
+    State(state): State<AppState>,
+    session: Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(second_factor): Json<SecondFactor>,
+) -> Result<(), ApiError> {
+    let user_id = session
+        .get::<uuid::Uuid>(PENDING_USER_ID)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let limit = state.config.borrow().auth.second_factor_limit;
+    state
+        .rate_limiter
+        .check("second_factor", &user_id.to_string(), limit)
+        .await?;
+
+    let mut tx = conn.begin().await?;
+    match second_factor {
+        SecondFactor {
+            code: Some(code), ..
+        } => use_code(&mut tx, &state.totp_secrets, user_id, &code).await?,
+        SecondFactor {
+            recovery_code: Some(recovery_code),
+            ..
+        } => {
+            let used = sqlx::query!(
+                "DELETE FROM recovery_codes WHERE user_id = $1 AND code_hash = $2",
+                user_id,
+                hash_recovery_code(&recovery_code),
+            )
+            .execute(&mut *tx)
+            .await?;
+            if used.rows_affected() == 0 {
+                return Err(ApiError::unprocessable_entity([(
+                    "recovery_code",
+                    "invalid code",
+                )]));
+            }
+            tracing::info!(%user_id, "Logged in with a recovery code");
+        }
+        _ => return Err(ApiError::BadRequest),
+    }
+    tx.commit().await?;
+
+    session.remove_value(PENDING_USER_ID).await?;
+    session.cycle_id().await?;
+    session.insert("user_id", user_id).await?;
+    session.insert(SECOND_FACTOR, true).await?;
+    Ok(())
+}
+
+/// Turns two-factor authentication off, which admins can't do.
+pub(super) async fn disable(
+    State(state): State<AppState>,
+    auth_user: SessionUser,
+    session: Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(Code { code }): Json<Code>,
+) -> Result<(), ApiError> {
+    let mut tx = conn.begin().await?;
+    let is_admin = sqlx::query_scalar!("SELECT is_admin FROM users WHERE user_id = $1", *auth_user)
+        .fetch_one(&mut *tx)
+        .await?;
+    if is_admin {
+        return Err(ApiError::Forbidden);
+    }
+    use_code(&mut tx, &state.totp_secrets, *auth_user, &code).await?;
+    sqlx::query!("DELETE FROM user_totp WHERE user_id = $1", *auth_user)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", *auth_user)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    session.remove_value(SECOND_FACTOR).await?;
+    tracing::info!(user_id = %*auth_user, "Disabled two-factor authentication");
+    Ok(())
+}
+
+/// Replaces the recovery codes, e.g. when they're running out.
+pub(super) async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    auth_user: SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(Code { code }): Json<Code>,
+) -> Result<Json<RecoveryCodes>, ApiError> {
+    let mut tx = conn.begin().await?;
+    use_code(&mut tx, &state.totp_secrets, *auth_user, &code).await?;
+    let recovery_codes = replace_recovery_codes(&mut tx, *auth_user).await?;
+    tx.commit().await?;
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 58_000_000;
+
+    fn code_at(secret: &str, step: u64) -> String {
+        totp(secret, None, String::new())
+            .unwrap()
+            .generate(step * STEP_SECONDS)
+    }
+
+    #[test]
+    fn accepts_a_step_of_drift_either_way() {
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        for step in [NOW - 1, NOW, NOW + 1] {
+            let code = code_at(&secret, step);
+            assert_eq!(
+                verify_code_at(&secret, &code, 0, NOW).unwrap(),
+                Some(step as i64)
+            );
+        }
+        for step in [NOW - 2, NOW + 2] {
+            let code = code_at(&secret, step);
+            assert_eq!(verify_code_at(&secret, &code, 0, NOW).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_codes_of_used_steps() {
+        let secret = Secret::generate_secret().to_encoded().to_string();
+        let code = code_at(&secret, NOW);
+        assert_eq!(
+            verify_code_at(&secret, &code, NOW as i64 - 1, NOW).unwrap(),
+            Some(NOW as i64)
+        );
+        assert_eq!(
+            verify_code_at(&secret, &code, NOW as i64, NOW).unwrap(),
+            None
+        );
+        let earlier = code_at(&secret, NOW - 1);
+        assert_eq!(
+            verify_code_at(&secret, &earlier, NOW as i64, NOW).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn recovery_codes_ignore_dashes_case_and_whitespace() {
+        let expected = hash_recovery_code("abcd-efgh-1234");
+        assert_eq!(hash_recovery_code("ABCD-EFGH-1234"), expected);
+        assert_eq!(hash_recovery_code(" abcdefgh1234 "), expected);
+        assert_ne!(hash_recovery_code("abcd-efgh-1235"), expected);
+    }
+
+    #[test]
+    fn secrets_only_decrypt_for_their_user() {
+        let secrets = TotpSecrets::new(&SecretString::from("a test key".to_owned()));
+        let user_id = uuid::Uuid::new_v4();
+        let (ciphertext, nonce) = secrets.encrypt(user_id, "JBSWY3DPEHPK3PXP").unwrap();
+        let stored = || StoredSecret {
+            secret: None,
+            secret_ciphertext: Some(ciphertext.clone()),
+            secret_nonce: Some(nonce.clone()),
+        };
+        assert_eq!(
+            secrets.decrypt(user_id, stored()).unwrap(),
+            "JBSWY3DPEHPK3PXP"
+        );
+        assert!(secrets.decrypt(uuid::Uuid::new_v4(), stored()).is_err());
+    }
+}
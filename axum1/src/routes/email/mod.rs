@@ -0,0 +1,126 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    email::preferences::EmailCategory,
+    error::ApiError,
+    extractors::{AuthUser, DatabaseConnection},
+    state::AppState,
+};
+
+mod webhooks;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/preferences", get(preferences).put(update_preferences))
+        .route("/unsubscribe", post(unsubscribe))
+        .route("/webhooks/postmark", post(webhooks::postmark))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Preference {
+    category: EmailCategory,
+    enabled: bool,
+}
+
+/// Every category, including those the user never chose about.
+async fn preferences(
+    auth_user: AuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Vec<Preference>>, ApiError> {
+    let stored = sqlx::query_as!(
+        Preference,
+        r#"
+        SELECT category AS "category: EmailCategory", enabled
+        FROM email_preferences
+        WHERE user_id = $1
+        "#,
+        *auth_user
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let preferences = EmailCategory::ALL
+        .into_iter()
+        .map(|category| Preference {
+            category,
+            enabled: stored
+                .iter()
+                .find(|preference| preference.category == category)
+                .map_or(category.enabled_by_default(), |preference| {
+                    preference.enabled
+                }),
+        })
+        .collect();
+    Ok(Json(preferences))
+}
+
+async fn update_preferences(
+    auth_user: AuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(preferences): Json<Vec<Preference>>,
+) -> Result<StatusCode, ApiError> {
+    for preference in preferences {
+        set_preference(&mut conn, *auth_user, preference).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_preference(
+    conn: &mut sqlx::PgConnection,
+    user_id: uuid::Uuid,
+    preference: Preference,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO email_preferences (user_id, category, enabled)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, category) DO UPDATE SET enabled = EXCLUDED.enabled
+        "#,
+        user_id,
+        preference.category as _,
+        preference.enabled,
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeQuery {
+    user: uuid::Uuid,
+    category: EmailCategory,
+    signature: String,
+}
+
+/// The target of signed unsubscribe links. Mail clients `POST` to it for a one-click unsubscribe
+/// (RFC 8058), with `List-Unsubscribe=One-Click` in the body, which isn't needed. The frontend's
+/// unsubscribe page calls it the same way, so no login is required.
+async fn unsubscribe(
+    State(state): State<AppState>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Query(query): Query<UnsubscribeQuery>,
+) -> Result<StatusCode, ApiError> {
+    if !state
+        .email_client
+        .unsubscribe_links()
+        .verify(query.user, query.category, &query.signature)
+    {
+        return Err(ApiError::Forbidden);
+    }
+    let preference = Preference {
+        category: query.category,
+        enabled: false,
+    };
+    match set_preference(&mut conn, query.user, preference).await {
+        // The user was deleted since.
+        Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => {}
+        result => result?,
+    }
+    tracing::info!(user_id = %query.user, category = query.category.as_str(), "Unsubscribed");
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,199 @@
+//! Bounce and complaint webhooks of email providers, which feed `email_suppressions`.
+use axum::{extract::State, http::HeaderMap, http::StatusCode, Json};
+use base64::Engine;
+use secrecy::ExposeSecret;
+use serde::Deserialize;
+
+use crate::{
+    email::preferences::SuppressionReason, error::ApiError, extractors::DatabaseConnection,
+    state::AppState,
+};
+
+/// Checks the basic auth the webhook was configured with. Any username works, the password must
+/// be `email_client.webhook_secret`. Pretends the webhook doesn't exist without a secret.
+fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(secret) = state.config.borrow().email_client.webhook_secret.clone() else {
+        return Err(ApiError::NotFound);
+    };
+    let password = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|credentials| {
+            base64::engine::general_purpose::STANDARD
+                .decode(credentials)
+                .ok()
+        })
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| {
+            credentials
+                .split_once(':')
+                .map(|(_, password)| password.to_owned())
+        })
+        .ok_or(ApiError::Unauthorized)?;
+    // `blake3::Hash` compares in constant time.
+    if blake3::hash(password.as_bytes()) != blake3::hash(secret.expose_secret().as_bytes()) {
+        return Err(ApiError::Unauthorized);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PostmarkEvent {
+    record_type: String,
+    /// The kind of bounce, only set for bounces.
+    #[serde(rename = "Type")]
+    bounce_type: Option<String>,
+    email: Option<String>,
+}
+
+/// Receives Postmark's bounce and spam complaint webhooks. Hard bounces and complaints suppress
+/// the address, everything else (soft bounces, deliveries, ...) is ignored.
+pub(super) async fn postmark(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(details): Json<serde_json::Value>,
+) -> Result<StatusCode, ApiError> {
+    authenticate(&state, &headers)?;
+    let event = PostmarkEvent::deserialize(&details).map_err(|_| ApiError::BadRequest)?;
+    let reason = match (event.record_type.as_str(), event.bounce_type.as_deref()) {
+        ("Bounce", Some("HardBounce")) => SuppressionReason::HardBounce,
+        ("SpamComplaint", _) => SuppressionReason::SpamComplaint,
+        _ => return Ok(StatusCode::OK),
+    };
+    let Some(email) = event.email else {
+        return Err(ApiError::BadRequest);
+    };
+
+    sqlx::query!(
+        r#"
+        INSERT INTO email_suppressions (email, reason, details)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (email) DO UPDATE SET reason = EXCLUDED.reason, details = EXCLUDED.details
+        "#,
+        email,
+        reason as _,
+        details,
+    )
+    .execute(&mut *conn)
+    .await?;
+    tracing::info!(?reason, "Suppressed an email address");
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Method, Request},
+    };
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        email::{messages::DigestEmail, templates::EmailMessage, Delivery},
+        testing::{create_user, TestApp},
+    };
+
+    async fn app(pool: PgPool) -> TestApp {
+        TestApp::with_settings(pool, |settings| {
+            settings.email_client.webhook_secret = Some("webhook secret".to_owned().into());
+        })
+        .await
+    }
+
+    async fn notify(
+        app: &mut TestApp,
+        password: &str,
+        event: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("postmark:{password}"));
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/email/webhooks/postmark")
+            .header(header::AUTHORIZATION, format!("Basic {credentials}"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(event.to_string()))
+            .unwrap();
+        app.request(request).await
+    }
+
+    async fn suppression(pool: &PgPool, email: &str) -> Option<SuppressionReason> {
+        sqlx::query_scalar!(
+            r#"SELECT reason AS "reason: SuppressionReason" FROM email_suppressions WHERE email = $1"#,
+            email
+        )
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+    }
+
+    fn bounce(kind: &str, email: &str) -> serde_json::Value {
+        serde_json::json!({ "RecordType": "Bounce", "Type": kind, "Email": email })
+    }
+
+    #[sqlx::test]
+    async fn webhooks_only_exist_with_a_secret(pool: PgPool) {
+        let mut app = TestApp::new(pool).await;
+        let (status, _) = notify(&mut app, "", bounce("HardBounce", "a@example.com")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn webhooks_need_the_secret(pool: PgPool) {
+        let mut app = app(pool.clone()).await;
+        let (status, _) = notify(&mut app, "guess", bounce("HardBounce", "a@example.com")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(suppression(&pool, "a@example.com").await, None);
+    }
+
+    #[sqlx::test]
+    async fn hard_bounces_and_complaints_suppress_the_address(pool: PgPool) {
+        let mut app = app(pool.clone()).await;
+        for event in [
+            bounce("SoftBounce", "soft@example.com"),
+            serde_json::json!({ "RecordType": "Delivery", "Email": "delivered@example.com" }),
+            bounce("HardBounce", "hard@example.com"),
+            serde_json::json!({ "RecordType": "SpamComplaint", "Email": "spam@example.com" }),
+        ] {
+            let (status, _) = notify(&mut app, "webhook secret", event).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        assert_eq!(suppression(&pool, "soft@example.com").await, None);
+        assert_eq!(suppression(&pool, "delivered@example.com").await, None);
+        assert_eq!(
+            suppression(&pool, "hard@example.com").await,
+            Some(SuppressionReason::HardBounce)
+        );
+        assert_eq!(
+            suppression(&pool, "spam@example.com").await,
+            Some(SuppressionReason::SpamComplaint)
+        );
+    }
+
+    #[sqlx::test]
+    async fn suppressed_addresses_get_no_more_email(pool: PgPool) {
+        let mut app = app(pool.clone()).await;
+        let user = create_user(&pool, "hard").await;
+        notify(
+            &mut app,
+            "webhook secret",
+            bounce("HardBounce", "hard@example.com"),
+        )
+        .await;
+
+        let digest = DigestEmail::sample("http://localhost:3000");
+        let delivery = app
+            .state
+            .email_client
+            .send_to_user(user, &digest)
+            .await
+            .unwrap();
+        assert_eq!(delivery, Delivery::Suppressed);
+        assert!(app.emails.sent().is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use axum::{
     extract::Path,
     middleware::from_extractor_with_state,
     routing::{delete, get, post},
-    Json, Router,
+    Extension, Json, Router,
 };
 // Because we need to deserialize a sequence from a form, we need `axum-extra`.
 // See: https://github.com/tokio-rs/axum/pull/1031
@@ -23,7 +23,7 @@ use self::suggestion::{
     apply_suggestion, decline_suggestion, get_ingredient_suggestion, get_ingredient_suggestions,
 };
 
-use super::admin::AdminUser;
+use super::{admin::AdminUser, oauth_server::OAuthResource};
 
 pub fn router(state: AppState) -> Router<AppState> {
     let admin_services = Router::new()
@@ -42,7 +42,10 @@ pub fn router(state: AppState) -> Router<AppState> {
         .route("/all", get(all_ingredients))
         .route("/category/:category", get(ingredients_by_category))
         .route("/:name", get(get_ingredient))
-        .route("/favorite/:name", post(make_favorite)) // TODO: swap route to `/:name/favorite` maybe for consistency?
+        .route(
+            "/favorite/:name",
+            post(make_favorite).layer(Extension(OAuthResource::Favorites)),
+        ) // TODO: swap route to `/:name/favorite` maybe for consistency?
         .route("/:name/suggestion", post(add_ingredient_suggestion))
         .merge(admin_services)
 }
@@ -192,7 +192,7 @@ pub async fn apply_suggestion(
     let mut tx = conn.begin().await?;
 
     let suggestion_row = sqlx::query!(
-        r#"SELECT is_delete_vote FROM ingredient_suggestions WHERE id = $1"#,
+        r#"SELECT is_delete_vote, user_id FROM ingredient_suggestions WHERE id = $1"#,
         id
     )
     .fetch_optional(&mut *tx)
@@ -242,6 +242,22 @@ pub async fn apply_suggestion(
         .await
         .context("failed to delete from suggestions table")?;
     }
+
+    // Shown on the suggester's author page, the suggestion itself is gone now.
+    if let Some(user_id) = suggestion_row.user_id {
+        sqlx::query!(
+            r#"
+            INSERT INTO user_profiles (user_id, accepted_suggestions)
+            VALUES ($1, 1)
+            ON CONFLICT (user_id)
+            DO UPDATE SET accepted_suggestions = user_profiles.accepted_suggestions + 1
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .context("failed to count the accepted suggestion")?;
+    }
     tx.commit().await?;
 
     Ok(())
@@ -1,4 +1,7 @@
 pub mod admin;
 pub mod auth;
+pub mod email;
 pub mod ingredient;
+pub mod oauth_server;
+pub mod profile;
 pub mod recipe;
@@ -0,0 +1,151 @@
+//! Registering third-party apps. Any user can, and manages the apps they registered.
+use axum::{extract::Path, Json};
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use super::normalize_scopes;
+use crate::{
+    error::ApiError,
+    extractors::{DatabaseConnection, SessionUser},
+    routes::auth::tokens,
+};
+
+/// Tells client secrets apart from other secrets, e.g. for secret scanners.
+const SECRET_PREFIX: &str = "rcps_";
+
+#[derive(Serialize)]
+pub(super) struct ClientDetails {
+    client_id: uuid::Uuid,
+    name: String,
+    redirect_uris: Vec<String>,
+    scopes: Vec<String>,
+    confidential: bool,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+pub(super) async fn list(
+    auth_user: SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Vec<ClientDetails>>, ApiError> {
+    let clients = sqlx::query_as!(
+        ClientDetails,
+        r#"
+        SELECT client_id, name, redirect_uris, scopes,
+            secret_hash IS NOT NULL AS "confidential!", created_at
+        FROM oauth_clients
+        WHERE owner_id = $1
+        ORDER BY created_at
+        "#,
+        *auth_user
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(Json(clients))
+}
+
+#[derive(Deserialize, Validate)]
+pub(super) struct NewClient {
+    #[validate(length(min = 1, max = 100, message = "must be between 1 and 100 characters"))]
+    name: String,
+    #[validate(length(
+        min = 1,
+        max = 10,
+        message = "must have between 1 and 10 redirect URIs"
+    ))]
+    redirect_uris: Vec<String>,
+    scopes: Vec<String>,
+    /// Apps with a backend that can keep a secret. Mobile and single-page apps can't.
+    confidential: bool,
+}
+
+/// Redirect URIs have to be absolute and use HTTPS, except on loopback for development, or a
+/// custom scheme for mobile apps. They can't have fragments.
+fn is_valid_redirect_uri(uri: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(uri) else {
+        return false;
+    };
+    if url.fragment().is_some() {
+        return false;
+    }
+    match url.scheme() {
+        "https" => true,
+        "http" => matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]")),
+        "javascript" | "data" | "file" => false,
+        _ => true,
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct RegisteredClient {
+    /// Shown only this once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<String>,
+    #[serde(flatten)]
+    details: ClientDetails,
+}
+
+pub(super) async fn register(
+    auth_user: SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(form): Json<NewClient>,
+) -> Result<Json<RegisteredClient>, ApiError> {
+    form.validate()
+        .map_err(ApiError::unprocessable_entity_from_validation_errors)?;
+    if !form
+        .redirect_uris
+        .iter()
+        .all(|uri| is_valid_redirect_uri(uri))
+    {
+        return Err(ApiError::unprocessable_entity([(
+            "redirect_uris",
+            "must be absolute HTTPS URIs without a fragment",
+        )]));
+    }
+    let scopes = normalize_scopes(form.scopes.iter().map(String::as_str))
+        .filter(|scopes| !scopes.is_empty())
+        .ok_or_else(|| ApiError::unprocessable_entity([("scopes", "unknown scope")]))?;
+
+    let client_secret = form
+        .confidential
+        .then(|| format!("{SECRET_PREFIX}{}", tokens::generate()));
+    let details = sqlx::query_as!(
+        ClientDetails,
+        r#"
+        INSERT INTO oauth_clients (owner_id, name, secret_hash, redirect_uris, scopes)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING client_id, name, redirect_uris, scopes,
+            secret_hash IS NOT NULL AS "confidential!", created_at
+        "#,
+        *auth_user,
+        form.name,
+        client_secret.as_deref().map(tokens::hash),
+        &form.redirect_uris,
+        &scopes,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    tracing::info!(user_id = %*auth_user, client_id = %details.client_id, "Registered an OAuth client");
+    Ok(Json(RegisteredClient {
+        client_secret,
+        details,
+    }))
+}
+
+/// Removes an app, along with every grant and token it has.
+pub(super) async fn delete(
+    auth_user: SessionUser,
+    Path(client_id): Path<uuid::Uuid>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<(), ApiError> {
+    let deleted = sqlx::query!(
+        "DELETE FROM oauth_clients WHERE client_id = $1 AND owner_id = $2",
+        client_id,
+        *auth_user
+    )
+    .execute(&mut *conn)
+    .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Ok(())
+}
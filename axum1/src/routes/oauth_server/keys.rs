@@ -0,0 +1,66 @@
+//! The key ID tokens are signed with (RS256), and its public half for clients to verify them.
+use anyhow::Context;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rsa::{
+    pkcs1::EncodeRsaPrivateKey, pkcs8::DecodePrivateKey, traits::PublicKeyParts, RsaPrivateKey,
+};
+use secrecy::ExposeSecret;
+use serde::Serialize;
+
+use crate::config::OAuthServerSettings;
+
+pub struct SigningKey {
+    encoding_key: EncodingKey,
+    key_id: String,
+    /// The public key's modulus and exponent, base64url encoded for the JWK.
+    n: String,
+    e: String,
+}
+
+impl SigningKey {
+    pub fn new(settings: &OAuthServerSettings) -> anyhow::Result<Self> {
+        let private_key = match &settings.signing_key {
+            Some(pem) => RsaPrivateKey::from_pkcs8_pem(pem.expose_secret())
+                .context("The OAuth signing key isn't an RSA private key in PKCS#8 PEM")?,
+            None => {
+                tracing::warn!("No OAuth signing key is configured, generating one for this run");
+                RsaPrivateKey::new(&mut rand::thread_rng(), 2048)
+                    .context("Failed to generate an OAuth signing key")?
+            }
+        };
+        let der = private_key
+            .to_pkcs1_der()
+            .context("Failed to encode the OAuth signing key")?;
+
+        let modulus = private_key.n().to_bytes_be();
+        // Rotating the key changes its ID, so clients know to fetch the JWKS again.
+        let key_id = blake3::hash(&modulus).to_hex()[..16].to_owned();
+        Ok(Self {
+            encoding_key: EncodingKey::from_rsa_der(der.as_bytes()),
+            key_id,
+            n: URL_SAFE_NO_PAD.encode(modulus),
+            e: URL_SAFE_NO_PAD.encode(private_key.e().to_bytes_be()),
+        })
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> anyhow::Result<String> {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(self.key_id.clone());
+        jsonwebtoken::encode(&header, claims, &self.encoding_key).context("Failed to sign a JWT")
+    }
+
+    /// The JSON Web Key Set with the public key.
+    pub fn jwks(&self) -> serde_json::Value {
+        serde_json::json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": self.key_id,
+                "n": self.n,
+                "e": self.e,
+            }]
+        })
+    }
+}
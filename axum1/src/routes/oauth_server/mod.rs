@@ -0,0 +1,451 @@
+//! Us as an OAuth2 authorization server and OpenID Connect provider, for third-party apps acting
+//! on behalf of our users.
+//!
+//! Users register the apps (`clients`). Apps use the authorization code flow, always with PKCE
+//! (S256): they send the user to the frontend's `/oauth/authorize` page, which asks
+//! `GET /oauth/authorize` what to show on the consent screen, and `POST /oauth/authorize` where to
+//! send the user back to once they decided. Apps exchange the code at `POST /oauth/token`.
+//!
+//! Access tokens are opaque, and only accepted by routes marked with an `OAuthResource`: those of
+//! recipes and favorites, within the token's scopes.
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{request::Parts, HeaderMap, Method},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, PgConnection, PgPool};
+
+use crate::{
+    error::ApiError,
+    extractors::{bearer_token, DatabaseConnection, SessionUser},
+    routes::auth::tokens,
+    state::AppState,
+};
+
+mod clients;
+mod keys;
+mod token;
+
+pub use keys::SigningKey;
+
+/// Tells access tokens apart from personal access tokens.
+pub const ACCESS_TOKEN_PREFIX: &str = "rcpo_";
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/oauth/jwks", get(jwks))
+        .route(
+            "/oauth/authorize",
+            get(authorization_details).post(authorize),
+        )
+        .route("/oauth/token", post(token::token))
+        .route("/oauth/userinfo", get(userinfo))
+        .route("/oauth/clients", get(clients::list).post(clients::register))
+        .route("/oauth/clients/:client_id", delete(clients::delete))
+        .route("/oauth/grants", get(list_grants))
+        .route("/oauth/grants/:client_id", delete(revoke_grant))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    OpenId,
+    Profile,
+    Email,
+    RecipesRead,
+    RecipesWrite,
+    FavoritesRead,
+    FavoritesWrite,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 7] = [
+        Scope::OpenId,
+        Scope::Profile,
+        Scope::Email,
+        Scope::RecipesRead,
+        Scope::RecipesWrite,
+        Scope::FavoritesRead,
+        Scope::FavoritesWrite,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::OpenId => "openid",
+            Self::Profile => "profile",
+            Self::Email => "email",
+            Self::RecipesRead => "recipes:read",
+            Self::RecipesWrite => "recipes:write",
+            Self::FavoritesRead => "favorites:read",
+            Self::FavoritesWrite => "favorites:write",
+        }
+    }
+}
+
+/// Checks scopes, and drops duplicates. `None` if any of them is unknown.
+fn normalize_scopes<'a>(scopes: impl IntoIterator<Item = &'a str>) -> Option<Vec<String>> {
+    let mut normalized: Vec<String> = Vec::new();
+    for scope in scopes {
+        Scope::ALL.iter().find(|known| known.as_str() == scope)?;
+        if !normalized.iter().any(|seen| seen == scope) {
+            normalized.push(scope.to_owned());
+        }
+    }
+    Some(normalized)
+}
+
+fn has_scope(scopes: &[String], scope: Scope) -> bool {
+    scopes.iter().any(|granted| granted == scope.as_str())
+}
+
+/// Marks the routes that accept access tokens, with what they're about. Safe requests need the
+/// resource's read scope, others its write scope.
+#[derive(Clone, Copy, Debug)]
+pub enum OAuthResource {
+    Recipes,
+    Favorites,
+}
+
+impl OAuthResource {
+    fn scope(self, method: &Method) -> Scope {
+        match (self, method.is_safe()) {
+            (Self::Recipes, true) => Scope::RecipesRead,
+            (Self::Recipes, false) => Scope::RecipesWrite,
+            (Self::Favorites, true) => Scope::FavoritesRead,
+            (Self::Favorites, false) => Scope::FavoritesWrite,
+        }
+    }
+}
+
+struct AccessToken {
+    user_id: uuid::Uuid,
+    scopes: Vec<String>,
+}
+
+async fn find_access_token(pool: &PgPool, token: &str) -> Result<AccessToken, ApiError> {
+    sqlx::query_as!(
+        AccessToken,
+        r#"
+        SELECT user_id, scopes FROM oauth_tokens
+        WHERE token_hash = $1 AND kind = 'access' AND expires_at > NOW()
+        "#,
+        tokens::hash(token)
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::Unauthorized)
+}
+
+/// Returns the user an access token acts for, if it's valid and its scopes cover the request.
+pub async fn authenticate_access_token(
+    pool: &PgPool,
+    token: &str,
+    parts: &Parts,
+) -> Result<uuid::Uuid, ApiError> {
+    let access_token = find_access_token(pool, token).await?;
+    let resource = parts
+        .extensions
+        .get::<OAuthResource>()
+        .ok_or(ApiError::Forbidden)?;
+    if !has_scope(&access_token.scopes, resource.scope(&parts.method)) {
+        return Err(ApiError::Forbidden);
+    }
+    Ok(access_token.user_id)
+}
+
+async fn discovery(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let (issuer, frontend_url) = {
+        let config = state.config.borrow();
+        (
+            config.application_settings.public_url(),
+            config.frontend_url.clone(),
+        )
+    };
+    let issuer = issuer.trim_end_matches('/');
+    Json(serde_json::json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", frontend_url.trim_end_matches('/')),
+        "token_endpoint": format!("{issuer}/oauth/token"),
+        "userinfo_endpoint": format!("{issuer}/oauth/userinfo"),
+        "jwks_uri": format!("{issuer}/oauth/jwks"),
+        "scopes_supported": Scope::ALL.map(Scope::as_str),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", "refresh_token"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+        "claims_supported": ["sub", "iss", "aud", "exp", "iat", "nonce", "name", "email", "email_verified"],
+    }))
+}
+
+async fn jwks(Extension(signing_key): Extension<Arc<SigningKey>>) -> Json<serde_json::Value> {
+    Json(signing_key.jwks())
+}
+
+#[derive(Deserialize)]
+struct AuthorizationRequest {
+    response_type: String,
+    client_id: uuid::Uuid,
+    redirect_uri: String,
+    scope: String,
+    state: Option<String>,
+    code_challenge: String,
+    code_challenge_method: String,
+    nonce: Option<String>,
+}
+
+struct Client {
+    name: String,
+    redirect_uris: Vec<String>,
+    scopes: Vec<String>,
+}
+
+/// Checks an authorization request, and returns its client and scopes. Errors aren't redirected
+/// back to the client, the consent screen shows them instead.
+async fn check_request(
+    conn: &mut PgConnection,
+    request: &AuthorizationRequest,
+) -> Result<(Client, Vec<String>), ApiError> {
+    let client = sqlx::query_as!(
+        Client,
+        "SELECT name, redirect_uris, scopes FROM oauth_clients WHERE client_id = $1",
+        request.client_id
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or_else(|| ApiError::unprocessable_entity([("client_id", "unknown client")]))?;
+
+    if !client.redirect_uris.contains(&request.redirect_uri) {
+        return Err(ApiError::unprocessable_entity([(
+            "redirect_uri",
+            "not registered for the client",
+        )]));
+    }
+    if request.response_type != "code" {
+        return Err(ApiError::unprocessable_entity([(
+            "response_type",
+            "only `code` is supported",
+        )]));
+    }
+    // A base64url encoded SHA-256 hash is 43 characters.
+    if request.code_challenge_method != "S256" || request.code_challenge.len() != 43 {
+        return Err(ApiError::unprocessable_entity([(
+            "code_challenge",
+            "must be an S256 challenge",
+        )]));
+    }
+    let scopes = normalize_scopes(request.scope.split_whitespace())
+        .filter(|scopes| {
+            !scopes.is_empty() && scopes.iter().all(|scope| client.scopes.contains(scope))
+        })
+        .ok_or_else(|| ApiError::unprocessable_entity([("scope", "not allowed for the client")]))?;
+    Ok((client, scopes))
+}
+
+#[derive(Serialize)]
+struct AuthorizationDetails {
+    client_name: String,
+    scopes: Vec<String>,
+    /// Whether the user has to be asked, or agreed to these scopes already.
+    consent_required: bool,
+}
+
+/// What the consent screen shows.
+async fn authorization_details(
+    auth_user: SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Query(request): Query<AuthorizationRequest>,
+) -> Result<Json<AuthorizationDetails>, ApiError> {
+    let (client, scopes) = check_request(&mut conn, &request).await?;
+    let granted = sqlx::query_scalar!(
+        "SELECT scopes FROM oauth_grants WHERE user_id = $1 AND client_id = $2",
+        *auth_user,
+        request.client_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .unwrap_or_default();
+
+    Ok(Json(AuthorizationDetails {
+        client_name: client.name,
+        consent_required: !scopes.iter().all(|scope| granted.contains(scope)),
+        scopes,
+    }))
+}
+
+#[derive(Deserialize)]
+struct Decision {
+    #[serde(flatten)]
+    request: AuthorizationRequest,
+    approved: bool,
+}
+
+#[derive(Serialize)]
+struct Redirect {
+    /// Where to send the user back to the client, with a code or an error.
+    redirect_to: String,
+}
+
+/// Records the user's decision on the consent screen.
+async fn authorize(
+    State(state): State<AppState>,
+    auth_user: SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(Decision { request, approved }): Json<Decision>,
+) -> Result<Json<Redirect>, ApiError> {
+    let (_, scopes) = check_request(&mut conn, &request).await?;
+    let mut redirect_to =
+        reqwest::Url::parse(&request.redirect_uri).map_err(|_| ApiError::BadRequest)?;
+
+    if approved {
+        let code_ttl = state
+            .config
+            .borrow()
+            .oauth_server
+            .authorization_code_ttl_seconds;
+        let code = tokens::generate();
+        sqlx::query!(
+            r#"
+            INSERT INTO oauth_grants (user_id, client_id, scopes)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, client_id) DO UPDATE
+            SET scopes = ARRAY(SELECT DISTINCT UNNEST(oauth_grants.scopes || EXCLUDED.scopes))
+            "#,
+            *auth_user,
+            request.client_id,
+            &scopes,
+        )
+        .execute(&mut *conn)
+        .await?;
+        sqlx::query!(
+            r#"
+            INSERT INTO oauth_authorization_codes
+                (code_hash, client_id, user_id, redirect_uri, scopes, code_challenge, nonce, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, NOW() + make_interval(secs => $8))
+            "#,
+            tokens::hash(&code),
+            request.client_id,
+            *auth_user,
+            request.redirect_uri,
+            &scopes,
+            request.code_challenge,
+            request.nonce,
+            code_ttl as f64,
+        )
+        .execute(&mut *conn)
+        .await?;
+        redirect_to.query_pairs_mut().append_pair("code", &code);
+    } else {
+        redirect_to
+            .query_pairs_mut()
+            .append_pair("error", "access_denied");
+    }
+    if let Some(client_state) = &request.state {
+        redirect_to
+            .query_pairs_mut()
+            .append_pair("state", client_state);
+    }
+    Ok(Json(Redirect {
+        redirect_to: redirect_to.into(),
+    }))
+}
+
+#[derive(Serialize)]
+struct UserInfo {
+    sub: uuid::Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+}
+
+/// The OpenID Connect userinfo endpoint, with the claims the access token's scopes allow.
+async fn userinfo(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<UserInfo>, ApiError> {
+    let token = bearer_token(&headers)?.ok_or(ApiError::Unauthorized)?;
+    let access_token = find_access_token(&state.db_pool, token).await?;
+    if !has_scope(&access_token.scopes, Scope::OpenId) {
+        return Err(ApiError::Forbidden);
+    }
+    let user = sqlx::query!(
+        "SELECT name, email, confirmed FROM users WHERE user_id = $1",
+        access_token.user_id
+    )
+    .fetch_one(&state.db_pool)
+    .await?;
+
+    let profile = has_scope(&access_token.scopes, Scope::Profile);
+    let email = has_scope(&access_token.scopes, Scope::Email);
+    Ok(Json(UserInfo {
+        sub: access_token.user_id,
+        name: profile.then_some(user.name),
+        email: email.then_some(user.email),
+        email_verified: email.then_some(user.confirmed),
+    }))
+}
+
+#[derive(Serialize)]
+struct Grant {
+    client_id: uuid::Uuid,
+    client_name: String,
+    scopes: Vec<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// The apps the user let act on their behalf.
+async fn list_grants(
+    auth_user: SessionUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Vec<Grant>>, ApiError> {
+    let grants = sqlx::query_as!(
+        Grant,
+        r#"
+        SELECT g.client_id, c.name AS client_name, g.scopes, g.created_at
+        FROM oauth_grants g
+        INNER JOIN oauth_clients c ON c.client_id = g.client_id
+        WHERE g.user_id = $1
+        ORDER BY g.created_at
+        "#,
+        *auth_user
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(Json(grants))
+}
+
+/// Withdraws the consent given to an app, along with every token it has.
+async fn revoke_grant(
+    auth_user: SessionUser,
+    Path(client_id): Path<uuid::Uuid>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<(), ApiError> {
+    let mut tx = conn.begin().await?;
+    let revoked = sqlx::query!(
+        "DELETE FROM oauth_grants WHERE user_id = $1 AND client_id = $2",
+        *auth_user,
+        client_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    if revoked.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    sqlx::query!(
+        "DELETE FROM oauth_tokens WHERE user_id = $1 AND client_id = $2",
+        *auth_user,
+        client_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
@@ -0,0 +1,424 @@
+//! The token endpoint: exchanges authorization codes and refresh tokens for access tokens.
+//!
+//! Its errors have the shape RFC 6749 asks for rather than ours, OAuth client libraries parse them.
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header::CACHE_CONTROL, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Form, Json,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::Utc;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Acquire, PgConnection};
+
+use super::{has_scope, normalize_scopes, Scope, SigningKey, ACCESS_TOKEN_PREFIX};
+use crate::{
+    config::OAuthServerSettings, error::ApiError, extractors::DatabaseConnection,
+    routes::auth::tokens, state::AppState,
+};
+
+const REFRESH_TOKEN_PREFIX: &str = "rcpr_";
+
+pub(super) enum TokenError {
+    InvalidRequest(&'static str),
+    InvalidClient,
+    InvalidGrant(&'static str),
+    UnsupportedGrantType,
+    InvalidScope,
+    Internal(ApiError),
+}
+
+impl From<ApiError> for TokenError {
+    fn from(e: ApiError) -> Self {
+        Self::Internal(e)
+    }
+}
+
+impl From<sqlx::Error> for TokenError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Internal(e.into())
+    }
+}
+
+impl From<anyhow::Error> for TokenError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Internal(e.into())
+    }
+}
+
+impl IntoResponse for TokenError {
+    fn into_response(self) -> Response {
+        let (status, error, description) = match self {
+            Self::InvalidRequest(description) => (
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                Some(description),
+            ),
+            Self::InvalidClient => (StatusCode::UNAUTHORIZED, "invalid_client", None),
+            Self::InvalidGrant(description) => {
+                (StatusCode::BAD_REQUEST, "invalid_grant", Some(description))
+            }
+            Self::UnsupportedGrantType => (StatusCode::BAD_REQUEST, "unsupported_grant_type", None),
+            Self::InvalidScope => (StatusCode::BAD_REQUEST, "invalid_scope", None),
+            Self::Internal(e) => return e.into_response(),
+        };
+        let body = serde_json::json!({
+            "error": error,
+            "error_description": description,
+        });
+        (status, Json(body)).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct TokenRequest {
+    grant_type: String,
+    code: Option<String>,
+    redirect_uri: Option<String>,
+    code_verifier: Option<String>,
+    refresh_token: Option<String>,
+    /// Narrows the scopes of a refresh.
+    scope: Option<String>,
+    client_id: Option<uuid::Uuid>,
+    client_secret: Option<SecretString>,
+}
+
+/// Authenticates the client with HTTP Basic auth or the form's `client_id` and `client_secret`.
+/// Public clients only send their ID.
+async fn authenticate_client(
+    conn: &mut PgConnection,
+    headers: &HeaderMap,
+    request: &TokenRequest,
+) -> Result<uuid::Uuid, TokenError> {
+    let basic = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|credentials| STANDARD.decode(credentials).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok());
+    let (client_id, secret) = match &basic {
+        Some(credentials) => {
+            let (id, secret) = credentials
+                .split_once(':')
+                .ok_or(TokenError::InvalidClient)?;
+            let id = id.parse().map_err(|_| TokenError::InvalidClient)?;
+            (id, Some(secret))
+        }
+        None => (
+            request.client_id.ok_or(TokenError::InvalidClient)?,
+            request
+                .client_secret
+                .as_ref()
+                .map(|secret| secret.expose_secret()),
+        ),
+    };
+
+    let secret_hash = sqlx::query_scalar!(
+        "SELECT secret_hash FROM oauth_clients WHERE client_id = $1",
+        client_id
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(TokenError::InvalidClient)?;
+    match (secret_hash, secret) {
+        (None, None) => Ok(client_id),
+        (Some(expected), Some(secret)) if tokens::verify(secret, &expected) => Ok(client_id),
+        _ => Err(TokenError::InvalidClient),
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: u64,
+    refresh_token: String,
+    scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+}
+
+pub(super) async fn token(
+    State(state): State<AppState>,
+    Extension(signing_key): Extension<Arc<SigningKey>>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    headers: HeaderMap,
+    Form(request): Form<TokenRequest>,
+) -> Result<impl IntoResponse, TokenError> {
+    let (settings, issuer) = {
+        let config = state.config.borrow();
+        (
+            config.oauth_server.clone(),
+            config.application_settings.public_url(),
+        )
+    };
+    let mut tx = conn.begin().await?;
+    let client_id = authenticate_client(&mut tx, &headers, &request).await?;
+
+    let (user_id, scopes, nonce) = match request.grant_type.as_str() {
+        "authorization_code" => exchange_code(&mut tx, client_id, &request).await?,
+        "refresh_token" => {
+            let (user_id, scopes) = refresh(&mut tx, client_id, &request).await?;
+            (user_id, scopes, None)
+        }
+        _ => return Err(TokenError::UnsupportedGrantType),
+    };
+
+    let access_token = format!("{ACCESS_TOKEN_PREFIX}{}", tokens::generate());
+    let refresh_token = format!("{REFRESH_TOKEN_PREFIX}{}", tokens::generate());
+    sqlx::query!(
+        r#"
+        INSERT INTO oauth_tokens (token_hash, kind, client_id, user_id, scopes, expires_at)
+        VALUES
+            ($1, 'access', $3, $4, $5, NOW() + make_interval(secs => $6)),
+            ($2, 'refresh', $3, $4, $5, NOW() + make_interval(days => $7))
+        "#,
+        tokens::hash(&access_token),
+        tokens::hash(&refresh_token),
+        client_id,
+        user_id,
+        &scopes,
+        settings.access_token_ttl_seconds as f64,
+        settings.refresh_token_ttl_days as i32,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // ID tokens only come with codes, refreshes don't authenticate the user again.
+    let id_token = match (
+        has_scope(&scopes, Scope::OpenId),
+        request.grant_type.as_str(),
+    ) {
+        (true, "authorization_code") => Some(id_token(
+            &signing_key,
+            &settings,
+            issuer,
+            client_id,
+            user_id,
+            nonce,
+        )?),
+        _ => None,
+    };
+    tx.commit().await?;
+
+    Ok((
+        [(CACHE_CONTROL, "no-store")],
+        Json(TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: settings.access_token_ttl_seconds,
+            refresh_token,
+            scope: scopes.join(" "),
+            id_token,
+        }),
+    ))
+}
+
+/// Uses up an authorization code. Returns the user, the scopes and the nonce it was issued with.
+async fn exchange_code(
+    conn: &mut PgConnection,
+    client_id: uuid::Uuid,
+    request: &TokenRequest,
+) -> Result<(uuid::Uuid, Vec<String>, Option<String>), TokenError> {
+    let (Some(code), Some(redirect_uri), Some(code_verifier)) =
+        (&request.code, &request.redirect_uri, &request.code_verifier)
+    else {
+        return Err(TokenError::InvalidRequest(
+            "code, redirect_uri and code_verifier are required",
+        ));
+    };
+
+    let stored = sqlx::query!(
+        r#"
+        DELETE FROM oauth_authorization_codes
+        WHERE code_hash = $1
+        RETURNING client_id, user_id, redirect_uri, scopes, code_challenge, nonce, expires_at
+        "#,
+        tokens::hash(code)
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(TokenError::InvalidGrant("unknown or used code"))?;
+
+    if stored.client_id != client_id || stored.expires_at <= Utc::now() {
+        return Err(TokenError::InvalidGrant("unknown or used code"));
+    }
+    if &stored.redirect_uri != redirect_uri {
+        return Err(TokenError::InvalidGrant("redirect_uri doesn't match"));
+    }
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+    if challenge != stored.code_challenge {
+        return Err(TokenError::InvalidGrant("code_verifier doesn't match"));
+    }
+    Ok((stored.user_id, stored.scopes, stored.nonce))
+}
+
+/// Uses up a refresh token of the client, it's replaced with a new one. Returns the user and the
+/// scopes.
+async fn refresh(
+    conn: &mut PgConnection,
+    client_id: uuid::Uuid,
+    request: &TokenRequest,
+) -> Result<(uuid::Uuid, Vec<String>), TokenError> {
+    let refresh_token = request
+        .refresh_token
+        .as_deref()
+        .ok_or(TokenError::InvalidRequest("refresh_token is required"))?;
+
+    let stored = sqlx::query!(
+        r#"
+        DELETE FROM oauth_tokens
+        WHERE token_hash = $1 AND kind = 'refresh' AND client_id = $2
+        RETURNING user_id, scopes, expires_at
+        "#,
+        tokens::hash(refresh_token),
+        client_id,
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(TokenError::InvalidGrant("unknown or used refresh token"))?;
+    if stored.expires_at <= Utc::now() {
+        return Err(TokenError::InvalidGrant("unknown or used refresh token"));
+    }
+
+    let scopes = match &request.scope {
+        Some(scope) => normalize_scopes(scope.split_whitespace())
+            .filter(|scopes| scopes.iter().all(|scope| stored.scopes.contains(scope)))
+            .ok_or(TokenError::InvalidScope)?,
+        None => stored.scopes,
+    };
+    Ok((stored.user_id, scopes))
+}
+
+#[derive(Serialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: uuid::Uuid,
+    aud: uuid::Uuid,
+    iat: i64,
+    exp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+}
+
+fn id_token(
+    signing_key: &SigningKey,
+    settings: &OAuthServerSettings,
+    issuer: String,
+    client_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    nonce: Option<String>,
+) -> anyhow::Result<String> {
+    let now = Utc::now().timestamp();
+    signing_key.sign(&IdTokenClaims {
+        iss: issuer.trim_end_matches('/').to_owned(),
+        sub: user_id,
+        aud: client_id,
+        iat: now,
+        exp: now + settings.access_token_ttl_seconds as i64,
+        nonce,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use sqlx::PgPool;
+
+    use super::REFRESH_TOKEN_PREFIX;
+    use crate::{
+        routes::auth::tokens,
+        testing::{create_user, TestApp},
+    };
+
+    /// A public client, which authenticates with its ID only.
+    async fn create_client(pool: &PgPool, owner_id: uuid::Uuid) -> uuid::Uuid {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO oauth_clients (owner_id, name, redirect_uris, scopes)
+            VALUES ($1, 'App', '{https://app.example.com/callback}', '{profile}')
+            RETURNING client_id
+            "#,
+            owner_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn create_refresh_token(
+        pool: &PgPool,
+        client_id: uuid::Uuid,
+        user_id: uuid::Uuid,
+    ) -> String {
+        let token = format!("{REFRESH_TOKEN_PREFIX}{}", tokens::generate());
+        sqlx::query!(
+            r#"
+            INSERT INTO oauth_tokens (token_hash, kind, client_id, user_id, scopes, expires_at)
+            VALUES ($1, 'refresh', $2, $3, '{profile}', NOW() + INTERVAL '1 day')
+            "#,
+            tokens::hash(&token),
+            client_id,
+            user_id,
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        token
+    }
+
+    async fn refresh(
+        app: &mut TestApp,
+        client_id: uuid::Uuid,
+        token: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        app.submit(
+            Method::POST,
+            "/oauth/token",
+            &[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", token),
+                ("client_id", &client_id.to_string()),
+            ],
+        )
+        .await
+    }
+
+    #[sqlx::test]
+    async fn refresh_tokens_are_replaced(pool: PgPool) {
+        let user_id = create_user(&pool, "ursula").await;
+        let client_id = create_client(&pool, user_id).await;
+        let token = create_refresh_token(&pool, client_id, user_id).await;
+        let mut app = TestApp::new(pool).await;
+
+        let (status, body) = refresh(&mut app, client_id, &token).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["scope"], "profile");
+        let (status, body) = refresh(&mut app, client_id, &token).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_grant");
+    }
+
+    #[sqlx::test]
+    async fn other_clients_can_neither_use_nor_burn_refresh_tokens(pool: PgPool) {
+        let user_id = create_user(&pool, "ursula").await;
+        let client_id = create_client(&pool, user_id).await;
+        let other_client_id = create_client(&pool, user_id).await;
+        let token = create_refresh_token(&pool, client_id, user_id).await;
+        let mut app = TestApp::new(pool).await;
+
+        let (status, body) = refresh(&mut app, other_client_id, &token).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_grant");
+        let (status, _) = refresh(&mut app, client_id, &token).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
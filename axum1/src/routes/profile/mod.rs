@@ -0,0 +1,577 @@
+//! Profiles: what users tell about themselves, and their public author pages.
+//!
+//! Names aren't unique, so author pages can also be looked up by the user's ID, which every
+//! profile includes. A name shared by several accounts is a conflict.
+use axum::{
+    extract::Path,
+    http::HeaderMap,
+    response::Response,
+    routing::{get, put},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, PgConnection};
+use validator::Validate;
+
+use crate::{
+    error::ApiError,
+    extractors::{AuthUser, DatabaseConnection, MaybeAuthUser},
+    state::AppState,
+    upload::{self, scan::ScanStatus},
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/me", get(me))
+        .route("/me/profile", put(update_profile))
+        .route("/me/avatar", put(set_avatar).delete(delete_avatar))
+        .route("/u/:name", get(author_page))
+        .route("/u/:name/avatar", get(avatar))
+}
+
+#[derive(Serialize)]
+pub(super) struct Profile {
+    id: uuid::Uuid,
+    name: String,
+    display_name: Option<String>,
+    bio: Option<String>,
+    /// Served at `/u/:name/avatar`, once the scanner has cleared it.
+    has_avatar: bool,
+    preferred_cuisines: Vec<String>,
+    joined_at: DateTime<Utc>,
+}
+
+async fn find_profile(conn: &mut PgConnection, user_id: uuid::Uuid) -> Result<Profile, ApiError> {
+    let profile = sqlx::query!(
+        r#"
+        SELECT u.user_id, u.name, p.display_name, p.bio, u.created_at,
+            up.file_name IS NOT NULL AS "has_avatar!"
+        FROM users u
+        LEFT JOIN user_profiles p ON p.user_id = u.user_id
+        LEFT JOIN uploads up ON up.uploader_id = p.user_id
+            AND up.file_name = p.avatar_file_name
+            AND up.scan_status = 'clean'
+        WHERE u.user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    let preferred_cuisines = sqlx::query_scalar!(
+        r#"
+        SELECT c.name FROM user_preferred_cuisines pc
+        INNER JOIN cuisines c ON c.id = pc.cuisine_id
+        WHERE pc.user_id = $1
+        ORDER BY c.name
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(Profile {
+        id: profile.user_id,
+        name: profile.name,
+        display_name: profile.display_name,
+        bio: profile.bio,
+        has_avatar: profile.has_avatar,
+        preferred_cuisines,
+        joined_at: profile.created_at,
+    })
+}
+
+/// The profile of whoever is logged in, if anyone.
+async fn me(
+    maybe_auth_user: MaybeAuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<Option<Profile>>, ApiError> {
+    let Some(auth_user) = maybe_auth_user.into_inner() else {
+        return Ok(Json(None));
+    };
+    let profile = find_profile(&mut conn, *auth_user).await?;
+    Ok(Json(Some(profile)))
+}
+
+#[derive(Deserialize, Validate)]
+pub(super) struct UpdateProfile {
+    #[validate(length(min = 1, max = 60, message = "must be between 1 and 60 characters"))]
+    display_name: Option<String>,
+    #[validate(length(max = 500, message = "must be at most 500 characters"))]
+    bio: Option<String>,
+    #[validate(length(max = 10, message = "at most 10 cuisines can be chosen"))]
+    #[serde(default)]
+    preferred_cuisines: Vec<String>,
+}
+
+/// Replaces the profile, blank fields are cleared. The avatar is set separately.
+async fn update_profile(
+    auth_user: AuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(mut form): Json<UpdateProfile>,
+) -> Result<Json<Profile>, ApiError> {
+    let blank_to_none = |value: Option<String>| {
+        value
+            .map(|value| value.trim().to_owned())
+            .filter(|value| !value.is_empty())
+    };
+    form.display_name = blank_to_none(form.display_name);
+    form.bio = blank_to_none(form.bio);
+    form.validate()
+        .map_err(ApiError::unprocessable_entity_from_validation_errors)?;
+
+    let mut tx = conn.begin().await?;
+    let unknown = sqlx::query_scalar!(
+        r#"
+        SELECT requested.name AS "name!" FROM UNNEST($1::TEXT[]) AS requested (name)
+        WHERE NOT EXISTS (SELECT 1 FROM cuisines c WHERE c.name = requested.name)
+        "#,
+        &form.preferred_cuisines
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if !unknown.is_empty() {
+        return Err(ApiError::unprocessable_entity([(
+            "preferred_cuisines",
+            format!("unknown cuisines: {}", unknown.join(", ")),
+        )]));
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO user_profiles (user_id, display_name, bio)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id) DO UPDATE SET display_name = $2, bio = $3
+        "#,
+        *auth_user,
+        form.display_name,
+        form.bio,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM user_preferred_cuisines WHERE user_id = $1",
+        *auth_user
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO user_preferred_cuisines (user_id, cuisine_id)
+        SELECT $1, id FROM cuisines WHERE name = ANY($2)
+        "#,
+        *auth_user,
+        &form.preferred_cuisines
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let profile = find_profile(&mut tx, *auth_user).await?;
+    tx.commit().await?;
+    Ok(Json(profile))
+}
+
+#[derive(Deserialize)]
+pub(super) struct SetAvatar {
+    /// One of the user's own uploads.
+    file_name: String,
+}
+
+/// Uses an upload as the avatar. Uploads that are still being scanned show up once they're clean.
+async fn set_avatar(
+    auth_user: AuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(form): Json<SetAvatar>,
+) -> Result<(), ApiError> {
+    let scan_status = sqlx::query_scalar!(
+        r#"
+        SELECT scan_status AS "scan_status: ScanStatus" FROM uploads
+        WHERE uploader_id = $1 AND file_name = $2
+        "#,
+        *auth_user,
+        form.file_name
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    match scan_status {
+        None => {
+            return Err(ApiError::unprocessable_entity([(
+                "file_name",
+                "there's no upload with this name",
+            )]))
+        }
+        Some(ScanStatus::Quarantined) => {
+            return Err(ApiError::unprocessable_entity([(
+                "file_name",
+                "the upload was quarantined",
+            )]))
+        }
+        Some(ScanStatus::Pending | ScanStatus::Clean) => {}
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO user_profiles (user_id, avatar_file_name)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET avatar_file_name = $2
+        "#,
+        *auth_user,
+        form.file_name,
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Removes the avatar. The upload itself is kept.
+async fn delete_avatar(
+    auth_user: AuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<(), ApiError> {
+    sqlx::query!(
+        "UPDATE user_profiles SET avatar_file_name = NULL WHERE user_id = $1",
+        *auth_user
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Finds the user `/u/:name` is about, by ID or by name.
+async fn find_author(conn: &mut PgConnection, name: &str) -> Result<uuid::Uuid, ApiError> {
+    if let Ok(user_id) = name.parse::<uuid::Uuid>() {
+        return sqlx::query_scalar!("SELECT user_id FROM users WHERE user_id = $1", user_id)
+            .fetch_optional(conn)
+            .await?
+            .ok_or(ApiError::NotFound);
+    }
+    let user_ids = sqlx::query_scalar!("SELECT user_id FROM users WHERE name = $1 LIMIT 2", name)
+        .fetch_all(conn)
+        .await?;
+    match user_ids.as_slice() {
+        [] => Err(ApiError::NotFound),
+        [user_id] => Ok(*user_id),
+        _ => Err(ApiError::Conflict),
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct AuthorRecipe {
+    name: String,
+    description: String,
+    cuisine: String,
+    favorites: i64,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub(super) struct ContributionStats {
+    recipes: usize,
+    /// How many times the author's recipes were favorited, altogether.
+    favorites_received: i64,
+    ingredients_created: i64,
+    accepted_suggestions: i32,
+}
+
+#[derive(Serialize)]
+pub(super) struct AuthorPage {
+    #[serde(flatten)]
+    profile: Profile,
+    stats: ContributionStats,
+    recipes: Vec<AuthorRecipe>,
+}
+
+/// An author's public page: their profile, recipes and what they contributed.
+#[tracing::instrument(skip(conn))]
+async fn author_page(
+    Path(name): Path<String>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+) -> Result<Json<AuthorPage>, ApiError> {
+    let user_id = find_author(&mut conn, &name).await?;
+    let profile = find_profile(&mut conn, user_id).await?;
+
+    let recipes = sqlx::query_as!(
+        AuthorRecipe,
+        r#"
+        SELECT r.name, r.description, c.name AS cuisine, r.created_at,
+            COUNT(fr.user_id) AS "favorites!"
+        FROM recipes r
+        INNER JOIN cuisines c ON c.id = r.cuisine_id
+        LEFT JOIN favorite_recipe fr ON fr.recipe_id = r.id
+        WHERE r.creator_id = $1
+        GROUP BY r.id, c.name
+        ORDER BY r.created_at DESC
+        "#,
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let contributions = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM ingredients WHERE creator_id = $1) AS "ingredients_created!",
+            COALESCE(
+                (SELECT accepted_suggestions FROM user_profiles WHERE user_id = $1), 0
+            ) AS "accepted_suggestions!"
+        "#,
+        user_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let stats = ContributionStats {
+        recipes: recipes.len(),
+        favorites_received: recipes.iter().map(|recipe| recipe.favorites).sum(),
+        ingredients_created: contributions.ingredients_created,
+        accepted_suggestions: contributions.accepted_suggestions,
+    };
+    Ok(Json(AuthorPage {
+        profile,
+        stats,
+        recipes,
+    }))
+}
+
+/// Avatars are public, unlike other uploads.
+async fn avatar(
+    Path(name): Path<String>,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let user_id = find_author(&mut conn, &name).await?;
+    let file_name = sqlx::query_scalar!(
+        "SELECT avatar_file_name FROM user_profiles WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .flatten()
+    .ok_or(ApiError::NotFound)?;
+
+    // Not cleared by the scanner yet, as far as anyone else is concerned there's no avatar.
+    upload::serve(&mut conn, user_id, &file_name, &headers)
+        .await
+        .map_err(|e| match e {
+            ApiError::Forbidden => ApiError::NotFound,
+            e => e,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::Path,
+        http::{Method, StatusCode},
+    };
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use crate::{
+        extractors::DatabaseConnection,
+        routes::ingredient::suggestion::apply_suggestion,
+        testing::{create_user, create_user_with_password, TestApp},
+    };
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    async fn logged_in(pool: &PgPool) -> (TestApp, uuid::Uuid) {
+        let user_id = create_user_with_password(pool, "ursula", PASSWORD).await;
+        let mut app = TestApp::new(pool.clone()).await;
+        let (status, _) = app.log_in("ursula@example.com", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK);
+        (app, user_id)
+    }
+
+    async fn create_recipe(pool: &PgPool, creator_id: uuid::Uuid) -> uuid::Uuid {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO recipes
+                (name, description, creator_id, prep_time, cook_time, difficulty, steps, cuisine_id,
+                meal_type)
+            SELECT 'Pasta', '', $1, 10, 10, 'easy', '{}', id, 'dinner'
+            FROM cuisines WHERE name = 'Italian'
+            RETURNING id
+            "#,
+            creator_id,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn create_ingredient(pool: &PgPool, name: &str, creator_id: uuid::Uuid) -> uuid::Uuid {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO ingredients
+                (name, original_name, calories_per_100g, protein, water, fat, sugar, carbohydrate,
+                fiber, caffeine, contains_alcohol, creator_id)
+            VALUES ($1, $1, 100, 1, 1, 1, 1, 1, 1, 0, FALSE, $2)
+            RETURNING id
+            "#,
+            name,
+            creator_id,
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn me_is_whoever_is_logged_in(pool: PgPool) {
+        let mut app = TestApp::new(pool.clone()).await;
+        let (status, body) = app.submit(Method::GET, "/me", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::Value::Null);
+
+        let (mut app, user_id) = logged_in(&pool).await;
+        let (_, body) = app.submit(Method::GET, "/me", &[]).await;
+        assert_eq!(body["id"], user_id.to_string());
+        assert_eq!(body["name"], "ursula");
+        assert_eq!(body["has_avatar"], false);
+    }
+
+    #[sqlx::test]
+    async fn profiles_are_replaced_and_blank_fields_cleared(pool: PgPool) {
+        let (mut app, _) = logged_in(&pool).await;
+
+        let (status, body) = app
+            .send(
+                Method::PUT,
+                "/me/profile",
+                json!({
+                    "display_name": "Ursula K.",
+                    "bio": "Mostly soups.",
+                    "preferred_cuisines": ["Italian"],
+                }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["display_name"], "Ursula K.");
+        assert_eq!(body["bio"], "Mostly soups.");
+        assert_eq!(body["preferred_cuisines"], json!(["Italian"]));
+
+        let (status, body) = app
+            .send(Method::PUT, "/me/profile", json!({ "display_name": "  " }))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["display_name"], serde_json::Value::Null);
+        assert_eq!(body["bio"], serde_json::Value::Null);
+        assert_eq!(body["preferred_cuisines"], json!([]));
+    }
+
+    #[sqlx::test]
+    async fn unknown_cuisines_are_refused(pool: PgPool) {
+        let (mut app, _) = logged_in(&pool).await;
+
+        let (status, body) = app
+            .send(
+                Method::PUT,
+                "/me/profile",
+                json!({ "preferred_cuisines": ["Italian", "Martian"] }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body,
+            json!({ "errors": { "preferred_cuisines": ["unknown cuisines: Martian"] } })
+        );
+    }
+
+    #[sqlx::test]
+    async fn avatars_are_own_uploads(pool: PgPool) {
+        let (mut app, _) = logged_in(&pool).await;
+
+        let (status, _) = app
+            .send(
+                Method::PUT,
+                "/me/avatar",
+                json!({ "file_name": "nope.png" }),
+            )
+            .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, _) = app.submit(Method::GET, "/u/ursula/avatar", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn author_pages_count_contributions(pool: PgPool) {
+        let ursula = create_user(&pool, "ursula").await;
+        let recipe_id = create_recipe(&pool, ursula).await;
+        for name in ["vera", "wanda"] {
+            let fan = create_user(&pool, name).await;
+            sqlx::query!(
+                "INSERT INTO favorite_recipe (recipe_id, user_id) VALUES ($1, $2)",
+                recipe_id,
+                fan
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        create_ingredient(&pool, "Basil", ursula).await;
+        let admin = create_user(&pool, "admin").await;
+        let tomato = create_ingredient(&pool, "Tomato", admin).await;
+        let suggestion_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO ingredient_suggestions (ingredient_id, user_id, calories_per_100g)
+            VALUES ($1, $2, 18)
+            RETURNING id
+            "#,
+            tomato,
+            ursula
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        apply_suggestion(
+            DatabaseConnection(pool.acquire().await.unwrap()),
+            Path(("Tomato".to_owned(), suggestion_id)),
+        )
+        .await
+        .unwrap();
+        let mut app = TestApp::new(pool).await;
+
+        let (status, body) = app.submit(Method::GET, "/u/ursula", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["stats"],
+            json!({
+                "recipes": 1,
+                "favorites_received": 2,
+                "ingredients_created": 1,
+                "accepted_suggestions": 1,
+            })
+        );
+        assert_eq!(body["recipes"][0]["name"], "Pasta");
+        assert_eq!(body["recipes"][0]["cuisine"], "Italian");
+        assert_eq!(body["recipes"][0]["favorites"], 2);
+    }
+
+    #[sqlx::test]
+    async fn shared_names_are_looked_up_by_id(pool: PgPool) {
+        create_user(&pool, "ursula").await;
+        let other = sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (name, email, confirmed)
+            VALUES ('ursula', 'other.ursula@example.com', TRUE)
+            RETURNING user_id
+            "#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let mut app = TestApp::new(pool).await;
+
+        let (status, _) = app.submit(Method::GET, "/u/ursula", &[]).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, body) = app.submit(Method::GET, &format!("/u/{other}"), &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["id"], other.to_string());
+        let (status, _) = app.submit(Method::GET, "/u/nobody", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -3,12 +3,15 @@ use axum::{
     extract::{FromRef, FromRequestParts, Path},
     http::request::Parts,
 };
-use tower_sessions::Session;
 
-use crate::{error::ApiError, extractors::DatabaseConnection, state::AppState};
+use crate::{
+    error::ApiError,
+    extractors::{AuthUser, DatabaseConnection},
+    state::AppState,
+};
 
 #[derive(Debug)]
-pub struct RecipeCreator(uuid::Uuid);
+pub struct RecipeCreator;
 
 #[async_trait]
 impl<S> FromRequestParts<S> for RecipeCreator
@@ -19,32 +22,27 @@ where
     type Rejection = ApiError;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let session = Session::from_request_parts(parts, state)
+        let user_id = AuthUser::from_request_parts(parts, state)
             .await
-            .expect("`SessionLayer` should be added");
+            .map_err(|_| ApiError::Forbidden)?;
 
         let Path(recipe_name) = Path::<String>::from_request_parts(parts, state)
             .await
             .expect("Recipe name is missing from the URL");
 
-        let user_id = session
-            .get::<uuid::Uuid>("user_id")
-            .await?
-            .ok_or(ApiError::Forbidden)?;
-
         let DatabaseConnection(mut conn) = DatabaseConnection::from_request_parts(parts, state)
             .await
             .expect("Database extension is missing");
 
         sqlx::query!(
             "SELECT 1 AS _e FROM recipes WHERE creator_id = $1 AND name = $2",
-            user_id,
+            *user_id,
             recipe_name
         )
         .fetch_optional(&mut *conn)
         .await?
         .ok_or(ApiError::Forbidden)?;
 
-        Ok(Self(user_id))
+        Ok(Self)
     }
 }
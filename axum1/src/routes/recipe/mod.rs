@@ -1,9 +1,10 @@
 use anyhow::Context;
 use axum::{
+    async_trait,
     extract::{Json, Path, Query, State},
     http::StatusCode,
     routing::{get, post},
-    Router,
+    Extension, Router,
 };
 use axum_extra::extract::Form;
 use sqlx::{types::BigDecimal, Acquire};
@@ -12,6 +13,9 @@ use validator::Validate;
 use crate::{
     error::{ApiError, ResultExt},
     extractors::{AuthUser, DatabaseConnection, MaybeAuthUser},
+    queue::{enqueue_once, Job, JobContext},
+    routes::oauth_server::OAuthResource,
+    search::IndexSearch,
     sse::Notification,
     state::AppState,
     RE_RECIPE,
@@ -27,19 +31,27 @@ mod extractors;
 pub fn router() -> Router<AppState> {
     let action_router = Router::new()
         .route("/my-recipes", get(my_recipes))
-        .route("/favorites", get(my_favorite_recipes))
+        .route(
+            "/favorites",
+            get(my_favorite_recipes).layer(Extension(OAuthResource::Favorites)),
+        )
         .route("/popular", get(most_popular_recipes))
         .route("/hot", get(hot_recipes));
 
+    // Third-party apps may use these with the matching scopes. The innermost marker wins.
     Router::new()
         .route("/", post(insert_full_recipe))
         .route("/:name", get(get_recipe_with_ingredients))
-        .route("/:name/favorite", post(toggle_favorite_recipe))
+        .route(
+            "/:name/favorite",
+            post(toggle_favorite_recipe).layer(Extension(OAuthResource::Favorites)),
+        )
         .route(
             "/:name/ingredient",
             post(add_or_update_ingredient_to_recipe).delete(delete_ingredient_from_recipe),
         )
         .nest("/action", action_router)
+        .layer(Extension(OAuthResource::Recipes))
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, sqlx::FromRow)]
@@ -190,12 +202,6 @@ async fn get_recipe_with_ingredients(
     }))
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, sqlx::FromRow)]
-struct Recipe {
-    name: String,
-    description: String,
-}
-
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, sqlx::FromRow)]
 struct RecipeFull {
     name: String,
@@ -377,6 +383,12 @@ async fn insert_full_recipe(
         })?;
     }
 
+    // Make the new recipe searchable without waiting for the next periodic indexing run. Every
+    // run reindexes everything, so one that's waiting already covers this recipe too.
+    enqueue_once(&mut *tx, &IndexSearch)
+        .await
+        .context("Failed to enqueue search indexing")?;
+
     tx.commit().await?;
 
     channel.send(Notification::new_recipe(name)).unwrap();
@@ -494,6 +506,7 @@ async fn most_popular_recipes(
     Ok(Json(results))
 }
 
+/// Reads from the `hot_recipes` materialized view, which is kept up to date by `RefreshHotRecipes`.
 #[tracing::instrument(skip(conn))]
 async fn hot_recipes(
     DatabaseConnection(mut conn): DatabaseConnection,
@@ -503,11 +516,8 @@ async fn hot_recipes(
     let results = sqlx::query_as!(
         RecipeWithFavoriteCount,
         r#"
-        SELECT r.name, COUNT(fr.recipe_id) FROM favorite_recipe fr
-        INNER JOIN recipes r ON r.id = fr.recipe_id
-        WHERE fr.created_at > current_timestamp - INTERVAL '14 days'
-        GROUP BY r.name
-        ORDER BY count DESC
+        SELECT name AS "name!", favorites AS count FROM hot_recipes
+        ORDER BY favorites DESC
         LIMIT $1
         "#,
         limit
@@ -517,3 +527,19 @@ async fn hot_recipes(
 
     Ok(Json(results))
 }
+
+/// Recomputes the most favorited recipes of the last two weeks.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RefreshHotRecipes;
+
+#[async_trait]
+impl Job for RefreshHotRecipes {
+    const JOB_TYPE: &'static str = "refresh_hot_recipes";
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        sqlx::query!("REFRESH MATERIALIZED VIEW CONCURRENTLY hot_recipes")
+            .execute(&ctx.pool)
+            .await?;
+        Ok(())
+    }
+}
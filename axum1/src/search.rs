@@ -1,41 +1,11 @@
+use axum::async_trait;
 use meilisearch_sdk::client::Client;
 use sqlx::{Pool, Postgres};
 
-use crate::{config::Settings, queue::get_connection_pool, routes::ingredient::FoodCategory};
-
-pub async fn run_meili_indexer_until_stopped(
-    mut config: tokio::sync::watch::Receiver<Settings>,
-) -> Result<(), anyhow::Error> {
-    let Settings {
-        database, meili, ..
-    } = config.borrow_and_update().clone();
-    let meili_client = Client::new(meili.url, Some(meili.master_key))?;
-    let pool = get_connection_pool(&database);
-    // TODO: These defaults are hidden here, maybe there's a better place for them?
-    let retry_seconds = meili.retry_seconds.unwrap_or(60);
-    let max_retries = meili.max_retries.unwrap_or(5);
-    let indexing_interval_seconds = meili.indexing_interval_seconds.unwrap_or(3600);
-    let mut current_retries = 0;
-    // I don't really know whether this is a good idea yet. Maybe the whole MeiliSearch indexing should be its own crate.
-    loop {
-        match run_meili_indexer(&pool, &meili_client).await {
-            Ok(_) => {
-                tokio::time::sleep(std::time::Duration::from_secs(indexing_interval_seconds)).await;
-            }
-            Err(e) => {
-                if max_retries > current_retries {
-                    current_retries += 1;
-                    let left = max_retries - current_retries;
-                    tracing::error!(error.message = %e, "Failed search indexing, retrying in {retry_seconds} seconds ({left} retries left).");
-                    tokio::time::sleep(std::time::Duration::from_secs(retry_seconds)).await;
-                    continue;
-                } else {
-                    return Err(e);
-                }
-            }
-        }
-    }
-}
+use crate::{
+    queue::{Job, JobContext},
+    routes::ingredient::FoodCategory,
+};
 
 pub async fn run_meili_indexer(
     pool: &Pool<Postgres>,
@@ -51,6 +21,21 @@ pub async fn run_meili_indexer(
     Ok(())
 }
 
+/// Reindexes everything in MeiliSearch. Runs on the `search_index` schedule, and is also enqueued
+/// when the data changed and waiting for the next scheduled run isn't good enough.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct IndexSearch;
+
+#[async_trait]
+impl Job for IndexSearch {
+    const JOB_TYPE: &'static str = "search_index";
+    const PRIORITY: i32 = -10;
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        run_meili_indexer(&ctx.pool, &ctx.meili_client).await
+    }
+}
+
 async fn meili_indexing_task<T: serde::Serialize + Sync + Send>(
     client: &Client,
     records: &[T],
@@ -1,22 +1,22 @@
 use crate::{
     config::Settings,
-    email::EmailClient,
-    routes::{admin, auth, ingredient, recipe},
+    rate_limit::RateLimiter,
+    routes::{admin, auth, email, ingredient, oauth_server, profile, recipe},
     sse::{sse_handler, Notification},
     state::AppState,
     upload,
-    utils::{oauth_client_discord, oauth_client_google, shutdown_signal},
+    utils::{shutdown_signal, webauthn},
 };
 use anyhow::Context;
 use axum::{
     http::HeaderValue,
+    middleware,
     routing::{get, get_service},
     Extension, Router,
 };
 use axum_prometheus::PrometheusMetricLayerBuilder;
 use sqlx::postgres::PgPoolOptions;
 use std::{net::SocketAddr, sync::Arc};
-use time::Duration;
 use tokio::net::TcpListener;
 use tower_http::{cors::CorsLayer, services::ServeDir, trace::TraceLayer};
 use tower_sessions::{Expiry, SessionManagerLayer};
@@ -34,8 +34,12 @@ pub async fn application(
         config.application_settings.port,
     ));
 
-    let discord_oauth_client = oauth_client_discord(&config);
-    let google_oauth_client = oauth_client_google(&config);
+    let oauth_providers =
+        auth::OAuthProviders::new(&config).expect("Invalid OAuth provider configuration");
+    let webauthn = webauthn(&config);
+    let oauth_signing_key = Arc::new(
+        oauth_server::SigningKey::new(&config.oauth_server).expect("Invalid OAuth signing key"),
+    );
 
     let db_conn_str = config.database.connection_string();
 
@@ -62,15 +66,31 @@ pub async fn application(
     pool.wait_for_connect().await?;
     tracing::debug!("redis connected.");
 
+    let rate_limiter = RateLimiter::new(pool.clone());
+    let login_throttle = auth::LoginThrottle::new(pool.clone());
     let session_store = RedisStore::new(pool);
+    let sessions = auth::SessionIndex::new(session_store.clone());
     let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(
-            std::env::var("APP_ENVIRONMENT").unwrap_or_else(|_| String::from("local"))
-                == "production",
-        )
-        .with_expiry(Expiry::OnInactivity(Duration::minutes(10)));
+        .with_name(config.session.cookie_name.clone())
+        .with_secure(config.session.secure())
+        .with_same_site(config.session.same_site.into())
+        // Remembered sessions extend this, see `auth::track_session`.
+        .with_expiry(Expiry::OnInactivity(config.session.inactivity_timeout()));
+    let session_layer = match &config.session.domain {
+        Some(domain) => session_layer.with_domain(domain.clone()),
+        None => session_layer,
+    };
 
-    let email_client = EmailClient::from_config(config.email_client);
+    let email_client = config.email_client(db_pool.clone());
+
+    let totp_secrets = auth::TotpSecrets::new(
+        config
+            .auth
+            .totp_secret_key
+            .as_ref()
+            .context("auth.totp_secret_key is not set")?,
+    );
+    auth::encrypt_plaintext_secrets(&db_pool, &totp_secrets).await?;
 
     let (metric_layer, metric_handle) = PrometheusMetricLayerBuilder::new()
         .with_ignore_pattern("/admin")
@@ -85,6 +105,10 @@ pub async fn application(
         db_pool,
         config: dynamic_cfg,
         email_client,
+        rate_limiter,
+        login_throttle,
+        sessions,
+        totp_secrets,
         tx,
         rx,
     };
@@ -97,13 +121,21 @@ pub async fn application(
         .nest("/", auth::router())
         .nest("/admin", admin::router(app_state.clone()))
         .nest("/upload", upload::router(app_state.clone()))
+        .nest("/email", email::router())
+        .merge(oauth_server::router())
+        .merge(profile::router())
         .fallback_service(get_service(ServeDir::new("static")))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::track_session,
+        ))
         .layer(
             tower::ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
                 .layer(metric_layer)
-                .layer(Extension(discord_oauth_client))
-                .layer(Extension(google_oauth_client))
+                .layer(Extension(oauth_providers))
+                .layer(Extension(webauthn))
+                .layer(Extension(oauth_signing_key))
                 .layer(
                     CorsLayer::very_permissive()
                         .allow_origin(config.frontend_url.parse::<HeaderValue>().unwrap())
@@ -116,8 +148,11 @@ pub async fn application(
     let listener = TcpListener::bind(&addr).await.unwrap();
 
     tracing::debug!(%addr, "listening");
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .context("Failed to start server")
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .context("Failed to start server")
 }
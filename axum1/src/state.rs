@@ -6,7 +6,13 @@ use tokio::sync::{
     watch,
 };
 
-use crate::{config::Settings, email::EmailClient, sse::Notification};
+use crate::{
+    config::Settings,
+    email::EmailClient,
+    rate_limit::RateLimiter,
+    routes::auth::{LoginThrottle, SessionIndex, TotpSecrets},
+    sse::Notification,
+};
 
 #[derive(Clone)]
 pub struct AppState {
@@ -15,4 +21,8 @@ pub struct AppState {
     pub tx: Arc<broadcast::Sender<Notification>>,
     pub rx: Arc<broadcast::Receiver<Notification>>,
     pub email_client: EmailClient,
+    pub rate_limiter: RateLimiter,
+    pub login_throttle: LoginThrottle,
+    pub sessions: SessionIndex,
+    pub totp_secrets: TotpSecrets,
 }
@@ -0,0 +1,397 @@
+//! Helpers for tests that run against a database, which `#[sqlx::test]` creates and migrates for
+//! each test.
+//!
+//! Request-level tests go through [`TestApp`], which serves the API like the real server does,
+//! with Redis replaced by [`FakeRedis`] and emails kept in memory.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, Method, Request, StatusCode},
+    middleware, Extension, Router,
+};
+use meilisearch_sdk::client::Client;
+use once_cell::sync::Lazy;
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::PgPool;
+use tower::ServiceExt;
+use tower_sessions::{Expiry, SessionManagerLayer};
+use tower_sessions_redis_store::{
+    fred::{
+        mocks::{MockCommand, Mocks},
+        prelude::*,
+    },
+    RedisStore,
+};
+
+use crate::{
+    config::{Settings, TokenSettings},
+    email::{unsubscribe::UnsubscribeLinks, EmailClient, InMemoryTransport},
+    queue::JobContext,
+    rate_limit::RateLimiter,
+    routes::{
+        auth::{self, password::compute_password_hash},
+        email, oauth_server, profile,
+    },
+    state::AppState,
+    upload::scan::Scanner,
+    utils::webauthn,
+};
+
+/// Creates a confirmed user without a password, named `name` with the address `name@example.com`.
+pub(crate) async fn create_user(pool: &PgPool, name: &str) -> uuid::Uuid {
+    sqlx::query_scalar!(
+        "INSERT INTO users (name, email, confirmed) VALUES ($1, $2, TRUE) RETURNING user_id",
+        name,
+        format!("{name}@example.com"),
+    )
+    .fetch_one(pool)
+    .await
+    .expect("Failed to create a test user")
+}
+
+/// Creates a confirmed user like [`create_user`], who logs in with `password`.
+pub(crate) async fn create_user_with_password(
+    pool: &PgPool,
+    name: &str,
+    password: &str,
+) -> uuid::Uuid {
+    let user_id = create_user(pool, name).await;
+    let password_hash = compute_password_hash(SecretString::from(password.to_owned())).unwrap();
+    sqlx::query!(
+        "UPDATE users SET password_hash = $2 WHERE user_id = $1",
+        user_id,
+        password_hash.expose_secret(),
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to set the password of a test user");
+    user_id
+}
+
+/// An email client that keeps what it sends in `transport`.
+pub(crate) fn email_client(pool: PgPool, transport: InMemoryTransport) -> EmailClient {
+    let unsubscribe = UnsubscribeLinks::new(
+        &SecretString::from("unsubscribe secret".to_owned()),
+        "http://localhost:8000".to_owned(),
+        "http://localhost:3000".to_owned(),
+    );
+    EmailClient::new(
+        "recipes@example.com".to_owned(),
+        Arc::new(transport),
+        pool,
+        unsubscribe,
+    )
+}
+
+/// A job context that keeps emails in memory and scans uploads with `scanner`.
+pub(crate) fn job_context(pool: PgPool, scanner: Arc<dyn Scanner>) -> JobContext {
+    JobContext {
+        email_client: email_client(pool.clone(), InMemoryTransport::new()),
+        pool,
+        scanner,
+        meili_client: Client::new("http://localhost:7700", None::<String>)
+            .expect("Failed to create a Meilisearch client"),
+        frontend_url: "http://localhost:3000".to_owned(),
+        tokens: TokenSettings::default(),
+    }
+}
+
+/// The settings CI runs with, which don't need anything but Postgres.
+pub(crate) fn settings() -> Settings {
+    config::Config::builder()
+        .add_source(config::File::with_name(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/configuration/ci.yml"
+        )))
+        .build()
+        .and_then(config::Config::try_deserialize)
+        .expect("Failed to load configuration/ci.yml")
+}
+
+/// Keeps values in memory, with just enough commands for the session store, the rate limiter and
+/// the login throttle.
+#[derive(Debug, Default)]
+pub(crate) struct FakeRedis {
+    values: Mutex<HashMap<String, (RedisValue, Option<Instant>)>>,
+}
+
+impl Mocks for FakeRedis {
+    fn process_command(&self, command: MockCommand) -> Result<RedisValue, RedisError> {
+        let mut values = self.values.lock().unwrap();
+        let now = Instant::now();
+        values.retain(|_, (_, expires_at)| expires_at.is_none_or(|at| at > now));
+
+        let mut args = command.args.into_iter();
+        let mut string = || {
+            args.next()
+                .and_then(|arg| arg.as_string())
+                .ok_or_else(|| RedisError::new(RedisErrorKind::InvalidArgument, "Missing argument"))
+        };
+        let seconds = |arg: String| {
+            arg.parse::<u64>()
+                .map_err(|_| RedisError::new(RedisErrorKind::InvalidArgument, "Not a number"))
+        };
+        match &*command.cmd {
+            "GET" => Ok(values
+                .get(&string()?)
+                .map_or(RedisValue::Null, |(value, _)| value.clone())),
+            "SET" => {
+                let key = string()?;
+                let value = args
+                    .next()
+                    .ok_or_else(|| RedisError::new(RedisErrorKind::InvalidArgument, "No value"))?;
+                let mut args = args.filter_map(|arg| arg.as_string());
+                let mut expires_at = None;
+                while let Some(option) = args.next() {
+                    let mut argument = || args.next().map(seconds).transpose();
+                    match option.as_str() {
+                        "EX" => expires_at = argument()?.map(|s| now + Duration::from_secs(s)),
+                        "EXAT" => {
+                            let at = argument()?.unwrap_or_default() as i64;
+                            let left = at - chrono::Utc::now().timestamp();
+                            expires_at = Some(now + Duration::from_secs(left.max(0) as u64));
+                        }
+                        "NX" if values.contains_key(&key) => return Ok(RedisValue::Null),
+                        "XX" if !values.contains_key(&key) => return Ok(RedisValue::Null),
+                        _ => {}
+                    }
+                }
+                values.insert(key, (value, expires_at));
+                Ok(RedisValue::new_ok())
+            }
+            "DEL" => {
+                let mut deleted = 0;
+                while let Ok(key) = string() {
+                    deleted += i64::from(values.remove(&key).is_some());
+                }
+                Ok(deleted.into())
+            }
+            "INCR" => {
+                let (value, _) = values
+                    .entry(string()?)
+                    .or_insert((RedisValue::Integer(0), None));
+                let incremented = value.as_i64().unwrap_or_default() + 1;
+                *value = incremented.into();
+                Ok(incremented.into())
+            }
+            "EXPIRE" => {
+                let key = string()?;
+                let expires_at = now + Duration::from_secs(seconds(string()?)?);
+                Ok(match values.get_mut(&key) {
+                    Some((_, at)) => {
+                        *at = Some(expires_at);
+                        1.into()
+                    }
+                    None => 0.into(),
+                })
+            }
+            "TTL" => Ok(match values.get(&string()?) {
+                Some((_, Some(at))) => (at.duration_since(now).as_secs_f64().ceil() as i64).into(),
+                Some((_, None)) => (-1).into(),
+                None => (-2).into(),
+            }),
+            cmd => Err(RedisError::new(
+                RedisErrorKind::Unknown,
+                format!("FakeRedis doesn't know {cmd}"),
+            )),
+        }
+    }
+}
+
+/// Generating RSA keys takes a while, so every test shares one.
+static SIGNING_KEY: Lazy<Arc<oauth_server::SigningKey>> = Lazy::new(|| {
+    Arc::new(
+        oauth_server::SigningKey::new(&settings().oauth_server)
+            .expect("Failed to create an OAuth signing key"),
+    )
+});
+
+/// The API, served from memory with a cookie jar, as a browser would use it. A clone shares the
+/// app and copies the cookies, like a stolen session would.
+#[derive(Clone)]
+pub(crate) struct TestApp {
+    pub state: AppState,
+    pub emails: InMemoryTransport,
+    /// The address requests are made from.
+    pub ip: IpAddr,
+    router: Router,
+    cookies: HashMap<String, String>,
+}
+
+impl TestApp {
+    pub async fn new(pool: PgPool) -> Self {
+        Self::with_settings(pool, |_| {}).await
+    }
+
+    pub async fn with_settings(pool: PgPool, configure: impl FnOnce(&mut Settings)) -> Self {
+        let mut config = settings();
+        configure(&mut config);
+
+        let redis = RedisPool::new(
+            RedisConfig {
+                mocks: Some(Arc::new(FakeRedis::default())),
+                ..Default::default()
+            },
+            None,
+            None,
+            None,
+            1,
+        )
+        .expect("Failed to create the Redis pool");
+        redis.init().await.expect("Failed to connect to FakeRedis");
+
+        let emails = InMemoryTransport::new();
+        let session_store = RedisStore::new(redis.clone());
+        let state = AppState {
+            email_client: email_client(pool.clone(), emails.clone()),
+            db_pool: pool,
+            rate_limiter: RateLimiter::new(redis.clone()),
+            login_throttle: auth::LoginThrottle::new(redis),
+            sessions: auth::SessionIndex::new(session_store.clone()),
+            totp_secrets: auth::TotpSecrets::new(
+                config
+                    .auth
+                    .totp_secret_key
+                    .as_ref()
+                    .expect("auth.totp_secret_key is set in CI"),
+            ),
+            tx: Arc::new(tokio::sync::broadcast::channel(16).0),
+            rx: Arc::new(tokio::sync::broadcast::channel(16).1),
+            config: tokio::sync::watch::channel(config.clone()).1,
+        };
+
+        let session_layer = SessionManagerLayer::new(session_store)
+            .with_name(config.session.cookie_name.clone())
+            .with_secure(false)
+            .with_expiry(Expiry::OnInactivity(config.session.inactivity_timeout()));
+        let router = Router::new()
+            .merge(auth::router())
+            .nest("/email", email::router())
+            .merge(oauth_server::router())
+            .merge(profile::router())
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                auth::track_session,
+            ))
+            .layer(Extension(
+                auth::OAuthProviders::new(&config).expect("Invalid OAuth providers"),
+            ))
+            .layer(Extension(webauthn(&config)))
+            .layer(Extension(Arc::clone(&SIGNING_KEY)))
+            .layer(session_layer)
+            .with_state(state.clone());
+
+        Self {
+            state,
+            emails,
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            router,
+            cookies: HashMap::new(),
+        }
+    }
+
+    /// Another browser using the same app, without cookies.
+    pub fn another_browser(&self) -> Self {
+        Self {
+            cookies: HashMap::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Sends a request with the cookies of earlier responses, and keeps the ones it gets back.
+    /// The body is `null` if the response has none, a string if it isn't JSON.
+    pub async fn request(&mut self, mut request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::new(self.ip, 54321)));
+        if !self.cookies.is_empty() {
+            let cookies = self
+                .cookies
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join("; ");
+            request
+                .headers_mut()
+                .insert(header::COOKIE, cookies.parse().unwrap());
+        }
+
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        for set_cookie in response.headers().get_all(header::SET_COOKIE) {
+            let cookie =
+                tower_sessions::cookie::Cookie::parse(set_cookie.to_str().unwrap().to_owned())
+                    .unwrap();
+            let removed = cookie
+                .max_age()
+                .is_some_and(|max_age| max_age.is_zero() || max_age.is_negative());
+            if removed {
+                self.cookies.remove(cookie.name());
+            } else {
+                self.cookies
+                    .insert(cookie.name().to_owned(), cookie.value().to_owned());
+            }
+        }
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = if body.is_empty() {
+            serde_json::Value::Null
+        } else {
+            serde_json::from_slice(&body).unwrap_or_else(|_| {
+                serde_json::Value::String(String::from_utf8_lossy(&body).into_owned())
+            })
+        };
+        (status, body)
+    }
+
+    /// Sends a JSON body, like most of the frontend does.
+    pub async fn send(
+        &mut self,
+        method: Method,
+        uri: &str,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        self.request(request).await
+    }
+
+    /// Sends a form, like the login and registration forms of the frontend do.
+    pub async fn submit(
+        &mut self,
+        method: Method,
+        uri: &str,
+        form: &[(&str, &str)],
+    ) -> (StatusCode, serde_json::Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(serde_urlencoded::to_string(form).unwrap()))
+            .unwrap();
+        self.request(request).await
+    }
+
+    /// Logs in with a password.
+    pub async fn log_in(&mut self, email: &str, password: &str) -> (StatusCode, serde_json::Value) {
+        self.submit(
+            Method::POST,
+            "/auth",
+            &[("email", email), ("password", password)],
+        )
+        .await
+    }
+}
@@ -0,0 +1,792 @@
+use anyhow::Context;
+use axum::{
+    body::Body,
+    body::Bytes,
+    extract::{DefaultBodyLimit, Multipart, Path},
+    http::{
+        header::{ETAG, IF_NONE_MATCH},
+        HeaderMap, StatusCode,
+    },
+    middleware::from_extractor_with_state,
+    response::{IntoResponse, Response},
+    routing::{get, patch, post},
+    BoxError, Router,
+};
+use futures::{Stream, TryStreamExt};
+use sqlx::{Acquire, PgConnection};
+use std::{
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tower_http::limit::RequestBodyLimitLayer;
+
+use crate::{
+    error::ApiError,
+    extractors::{AuthUser, DatabaseConnection, Uploader},
+    queue::enqueue,
+    routes::admin::AdminUser,
+    state::AppState,
+};
+
+mod resumable;
+pub mod scan;
+
+pub use resumable::PurgeExpiredUploadSessions;
+use scan::{ScanStatus, ScanUpload};
+
+pub const UPLOADS_DIRECTORY: &str = "uploads";
+
+/// Content-addressed storage, every file lives under `blobs/<first two hex digits>/<blake3 hash>`.
+const BLOBS_DIRECTORY: &str = "blobs";
+
+/// Uploads in progress are written here first, so they can be renamed into place atomically.
+const TEMP_DIRECTORY: &str = "tmp";
+
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/:file_name", post(save_request_body))
+        .route_layer(from_extractor_with_state::<AdminUser, _>(state))
+        .route("/:file_name", get(download).delete(delete_upload))
+        .route("/", post(accept_form))
+        .route("/sessions", post(resumable::create_session))
+        .route(
+            "/sessions/:id",
+            patch(resumable::append_chunk).head(resumable::session_offset),
+        )
+        .route("/sessions/:id/finalize", post(resumable::finalize))
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(25 * 1024 * 1024)) // 25mb
+}
+
+pub async fn save_request_body(
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(file_name): Path<String>,
+    uploader: Uploader,
+    body: Body,
+) -> Result<(), ApiError> {
+    let mut tx = conn.begin().await?;
+    let (staged, _) = stream_to_file(
+        &file_name,
+        &uploader,
+        uploader.bytes_remaining,
+        body.into_data_stream(),
+        &mut tx,
+    )
+    .await?;
+
+    // On failure the staged file is dropped, which removes it from disk.
+    tx.commit().await?;
+    persist_uploads(&mut conn, vec![staged]).await
+}
+
+// Handler that accepts a multipart form upload and streams each field to a file.
+//
+// The upload is all-or-nothing: if any of the fields fails (or the fields together exceed the
+// remaining daily quota), none of the files are kept and none of the rows are inserted.
+pub async fn accept_form(
+    uploader: Uploader,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    mut multipart: Multipart,
+) -> Result<(), ApiError> {
+    let mut tx = conn.begin().await?;
+    let mut staged = Vec::new();
+    let mut bytes_remaining = uploader.bytes_remaining;
+
+    let result = async {
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .context("Failed to get next field")?
+        {
+            let Some(file_name) = field.file_name().map(ToOwned::to_owned) else {
+                continue;
+            };
+
+            let (upload, bytes) =
+                stream_to_file(&file_name, &uploader, bytes_remaining, field, &mut tx).await?;
+            bytes_remaining -= bytes;
+            staged.push(upload);
+        }
+        Ok::<_, ApiError>(())
+    }
+    .await;
+
+    // On error, dropping the transaction rolls back the `uploads` rows and dropping the staged
+    // uploads removes the files written so far.
+    result?;
+
+    tx.commit().await?;
+    persist_uploads(&mut conn, staged).await
+}
+
+pub async fn download(
+    auth_user: AuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(file_name): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    serve(&mut conn, *auth_user, &file_name, &headers).await
+}
+
+/// Streams one of `uploader_id`'s uploads, it's up to the caller who may see it.
+pub(crate) async fn serve(
+    conn: &mut PgConnection,
+    uploader_id: uuid::Uuid,
+    file_name: &str,
+    headers: &HeaderMap,
+) -> Result<Response, ApiError> {
+    let upload = sqlx::query!(
+        r#"
+        SELECT blob_hash, scan_status as "scan_status: ScanStatus" FROM uploads
+        WHERE uploader_id = $1 AND file_name = $2
+        "#,
+        uploader_id,
+        file_name
+    )
+    .fetch_optional(conn)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    // Files are only visible once the scanner has cleared them.
+    if upload.scan_status != ScanStatus::Clean {
+        return Err(ApiError::Forbidden);
+    }
+
+    let Some(hash) = upload.blob_hash else {
+        let file = File::open(legacy_path(uploader_id, file_name))
+            .await
+            .map_err(|_| ApiError::NotFound)?;
+        return Ok(Body::from_stream(ReaderStream::new(file)).into_response());
+    };
+
+    // The content hash never changes for the same bytes, so it makes a perfect strong validator.
+    let etag = format!("\"{hash}\"");
+
+    if etag_matches(headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response());
+    }
+
+    let file = File::open(blob_path(&hash))
+        .await
+        .context("Blob is missing from the disk")?;
+
+    Ok(([(ETAG, etag)], Body::from_stream(ReaderStream::new(file))).into_response())
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .any(|candidate| candidate == etag || candidate == "*")
+        })
+        .unwrap_or(false)
+}
+
+pub async fn delete_upload(
+    auth_user: AuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(file_name): Path<String>,
+) -> Result<(), ApiError> {
+    let mut tx = conn.begin().await?;
+
+    let deleted = sqlx::query!(
+        "DELETE FROM uploads WHERE uploader_id = $1 AND file_name = $2 RETURNING blob_hash",
+        *auth_user,
+        file_name
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    let orphan = match deleted.blob_hash {
+        Some(hash) => release_blob(&mut tx, &hash)
+            .await?
+            .then_some(Orphan::Blob(hash)),
+        None => Some(Orphan::Legacy(legacy_path(*auth_user, &file_name))),
+    };
+
+    sqlx::query!(
+        r#"
+        UPDATE user_profiles SET avatar_file_name = NULL
+        WHERE user_id = $1 AND avatar_file_name = $2
+        "#,
+        *auth_user,
+        file_name
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    if let Some(orphan) = orphan {
+        remove_orphan(&mut conn, orphan).await?;
+    }
+
+    Ok(())
+}
+
+/// How many bytes `user_id` has uploaded in the current daily quota window, including deleted and
+/// overwritten uploads, and resumable uploads that were started.
+pub(crate) async fn bytes_used_today(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT COALESCE(SUM(bytes), 0)::BIGINT AS "bytes_used!" FROM upload_usage
+        WHERE uploader_id = $1 AND created_at > current_timestamp - INTERVAL '1 days'
+        "#,
+        user_id
+    )
+    .fetch_one(conn)
+    .await
+}
+
+/// Counts `bytes` against the uploader's quota, unless that would exceed it.
+///
+/// Holds a per-user advisory lock until the transaction ends, so concurrent uploads of the same
+/// user are counted one after the other, each seeing what the others used.
+async fn record_usage(
+    conn: &mut PgConnection,
+    uploader: &Uploader,
+    bytes: u64,
+) -> Result<(), ApiError> {
+    let user_id = uploader.id;
+    let bytes = i64::try_from(bytes).context("Upload is too large to count")?;
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock(hashtextextended($1::TEXT, 0))",
+        format!("upload_quota:{user_id}")
+    )
+    .execute(&mut *conn)
+    .await?;
+    let bytes_used = bytes_used_today(&mut *conn, user_id).await?;
+    if bytes_used.saturating_add(bytes) > uploader.daily_limit_bytes {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    sqlx::query!(
+        "INSERT INTO upload_usage (uploader_id, bytes) VALUES ($1, $2)",
+        user_id,
+        bytes
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Drops one reference to a blob, and deletes its row once nothing refers to it anymore.
+///
+/// Returns whether the blob became orphaned, in which case its file should be removed with
+/// `remove_orphan` after the transaction has been committed.
+async fn release_blob(conn: &mut PgConnection, hash: &str) -> Result<bool, sqlx::Error> {
+    let ref_count = sqlx::query_scalar!(
+        "UPDATE upload_blobs SET ref_count = ref_count - 1 WHERE hash = $1 RETURNING ref_count",
+        hash
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if ref_count > 0 {
+        return Ok(false);
+    }
+
+    sqlx::query!("DELETE FROM upload_blobs WHERE hash = $1", hash)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(true)
+}
+
+/// Serializes moving a blob's file into place with removing it, until the transaction ends.
+///
+/// Rows are committed before files are touched, so an upload of the same contents can bring a
+/// blob back between the commit that orphaned it and the removal of its file.
+async fn lock_blob(conn: &mut PgConnection, hash: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "SELECT pg_advisory_xact_lock(hashtextextended($1::TEXT, 0))",
+        format!("upload_blob:{hash}")
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// A file that lost its last reference.
+enum Orphan {
+    Blob(String),
+    Legacy(PathBuf),
+}
+
+/// Removes the file of an orphan, unless its blob has been uploaded again in the meantime.
+async fn remove_orphan(conn: &mut PgConnection, orphan: Orphan) -> Result<(), sqlx::Error> {
+    let hash = match orphan {
+        Orphan::Blob(hash) => hash,
+        // Those belong to a single upload, nothing can bring them back.
+        Orphan::Legacy(path) => {
+            remove_file_if_exists(&path).await;
+            return Ok(());
+        }
+    };
+    let mut tx = conn.begin().await?;
+    lock_blob(&mut tx, &hash).await?;
+    let referenced = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM upload_blobs WHERE hash = $1) AS "referenced!""#,
+        hash
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if !referenced {
+        remove_file_if_exists(&blob_path(&hash)).await;
+    }
+    tx.commit().await
+}
+
+pub(crate) fn blob_path(hash: &str) -> PathBuf {
+    std::path::Path::new(UPLOADS_DIRECTORY)
+        .join(BLOBS_DIRECTORY)
+        .join(&hash[..2])
+        .join(hash)
+}
+
+// Where files uploaded before content addressing live.
+fn legacy_path(user_id: uuid::Uuid, file_name: &str) -> PathBuf {
+    std::path::Path::new(UPLOADS_DIRECTORY)
+        .join(user_id.to_string())
+        .join(file_name)
+}
+
+async fn remove_file_if_exists(path: &std::path::Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        if e.kind() != ErrorKind::NotFound {
+            tracing::error!("Failed to remove {:?}: {:?}", path, e);
+        }
+    }
+}
+
+/// An upload that has been written to a temporary file, but not yet moved to its blob location.
+///
+/// The temporary file is removed when this is dropped without being persisted, so partially
+/// written files are cleaned up on errors and when the request future is cancelled alike.
+struct StagedUpload {
+    temp_path: PathBuf,
+    hash: String,
+    /// Enqueued once the file is in place, so the scanner doesn't run before there's anything to
+    /// scan.
+    scan: ScanUpload,
+    /// What the upload has replaced, if that was its last reference.
+    orphan: Option<Orphan>,
+    remove_on_drop: bool,
+}
+
+impl StagedUpload {
+    async fn persist(mut self, conn: &mut PgConnection) -> anyhow::Result<()> {
+        let blob_path = blob_path(&self.hash);
+        if let Some(parent) = blob_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut tx = conn.begin().await?;
+        lock_blob(&mut tx, &self.hash).await?;
+        // If the blob already exists, this replaces it with identical contents.
+        tokio::fs::rename(&self.temp_path, &blob_path).await?;
+        self.remove_on_drop = false;
+        enqueue(&mut *tx, &self.scan)
+            .await
+            .context("Failed to enqueue upload scan task")?;
+        tx.commit().await?;
+
+        if let Some(orphan) = self.orphan.take() {
+            remove_orphan(conn, orphan).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StagedUpload {
+    fn drop(&mut self) {
+        if !self.remove_on_drop {
+            return;
+        }
+        let temp_path = std::mem::take(&mut self.temp_path);
+        let remove = move || {
+            if let Err(e) = std::fs::remove_file(&temp_path) {
+                if e.kind() != ErrorKind::NotFound {
+                    tracing::error!(
+                        "Failed to clean up partially written upload {:?}: {:?}",
+                        temp_path,
+                        e
+                    );
+                }
+            }
+        };
+        // Dropped on the runtime's worker threads more often than not, which mustn't block.
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(remove)),
+            Err(_) => remove(),
+        }
+    }
+}
+
+/// Moves uploads into place, once the transaction that recorded them has been committed.
+async fn persist_uploads(
+    conn: &mut PgConnection,
+    staged: Vec<StagedUpload>,
+) -> Result<(), ApiError> {
+    for upload in staged {
+        upload
+            .persist(conn)
+            .await
+            .context("Failed to move the uploaded file to its final location")?;
+    }
+
+    Ok(())
+}
+
+fn temp_directory() -> PathBuf {
+    std::path::Path::new(UPLOADS_DIRECTORY).join(TEMP_DIRECTORY)
+}
+
+/// Copies at most `limit` bytes of the stream into `writer`, passing every chunk to `inspect`
+/// on the way.
+///
+/// Returns the number of bytes copied, or `ApiError::PayloadTooLarge` as soon as the stream turns
+/// out to be longer than `limit`.
+async fn copy_limited<S, E, W>(
+    stream: S,
+    writer: &mut W,
+    limit: u64,
+    inspect: impl FnMut(&Bytes),
+) -> Result<u64, ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<BoxError>,
+    W: AsyncWrite + Unpin,
+{
+    // Convert the stream into an `AsyncRead`.
+    let body_with_io_error = stream.inspect_ok(inspect).map_err(io::Error::other);
+    let body_reader = StreamReader::new(body_with_io_error);
+    futures::pin_mut!(body_reader);
+
+    // Read at most one byte past the limit to tell an exact fit apart from an overflow.
+    let mut body_reader = body_reader.take(limit.saturating_add(1));
+
+    let bytes_copied = tokio::io::copy(&mut body_reader, writer)
+        .await
+        .map_err(|_| ApiError::BadRequest)?;
+    writer.flush().await.map_err(|_| ApiError::BadRequest)?;
+
+    if bytes_copied > limit {
+        tracing::info!("upload aborted, size limit exceeded");
+        return Err(ApiError::PayloadTooLarge);
+    }
+    Ok(bytes_copied)
+}
+
+/// Streams the body into a temporary file while hashing it, and records it in the `uploads` and
+/// `upload_blobs` tables.
+///
+/// At most `bytes_remaining` bytes are accepted. If the stream is longer than that, copying stops
+/// immediately, the partial file is removed and `ApiError::PayloadTooLarge` is returned. The same
+/// goes if uploads that ran in parallel used up the quota in the meantime.
+/// The returned `StagedUpload` must be persisted after the transaction has been committed.
+async fn stream_to_file<S, E, P>(
+    path: P,
+    uploader: &Uploader,
+    bytes_remaining: u64,
+    stream: S,
+    conn: &mut PgConnection,
+) -> Result<(StagedUpload, u64), ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Into<BoxError>,
+    P: AsRef<std::path::Path>,
+{
+    if !path_is_valid(&path) {
+        return Err(ApiError::BadRequest);
+    }
+
+    // Create the necessary directories.
+    let temp_dir = temp_directory();
+    if let Err(e) = tokio::fs::create_dir_all(temp_dir.clone()).await {
+        tracing::error!(
+            "Upload directory doesn't exist and couldn't be created: {:?}",
+            e
+        );
+    }
+    let file_name = path
+        .as_ref()
+        .to_str()
+        .expect("only valid files can be uploaded");
+    let mut staged = StagedUpload {
+        temp_path: temp_dir.join(format!("{}.part", uuid::Uuid::new_v4())),
+        hash: String::new(),
+        scan: ScanUpload {
+            uploader_id: uploader.id,
+            file_name: file_name.to_owned(),
+        },
+        orphan: None,
+        remove_on_drop: true,
+    };
+
+    let mut hasher = blake3::Hasher::new();
+    let mut file = BufWriter::new(
+        File::create(&staged.temp_path)
+            .await
+            .map_err(|_| ApiError::BadRequest)?,
+    );
+
+    let bytes_copied = copy_limited(stream, &mut file, bytes_remaining, |chunk| {
+        hasher.update(chunk);
+    })
+    .await?;
+    tracing::info!("written {bytes_copied} bytes");
+
+    let hash = hasher.finalize().to_hex().to_string();
+    record_usage(conn, uploader, bytes_copied).await?;
+    staged.orphan = record_upload(conn, uploader.id, file_name, &hash, bytes_copied).await?;
+    staged.hash = hash;
+
+    Ok((staged, bytes_copied))
+}
+
+/// Records that `user_id` has uploaded the blob `hash` under `file_name`, replacing whatever was
+/// stored under that name before. The upload starts out pending, it's scanned once it's persisted.
+///
+/// Returns what was replaced if it has no references left, it should be removed with
+/// `remove_orphan` once the transaction has been committed.
+async fn record_upload(
+    conn: &mut PgConnection,
+    user_id: uuid::Uuid,
+    file_name: &str,
+    hash: &str,
+    bytes: u64,
+) -> Result<Option<Orphan>, ApiError> {
+    let bytes = i32::try_from(bytes).context("Upload is too large for the uploads table")?;
+
+    let previous = sqlx::query!(
+        "SELECT blob_hash FROM uploads WHERE uploader_id = $1 AND file_name = $2 FOR UPDATE",
+        user_id,
+        file_name
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO upload_blobs (hash, bytes, ref_count) VALUES ($1, $2, 1)
+        ON CONFLICT (hash)
+        DO UPDATE SET ref_count = upload_blobs.ref_count + 1
+        "#,
+        hash,
+        bytes
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO uploads (uploader_id, bytes, file_name, blob_hash) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (uploader_id, file_name)
+        DO UPDATE SET
+            bytes = EXCLUDED.bytes,
+            blob_hash = EXCLUDED.blob_hash,
+            scan_status = 'pending',
+            created_at = NOW()
+        "#,
+        user_id,
+        bytes,
+        file_name,
+        hash
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let orphan = match previous.map(|r| r.blob_hash) {
+        Some(Some(previous_hash)) => release_blob(&mut *conn, &previous_hash)
+            .await?
+            .then_some(Orphan::Blob(previous_hash)),
+        Some(None) => Some(Orphan::Legacy(legacy_path(user_id, file_name))),
+        None => None,
+    };
+
+    Ok(orphan)
+}
+
+// to prevent directory traversal attacks we ensure the path consists of exactly one normal
+// component
+fn path_is_valid<P: AsRef<std::path::Path>>(path: P) -> bool {
+    let mut components = path.as_ref().components().peekable();
+
+    if let Some(first) = components.peek() {
+        if !matches!(first, std::path::Component::Normal(_)) {
+            return false;
+        }
+    }
+
+    components.count() == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::testing::create_user;
+
+    fn chunks(chunks: &[&'static str]) -> impl Stream<Item = Result<Bytes, io::Error>> {
+        futures::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[tokio::test]
+    async fn copies_streams_up_to_the_limit() {
+        let mut written = Vec::new();
+        let mut inspected = 0;
+        let copied = copy_limited(chunks(&["abc", "def"]), &mut written, 6, |chunk| {
+            inspected += chunk.len()
+        })
+        .await
+        .unwrap();
+        assert_eq!(copied, 6);
+        assert_eq!(inspected, 6);
+        assert_eq!(written, b"abcdef");
+    }
+
+    #[tokio::test]
+    async fn rejects_streams_over_the_limit() {
+        let mut written = Vec::new();
+        let result = copy_limited(chunks(&["abc", "defg"]), &mut written, 6, |_| {}).await;
+        assert!(matches!(result, Err(ApiError::PayloadTooLarge)));
+        assert!(written.len() <= 7);
+    }
+
+    #[tokio::test]
+    async fn stream_errors_are_bad_requests() {
+        let stream = futures::stream::iter([
+            Ok(Bytes::from_static(b"abc")),
+            Err(io::Error::other("connection reset")),
+        ]);
+        let result = copy_limited(stream, &mut Vec::new(), 6, |_| {}).await;
+        assert!(matches!(result, Err(ApiError::BadRequest)));
+    }
+
+    async fn upload(pool: &PgPool, user_id: uuid::Uuid, file_name: &str, contents: &str) {
+        let uploader = Uploader {
+            id: user_id,
+            bytes_remaining: 1024,
+            daily_limit_bytes: 1024,
+        };
+        let mut conn = pool.acquire().await.unwrap();
+        let mut tx = conn.begin().await.unwrap();
+        let (staged, _) = stream_to_file(
+            file_name,
+            &uploader,
+            uploader.bytes_remaining,
+            futures::stream::iter([Ok::<_, io::Error>(Bytes::from(contents.to_owned()))]),
+            &mut tx,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        persist_uploads(&mut conn, vec![staged]).await.unwrap();
+    }
+
+    async fn ref_count(pool: &PgPool, hash: &str) -> Option<i32> {
+        sqlx::query_scalar!("SELECT ref_count FROM upload_blobs WHERE hash = $1", hash)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn identical_uploads_share_a_blob_until_both_are_replaced(pool: PgPool) {
+        let alice = create_user(&pool, "alice").await;
+        let bob = create_user(&pool, "bob").await;
+        // Unique per test run, blobs are stored outside of the test database.
+        let shared = format!("shared {}", uuid::Uuid::new_v4());
+        let hash = blake3::hash(shared.as_bytes()).to_hex().to_string();
+
+        upload(&pool, alice, "a.txt", &shared).await;
+        upload(&pool, bob, "b.txt", &shared).await;
+        assert_eq!(ref_count(&pool, &hash).await, Some(2));
+        assert_eq!(std::fs::read_to_string(blob_path(&hash)).unwrap(), shared);
+
+        upload(&pool, alice, "a.txt", &uuid::Uuid::new_v4().to_string()).await;
+        assert_eq!(ref_count(&pool, &hash).await, Some(1));
+        assert!(blob_path(&hash).exists());
+
+        upload(&pool, bob, "b.txt", &uuid::Uuid::new_v4().to_string()).await;
+        assert_eq!(ref_count(&pool, &hash).await, None);
+        assert!(!blob_path(&hash).exists());
+    }
+
+    #[sqlx::test]
+    async fn orphans_that_were_uploaded_again_are_kept(pool: PgPool) {
+        let alice = create_user(&pool, "alice").await;
+        let contents = format!("orphan {}", uuid::Uuid::new_v4());
+        let hash = blake3::hash(contents.as_bytes()).to_hex().to_string();
+        upload(&pool, alice, "a.txt", &contents).await;
+
+        let mut conn = pool.acquire().await.unwrap();
+        sqlx::query!("DELETE FROM uploads WHERE uploader_id = $1", alice)
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        assert!(release_blob(&mut conn, &hash).await.unwrap());
+        // Uploaded again before the file of the orphaned blob was removed.
+        upload(&pool, alice, "b.txt", &contents).await;
+        remove_orphan(&mut conn, Orphan::Blob(hash.clone()))
+            .await
+            .unwrap();
+
+        assert_eq!(ref_count(&pool, &hash).await, Some(1));
+        assert!(blob_path(&hash).exists());
+    }
+
+    #[sqlx::test]
+    async fn scans_are_enqueued_once_the_blob_is_in_place(pool: PgPool) {
+        let alice = create_user(&pool, "alice").await;
+        let contents = format!("scanned {}", uuid::Uuid::new_v4());
+        let uploader = Uploader {
+            id: alice,
+            bytes_remaining: 1024,
+            daily_limit_bytes: 1024,
+        };
+        let scans = || {
+            sqlx::query_scalar!(
+                r#"SELECT COUNT(*) AS "count!" FROM jobs WHERE job_type = 'upload_scan'"#
+            )
+            .fetch_one(&pool)
+        };
+
+        let mut conn = pool.acquire().await.unwrap();
+        let mut tx = conn.begin().await.unwrap();
+        let (staged, _) = stream_to_file(
+            "a.txt",
+            &uploader,
+            uploader.bytes_remaining,
+            futures::stream::iter([Ok::<_, io::Error>(Bytes::from(contents.clone()))]),
+            &mut tx,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(scans().await.unwrap(), 0);
+
+        let hash = staged.hash.clone();
+        persist_uploads(&mut conn, vec![staged]).await.unwrap();
+        assert_eq!(scans().await.unwrap(), 1);
+        assert!(blob_path(&hash).exists());
+    }
+}
@@ -0,0 +1,430 @@
+//! Resumable uploads, loosely following the tus protocol (https://tus.io).
+//!
+//! 1. `POST /sessions` with the file name and its total length opens a session.
+//! 2. `PATCH /sessions/:id` appends a chunk. Its `Upload-Offset` header must match the number of
+//!    bytes received so far.
+//! 3. `HEAD /sessions/:id` reports the current offset, so clients can pick up where they left off
+//!    after a dropped connection.
+//! 4. `POST /sessions/:id/finalize` stores the complete file like any other upload.
+//!
+//! Sessions live in Postgres, so they survive restarts. They expire with the daily quota window.
+//! Their full length counts against the quota as soon as they're opened. Expired sessions and the
+//! bytes they received are removed by `PurgeExpiredUploadSessions`.
+use anyhow::Context;
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{Path, State},
+    http::{header::CACHE_CONTROL, HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use sqlx::{Acquire, PgConnection, PgPool};
+use std::{io::SeekFrom, path::PathBuf, time::Duration};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncSeekExt, BufWriter},
+};
+
+use super::{
+    copy_limited, path_is_valid, persist_uploads, record_upload, record_usage,
+    remove_file_if_exists, scan::ScanUpload, temp_directory, StagedUpload,
+};
+use crate::{
+    error::ApiError,
+    extractors::{AuthUser, DatabaseConnection, Uploader},
+    queue::{Job, JobContext},
+    state::AppState,
+};
+
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+
+/// How long a chunk may take. The session is claimed for that long, and the chunk is cut off
+/// once it runs out.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(serde::Deserialize)]
+pub struct NewSession {
+    file_name: String,
+    length: u64,
+}
+
+#[derive(serde::Serialize)]
+pub struct SessionCreated {
+    id: uuid::Uuid,
+}
+
+struct UploadSession {
+    bytes_total: i32,
+    bytes_received: i32,
+}
+
+pub async fn create_session(
+    uploader: Uploader,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Json(session): Json<NewSession>,
+) -> Result<(StatusCode, Json<SessionCreated>), ApiError> {
+    if !path_is_valid(&session.file_name) || session.length == 0 {
+        return Err(ApiError::BadRequest);
+    }
+    if session.length > uploader.bytes_remaining {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    let bytes_total =
+        i32::try_from(session.length).context("Upload is too large for the uploads table")?;
+
+    let mut tx = conn.begin().await?;
+    // The whole length is counted right away, whether or not the upload is ever finished.
+    record_usage(&mut tx, &uploader, session.length).await?;
+    let id = sqlx::query_scalar!(
+        r#"
+        INSERT INTO upload_sessions (uploader_id, file_name, bytes_total)
+        VALUES ($1, $2, $3)
+        RETURNING id
+        "#,
+        uploader.id,
+        session.file_name,
+        bytes_total
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok((StatusCode::CREATED, Json(SessionCreated { id })))
+}
+
+pub async fn session_offset(
+    auth_user: AuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<Response, ApiError> {
+    let session = sqlx::query_as!(
+        UploadSession,
+        r#"
+        SELECT bytes_total, bytes_received FROM upload_sessions
+        WHERE id = $1 AND uploader_id = $2
+            AND created_at > current_timestamp - INTERVAL '1 days'
+        "#,
+        id,
+        *auth_user
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    Ok((
+        [
+            (UPLOAD_OFFSET, session.bytes_received.to_string()),
+            (UPLOAD_LENGTH, session.bytes_total.to_string()),
+            (CACHE_CONTROL, String::from("no-store")),
+        ],
+        (),
+    )
+        .into_response())
+}
+
+/// Appends a chunk. The session is claimed while the chunk is written, without holding a
+/// connection, so concurrent requests for the same session can't interleave.
+pub async fn append_chunk(
+    auth_user: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<uuid::Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let offset = headers
+        .get(UPLOAD_OFFSET)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i32>().ok())
+        .ok_or(ApiError::BadRequest)?;
+
+    let (session, claimed_until) = claim_session(&state.db_pool, id, *auth_user).await?;
+    let written = if offset == session.bytes_received {
+        tokio::time::timeout(CHUNK_TIMEOUT, write_chunk(id, &session, body))
+            .await
+            .unwrap_or(Err(ApiError::BadRequest))
+    } else {
+        Err(ApiError::Conflict)
+    };
+    let bytes_received = match &written {
+        Ok(bytes_copied) => session.bytes_received + *bytes_copied as i32,
+        Err(_) => session.bytes_received,
+    };
+
+    // Releases the claim, and records the new offset if the chunk was written.
+    sqlx::query!(
+        r#"
+        UPDATE upload_sessions SET bytes_received = $3, writing_until = NULL
+        WHERE id = $1 AND writing_until = $2
+        "#,
+        id,
+        claimed_until,
+        bytes_received
+    )
+    .execute(&state.db_pool)
+    .await?;
+    written?;
+
+    Ok((
+        StatusCode::NO_CONTENT,
+        [(UPLOAD_OFFSET, bytes_received.to_string())],
+    )
+        .into_response())
+}
+
+/// Claims a session for writing a chunk until the returned time.
+async fn claim_session(
+    pool: &PgPool,
+    id: uuid::Uuid,
+    user_id: uuid::Uuid,
+) -> Result<(UploadSession, DateTime<Utc>), ApiError> {
+    let claimed = sqlx::query!(
+        r#"
+        UPDATE upload_sessions SET writing_until = NOW() + make_interval(secs => $3)
+        WHERE id = $1 AND uploader_id = $2
+            AND created_at > current_timestamp - INTERVAL '1 days'
+            AND (writing_until IS NULL OR writing_until < NOW())
+        RETURNING bytes_total, bytes_received, writing_until AS "writing_until!"
+        "#,
+        id,
+        user_id,
+        // A little longer than the chunk may take, so the claim can't run out while it's written.
+        (CHUNK_TIMEOUT + Duration::from_secs(30)).as_secs_f64()
+    )
+    .fetch_optional(pool)
+    .await?;
+    if let Some(claimed) = claimed {
+        let session = UploadSession {
+            bytes_total: claimed.bytes_total,
+            bytes_received: claimed.bytes_received,
+        };
+        return Ok((session, claimed.writing_until));
+    }
+
+    // Either there's no such session, or another chunk is being written to it.
+    let exists = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM upload_sessions
+            WHERE id = $1 AND uploader_id = $2
+                AND created_at > current_timestamp - INTERVAL '1 days'
+        ) AS "exists!"
+        "#,
+        id,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Err(if exists {
+        ApiError::Conflict
+    } else {
+        ApiError::NotFound
+    })
+}
+
+/// Writes a chunk at the end of the bytes the session has received, and returns its length.
+async fn write_chunk(id: uuid::Uuid, session: &UploadSession, body: Body) -> Result<u64, ApiError> {
+    let offset = session.bytes_received;
+    if let Err(e) = tokio::fs::create_dir_all(temp_directory()).await {
+        tracing::error!(
+            "Upload directory doesn't exist and couldn't be created: {:?}",
+            e
+        );
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(session_path(id))
+        .await
+        .context("Failed to open the upload session file")?;
+
+    // A chunk that was interrupted halfway may have left bytes past the recorded offset behind.
+    file.set_len(offset as u64)
+        .await
+        .context("Failed to truncate the upload session file")?;
+    file.seek(SeekFrom::End(0))
+        .await
+        .context("Failed to seek in the upload session file")?;
+
+    let bytes_left = (session.bytes_total - session.bytes_received) as u64;
+    let mut file = BufWriter::new(file);
+    copy_limited(body.into_data_stream(), &mut file, bytes_left, |_| {}).await
+}
+
+pub async fn finalize(
+    auth_user: AuthUser,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(id): Path<uuid::Uuid>,
+) -> Result<(), ApiError> {
+    let mut tx = conn.begin().await?;
+    let session = lock_session(&mut tx, id, *auth_user).await?;
+
+    if session.bytes_received != session.bytes_total || session.writing {
+        return Err(ApiError::Conflict);
+    }
+
+    let temp_path = session_path(id);
+    let hash = hash_file(temp_path.clone()).await?;
+
+    sqlx::query!("DELETE FROM upload_sessions WHERE id = $1", id)
+        .execute(&mut *tx)
+        .await?;
+
+    let orphan = record_upload(
+        &mut tx,
+        *auth_user,
+        &session.file_name,
+        &hash,
+        session.bytes_total as u64,
+    )
+    .await?;
+
+    // Until the session is deleted, the received bytes are kept so finalizing can be retried.
+    tx.commit().await?;
+
+    // Nothing refers to the file once the session is gone, so it's removed if it can't be moved
+    // into place.
+    let staged = StagedUpload {
+        temp_path,
+        hash,
+        scan: ScanUpload {
+            uploader_id: *auth_user,
+            file_name: session.file_name,
+        },
+        orphan,
+        remove_on_drop: true,
+    };
+    persist_uploads(&mut conn, vec![staged]).await
+}
+
+/// A session that's about to be finalized.
+struct LockedSession {
+    file_name: String,
+    bytes_total: i32,
+    bytes_received: i32,
+    /// Whether a chunk is being written to it.
+    writing: bool,
+}
+
+async fn lock_session(
+    conn: &mut PgConnection,
+    id: uuid::Uuid,
+    user_id: uuid::Uuid,
+) -> Result<LockedSession, ApiError> {
+    sqlx::query_as!(
+        LockedSession,
+        r#"
+        SELECT file_name, bytes_total, bytes_received,
+            COALESCE(writing_until > NOW(), FALSE) AS "writing!"
+        FROM upload_sessions
+        WHERE id = $1 AND uploader_id = $2
+            AND created_at > current_timestamp - INTERVAL '1 days'
+        FOR UPDATE
+        "#,
+        id,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(ApiError::NotFound)
+}
+
+fn session_path(id: uuid::Uuid) -> PathBuf {
+    temp_directory().join(format!("{id}.part"))
+}
+
+async fn hash_file(path: PathBuf) -> Result<String, ApiError> {
+    let hash = crate::utils::spawn_blocking_with_tracing(move || {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = blake3::Hasher::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok::<_, std::io::Error>(hasher.finalize().to_hex().to_string())
+    })
+    .await
+    .context("Failed to hash the uploaded file")?
+    .context("Failed to read the uploaded file")?;
+    Ok(hash)
+}
+
+/// Deletes upload sessions that expired before they were finalized, along with their files.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PurgeExpiredUploadSessions;
+
+#[async_trait]
+impl Job for PurgeExpiredUploadSessions {
+    const JOB_TYPE: &'static str = "purge_expired_upload_sessions";
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        // Sessions that a chunk is still being written to are deleted once it's done.
+        let expired = sqlx::query_scalar!(
+            r#"
+            DELETE FROM upload_sessions
+            WHERE created_at <= current_timestamp - INTERVAL '1 days'
+                AND (writing_until IS NULL OR writing_until < NOW())
+            RETURNING id
+            "#
+        )
+        .fetch_all(&ctx.pool)
+        .await?;
+        for id in &expired {
+            remove_file_if_exists(&session_path(*id)).await;
+        }
+        tracing::info!("Purged {} expired upload sessions", expired.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::create_user;
+
+    async fn open_session(pool: &PgPool, user_id: uuid::Uuid) -> uuid::Uuid {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO upload_sessions (uploader_id, file_name, bytes_total)
+            VALUES ($1, 'a.txt', 10)
+            RETURNING id
+            "#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn one_chunk_at_a_time_can_claim_a_session(pool: PgPool) {
+        let alice = create_user(&pool, "alice").await;
+        let bob = create_user(&pool, "bob").await;
+        let id = open_session(&pool, alice).await;
+
+        let (session, _) = claim_session(&pool, id, alice).await.unwrap();
+        assert_eq!((session.bytes_received, session.bytes_total), (0, 10));
+        assert!(matches!(
+            claim_session(&pool, id, alice).await,
+            Err(ApiError::Conflict)
+        ));
+        assert!(matches!(
+            claim_session(&pool, id, bob).await,
+            Err(ApiError::NotFound)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn claims_run_out(pool: PgPool) {
+        let alice = create_user(&pool, "alice").await;
+        let id = open_session(&pool, alice).await;
+
+        claim_session(&pool, id, alice).await.unwrap();
+        // As if the chunk's request had died without releasing it.
+        sqlx::query!("UPDATE upload_sessions SET writing_until = NOW() - INTERVAL '1 second'",)
+            .execute(&pool)
+            .await
+            .unwrap();
+        claim_session(&pool, id, alice).await.unwrap();
+    }
+}
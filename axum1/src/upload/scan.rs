@@ -0,0 +1,299 @@
+use anyhow::Context;
+use axum::async_trait;
+use std::{path::PathBuf, time::Duration};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+
+use super::blob_path;
+use crate::queue::{Job, JobContext};
+
+/// Chunk size used when streaming files to clamd. It has to stay below clamd's `StreamMaxLength`.
+const CLAMAV_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(sqlx::Type, Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[sqlx(rename_all = "snake_case", type_name = "scan_status")]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    Pending,
+    Clean,
+    Quarantined,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    /// Contains the name of the signature that matched.
+    Infected(String),
+}
+
+impl ScanVerdict {
+    pub fn status(&self) -> ScanStatus {
+        match self {
+            Self::Clean => ScanStatus::Clean,
+            Self::Infected(_) => ScanStatus::Quarantined,
+        }
+    }
+}
+
+/// Inspects finalized uploads before they become visible.
+///
+/// An `Err` means the file couldn't be scanned at all, in which case the upload stays pending.
+#[async_trait]
+pub trait Scanner: Send + Sync {
+    async fn scan(&self, path: &std::path::Path) -> anyhow::Result<ScanVerdict>;
+}
+
+/// Accepts everything. This is the default when no scanner is configured.
+pub struct NoopScanner;
+
+#[async_trait]
+impl Scanner for NoopScanner {
+    async fn scan(&self, _path: &std::path::Path) -> anyhow::Result<ScanVerdict> {
+        Ok(ScanVerdict::Clean)
+    }
+}
+
+/// Streams files to a clamd daemon over its local Unix socket using the `INSTREAM` command.
+pub struct ClamAvScanner {
+    socket_path: PathBuf,
+    timeout: Duration,
+}
+
+impl ClamAvScanner {
+    pub fn new(socket_path: impl Into<PathBuf>, timeout: Duration) -> Self {
+        Self {
+            socket_path: socket_path.into(),
+            timeout,
+        }
+    }
+
+    async fn instream(&self, path: &std::path::Path) -> anyhow::Result<ScanVerdict> {
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("Failed to open {path:?} for scanning"))?;
+        let mut socket = UnixStream::connect(&self.socket_path)
+            .await
+            .context("Failed to connect to clamd")?;
+
+        socket.write_all(b"zINSTREAM\0").await?;
+
+        // The stream is sent as chunks prefixed by their length, and terminated by an empty chunk.
+        let mut buf = vec![0; CLAMAV_CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            socket.write_all(&(n as u32).to_be_bytes()).await?;
+            socket.write_all(&buf[..n]).await?;
+        }
+        socket.write_all(&0u32.to_be_bytes()).await?;
+
+        let mut response = Vec::new();
+        socket.read_to_end(&mut response).await?;
+        parse_clamd_response(&String::from_utf8_lossy(&response))
+    }
+}
+
+#[async_trait]
+impl Scanner for ClamAvScanner {
+    async fn scan(&self, path: &std::path::Path) -> anyhow::Result<ScanVerdict> {
+        tokio::time::timeout(self.timeout, self.instream(path))
+            .await
+            .context("clamd did not answer in time")?
+    }
+}
+
+// clamd answers with `stream: OK`, `stream: <signature> FOUND` or `<reason> ERROR`.
+fn parse_clamd_response(response: &str) -> anyhow::Result<ScanVerdict> {
+    let response = response.trim_end_matches('\0').trim();
+    let result = response
+        .strip_prefix("stream: ")
+        .ok_or_else(|| anyhow::anyhow!("unexpected clamd response: {response}"))?;
+
+    if result == "OK" {
+        Ok(ScanVerdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(ScanVerdict::Infected(signature.to_owned()))
+    } else {
+        anyhow::bail!("clamd failed to scan: {result}")
+    }
+}
+
+/// Scans a finalized upload, and marks it either clean or quarantined.
+///
+/// If the file can't be scanned, or isn't in place yet, the job fails and the upload stays
+/// pending.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ScanUpload {
+    pub uploader_id: uuid::Uuid,
+    pub file_name: String,
+}
+
+#[async_trait]
+impl Job for ScanUpload {
+    const JOB_TYPE: &'static str = "upload_scan";
+
+    async fn run(self, ctx: &JobContext) -> anyhow::Result<()> {
+        let upload = sqlx::query!(
+            "SELECT blob_hash FROM uploads WHERE uploader_id = $1 AND file_name = $2",
+            self.uploader_id,
+            self.file_name
+        )
+        .fetch_optional(&ctx.pool)
+        .await?;
+
+        // The upload has been deleted since.
+        let Some(upload) = upload else {
+            return Ok(());
+        };
+        let hash = upload
+            .blob_hash
+            .ok_or_else(|| anyhow::anyhow!("upload has no blob to scan"))?;
+
+        // Never let a scanner clear a file it couldn't look at.
+        let path = blob_path(&hash);
+        anyhow::ensure!(
+            tokio::fs::try_exists(&path).await?,
+            "The blob of {} isn't in place yet",
+            self.file_name
+        );
+        let verdict = ctx.scanner.scan(&path).await?;
+        if let ScanVerdict::Infected(signature) = &verdict {
+            tracing::warn!(%signature, file_name = %self.file_name, "Quarantined an infected upload");
+        }
+
+        // Only touch the row if it still has the contents that were scanned.
+        sqlx::query!(
+            r#"
+            UPDATE uploads SET scan_status = $4
+            WHERE uploader_id = $1 AND file_name = $2 AND blob_hash = $3
+            "#,
+            self.uploader_id,
+            self.file_name,
+            hash,
+            verdict.status() as _,
+        )
+        .execute(&ctx.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::testing::{create_user, job_context};
+
+    struct FindsSignature(&'static str);
+
+    #[async_trait]
+    impl Scanner for FindsSignature {
+        async fn scan(&self, _path: &std::path::Path) -> anyhow::Result<ScanVerdict> {
+            Ok(ScanVerdict::Infected(self.0.to_owned()))
+        }
+    }
+
+    /// Records a pending upload of `contents`, and returns its hash.
+    async fn pending_upload(pool: &PgPool, user_id: uuid::Uuid, contents: &str) -> String {
+        let hash = blake3::hash(contents.as_bytes()).to_hex().to_string();
+        sqlx::query!(
+            "INSERT INTO upload_blobs (hash, bytes, ref_count) VALUES ($1, $2, 1)",
+            hash,
+            contents.len() as i32
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            r#"
+            INSERT INTO uploads (uploader_id, bytes, file_name, blob_hash)
+            VALUES ($1, $2, 'a.txt', $3)
+            "#,
+            user_id,
+            contents.len() as i32,
+            hash
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+        hash
+    }
+
+    fn put_blob(hash: &str, contents: &str) {
+        let path = blob_path(hash);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    async fn scan_status(pool: &PgPool, user_id: uuid::Uuid) -> ScanStatus {
+        sqlx::query_scalar!(
+            r#"SELECT scan_status AS "scan_status: ScanStatus" FROM uploads WHERE uploader_id = $1"#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    fn job(uploader_id: uuid::Uuid) -> ScanUpload {
+        ScanUpload {
+            uploader_id,
+            file_name: "a.txt".to_owned(),
+        }
+    }
+
+    #[sqlx::test]
+    async fn clean_uploads_become_visible(pool: PgPool) {
+        let alice = create_user(&pool, "alice").await;
+        let contents = format!("clean {}", uuid::Uuid::new_v4());
+        let hash = pending_upload(&pool, alice, &contents).await;
+        put_blob(&hash, &contents);
+
+        let ctx = job_context(pool.clone(), Arc::new(NoopScanner));
+        job(alice).run(&ctx).await.unwrap();
+        assert_eq!(scan_status(&pool, alice).await, ScanStatus::Clean);
+    }
+
+    #[sqlx::test]
+    async fn infected_uploads_are_quarantined(pool: PgPool) {
+        let alice = create_user(&pool, "alice").await;
+        let contents = format!("infected {}", uuid::Uuid::new_v4());
+        let hash = pending_upload(&pool, alice, &contents).await;
+        put_blob(&hash, &contents);
+
+        let ctx = job_context(pool.clone(), Arc::new(FindsSignature("Eicar-Signature")));
+        job(alice).run(&ctx).await.unwrap();
+        assert_eq!(scan_status(&pool, alice).await, ScanStatus::Quarantined);
+    }
+
+    #[sqlx::test]
+    async fn uploads_without_their_blob_stay_pending(pool: PgPool) {
+        let alice = create_user(&pool, "alice").await;
+        pending_upload(&pool, alice, &format!("missing {}", uuid::Uuid::new_v4())).await;
+
+        let ctx = job_context(pool.clone(), Arc::new(NoopScanner));
+        assert!(job(alice).run(&ctx).await.is_err());
+        assert_eq!(scan_status(&pool, alice).await, ScanStatus::Pending);
+    }
+
+    #[test]
+    fn parses_clamd_responses() {
+        assert_eq!(
+            parse_clamd_response("stream: OK\0").unwrap(),
+            ScanVerdict::Clean
+        );
+        assert_eq!(
+            parse_clamd_response("stream: Eicar-Signature FOUND\0").unwrap(),
+            ScanVerdict::Infected("Eicar-Signature".to_owned())
+        );
+        assert!(parse_clamd_response("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+}
@@ -1,9 +1,10 @@
-use oauth2::{
-    basic::BasicClient, AuthUrl, ClientId, ClientSecret, RedirectUrl, RevocationUrl, TokenUrl,
-};
 use tokio::task::{JoinError, JoinHandle};
+use webauthn_rs::prelude::{Url, Webauthn, WebauthnBuilder};
 
-use std::fmt::{Debug, Display};
+use std::{
+    fmt::{Debug, Display},
+    sync::Arc,
+};
 
 use crate::config::Settings;
 
@@ -88,52 +89,25 @@ pub async fn shutdown_signal() {
     tracing::warn!("signal received, starting graceful shutdown");
 }
 
-pub fn oauth_client_discord(config: &Settings) -> DiscordOAuthClient {
-    let client_id = config.oauth.discord.client_id.clone();
-    let client_secret = config.oauth.discord.client_secret.clone();
-    let redirect_url = config.oauth.discord.redirect_url.clone();
-    let auth_url = config.oauth.discord.auth_url.clone();
-    let token_url = config.oauth.discord.token_url.clone();
-    let revocation_url = config.oauth.discord.revocation_url.clone();
-
-    DiscordOAuthClient(
-        BasicClient::new(
-            ClientId::new(client_id),
-            Some(ClientSecret::new(client_secret)),
-            AuthUrl::new(auth_url).unwrap(),
-            Some(TokenUrl::new(token_url).unwrap()),
-        )
-        .set_redirect_uri(RedirectUrl::new(redirect_url).unwrap())
-        .set_revocation_uri(
-            RevocationUrl::new(revocation_url).expect("Invalid revocation endpoint URL"),
-        ),
-    )
-}
-
-pub fn oauth_client_google(config: &Settings) -> GoogleOAuthClient {
-    let client_id = config.oauth.google.client_id.clone();
-    let client_secret = config.oauth.google.client_secret.clone();
-    let redirect_url = config.oauth.google.redirect_url.clone();
-    let auth_url = config.oauth.google.auth_url.clone();
-    let token_url = config.oauth.google.token_url.clone();
-    let revocation_url = config.oauth.google.revocation_url.clone();
+pub fn webauthn(config: &Settings) -> Arc<Webauthn> {
+    let origin = config
+        .webauthn
+        .origin
+        .as_deref()
+        .unwrap_or(&config.frontend_url);
+    let origin = Url::parse(origin).expect("Invalid WebAuthn origin");
+    let rp_id = match &config.webauthn.rp_id {
+        Some(rp_id) => rp_id.clone(),
+        None => origin
+            .host_str()
+            .expect("WebAuthn origin has no host")
+            .to_owned(),
+    };
 
-    GoogleOAuthClient(
-        BasicClient::new(
-            ClientId::new(client_id),
-            Some(ClientSecret::new(client_secret)),
-            AuthUrl::new(auth_url).unwrap(),
-            Some(TokenUrl::new(token_url).unwrap()),
-        )
-        .set_redirect_uri(RedirectUrl::new(redirect_url).unwrap())
-        .set_revocation_uri(
-            RevocationUrl::new(revocation_url).expect("Invalid revocation endpoint URL"),
-        ),
-    )
+    let webauthn = WebauthnBuilder::new(&rp_id, &origin)
+        .expect("Invalid WebAuthn relying party")
+        .rp_name(&config.webauthn.rp_name)
+        .build()
+        .expect("Invalid WebAuthn configuration");
+    Arc::new(webauthn)
 }
-
-#[derive(Clone, Debug)]
-pub struct GoogleOAuthClient(pub BasicClient);
-
-#[derive(Clone, Debug)]
-pub struct DiscordOAuthClient(pub BasicClient);
@@ -0,0 +1,102 @@
+import { CloseIcon } from '@chakra-ui/icons';
+import {
+  Box,
+  Center,
+  CircularProgress,
+  Flex,
+  Heading,
+  VStack,
+  Text,
+  Link,
+  Stack,
+} from '@chakra-ui/react';
+import { useRouter } from 'next/router';
+import { useEffect, useState } from 'react';
+import { useSWRConfig } from 'swr';
+import { Layout } from './layout';
+import NextLink from 'next/link';
+
+// Where providers send the user back to, it hands the code over to `/auth/oauth/:provider/authorize`.
+export const OAuthCallback = ({
+  provider,
+  displayName,
+}: {
+  provider: string;
+  displayName: string;
+}) => {
+  const router = useRouter();
+  const { mutate } = useSWRConfig();
+  const { code, state, error: providerError } = router.query;
+  const [error, setError] = useState<{ isError: boolean; message?: string }>({ isError: false });
+  useEffect(() => {
+    if (providerError) {
+      setError({ isError: true });
+      return;
+    }
+    if (!state || !code) return;
+    const params = new URLSearchParams({ code: String(code), state: String(state) });
+    fetch(`${process.env.NEXT_PUBLIC_BASE_URL}/auth/oauth/${provider}/authorize?${params}`, {
+      credentials: 'include',
+    }).then(async (r) => {
+      if (r.ok) {
+        mutate(`${process.env.NEXT_PUBLIC_BASE_URL}/me`);
+        router.push('/');
+      } else if (r.status === 422) {
+        const { errors } = await r.json();
+        const messages = Object.values(errors ?? {}).flat() as string[];
+        setError({ isError: true, message: messages[0] });
+      } else {
+        setError({ isError: true });
+      }
+    });
+  }, [router, code, state, mutate, providerError, provider]);
+
+  if (error.isError) {
+    return (
+      <Layout>
+        <Center mt="14">
+          <Box display="inline-block">
+            <Flex
+              flexDirection="column"
+              justifyContent="center"
+              alignItems="center"
+              bg={'red.500'}
+              rounded={'50px'}
+              w={'55px'}
+              h={'55px'}
+              textAlign="center"
+            >
+              <CloseIcon boxSize={'20px'} color={'white'} />
+            </Flex>
+          </Box>
+          <Stack>
+            <Heading as="h2" size="xl" mt={6} mb={2}>
+              Something went wrong. <br /> Details might be shown below.
+            </Heading>
+            {error.message && (
+              <>
+                <Text fontSize="md">{error.message}</Text>
+                <NextLink href="/login">
+                  <Link mt={4} color={'orange.400'}>
+                    Login with that email instead?
+                  </Link>
+                </NextLink>
+              </>
+            )}
+          </Stack>
+        </Center>
+      </Layout>
+    );
+  }
+
+  return (
+    <Layout>
+      <Center mt="14">
+        <VStack>
+          <Heading>{`Waiting for ${displayName} to authenticate..`}</Heading>
+          <CircularProgress isIndeterminate color="orange.400" />
+        </VStack>
+      </Center>
+    </Layout>
+  );
+};
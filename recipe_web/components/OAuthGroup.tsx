@@ -2,7 +2,7 @@ import { Button, ButtonGroup, VisuallyHidden } from '@chakra-ui/react';
 import { DiscordLogo, GoogleLogo } from './logos';
 
 const OAuthRedirect = async (provider: 'google' | 'discord') => {
-  const res = await fetch(`${process.env.NEXT_PUBLIC_BASE_URL}/auth/${provider}`, {
+  const res = await fetch(`${process.env.NEXT_PUBLIC_BASE_URL}/auth/oauth/${provider}`, {
     credentials: 'include',
   });
   const { uri } = await res.json();
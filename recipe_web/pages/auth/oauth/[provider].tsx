@@ -0,0 +1,14 @@
+import { useRouter } from 'next/router';
+import { OAuthCallback } from '../../../components/OAuthCallback';
+
+const displayNames: Record<string, string> = {
+  google: 'Google',
+  discord: 'Discord',
+};
+
+export default function OAuthAuthorize() {
+  const router = useRouter();
+  const provider = router.query.provider;
+  if (typeof provider !== 'string') return null;
+  return <OAuthCallback provider={provider} displayName={displayNames[provider] ?? provider} />;
+}